@@ -0,0 +1,384 @@
+//! Layout tests of the bindings, computed from the metadata.
+//!
+//! The expected sizes, alignments and field offsets are computed with the rules MSVC uses for
+//! the C headers: every field is aligned to the smaller of its alignment and the packing of
+//! the struct, unions overlay their fields, and the size is rounded up to the alignment.  The
+//! bindings must match them on both 32-bit and 64-bit targets.
+
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::winmd::{Type, TypeDefinition, FIELD_STATIC, TYPE_EXPLICIT_LAYOUT, TYPE_INTERFACE};
+
+/// The namespace and name of a type and its `(size, alignment)` on 32-bit and 64-bit targets.
+type External = (&'static str, &'static str, [(u32, u32); 2]);
+
+/// Layouts of the types the metadata references from the Windows SDK.
+const EXTERNAL: &[External] = &[
+    ("Windows.Win32.Foundation", "BOOL", [(4, 4), (4, 4)]),
+    ("Windows.Win32.Foundation", "FILETIME", [(8, 4), (8, 4)]),
+    ("Windows.Win32.Foundation", "HANDLE", [(4, 4), (8, 8)]),
+    ("Windows.Win32.Foundation", "HRESULT", [(4, 4), (4, 4)]),
+    ("Windows.Win32.Foundation", "PCSTR", [(4, 4), (8, 8)]),
+    ("Windows.Win32.Foundation", "PCWSTR", [(4, 4), (8, 8)]),
+    ("Windows.Win32.Foundation", "PSTR", [(4, 4), (8, 8)]),
+    ("Windows.Win32.Foundation", "PWSTR", [(4, 4), (8, 8)]),
+    (
+        "Windows.Win32.Graphics.Direct3D12",
+        "D3D12_BOX",
+        [(24, 4), (24, 4)],
+    ),
+    (
+        "Windows.Win32.Graphics.Direct3D12",
+        "D3D12_TILED_RESOURCE_COORDINATE",
+        [(16, 4), (16, 4)],
+    ),
+    (
+        "Windows.Win32.Graphics.Direct3D12",
+        "D3D12_TILE_REGION_SIZE",
+        [(16, 4), (16, 4)],
+    ),
+    (
+        "Windows.Win32.Storage.FileSystem",
+        "BY_HANDLE_FILE_INFORMATION",
+        [(52, 4), (52, 4)],
+    ),
+    ("System", "Guid", [(16, 4), (16, 4)]),
+];
+
+/// Pointer widths in bytes, in the order of the layouts.
+const POINTER_WIDTHS: [u32; 2] = [4, 8];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Layout {
+    pub size: u32,
+    pub align: u32,
+}
+
+/// The layout of a type on 32-bit and 64-bit targets.
+#[derive(Clone, Debug)]
+pub struct TypeLayout {
+    /// The name of the type in the bindings.
+    pub name: String,
+    pub layouts: [Layout; 2],
+    /// The names and offsets of the fields.
+    pub fields: Vec<(String, [u32; 2])>,
+}
+
+/// Computes the layout of the structs, unions and enums of `namespace` in a file.
+pub fn layouts(type_defs: &[TypeDefinition], namespace: &str) -> Result<Vec<TypeLayout>, String> {
+    let mut computer = Computer {
+        type_defs,
+        cache: BTreeMap::new(),
+    };
+    let mut layouts = Vec::new();
+    for (index, type_def) in type_defs.iter().enumerate() {
+        if kind(type_def) == Kind::Other || root(type_defs, index).namespace != namespace {
+            continue;
+        }
+        let (layouts_by_width, offsets) = computer.type_def(index)?;
+        let mut fields = Vec::new();
+        if kind(type_def) != Kind::Enum {
+            for (field, offsets) in instance_fields(type_def).zip(offsets) {
+                fields.push((field.name.clone(), offsets));
+            }
+        }
+        layouts.push(TypeLayout {
+            name: rust_name(type_defs, index),
+            layouts: layouts_by_width,
+            fields,
+        });
+    }
+    Ok(layouts)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Struct,
+    Union,
+    Enum,
+    Other,
+}
+
+fn kind(type_def: &TypeDefinition) -> Kind {
+    if type_def.flags & TYPE_INTERFACE != 0 {
+        return Kind::Other;
+    }
+    match type_def
+        .extends
+        .as_ref()
+        .map(|(namespace, name)| (namespace.as_str(), name.as_str()))
+    {
+        Some(("System", "Enum")) => Kind::Enum,
+        Some(("System", "ValueType")) if type_def.flags & TYPE_EXPLICIT_LAYOUT != 0 => Kind::Union,
+        Some(("System", "ValueType")) => Kind::Struct,
+        _ => Kind::Other,
+    }
+}
+
+fn instance_fields(
+    type_def: &TypeDefinition,
+) -> impl Iterator<Item = &crate::winmd::FieldDefinition> {
+    type_def
+        .fields
+        .iter()
+        .filter(|field| field.flags & FIELD_STATIC == 0)
+}
+
+/// The outermost type `index` is nested in.
+pub fn root(type_defs: &[TypeDefinition], mut index: usize) -> &TypeDefinition {
+    while let Some(enclosing) = type_defs[index].enclosing {
+        index = enclosing;
+    }
+    &type_defs[index]
+}
+
+/// The name of a type in the bindings, nested types are numbered within their enclosing type.
+fn rust_name(type_defs: &[TypeDefinition], index: usize) -> String {
+    match type_defs[index].enclosing {
+        None => type_defs[index].name.clone(),
+        Some(enclosing) => {
+            let position = (0..index)
+                .filter(|&other| type_defs[other].enclosing == Some(enclosing))
+                .count();
+            format!("{}_{position}", rust_name(type_defs, enclosing))
+        }
+    }
+}
+
+struct Computer<'a> {
+    type_defs: &'a [TypeDefinition],
+    cache: BTreeMap<usize, ([Layout; 2], Vec<[u32; 2]>)>,
+}
+
+impl Computer<'_> {
+    /// The layouts of a type definition and the offsets of its fields.
+    fn type_def(&mut self, index: usize) -> Result<([Layout; 2], Vec<[u32; 2]>), String> {
+        if let Some(cached) = self.cache.get(&index) {
+            return Ok(cached.clone());
+        }
+        let type_def = &self.type_defs[index];
+        let kind = kind(type_def);
+        let mut layouts = [Layout { size: 0, align: 1 }; 2];
+        let mut offsets = Vec::new();
+        for field in instance_fields(type_def) {
+            let mut field_offsets = [0; 2];
+            for (width, layout) in layouts.iter_mut().enumerate() {
+                let field_layout = self.ty(&field.ty, width)?;
+                let align = match type_def.packing {
+                    Some(packing) => field_layout.align.min(u32::from(packing)),
+                    None => field_layout.align,
+                };
+                let offset = match (kind, field.offset) {
+                    (Kind::Union, _) => 0,
+                    (_, Some(offset)) => offset,
+                    _ => layout.size.next_multiple_of(align),
+                };
+                layout.size = layout.size.max(offset + field_layout.size);
+                layout.align = layout.align.max(align);
+                field_offsets[width] = offset;
+            }
+            offsets.push(field_offsets);
+        }
+        if offsets.is_empty() {
+            return Err(format!("{} has no fields", type_def.name));
+        }
+        for layout in &mut layouts {
+            layout.size = layout.size.next_multiple_of(layout.align);
+        }
+        self.cache.insert(index, (layouts, offsets.clone()));
+        Ok((layouts, offsets))
+    }
+
+    fn ty(&mut self, ty: &Type, width: usize) -> Result<Layout, String> {
+        let primitive = |size| Layout { size, align: size };
+        Ok(match ty {
+            Type::Bool | Type::I8 | Type::U8 => primitive(1),
+            Type::Char | Type::I16 | Type::U16 => primitive(2),
+            Type::I32 | Type::U32 | Type::F32 => primitive(4),
+            Type::I64 | Type::U64 | Type::F64 => primitive(8),
+            Type::ISize | Type::USize | Type::Pointer(_) | Type::Class => {
+                primitive(POINTER_WIDTHS[width])
+            }
+            Type::Array(element, length) => {
+                let element = self.ty(element, width)?;
+                Layout {
+                    size: element.size * length,
+                    align: element.align,
+                }
+            }
+            Type::Def(index) => self.value_type(*index, width)?,
+            Type::Ref { namespace, name } => EXTERNAL
+                .iter()
+                .find(|(n, t, _)| n == namespace && t == name)
+                .map(|(_, _, layouts)| {
+                    let (size, align) = layouts[width];
+                    Layout { size, align }
+                })
+                .ok_or_else(|| format!("no layout of {namespace}.{name}, add it to EXTERNAL"))?,
+        })
+    }
+
+    fn value_type(&mut self, index: usize, width: usize) -> Result<Layout, String> {
+        Ok(match kind(&self.type_defs[index]) {
+            Kind::Struct | Kind::Union | Kind::Enum => self.type_def(index)?.0[width],
+            // Interfaces and delegates are pointers.
+            Kind::Other => Layout {
+                size: POINTER_WIDTHS[width],
+                align: POINTER_WIDTHS[width],
+            },
+        })
+    }
+}
+
+/// Imports and the `layout!` macro of the generated tests.
+const PRELUDE: &str = r#"
+use core::mem::{align_of, size_of, MaybeUninit};
+
+use super::*;
+
+/// Asserts the size and alignment of a type and the offsets of its fields.
+macro_rules! layout {
+    ($ty:ident: $size:literal, $align:literal { $($field:ident: $offset:literal),* $(,)? }) => {
+        assert_eq!(size_of::<$ty>(), $size, concat!("size of ", stringify!($ty)));
+        assert_eq!(align_of::<$ty>(), $align, concat!("alignment of ", stringify!($ty)));
+        $(
+            let value = MaybeUninit::<$ty>::uninit();
+            let base = value.as_ptr();
+            // SAFETY: Only computes the address of the field, nothing is read.
+            let field = unsafe { core::ptr::addr_of!((*base).$field) };
+            assert_eq!(
+                field as usize - base as usize,
+                $offset,
+                concat!("offset of ", stringify!($ty), "::", stringify!($field))
+            );
+        )*
+    };
+}
+"#;
+
+/// Generates the layout tests of the bindings, sorted by name like the bindings.
+pub fn tests(mut layouts: Vec<TypeLayout>, sdk: &str) -> String {
+    layouts.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut source = format!(
+        "// Bindings layout tests generated by `api_gen` for DirectStorage {sdk}, don't edit.\n"
+    );
+    source.push_str(PRELUDE);
+    for (width, bits) in [(0, 32), (1, 64)] {
+        writeln!(
+            source,
+            "\n#[cfg(target_pointer_width = \"{bits}\")]\n#[test]\nfn test_msvc_compat_{bits}bit() {{"
+        )
+        .unwrap();
+        for layout in &layouts {
+            let Layout { size, align } = layout.layouts[width];
+            let fields = layout
+                .fields
+                .iter()
+                .map(|(name, offsets)| format!("{name}: {}", offsets[width]))
+                .collect::<Vec<_>>();
+            let line = format!(
+                "    layout!({}: {size}, {align} {{ {} }});",
+                layout.name,
+                fields.join(", ")
+            )
+            .replace("{  }", "{}");
+            if line.len() <= 100 {
+                writeln!(source, "{line}").unwrap();
+            } else {
+                writeln!(source, "    layout!({}: {size}, {align} {{", layout.name).unwrap();
+                for field in &fields {
+                    writeln!(source, "        {field},").unwrap();
+                }
+                writeln!(source, "    }});").unwrap();
+            }
+        }
+        writeln!(source, "}}").unwrap();
+    }
+    source
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::winmd::FieldDefinition;
+
+    fn field(name: &str, ty: Type) -> FieldDefinition {
+        FieldDefinition {
+            flags: 0,
+            name: name.into(),
+            ty,
+            offset: None,
+            bitfields: Vec::new(),
+        }
+    }
+
+    fn type_def(name: &str, flags: u32, fields: Vec<FieldDefinition>) -> TypeDefinition {
+        TypeDefinition {
+            flags,
+            namespace: "Test".into(),
+            name: name.into(),
+            extends: Some(("System".into(), "ValueType".into())),
+            fields,
+            packing: None,
+            enclosing: None,
+        }
+    }
+
+    #[test]
+    fn test_layouts() {
+        let pointer = || Type::Pointer(Box::new(Type::U8));
+        let mut packed = type_def(
+            "PACKED",
+            0,
+            vec![field("a", Type::U8), field("b", Type::U64)],
+        );
+        packed.packing = Some(2);
+        let mut nested = type_def(
+            "_Anonymous_e__Union",
+            TYPE_EXPLICIT_LAYOUT,
+            vec![field("a", Type::U16), field("b", pointer())],
+        );
+        nested.namespace = String::new();
+        nested.enclosing = Some(0);
+        let type_defs = [
+            type_def(
+                "OUTER",
+                0,
+                vec![
+                    field("a", Type::U8),
+                    field("b", Type::Def(2)),
+                    field("c", Type::Array(Box::new(Type::U16), 3)),
+                ],
+            ),
+            packed,
+            nested,
+        ];
+
+        let layouts = layouts(&type_defs, "Test").unwrap();
+        let names: Vec<_> = layouts.iter().map(|layout| layout.name.as_str()).collect();
+        assert_eq!(names, ["OUTER", "PACKED", "OUTER_0"]);
+
+        let [outer, packed, union] = &layouts[..] else {
+            unreachable!()
+        };
+        let layout = |size, align| Layout { size, align };
+        assert_eq!(outer.layouts, [layout(16, 4), layout(24, 8)]);
+        assert_eq!(outer.fields[1], ("b".into(), [4, 8]));
+        assert_eq!(outer.fields[2], ("c".into(), [8, 16]));
+        assert_eq!(packed.layouts, [layout(10, 2), layout(10, 2)]);
+        assert_eq!(packed.fields[1], ("b".into(), [2, 2]));
+        assert_eq!(union.layouts, [layout(4, 4), layout(8, 8)]);
+        assert_eq!(union.fields[1], ("b".into(), [0, 0]));
+    }
+
+    #[test]
+    fn test_unknown_type() {
+        let ty = Type::Ref {
+            namespace: "Windows.Win32.Foundation".into(),
+            name: "RECT".into(),
+        };
+        let type_defs = [type_def("S", 0, vec![field("a", ty)])];
+        let error = layouts(&type_defs, "Test").unwrap_err();
+        assert!(error.contains("Windows.Win32.Foundation.RECT"));
+    }
+}
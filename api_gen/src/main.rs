@@ -1,5 +1,165 @@
+//! Generates the bindings of DirectStorage and their layout tests from the metadata.
+//!
+//! ```sh
+//! cargo run -p api_gen -- [--sdk <version>] [--check]
+//! ```
+//!
+//! `--sdk` first builds the metadata of that version of the `Microsoft.Direct3D.DirectStorage`
+//! NuGet package with `dotnet build .metadata`, otherwise the metadata in `.windows/winmd` is
+//! used.  `--check` verifies that the checked-in files match what would be generated, instead
+//! of writing them.
+
+mod layout;
+mod postprocess;
+mod winmd;
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{exit, Command, Stdio},
+};
+
 use windows_bindgen::bindgen;
 
+const NAMESPACE: &str = "Microsoft.Direct3D.DirectStorage";
+const WINMD_DIR: &str = ".windows/winmd";
+/// Written by `.metadata/generate.proj` next to the metadata.
+const VERSION_FILE: &str = ".windows/winmd/Microsoft.Direct3D.DirectStorage.version";
+const LAYOUT_TESTS: &str = "src/bindings_layout.rs";
+
+struct Options {
+    sdk: Option<String>,
+    check: bool,
+}
+
 fn main() {
-    bindgen(["--etc", "bindings.txt"]).unwrap()
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|error| {
+        eprintln!("{error}\n\nArguments: [--sdk <version>] [--check]");
+        exit(2);
+    });
+
+    if let Some(sdk) = &options.sdk {
+        let status = Command::new("dotnet")
+            .args([
+                "build",
+                ".metadata",
+                &format!("-p:DirectStorageVersion={sdk}"),
+            ])
+            .status()
+            .expect("Can't run dotnet");
+        assert!(status.success(), "Building the metadata failed");
+    }
+    let sdk = std::fs::read_to_string(VERSION_FILE)
+        .unwrap_or_else(|_| panic!("Missing {VERSION_FILE}, build the metadata or pass --sdk"));
+    let sdk = sdk.trim();
+    if let Some(expected) = &options.sdk {
+        assert_eq!(
+            sdk, expected,
+            "The metadata was built for another SDK version"
+        );
+    }
+
+    let mut type_defs = Vec::new();
+    for entry in std::fs::read_dir(WINMD_DIR).unwrap() {
+        let path = entry.unwrap().path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "winmd")
+        {
+            type_defs.push(winmd::File::read(&path).unwrap().type_defs().unwrap());
+        }
+    }
+
+    // Generate everything into a scratch directory first, so that `--check` doesn't touch the
+    // checked-in files.  It is per process, so that concurrent runs don't overwrite each other.
+    let scratch = std::env::temp_dir().join(format!("api_gen-{}", std::process::id()));
+    std::fs::create_dir_all(&scratch).unwrap();
+    let (bindings_path, generated) = generate_bindings(&scratch.join("bindings.rs"));
+    std::fs::remove_dir_all(&scratch).unwrap();
+    let mut bitfields = Vec::new();
+    let mut layouts = Vec::new();
+    for type_defs in &type_defs {
+        bitfields.extend(postprocess::bitfields(type_defs, NAMESPACE).unwrap());
+        layouts.extend(layout::layouts(type_defs, NAMESPACE).unwrap());
+    }
+    let outputs = [
+        (
+            bindings_path,
+            rustfmt(&postprocess::bindings(&generated, sdk, bitfields).unwrap()),
+        ),
+        (
+            PathBuf::from(LAYOUT_TESTS),
+            rustfmt(&layout::tests(layouts, sdk)),
+        ),
+    ];
+
+    if options.check {
+        let mut stale = false;
+        for (path, contents) in &outputs {
+            if std::fs::read_to_string(path).ok().as_ref() != Some(contents) {
+                eprintln!("{} is out of date", path.display());
+                stale = true;
+            }
+        }
+        if stale {
+            eprintln!("Regenerate the bindings with `cargo run -p api_gen`");
+            exit(1);
+        }
+    } else {
+        for (path, contents) in &outputs {
+            std::fs::write(path, contents).unwrap();
+        }
+    }
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        sdk: None,
+        check: false,
+    };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sdk" => options.sdk = Some(args.next().ok_or("Missing value of --sdk")?),
+            "--check" => options.check = true,
+            _ => return Err(format!("Unknown argument: {arg}")),
+        }
+    }
+    Ok(options)
+}
+
+/// Formats `code` with `rustfmt` and the `rustfmt.toml` of the repository, so that the
+/// generated files pass `cargo fmt --check`.
+fn rustfmt(code: &str) -> String {
+    let mut child = Command::new("rustfmt")
+        .args(["--edition", "2021", "--emit", "stdout"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("Can't run rustfmt");
+    // rustfmt reads all of its input before writing anything.
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(code.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "rustfmt failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs windows-bindgen with `bindings.txt`, writing to `scratch` instead of its `--out`.
+/// Returns the `--out` path and the generated code.
+fn generate_bindings(scratch: &Path) -> (PathBuf, String) {
+    let config = std::fs::read_to_string("bindings.txt").unwrap();
+    let mut args: Vec<&str> = config.split_whitespace().collect();
+    let out = args
+        .iter()
+        .position(|&arg| arg == "--out")
+        .expect("bindings.txt has no --out")
+        + 1;
+    let path = PathBuf::from(args[out]);
+    args[out] = scratch.to_str().unwrap();
+    bindgen(args).unwrap();
+    (path, std::fs::read_to_string(scratch).unwrap())
 }
@@ -0,0 +1,294 @@
+//! Post-processing of the bindings generated by windows-bindgen, so that nothing has to be
+//! patched by hand after regenerating them.
+//!
+//! windows-bindgen only emits the backing fields of C bitfields and can't derive `Debug` on
+//! unions.  This adds [`bitfield!`] invocations generating the accessors of the bitfields
+//! described by the `NativeBitfieldAttribute`s of the metadata, and an opaque `Debug`
+//! implementation to every union.
+
+use crate::{
+    layout::root,
+    winmd::{Bitfield, Type, TypeDefinition},
+};
+
+/// The types of bitfields by struct and bitfield name, which the metadata doesn't keep.  Other
+/// bitfields have the type of their backing field.
+const BITFIELD_TYPES: &[(&str, &str, &str)] = &[
+    (
+        "DSTORAGE_REQUEST_OPTIONS",
+        "CompressionFormat",
+        "DSTORAGE_COMPRESSION_FORMAT",
+    ),
+    (
+        "DSTORAGE_REQUEST_OPTIONS",
+        "SourceType",
+        "DSTORAGE_REQUEST_SOURCE_TYPE",
+    ),
+    (
+        "DSTORAGE_REQUEST_OPTIONS",
+        "DestinationType",
+        "DSTORAGE_REQUEST_DESTINATION_TYPE",
+    ),
+];
+
+/// The bitfields of a struct.
+#[derive(Clone, Debug)]
+pub struct Bitfields {
+    pub name: String,
+    /// The backing fields with their type and bitfields.
+    pub storage: Vec<(String, &'static str, Vec<Accessor>)>,
+}
+
+/// A bitfield and the type of its accessors.
+#[derive(Clone, Debug)]
+pub struct Accessor {
+    pub bitfield: Bitfield,
+    /// A newtype around an integer as `(newtype, integer)`, or the type of the backing field.
+    pub ty: (String, Option<&'static str>),
+}
+
+fn integer(ty: &Type) -> Option<&'static str> {
+    Some(match ty {
+        Type::I8 => "i8",
+        Type::U8 => "u8",
+        Type::I16 => "i16",
+        Type::U16 => "u16",
+        Type::I32 => "i32",
+        Type::U32 => "u32",
+        Type::I64 => "i64",
+        Type::U64 => "u64",
+        _ => return None,
+    })
+}
+
+/// Collects the bitfields of the structs of `namespace` in a file.
+pub fn bitfields(type_defs: &[TypeDefinition], namespace: &str) -> Result<Vec<Bitfields>, String> {
+    let mut structs = Vec::new();
+    for (index, type_def) in type_defs.iter().enumerate() {
+        if root(type_defs, index).namespace != namespace {
+            continue;
+        }
+        let mut storage = Vec::new();
+        for field in &type_def.fields {
+            if field.bitfields.is_empty() {
+                if field.name.starts_with("_bitfield") {
+                    return Err(format!(
+                        "{}::{} has no NativeBitfieldAttribute",
+                        type_def.name, field.name
+                    ));
+                }
+                continue;
+            }
+            let storage_ty = integer(&field.ty)
+                .filter(|ty| ty.starts_with('u'))
+                .ok_or_else(|| format!("{}::{} isn't unsigned", type_def.name, field.name))?;
+            let mut accessors = Vec::new();
+            for bitfield in &field.bitfields {
+                let ty = BITFIELD_TYPES
+                    .iter()
+                    .find(|(s, b, _)| *s == type_def.name && *b == bitfield.name)
+                    .map(|&(_, _, newtype)| {
+                        let inner = type_defs
+                            .iter()
+                            .find(|enum_def| {
+                                enum_def.name == newtype && enum_def.enclosing.is_none()
+                            })
+                            .and_then(|enum_def| {
+                                enum_def.fields.iter().find(|f| f.name == "value__")
+                            })
+                            .and_then(|value| integer(&value.ty))
+                            .ok_or_else(|| format!("{newtype} isn't an enum of {namespace}"))?;
+                        Ok::<_, String>((newtype.to_owned(), Some(inner)))
+                    })
+                    .transpose()?
+                    .unwrap_or((storage_ty.to_owned(), None));
+                accessors.push(Accessor {
+                    bitfield: bitfield.clone(),
+                    ty,
+                });
+            }
+            storage.push((field.name.clone(), storage_ty, accessors));
+        }
+        if !storage.is_empty() {
+            structs.push(Bitfields {
+                name: type_def.name.clone(),
+                storage,
+            });
+        }
+    }
+    Ok(structs)
+}
+
+/// Post-processes the bindings generated for DirectStorage `sdk`.
+pub fn bindings(source: &str, sdk: &str, mut bitfields: Vec<Bitfields>) -> Result<String, String> {
+    let mut lines = source.lines();
+    let mut output = String::new();
+    if let Some(header) = lines.next() {
+        output.push_str(header);
+        output.push('\n');
+    }
+    output.push_str(&format!(
+        "// Post-processed by `api_gen` for DirectStorage {sdk}, don't edit.\n"
+    ));
+
+    // The closing line of the current item and what to insert after it.
+    let mut pending: Option<(String, String)> = None;
+    for line in lines {
+        output.push_str(line);
+        output.push('\n');
+        if let Some((closing, insertion)) = &pending {
+            if line == closing {
+                output.push_str(insertion);
+                pending = None;
+            }
+            continue;
+        }
+        let item = line.trim_start();
+        let indent = &line[..line.len() - item.len()];
+        let closing = format!("{indent}}}");
+        if let Some(name) = item
+            .strip_prefix("pub union ")
+            .and_then(|i| i.strip_suffix(" {"))
+        {
+            pending = Some((closing, debug_impl(name, indent)));
+        } else if let Some(name) = item
+            .strip_prefix("pub struct ")
+            .and_then(|i| i.strip_suffix(" {"))
+        {
+            if let Some(index) = bitfields.iter().position(|b| b.name == name) {
+                pending = Some((closing, invocation(&bitfields.remove(index), indent)));
+            }
+        }
+    }
+    match bitfields.first() {
+        Some(missing) => Err(format!("no struct {} in the bindings", missing.name)),
+        None => Ok(output),
+    }
+}
+
+fn debug_impl(name: &str, indent: &str) -> String {
+    format!(
+        "{indent}impl core::fmt::Debug for {name} {{
+{indent}    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {{
+{indent}        f.debug_struct(\"{name}\").finish_non_exhaustive()
+{indent}    }}
+{indent}}}
+"
+    )
+}
+
+fn invocation(bitfields: &Bitfields, indent: &str) -> String {
+    let mut output = format!(
+        "{indent}bitfield! {{
+{indent}    /// Accessors of the C bitfields packed into the `_bitfield` fields.
+{indent}    impl {} {{
+",
+        bitfields.name
+    );
+    for (storage, storage_ty, accessors) in &bitfields.storage {
+        output.push_str(&format!("{indent}        {storage}: {storage_ty} {{\n"));
+        for Accessor { bitfield, ty } in accessors {
+            let Bitfield {
+                name,
+                offset,
+                length,
+            } = bitfield;
+            let ty = match ty {
+                (newtype, Some(inner)) => format!("{newtype}({inner})"),
+                (ty, None) => ty.clone(),
+            };
+            output.push_str(&format!(
+                "{indent}            {name}, set_{name}: {ty} [{offset}..{}];\n",
+                offset + length
+            ));
+        }
+        output.push_str(&format!("{indent}        }}\n"));
+    }
+    output.push_str(&format!("{indent}    }}\n{indent}}}\n"));
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: &str = "// Bindings generated by `windows-bindgen` 0.63.0
+
+pub mod Test {
+    #[repr(C)]
+    pub struct OPTIONS {
+        pub _bitfield1: u8,
+    }
+    #[repr(C)]
+    pub union VALUE {
+        pub A: u8,
+    }
+}
+";
+
+    #[test]
+    fn test_bindings() {
+        let bitfields = vec![Bitfields {
+            name: "OPTIONS".into(),
+            storage: vec![(
+                "_bitfield1".into(),
+                "u8",
+                vec![
+                    Accessor {
+                        bitfield: Bitfield {
+                            name: "Format".into(),
+                            offset: 0,
+                            length: 2,
+                        },
+                        ty: ("FORMAT".into(), Some("i32")),
+                    },
+                    Accessor {
+                        bitfield: Bitfield {
+                            name: "Reserved".into(),
+                            offset: 2,
+                            length: 6,
+                        },
+                        ty: ("u8".into(), None),
+                    },
+                ],
+            )],
+        }];
+        assert_eq!(
+            bindings(SOURCE, "1.0.0", bitfields.clone()).unwrap(),
+            "// Bindings generated by `windows-bindgen` 0.63.0
+// Post-processed by `api_gen` for DirectStorage 1.0.0, don't edit.
+
+pub mod Test {
+    #[repr(C)]
+    pub struct OPTIONS {
+        pub _bitfield1: u8,
+    }
+    bitfield! {
+        /// Accessors of the C bitfields packed into the `_bitfield` fields.
+        impl OPTIONS {
+            _bitfield1: u8 {
+                Format, set_Format: FORMAT(i32) [0..2];
+                Reserved, set_Reserved: u8 [2..8];
+            }
+        }
+    }
+    #[repr(C)]
+    pub union VALUE {
+        pub A: u8,
+    }
+    impl core::fmt::Debug for VALUE {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            f.debug_struct(\"VALUE\").finish_non_exhaustive()
+        }
+    }
+}
+"
+        );
+
+        let missing = Bitfields {
+            name: "MISSING".into(),
+            ..bitfields[0].clone()
+        };
+        assert!(bindings(SOURCE, "1.0.0", vec![missing]).is_err());
+    }
+}
@@ -0,0 +1,862 @@
+//! A minimal reader for the ECMA-335 metadata in `.winmd` files.
+//!
+//! It only reads what [`crate::layout`] needs to lay out the structs, unions and enums of a
+//! namespace: type definitions, their fields and field signatures, explicit layouts and
+//! nesting.  See ECMA-335 partition II, chapters 22 to 24, for the format.
+
+use std::{collections::HashMap, io, path::Path};
+
+/// Tables of the `#~` stream, in the order of their numbers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Table {
+    Module,
+    TypeRef,
+    TypeDef,
+    FieldPtr,
+    Field,
+    MethodPtr,
+    MethodDef,
+    ParamPtr,
+    Param,
+    InterfaceImpl,
+    MemberRef,
+    Constant,
+    CustomAttribute,
+    FieldMarshal,
+    DeclSecurity,
+    ClassLayout,
+    FieldLayout,
+    StandAloneSig,
+    EventMap,
+    EventPtr,
+    Event,
+    PropertyMap,
+    PropertyPtr,
+    Property,
+    MethodSemantics,
+    MethodImpl,
+    ModuleRef,
+    TypeSpec,
+    ImplMap,
+    FieldRva,
+    EncLog,
+    EncMap,
+    Assembly,
+    AssemblyProcessor,
+    AssemblyOs,
+    AssemblyRef,
+    AssemblyRefProcessor,
+    AssemblyRefOs,
+    File,
+    ExportedType,
+    ManifestResource,
+    NestedClass,
+    GenericParam,
+    MethodSpec,
+    GenericParamConstraint,
+}
+
+const TABLES: usize = Table::GenericParamConstraint as usize + 1;
+
+/// Column types of the tables.
+#[derive(Clone, Copy)]
+enum Column {
+    U16,
+    U32,
+    String,
+    Guid,
+    Blob,
+    Index(Table),
+    /// An index into one of the tables, with the table in the low bits.
+    Coded(&'static [Option<Table>]),
+}
+
+const TYPE_DEF_OR_REF: Column = Column::Coded(&[
+    Some(Table::TypeDef),
+    Some(Table::TypeRef),
+    Some(Table::TypeSpec),
+]);
+const RESOLUTION_SCOPE: Column = Column::Coded(&[
+    Some(Table::Module),
+    Some(Table::ModuleRef),
+    Some(Table::AssemblyRef),
+    Some(Table::TypeRef),
+]);
+const HAS_CUSTOM_ATTRIBUTE: Column = Column::Coded(&[
+    Some(Table::MethodDef),
+    Some(Table::Field),
+    Some(Table::TypeRef),
+    Some(Table::TypeDef),
+    Some(Table::Param),
+    Some(Table::InterfaceImpl),
+    Some(Table::MemberRef),
+    Some(Table::Module),
+    Some(Table::DeclSecurity),
+    Some(Table::Property),
+    Some(Table::Event),
+    Some(Table::StandAloneSig),
+    Some(Table::ModuleRef),
+    Some(Table::TypeSpec),
+    Some(Table::Assembly),
+    Some(Table::AssemblyRef),
+    Some(Table::File),
+    Some(Table::ExportedType),
+    Some(Table::ManifestResource),
+    Some(Table::GenericParam),
+    Some(Table::GenericParamConstraint),
+    Some(Table::MethodSpec),
+]);
+const MEMBER_REF_PARENT: Column = Column::Coded(&[
+    Some(Table::TypeDef),
+    Some(Table::TypeRef),
+    Some(Table::ModuleRef),
+    Some(Table::MethodDef),
+    Some(Table::TypeSpec),
+]);
+const CUSTOM_ATTRIBUTE_TYPE: Column = Column::Coded(&[
+    None,
+    None,
+    Some(Table::MethodDef),
+    Some(Table::MemberRef),
+    None,
+]);
+
+/// The columns of every table, see ECMA-335 II.22.
+fn columns(table: Table) -> &'static [Column] {
+    use Column::*;
+    use Table::*;
+
+    const HAS_CONSTANT: Column = Coded(&[Some(Field), Some(Param), Some(Property)]);
+    const HAS_FIELD_MARSHAL: Column = Coded(&[Some(Field), Some(Param)]);
+    const HAS_DECL_SECURITY: Column = Coded(&[Some(TypeDef), Some(MethodDef), Some(Assembly)]);
+    const HAS_SEMANTICS: Column = Coded(&[Some(Event), Some(Property)]);
+    const METHOD_DEF_OR_REF: Column = Coded(&[Some(MethodDef), Some(MemberRef)]);
+    const MEMBER_FORWARDED: Column = Coded(&[Some(Field), Some(MethodDef)]);
+    const IMPLEMENTATION: Column = Coded(&[Some(File), Some(AssemblyRef), Some(ExportedType)]);
+    const TYPE_OR_METHOD_DEF: Column = Coded(&[Some(TypeDef), Some(MethodDef)]);
+
+    match table {
+        Module => &[U16, String, Guid, Guid, Guid],
+        TypeRef => &[RESOLUTION_SCOPE, String, String],
+        TypeDef => &[
+            U32,
+            String,
+            String,
+            TYPE_DEF_OR_REF,
+            Index(Field),
+            Index(MethodDef),
+        ],
+        FieldPtr => &[Index(Field)],
+        Field => &[U16, String, Blob],
+        MethodPtr => &[Index(MethodDef)],
+        MethodDef => &[U32, U16, U16, String, Blob, Index(Param)],
+        ParamPtr => &[Index(Param)],
+        Param => &[U16, U16, String],
+        InterfaceImpl => &[Index(TypeDef), TYPE_DEF_OR_REF],
+        MemberRef => &[MEMBER_REF_PARENT, String, Blob],
+        // The type is a byte followed by a padding byte.
+        Constant => &[U16, HAS_CONSTANT, Blob],
+        CustomAttribute => &[HAS_CUSTOM_ATTRIBUTE, CUSTOM_ATTRIBUTE_TYPE, Blob],
+        FieldMarshal => &[HAS_FIELD_MARSHAL, Blob],
+        DeclSecurity => &[U16, HAS_DECL_SECURITY, Blob],
+        ClassLayout => &[U16, U32, Index(TypeDef)],
+        FieldLayout => &[U32, Index(Field)],
+        StandAloneSig => &[Blob],
+        EventMap => &[Index(TypeDef), Index(Event)],
+        EventPtr => &[Index(Event)],
+        Event => &[U16, String, TYPE_DEF_OR_REF],
+        PropertyMap => &[Index(TypeDef), Index(Property)],
+        PropertyPtr => &[Index(Property)],
+        Property => &[U16, String, Blob],
+        MethodSemantics => &[U16, Index(MethodDef), HAS_SEMANTICS],
+        MethodImpl => &[Index(TypeDef), METHOD_DEF_OR_REF, METHOD_DEF_OR_REF],
+        ModuleRef => &[String],
+        TypeSpec => &[Blob],
+        ImplMap => &[U16, MEMBER_FORWARDED, String, Index(ModuleRef)],
+        FieldRva => &[U32, Index(Field)],
+        EncLog => &[U32, U32],
+        EncMap => &[U32],
+        Assembly => &[U32, U16, U16, U16, U16, U32, Blob, String, String],
+        AssemblyProcessor => &[U32],
+        AssemblyOs => &[U32, U32, U32],
+        AssemblyRef => &[U16, U16, U16, U16, U32, Blob, String, String, Blob],
+        AssemblyRefProcessor => &[U32, Index(AssemblyRef)],
+        AssemblyRefOs => &[U32, U32, U32, Index(AssemblyRef)],
+        File => &[U32, String, Blob],
+        ExportedType => &[U32, U32, String, String, IMPLEMENTATION],
+        ManifestResource => &[U32, U32, String, IMPLEMENTATION],
+        NestedClass => &[Index(TypeDef), Index(TypeDef)],
+        GenericParam => &[U16, U16, TYPE_OR_METHOD_DEF, String],
+        MethodSpec => &[METHOD_DEF_OR_REF, Blob],
+        GenericParamConstraint => &[Index(GenericParam), TYPE_DEF_OR_REF],
+    }
+}
+
+/// All tables, indexed by their number.
+const ALL_TABLES: [Table; TABLES] = {
+    use Table::*;
+
+    [
+        Module,
+        TypeRef,
+        TypeDef,
+        FieldPtr,
+        Field,
+        MethodPtr,
+        MethodDef,
+        ParamPtr,
+        Param,
+        InterfaceImpl,
+        MemberRef,
+        Constant,
+        CustomAttribute,
+        FieldMarshal,
+        DeclSecurity,
+        ClassLayout,
+        FieldLayout,
+        StandAloneSig,
+        EventMap,
+        EventPtr,
+        Event,
+        PropertyMap,
+        PropertyPtr,
+        Property,
+        MethodSemantics,
+        MethodImpl,
+        ModuleRef,
+        TypeSpec,
+        ImplMap,
+        FieldRva,
+        EncLog,
+        EncMap,
+        Assembly,
+        AssemblyProcessor,
+        AssemblyOs,
+        AssemblyRef,
+        AssemblyRefProcessor,
+        AssemblyRefOs,
+        File,
+        ExportedType,
+        ManifestResource,
+        NestedClass,
+        GenericParam,
+        MethodSpec,
+        GenericParamConstraint,
+    ]
+};
+
+#[derive(Clone, Default)]
+struct TableData {
+    offset: usize,
+    rows: u32,
+    row_size: usize,
+    /// Offset and width of each column within a row.
+    columns: Vec<(usize, usize)>,
+}
+
+/// Flags of a [`TypeDefinition`].
+pub const TYPE_EXPLICIT_LAYOUT: u32 = 0x10;
+pub const TYPE_INTERFACE: u32 = 0x20;
+/// Flags of a [`FieldDefinition`].
+pub const FIELD_STATIC: u16 = 0x10;
+
+/// A type used in a field signature.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Type {
+    Bool,
+    Char,
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64,
+    F32,
+    F64,
+    ISize,
+    USize,
+    Pointer(Box<Type>),
+    /// A fixed size array.
+    Array(Box<Type>, u32),
+    /// A value type, by its index into [`File::type_defs()`].
+    Def(usize),
+    /// A value type defined in another file.
+    Ref {
+        namespace: String,
+        name: String,
+    },
+    /// A reference type, such as an interface or delegate, which are pointers in C.
+    Class,
+}
+
+#[derive(Clone, Debug)]
+pub struct FieldDefinition {
+    pub flags: u16,
+    pub name: String,
+    pub ty: Type,
+    /// Offset of explicit layouts.
+    pub offset: Option<u32>,
+    /// The bitfields packed into this field.
+    pub bitfields: Vec<Bitfield>,
+}
+
+/// A C bitfield, from the `NativeBitfieldAttribute` of its backing field.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bitfield {
+    pub name: String,
+    /// The first bit.
+    pub offset: u32,
+    /// The number of bits.
+    pub length: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct TypeDefinition {
+    pub flags: u32,
+    pub namespace: String,
+    pub name: String,
+    /// The namespace and name of the base type.
+    pub extends: Option<(String, String)>,
+    pub fields: Vec<FieldDefinition>,
+    /// `#pragma pack` of the type, if any.
+    pub packing: Option<u16>,
+    /// Index of the type it is nested in.
+    pub enclosing: Option<usize>,
+}
+
+/// Type definitions by the type they are nested in, namespace and name.
+type Names = HashMap<(Option<usize>, String, String), usize>;
+
+/// A `.winmd` file.
+pub struct File {
+    bytes: Vec<u8>,
+    strings: usize,
+    blobs: usize,
+    tables: Vec<TableData>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl File {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        Self::parse(std::fs::read(path)?)
+    }
+
+    pub fn parse(bytes: Vec<u8>) -> io::Result<Self> {
+        let mut file = Self {
+            bytes,
+            strings: 0,
+            blobs: 0,
+            tables: vec![TableData::default(); TABLES],
+        };
+
+        // PE headers, ECMA-335 II.25.
+        let pe = file.u32(0x3C)? as usize;
+        if file.u32(pe)? != 0x4550 {
+            return Err(invalid("not a PE file"));
+        }
+        let sections = file.u16(pe + 6)? as usize;
+        let optional = pe + 24;
+        let directories = match file.u16(optional)? {
+            0x10B => optional + 96,
+            0x20B => optional + 112,
+            _ => return Err(invalid("unknown optional header")),
+        };
+        let section_table = optional + file.u16(pe + 20)? as usize;
+        let rva_to_offset = |file: &Self, rva: u32| -> io::Result<usize> {
+            for i in 0..sections {
+                let section = section_table + i * 40;
+                let address = file.u32(section + 12)?;
+                let size = file.u32(section + 16)?.max(file.u32(section + 8)?);
+                if (address..address + size).contains(&rva) {
+                    return Ok((rva - address + file.u32(section + 20)?) as usize);
+                }
+            }
+            Err(invalid("address outside of the sections"))
+        };
+        // The CLI header is the 15th data directory.
+        let cli = rva_to_offset(&file, file.u32(directories + 14 * 8)?)?;
+        let metadata = rva_to_offset(&file, file.u32(cli + 8)?)?;
+
+        // Metadata root, ECMA-335 II.24.2.1.
+        if file.u32(metadata)? != 0x424A5342 {
+            return Err(invalid("no metadata signature"));
+        }
+        let version_length = file.u32(metadata + 12)? as usize;
+        let streams = metadata + 16 + version_length;
+        let mut header = streams + 4;
+        let mut tables = None;
+        for _ in 0..file.u16(streams + 2)? {
+            let offset = metadata + file.u32(header)? as usize;
+            let name_start = header + 8;
+            let name_end = file.bytes[name_start..]
+                .iter()
+                .position(|&b| b == 0)
+                .map(|n| name_start + n)
+                .ok_or_else(|| invalid("unterminated stream name"))?;
+            match &file.bytes[name_start..name_end] {
+                b"#Strings" => file.strings = offset,
+                b"#Blob" => file.blobs = offset,
+                b"#~" => tables = Some(offset),
+                _ => {}
+            }
+            header = name_start + (name_end - name_start + 4) / 4 * 4;
+        }
+        let tables = tables.ok_or_else(|| invalid("no #~ stream"))?;
+        file.parse_tables(tables)?;
+        Ok(file)
+    }
+
+    /// Parse the `#~` stream, ECMA-335 II.24.2.6.
+    fn parse_tables(&mut self, offset: usize) -> io::Result<()> {
+        let heap_sizes = self.byte(offset + 6)?;
+        let valid = self.u64(offset + 8)?;
+        let mut position = offset + 24;
+        for (i, table) in self.tables.iter_mut().enumerate() {
+            if valid & (1 << i) != 0 {
+                table.rows = u32::from_le_bytes(
+                    self.bytes
+                        .get(position..position + 4)
+                        .ok_or_else(|| invalid("truncated table header"))?
+                        .try_into()
+                        .unwrap(),
+                );
+                position += 4;
+            }
+        }
+        if valid >> TABLES != 0 {
+            return Err(invalid("unknown table"));
+        }
+
+        let rows = |table: Table| self.tables[table as usize].rows;
+        let heap = |flag: u8| if heap_sizes & flag != 0 { 4 } else { 2 };
+        let mut layouts = Vec::with_capacity(TABLES);
+        for table in ALL_TABLES {
+            let mut offsets = Vec::new();
+            let mut row_size = 0;
+            for column in columns(table) {
+                let width = match *column {
+                    Column::U16 => 2,
+                    Column::U32 => 4,
+                    Column::String => heap(0x01),
+                    Column::Guid => heap(0x02),
+                    Column::Blob => heap(0x04),
+                    Column::Index(table) => {
+                        if rows(table) < 1 << 16 {
+                            2
+                        } else {
+                            4
+                        }
+                    }
+                    Column::Coded(tables) => {
+                        let bits = coded_bits(tables);
+                        let max = tables.iter().flatten().map(|&t| rows(t)).max();
+                        if max.unwrap_or(0) < 1 << (16 - bits) {
+                            2
+                        } else {
+                            4
+                        }
+                    }
+                };
+                offsets.push((row_size, width));
+                row_size += width;
+            }
+            layouts.push((offsets, row_size));
+        }
+        for (table, (columns, row_size)) in self.tables.iter_mut().zip(layouts) {
+            table.offset = position;
+            table.row_size = row_size;
+            table.columns = columns;
+            position += row_size * table.rows as usize;
+        }
+        if position > self.bytes.len() {
+            return Err(invalid("truncated tables"));
+        }
+        Ok(())
+    }
+
+    fn byte(&self, offset: usize) -> io::Result<u8> {
+        self.bytes
+            .get(offset)
+            .copied()
+            .ok_or_else(|| invalid("unexpected end of file"))
+    }
+
+    fn u16(&self, offset: usize) -> io::Result<u16> {
+        Ok(u16::from_le_bytes([
+            self.byte(offset)?,
+            self.byte(offset + 1)?,
+        ]))
+    }
+
+    fn u32(&self, offset: usize) -> io::Result<u32> {
+        Ok(u32::from(self.u16(offset)?) | u32::from(self.u16(offset + 2)?) << 16)
+    }
+
+    fn u64(&self, offset: usize) -> io::Result<u64> {
+        Ok(u64::from(self.u32(offset)?) | u64::from(self.u32(offset + 4)?) << 32)
+    }
+
+    fn rows(&self, table: Table) -> usize {
+        self.tables[table as usize].rows as usize
+    }
+
+    /// Read `column` of the 0-based `row` of `table`.
+    fn column(&self, table: Table, row: usize, column: usize) -> u32 {
+        let data = &self.tables[table as usize];
+        let (offset, width) = data.columns[column];
+        let offset = data.offset + row * data.row_size + offset;
+        match width {
+            2 => u32::from(u16::from_le_bytes([
+                self.bytes[offset],
+                self.bytes[offset + 1],
+            ])),
+            _ => u32::from_le_bytes(self.bytes[offset..offset + 4].try_into().unwrap()),
+        }
+    }
+
+    fn string(&self, index: u32) -> io::Result<String> {
+        let start = self.strings + index as usize;
+        let bytes = self
+            .bytes
+            .get(start..)
+            .ok_or_else(|| invalid("string outside of the heap"))?;
+        let end = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or_else(|| invalid("unterminated string"))?;
+        String::from_utf8(bytes[..end].to_vec()).map_err(|_| invalid("invalid string"))
+    }
+
+    fn blob(&self, index: u32) -> io::Result<&[u8]> {
+        let mut reader = Reader(
+            self.bytes
+                .get(self.blobs + index as usize..)
+                .ok_or_else(|| invalid("blob outside of the heap"))?,
+        );
+        let length = reader.compressed()? as usize;
+        reader
+            .0
+            .get(..length)
+            .ok_or_else(|| invalid("truncated blob"))
+    }
+
+    /// Split a coded index into its table and 0-based row.
+    fn decode(&self, column: Column, value: u32) -> io::Result<(Table, usize)> {
+        let Column::Coded(tables) = column else {
+            unreachable!()
+        };
+        let bits = coded_bits(tables);
+        let table = tables
+            .get((value & ((1 << bits) - 1)) as usize)
+            .copied()
+            .flatten()
+            .ok_or_else(|| invalid("invalid coded index"))?;
+        let row = (value >> bits) as usize;
+        if row == 0 {
+            return Err(invalid("null coded index"));
+        }
+        Ok((table, row - 1))
+    }
+
+    /// The namespace and name of a `TypeDef` or `TypeRef` row.
+    fn type_name(&self, table: Table, row: usize) -> io::Result<(String, String)> {
+        let (name, namespace) = match table {
+            Table::TypeDef | Table::TypeRef => (1, 2),
+            _ => return Err(invalid("not a named type")),
+        };
+        Ok((
+            self.string(self.column(table, row, namespace))?,
+            self.string(self.column(table, row, name))?,
+        ))
+    }
+
+    /// All type definitions, in the order of the `TypeDef` table.
+    pub fn type_defs(&self) -> io::Result<Vec<TypeDefinition>> {
+        let type_defs = self.rows(Table::TypeDef);
+        let mut packing = vec![None; type_defs];
+        for row in 0..self.rows(Table::ClassLayout) {
+            let parent = self.column(Table::ClassLayout, row, 2) as usize;
+            if let Some(packing) = packing.get_mut(parent.wrapping_sub(1)) {
+                *packing = Some(self.column(Table::ClassLayout, row, 0) as u16).filter(|&p| p != 0);
+            }
+        }
+        let mut enclosing = vec![None; type_defs];
+        for row in 0..self.rows(Table::NestedClass) {
+            let nested = self.column(Table::NestedClass, row, 0) as usize;
+            let parent = self.column(Table::NestedClass, row, 1) as usize;
+            if let Some(enclosing) = enclosing.get_mut(nested.wrapping_sub(1)) {
+                *enclosing = Some(parent - 1);
+            }
+        }
+        let mut offsets = vec![None; self.rows(Table::Field)];
+        for row in 0..self.rows(Table::FieldLayout) {
+            let field = self.column(Table::FieldLayout, row, 1) as usize;
+            if let Some(offset) = offsets.get_mut(field.wrapping_sub(1)) {
+                *offset = Some(self.column(Table::FieldLayout, row, 0));
+            }
+        }
+        let mut bitfields = vec![Vec::new(); self.rows(Table::Field)];
+        for row in 0..self.rows(Table::CustomAttribute) {
+            let column = |column| self.column(Table::CustomAttribute, row, column);
+            let (Table::Field, field) = self.decode(HAS_CUSTOM_ATTRIBUTE, column(0))? else {
+                continue;
+            };
+            let (Table::MemberRef, constructor) = self.decode(CUSTOM_ATTRIBUTE_TYPE, column(1))?
+            else {
+                continue;
+            };
+            let parent = self.column(Table::MemberRef, constructor, 0);
+            let (Table::TypeRef, attribute) = self.decode(MEMBER_REF_PARENT, parent)? else {
+                continue;
+            };
+            if self.type_name(Table::TypeRef, attribute)?.1 == "NativeBitfieldAttribute" {
+                bitfields[field].push(Bitfield::parse(self.blob(column(2))?)?);
+            }
+        }
+        let mut names = HashMap::new();
+        for (row, &enclosing) in enclosing.iter().enumerate() {
+            let (namespace, name) = self.type_name(Table::TypeDef, row)?;
+            names.insert((enclosing, namespace, name), row);
+        }
+
+        (0..type_defs)
+            .map(|row| {
+                let (namespace, name) = self.type_name(Table::TypeDef, row)?;
+                let extends = match self.column(Table::TypeDef, row, 3) {
+                    0 => None,
+                    value => {
+                        let (table, row) = self.decode(TYPE_DEF_OR_REF, value)?;
+                        Some(self.type_name(table, row)?)
+                    }
+                };
+                let first = self.column(Table::TypeDef, row, 4) as usize - 1;
+                let last = match row + 1 < type_defs {
+                    true => self.column(Table::TypeDef, row + 1, 4) as usize - 1,
+                    false => self.rows(Table::Field),
+                };
+                let fields = (first..last)
+                    .map(|field| {
+                        let signature = self.blob(self.column(Table::Field, field, 2))?;
+                        let mut reader = Reader(signature);
+                        // FIELD
+                        if reader.byte()? != 0x06 {
+                            return Err(invalid("not a field signature"));
+                        }
+                        Ok(FieldDefinition {
+                            flags: self.column(Table::Field, field, 0) as u16,
+                            name: self.string(self.column(Table::Field, field, 1))?,
+                            ty: self.read_type(&mut reader, &names)?,
+                            offset: offsets[field],
+                            bitfields: bitfields[field].clone(),
+                        })
+                    })
+                    .collect::<io::Result<_>>()?;
+                Ok(TypeDefinition {
+                    flags: self.column(Table::TypeDef, row, 0),
+                    namespace,
+                    name,
+                    extends,
+                    fields,
+                    packing: packing[row],
+                    enclosing: enclosing[row],
+                })
+            })
+            .collect()
+    }
+
+    /// The type definition a `TypeRef` row refers to, if it is in this file.
+    fn resolve(&self, row: usize, names: &Names) -> io::Result<Option<usize>> {
+        let (namespace, name) = self.type_name(Table::TypeRef, row)?;
+        let enclosing = match self.column(Table::TypeRef, row, 0) {
+            0 => return Ok(None),
+            scope => match self.decode(RESOLUTION_SCOPE, scope)? {
+                (Table::Module, _) => None,
+                (Table::TypeRef, scope) => match self.resolve(scope, names)? {
+                    Some(enclosing) => Some(enclosing),
+                    None => return Ok(None),
+                },
+                _ => return Ok(None),
+            },
+        };
+        Ok(names.get(&(enclosing, namespace, name)).copied())
+    }
+
+    /// Read a type of a signature, ECMA-335 II.23.2.12.
+    fn read_type(&self, reader: &mut Reader<'_>, names: &Names) -> io::Result<Type> {
+        Ok(match reader.byte()? {
+            0x02 => Type::Bool,
+            0x03 => Type::Char,
+            0x04 => Type::I8,
+            0x05 => Type::U8,
+            0x06 => Type::I16,
+            0x07 => Type::U16,
+            0x08 => Type::I32,
+            0x09 => Type::U32,
+            0x0A => Type::I64,
+            0x0B => Type::U64,
+            0x0C => Type::F32,
+            0x0D => Type::F64,
+            0x18 => Type::ISize,
+            0x19 => Type::USize,
+            // PTR, including `void*`.
+            0x0F => match reader.0.first() {
+                Some(0x01) => {
+                    reader.byte()?;
+                    Type::Pointer(Box::new(Type::U8))
+                }
+                _ => Type::Pointer(Box::new(self.read_type(reader, names)?)),
+            },
+            // VALUETYPE
+            0x11 => {
+                let (table, row) = self.decode(TYPE_DEF_OR_REF, reader.compressed()?)?;
+                match table {
+                    Table::TypeDef => Type::Def(row),
+                    _ => match self.resolve(row, names)? {
+                        Some(type_def) => Type::Def(type_def),
+                        None => {
+                            let (namespace, name) = self.type_name(table, row)?;
+                            Type::Ref { namespace, name }
+                        }
+                    },
+                }
+            }
+            // CLASS, OBJECT, STRING, FNPTR and SZARRAY are references.
+            0x12 => {
+                reader.compressed()?;
+                Type::Class
+            }
+            0x0E | 0x1C => Type::Class,
+            // ARRAY: element type, rank, sizes and lower bounds.
+            0x14 => {
+                let element = self.read_type(reader, names)?;
+                let rank = reader.compressed()?;
+                let sizes: Vec<u32> = (0..reader.compressed()?)
+                    .map(|_| reader.compressed())
+                    .collect::<io::Result<_>>()?;
+                for _ in 0..reader.compressed()? {
+                    reader.compressed()?;
+                }
+                if rank != 1 || sizes.len() != 1 {
+                    return Err(invalid("unsupported array"));
+                }
+                Type::Array(Box::new(element), sizes[0])
+            }
+            // CMOD_REQD and CMOD_OPT modify the type that follows.
+            0x1F | 0x20 => {
+                reader.compressed()?;
+                self.read_type(reader, names)?
+            }
+            _ => return Err(invalid("unsupported field type")),
+        })
+    }
+}
+
+impl Bitfield {
+    /// Parse the value of a `NativeBitfieldAttribute(string name, long offset, long length)`,
+    /// ECMA-335 II.23.3.
+    fn parse(value: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(value);
+        if reader.bytes(2)? != [0x01, 0x00] {
+            return Err(invalid("no custom attribute prolog"));
+        }
+        let length = reader.compressed()? as usize;
+        let name = String::from_utf8(reader.bytes(length)?.to_vec())
+            .map_err(|_| invalid("invalid bitfield name"))?;
+        let mut integer = || {
+            let bytes = reader.bytes(8)?.try_into().unwrap();
+            u32::try_from(i64::from_le_bytes(bytes)).map_err(|_| invalid("invalid bitfield"))
+        };
+        Ok(Self {
+            name,
+            offset: integer()?,
+            length: integer()?,
+        })
+    }
+}
+
+/// Number of bits used for the table of a coded index.
+fn coded_bits(tables: &[Option<Table>]) -> u32 {
+    usize::BITS - (tables.len() - 1).leading_zeros()
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if length > self.0.len() {
+            return Err(invalid("truncated signature"));
+        }
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        let (&first, rest) = self
+            .0
+            .split_first()
+            .ok_or_else(|| invalid("truncated signature"))?;
+        self.0 = rest;
+        Ok(first)
+    }
+
+    /// Read a compressed unsigned integer, ECMA-335 II.23.2.
+    fn compressed(&mut self) -> io::Result<u32> {
+        let first = u32::from(self.byte()?);
+        Ok(match first {
+            _ if first & 0x80 == 0 => first,
+            _ if first & 0xC0 == 0x80 => (first & 0x3F) << 8 | u32::from(self.byte()?),
+            _ => {
+                let mut value = first & 0x1F;
+                for _ in 0..3 {
+                    value = value << 8 | u32::from(self.byte()?);
+                }
+                value
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compressed() {
+        for (bytes, value) in [
+            (&[0x03][..], 0x03),
+            (&[0x7F], 0x7F),
+            (&[0x80, 0x80], 0x80),
+            (&[0xBF, 0xFF], 0x3FFF),
+            (&[0xC0, 0x00, 0x40, 0x00], 0x4000),
+            (&[0xDF, 0xFF, 0xFF, 0xFF], 0x1FFF_FFFF),
+        ] {
+            assert_eq!(Reader(bytes).compressed().unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn test_bitfield() {
+        let mut value = vec![0x01, 0x00, 10];
+        value.extend_from_slice(b"SourceType");
+        value.extend_from_slice(&3i64.to_le_bytes());
+        value.extend_from_slice(&5i64.to_le_bytes());
+        value.extend_from_slice(&[0x00, 0x00]);
+        let bitfield = Bitfield::parse(&value).unwrap();
+        assert_eq!(
+            bitfield,
+            Bitfield {
+                name: "SourceType".into(),
+                offset: 3,
+                length: 5,
+            }
+        );
+
+        assert!(Bitfield::parse(&value[..value.len() - 10]).is_err());
+    }
+}
@@ -6,11 +6,14 @@
 // IMPLIED WARRANTIES OF FITNESS FOR A PARTICULAR
 // PURPOSE, MERCHANTABILITY, OR NON-INFRINGEMENT.
 //
-use std::{mem::ManuallyDrop, os::windows::ffi::OsStrExt, path::Path, process::exit};
+use std::{
+    mem::ManuallyDrop, os::windows::ffi::OsStrExt, path::Path, process::exit, sync::mpsc,
+    time::Duration,
+};
 
 use direct_storage::{
-    readonly_copy, runtime_loaded::DStorageGetFactory, IDStorageFactory, IDStorageFile,
-    IDStorageQueue, DSTORAGE_COMPRESSION_FORMAT_NONE, DSTORAGE_DESTINATION,
+    readonly_copy, runtime_loaded::DStorageGetFactory, waiter::EventWaiter, IDStorageFactory,
+    IDStorageFile, IDStorageQueue1, DSTORAGE_COMPRESSION_FORMAT_NONE, DSTORAGE_DESTINATION,
     DSTORAGE_DESTINATION_BUFFER, DSTORAGE_MAX_QUEUE_CAPACITY, DSTORAGE_PRIORITY_NORMAL,
     DSTORAGE_QUEUE_DESC, DSTORAGE_REQUEST, DSTORAGE_REQUEST_DESTINATION_BUFFER,
     DSTORAGE_REQUEST_OPTIONS, DSTORAGE_REQUEST_SOURCE_FILE, DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE,
@@ -18,20 +21,17 @@ use direct_storage::{
 use windows::{
     core::{PCSTR, PCWSTR},
     Win32::{
-        Foundation::{CloseHandle, INVALID_HANDLE_VALUE},
         Graphics::{
             Direct3D::D3D_FEATURE_LEVEL_12_0,
             Direct3D12::{
-                D3D12CreateDevice, ID3D12Device, ID3D12Fence, ID3D12Resource,
-                D3D12_FEATURE_DATA_SHADER_MODEL, D3D12_FEATURE_SHADER_MODEL, D3D12_FENCE_FLAG_NONE,
-                D3D12_HEAP_FLAG_NONE, D3D12_HEAP_PROPERTIES, D3D12_HEAP_TYPE_DEFAULT,
-                D3D12_RESOURCE_DESC, D3D12_RESOURCE_DIMENSION_BUFFER, D3D12_RESOURCE_STATE_COMMON,
-                D3D12_TEXTURE_LAYOUT_ROW_MAJOR, D3D_SHADER_MODEL_6_0,
+                D3D12CreateDevice, ID3D12Device, ID3D12Resource, D3D12_FEATURE_DATA_SHADER_MODEL,
+                D3D12_FEATURE_SHADER_MODEL, D3D12_HEAP_FLAG_NONE, D3D12_HEAP_PROPERTIES,
+                D3D12_HEAP_TYPE_DEFAULT, D3D12_RESOURCE_DESC, D3D12_RESOURCE_DIMENSION_BUFFER,
+                D3D12_RESOURCE_STATE_COMMON, D3D12_TEXTURE_LAYOUT_ROW_MAJOR, D3D_SHADER_MODEL_6_0,
             },
             Dxgi::Common::{DXGI_FORMAT_UNKNOWN, DXGI_SAMPLE_DESC},
         },
         Storage::FileSystem::BY_HANDLE_FILE_INFORMATION,
-        System::Threading::{CreateEventA, WaitForSingleObject},
     },
 };
 
@@ -96,7 +96,7 @@ fn main() {
         Device: unsafe { readonly_copy(&device) },
     };
 
-    let queue: IDStorageQueue = unsafe {
+    let queue: IDStorageQueue1 = unsafe {
         factory
             .CreateQueue(&queue_desc)
             .expect("Can't create DirectStorage queue")
@@ -169,36 +169,26 @@ fn main() {
 
     unsafe { queue.EnqueueRequest(&request) }
 
-    // Configure a fence to be signaled when the request is completed
-    let fence: ID3D12Fence = unsafe {
-        device
-            .CreateFence(0, D3D12_FENCE_FLAG_NONE)
-            .expect("Can't create a fence")
-    };
-
-    let fence_event =
-        unsafe { CreateEventA(None, false, false, None).expect("Can't create event") };
-
-    const FENCE_VALUE: u64 = 1;
-
-    unsafe {
-        fence
-            .SetEventOnCompletion(FENCE_VALUE, fence_event)
-            .expect("Can't set on completion event");
+    // Have the shared waiter thread report failures and the completion of the request.
+    let waiter = EventWaiter::new().expect("Can't create event waiter");
+    waiter
+        .watch_errors(&queue, |record| {
+            println!(
+                "The DirectStorage queue reported {} failure(s).",
+                record.FailureCount
+            )
+        })
+        .expect("Can't watch for errors");
 
-        queue.EnqueueSignal(&fence, FENCE_VALUE);
-        queue.Submit();
-    }
+    let (sender, receiver) = mpsc::channel();
+    waiter
+        .enqueue_callback(&queue, move || sender.send(()).unwrap())
+        .expect("Can't enqueue completion event");
+    unsafe { queue.Submit() };
 
     println!("Waiting for the DirectStorage request to complete.");
 
-    unsafe {
-        let _success = WaitForSingleObject(fence_event, 5 * 1000);
-
-        if fence_event != INVALID_HANDLE_VALUE {
-            CloseHandle(fence_event).expect("Failed to close Event object");
-        }
-    };
+    let _success = receiver.recv_timeout(Duration::from_secs(5));
 
     let error_record = unsafe { queue.RetrieveErrorRecord() };
 
@@ -0,0 +1,676 @@
+//! A minimal chunked archive format for packing assets that are loaded through DirectStorage.
+//!
+//! An archive is a flat sequence of chunk payloads followed by a table of contents ([`Toc`]) and
+//! a fixed-size footer.  Every [`Entry`] in the TOC is split in one or more [`Chunk`]s, each of
+//! which can be read with a single `DSTORAGE_SOURCE_FILE` request.
+//!
+//! ```text
+//! +------------------+------------------+-----+-------+--------------------------------+
+//! | chunk payload 0  | chunk payload 1  | ... |  TOC  | TOC offset | magic | version   |
+//! +------------------+------------------+-----+-------+--------------------------------+
+//! ```
+//!
+//! All integers are stored in little-endian byte order.
+//!
+//! [`ArchiveWriter`] hashes every chunk payload with XXH3 and stores byte-identical chunks only
+//! once: multiple TOC entries can point at the same `offset`/`compressed_size`.  This is
+//! invisible to readers, which still produce one independent request per [`Chunk`].
+//!
+//! [`PackPolicy`] decides how files are split into chunks and whether each chunk is worth
+//! compressing.
+//!
+//! Chunks can optionally carry a [`Checksum`] over their uncompressed data, see the
+//! [`checksum`] module.
+//!
+//! Entries can reference other entries through [`Entry::dependencies`], which
+//! [`dependencies::DependencyLoader`] uses to load an asset together with everything it needs.
+//!
+//! With the `mmap` feature enabled, archives can also be read from memory through
+//! `DSTORAGE_SOURCE_MEMORY` requests, see `mmap::MappedArchive`.
+
+use std::{
+    collections::{hash_map, HashMap},
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
+use xxhash_rust::xxh3::xxh3_128;
+
+use crate::{
+    readonly_copy, IDStorageFile, DSTORAGE_COMPRESSION_FORMAT, DSTORAGE_REQUEST_DESTINATION_TYPE,
+    DSTORAGE_REQUEST_OPTIONS, DSTORAGE_REQUEST_SOURCE_FILE, DSTORAGE_SOURCE_FILE,
+};
+
+pub mod checksum;
+pub mod dependencies;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod policy;
+
+pub use checksum::{Checksum, ChecksumError, ChecksumMismatch, Verifier, VerifyError};
+pub use dependencies::{
+    AssetId, DependencyError, DependencyLoader, LoadError, LoadHandle, LoadStatus,
+};
+pub use policy::{PackError, PackPolicy, PackedChunk};
+
+/// Magic bytes at the end of every archive.
+pub const MAGIC: [u8; 4] = *b"DSAR";
+
+/// Version of the archive layout written by [`ArchiveWriter`].  Version 2 added
+/// [`Chunk::checksum`] and version 3 [`Entry::dependencies`], older archives are still read
+/// without them.
+pub const VERSION: u32 = 3;
+
+const FOOTER_SIZE: u64 = 16;
+/// Smallest encoded size of an [`Entry`] and a [`Chunk`] in the TOC, used to bound the
+/// allocations made for the counts read from an untrusted TOC.
+const MIN_ENTRY_SIZE: u64 = 6;
+const MIN_CHUNK_SIZE: u64 = 17;
+
+/// Location and encoding of a single chunk inside an archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    /// Offset of the stored payload from the start of the archive.
+    pub offset: u64,
+    /// Size of the stored (possibly compressed) payload.
+    pub compressed_size: u32,
+    /// Size of the payload after decompression.
+    pub uncompressed_size: u32,
+    /// Format the payload is stored in, [`crate::DSTORAGE_COMPRESSION_FORMAT_NONE`] for
+    /// uncompressed chunks.
+    pub compression_format: DSTORAGE_COMPRESSION_FORMAT,
+    /// Optional checksum over the uncompressed data.
+    pub checksum: Option<Checksum>,
+}
+
+impl Chunk {
+    /// Request options for reading this chunk from a file into `destination_type`.
+    pub fn options(
+        &self,
+        destination_type: DSTORAGE_REQUEST_DESTINATION_TYPE,
+    ) -> DSTORAGE_REQUEST_OPTIONS {
+        let mut options = DSTORAGE_REQUEST_OPTIONS::default();
+        options.set_CompressionFormat(self.compression_format);
+        options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+        options.set_DestinationType(destination_type);
+        options
+    }
+
+    /// Describe this chunk as the source of a request reading from `file`, which must be the
+    /// archive this chunk was read from.
+    ///
+    /// # Safety
+    /// Borrows `file` through [`readonly_copy()`], the returned struct must not outlive `file`.
+    pub unsafe fn source_file(&self, file: &IDStorageFile) -> DSTORAGE_SOURCE_FILE {
+        DSTORAGE_SOURCE_FILE {
+            Source: unsafe { readonly_copy(file) },
+            Offset: self.offset,
+            Size: self.compressed_size,
+        }
+    }
+}
+
+/// A named asset in the archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    /// Chunks that make up this entry, in order of their uncompressed data.
+    pub chunks: Vec<Chunk>,
+    /// Indices into [`Toc::entries`] of the entries this one references, e.g. the textures of
+    /// a material.
+    pub dependencies: Vec<u32>,
+}
+
+impl Entry {
+    /// Total size of this entry after decompression.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.chunks.iter().map(|c| c.uncompressed_size as u64).sum()
+    }
+}
+
+/// Table of contents of an archive.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Toc {
+    pub entries: Vec<Entry>,
+}
+
+impl Toc {
+    /// Read the table of contents from the footer of an archive.
+    pub fn read_from<R: Read + Seek>(mut reader: R) -> io::Result<Self> {
+        let end = reader.seek(SeekFrom::End(0))?;
+        if end < FOOTER_SIZE {
+            return Err(invalid_data("archive is too small to contain a footer"));
+        }
+
+        reader.seek(SeekFrom::Start(end - FOOTER_SIZE))?;
+        let toc_offset = read_u64(&mut reader)?;
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("archive footer has an invalid magic"));
+        }
+        let version = read_u32(&mut reader)?;
+        if !(1..=VERSION).contains(&version) {
+            return Err(invalid_data(format!(
+                "unsupported archive version {version}"
+            )));
+        }
+        if toc_offset > end - FOOTER_SIZE {
+            return Err(invalid_data(
+                "TOC offset points past the end of the archive",
+            ));
+        }
+
+        reader.seek(SeekFrom::Start(toc_offset))?;
+        let mut reader = io::BufReader::new(reader.take(end - FOOTER_SIZE - toc_offset));
+
+        let num_entries = read_u32(&mut reader)?;
+        let mut entries = Vec::with_capacity(capacity(&reader, num_entries, MIN_ENTRY_SIZE));
+        for _ in 0..num_entries {
+            let name_len = read_u16(&mut reader)?;
+            let mut name = vec![0; name_len as usize];
+            reader.read_exact(&mut name)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid_data("entry name is not valid UTF-8"))?;
+
+            let num_chunks = read_u32(&mut reader)?;
+            let mut chunks = Vec::with_capacity(capacity(&reader, num_chunks, MIN_CHUNK_SIZE));
+            for _ in 0..num_chunks {
+                let chunk = Chunk {
+                    offset: read_u64(&mut reader)?,
+                    compressed_size: read_u32(&mut reader)?,
+                    uncompressed_size: read_u32(&mut reader)?,
+                    compression_format: DSTORAGE_COMPRESSION_FORMAT(read_u8(&mut reader)?),
+                    checksum: if version >= 2 {
+                        read_checksum(&mut reader)?
+                    } else {
+                        None
+                    },
+                };
+                let chunk_end = chunk.offset.checked_add(chunk.compressed_size as u64);
+                if chunk_end.map_or(true, |chunk_end| chunk_end > toc_offset) {
+                    return Err(invalid_data(format!(
+                        "chunk of `{name}` points outside of the payload section"
+                    )));
+                }
+                chunks.push(chunk);
+            }
+
+            let mut dependencies = Vec::new();
+            if version >= 3 {
+                let num_dependencies = read_u32(&mut reader)?;
+                for _ in 0..num_dependencies {
+                    let dependency = read_u32(&mut reader)?;
+                    if dependency >= num_entries {
+                        return Err(invalid_data(format!(
+                            "dependency of `{name}` points past the last entry"
+                        )));
+                    }
+                    dependencies.push(dependency);
+                }
+            }
+
+            entries.push(Entry {
+                name,
+                chunks,
+                dependencies,
+            });
+        }
+
+        Ok(Self { entries })
+    }
+
+    /// Look up an entry by name.
+    pub fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+}
+
+/// Payload of a single chunk passed to [`ArchiveWriter::add_entry()`].
+///
+/// Compression is left to the caller, e.g. through [`crate::IDStorageCompressionCodec`].
+#[derive(Clone, Copy, Debug)]
+pub struct ChunkData<'a> {
+    /// The payload as it should be stored in the archive.
+    pub data: &'a [u8],
+    /// Size of `data` after decompression, equal to `data.len()` for uncompressed chunks.
+    pub uncompressed_size: u32,
+    pub compression_format: DSTORAGE_COMPRESSION_FORMAT,
+    /// Checksum over the uncompressed data, see [`Checksum::crc32c()`] and [`Checksum::xxh3()`].
+    pub checksum: Option<Checksum>,
+}
+
+/// Key used to find byte-identical chunks.  The format and uncompressed size are included so
+/// that equal payloads with different interpretations are never merged.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct ChunkKey {
+    hash: u128,
+    compressed_size: u32,
+    uncompressed_size: u32,
+    compression_format: u8,
+}
+
+/// Writes an archive to `W`, storing byte-identical chunks only once.
+pub struct ArchiveWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    toc: Toc,
+    stored: HashMap<ChunkKey, u64>,
+    deduplicated_bytes: u64,
+}
+
+impl<W: Write> ArchiveWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            offset: 0,
+            toc: Toc::default(),
+            stored: HashMap::new(),
+            deduplicated_bytes: 0,
+        }
+    }
+
+    /// Append an entry consisting of `chunks`.  Chunks whose payload was already written, by this
+    /// or any previous entry, are not written again but reference the existing payload.
+    pub fn add_entry<'a>(
+        &mut self,
+        name: &str,
+        chunks: impl IntoIterator<Item = ChunkData<'a>>,
+    ) -> io::Result<&Entry> {
+        if name.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "entry name is longer than u16::MAX bytes",
+            ));
+        }
+
+        let mut entry = Entry {
+            name: name.to_owned(),
+            chunks: Vec::new(),
+            dependencies: Vec::new(),
+        };
+
+        for chunk in chunks {
+            let compressed_size = u32::try_from(chunk.data.len()).map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "chunk is bigger than u32::MAX")
+            })?;
+
+            let key = ChunkKey {
+                hash: xxh3_128(chunk.data),
+                compressed_size,
+                uncompressed_size: chunk.uncompressed_size,
+                compression_format: chunk.compression_format.0,
+            };
+
+            let offset = match self.stored.entry(key) {
+                hash_map::Entry::Occupied(o) => {
+                    self.deduplicated_bytes += compressed_size as u64;
+                    *o.get()
+                }
+                hash_map::Entry::Vacant(v) => {
+                    self.writer.write_all(chunk.data)?;
+                    let offset = self.offset;
+                    self.offset += compressed_size as u64;
+                    *v.insert(offset)
+                }
+            };
+
+            entry.chunks.push(Chunk {
+                offset,
+                compressed_size,
+                uncompressed_size: chunk.uncompressed_size,
+                compression_format: chunk.compression_format,
+                checksum: chunk.checksum,
+            });
+        }
+
+        self.toc.entries.push(entry);
+        Ok(self.toc.entries.last().unwrap())
+    }
+
+    /// Record that the entry named `entry` references the one named `dependency`.  Both must
+    /// have been added already.
+    pub fn add_dependency(&mut self, entry: &str, dependency: &str) -> io::Result<()> {
+        let position = |name: &str| {
+            self.toc
+                .entries
+                .iter()
+                .position(|e| e.name == name)
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("archive has no entry named `{name}`"),
+                    )
+                })
+        };
+        let dependency = position(dependency)? as u32;
+        let entry = position(entry)?;
+        let dependencies = &mut self.toc.entries[entry].dependencies;
+        if !dependencies.contains(&dependency) {
+            dependencies.push(dependency);
+        }
+        Ok(())
+    }
+
+    /// Number of payload bytes that were not written because an identical chunk was already
+    /// stored.
+    pub fn deduplicated_bytes(&self) -> u64 {
+        self.deduplicated_bytes
+    }
+
+    /// Write the table of contents and footer, returning the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let toc_offset = self.offset;
+
+        let mut toc = Vec::new();
+        toc.extend_from_slice(&count(self.toc.entries.len(), "entries")?);
+        for entry in &self.toc.entries {
+            toc.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+            toc.extend_from_slice(entry.name.as_bytes());
+            toc.extend_from_slice(&count(entry.chunks.len(), "chunks in an entry")?);
+            for chunk in &entry.chunks {
+                toc.extend_from_slice(&chunk.offset.to_le_bytes());
+                toc.extend_from_slice(&chunk.compressed_size.to_le_bytes());
+                toc.extend_from_slice(&chunk.uncompressed_size.to_le_bytes());
+                toc.push(chunk.compression_format.0);
+                match chunk.checksum {
+                    Some(checksum) => {
+                        toc.push(checksum.kind());
+                        toc.extend_from_slice(&checksum.value().to_le_bytes());
+                    }
+                    None => toc.push(0),
+                }
+            }
+            toc.extend_from_slice(&count(
+                entry.dependencies.len(),
+                "dependencies of an entry",
+            )?);
+            for dependency in &entry.dependencies {
+                toc.extend_from_slice(&dependency.to_le_bytes());
+            }
+        }
+
+        toc.extend_from_slice(&toc_offset.to_le_bytes());
+        toc.extend_from_slice(&MAGIC);
+        toc.extend_from_slice(&VERSION.to_le_bytes());
+
+        self.writer.write_all(&toc)?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+/// Number of elements to reserve for `count` records of at least `record_size` bytes each, which
+/// can't be more than what is left to read.
+fn capacity<R: Read>(reader: &io::BufReader<io::Take<R>>, count: u32, record_size: u64) -> usize {
+    let remaining = reader.get_ref().limit() + reader.buffer().len() as u64;
+    (count as u64).min(remaining / record_size) as usize
+}
+
+/// Encode the number of elements of a TOC list.
+fn count(len: usize, what: &str) -> io::Result<[u8; 4]> {
+    u32::try_from(len).map(u32::to_le_bytes).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("archive has more than u32::MAX {what}"),
+        )
+    })
+}
+
+fn read_checksum(r: &mut impl Read) -> io::Result<Option<Checksum>> {
+    let kind = read_u8(r)?;
+    let value = if kind != 0 { read_u64(r)? } else { 0 };
+    Checksum::from_parts(kind, value)
+        .ok_or_else(|| invalid_data(format!("unknown checksum kind {kind}")))
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut b = [0; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut b = [0; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_le_bytes(b))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut b = [0; 4];
+    r.read_exact(&mut b)?;
+    Ok(u32::from_le_bytes(b))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut b = [0; 8];
+    r.read_exact(&mut b)?;
+    Ok(u64::from_le_bytes(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{DSTORAGE_COMPRESSION_FORMAT_GDEFLATE, DSTORAGE_COMPRESSION_FORMAT_NONE};
+
+    fn uncompressed(data: &[u8]) -> ChunkData<'_> {
+        ChunkData {
+            data,
+            uncompressed_size: data.len() as u32,
+            compression_format: DSTORAGE_COMPRESSION_FORMAT_NONE,
+            checksum: None,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_entry("a", [uncompressed(b"hello"), uncompressed(b"world")])
+            .unwrap();
+        writer
+            .add_entry(
+                "b",
+                [ChunkData {
+                    data: b"zz",
+                    uncompressed_size: 100,
+                    compression_format: DSTORAGE_COMPRESSION_FORMAT_GDEFLATE,
+                    checksum: None,
+                }],
+            )
+            .unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let toc = Toc::read_from(Cursor::new(&archive)).unwrap();
+        assert_eq!(toc.entries.len(), 2);
+
+        let a = toc.find("a").unwrap();
+        assert_eq!(a.uncompressed_size(), 10);
+        assert_eq!(&archive[a.chunks[1].offset as usize..][..5], b"world");
+
+        let b = toc.find("b").unwrap();
+        assert_eq!(b.chunks[0].offset, 10);
+        assert_eq!(b.chunks[0].uncompressed_size, 100);
+        assert_eq!(
+            b.chunks[0].compression_format,
+            DSTORAGE_COMPRESSION_FORMAT_GDEFLATE
+        );
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let texture = [7u8; 64];
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_entry("level0/rock", [uncompressed(&texture), uncompressed(b"x")])
+            .unwrap();
+        writer
+            .add_entry("level1/rock", [uncompressed(&texture)])
+            .unwrap();
+        // Same bytes, different interpretation: must not be merged.
+        writer
+            .add_entry(
+                "level1/rock.gdeflate",
+                [ChunkData {
+                    data: &texture,
+                    uncompressed_size: 128,
+                    compression_format: DSTORAGE_COMPRESSION_FORMAT_GDEFLATE,
+                    checksum: None,
+                }],
+            )
+            .unwrap();
+        assert_eq!(writer.deduplicated_bytes(), 64);
+        let archive = writer.finish().unwrap().into_inner();
+
+        let toc = Toc::read_from(Cursor::new(&archive)).unwrap();
+        let first = toc.find("level0/rock").unwrap().chunks[0];
+        let second = toc.find("level1/rock").unwrap().chunks[0];
+        let third = toc.find("level1/rock.gdeflate").unwrap().chunks[0];
+        assert_eq!(first, second);
+        assert_ne!(first.offset, third.offset);
+
+        // Payload section holds the texture twice (once per interpretation) plus one byte.
+        assert_eq!(toc.entries[0].chunks[1].offset, 64);
+        assert_eq!(third.offset, 65);
+    }
+
+    #[test]
+    fn test_dependencies() {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()));
+        writer.add_entry("texture", [uncompressed(b"t")]).unwrap();
+        writer.add_entry("material", [uncompressed(b"m")]).unwrap();
+        writer.add_dependency("material", "texture").unwrap();
+        writer.add_dependency("material", "texture").unwrap();
+        let err = writer.add_dependency("material", "mesh").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let archive = writer.finish().unwrap().into_inner();
+
+        let toc = Toc::read_from(Cursor::new(&archive)).unwrap();
+        assert_eq!(toc.find("texture").unwrap().dependencies, []);
+        assert_eq!(toc.find("material").unwrap().dependencies, [0]);
+    }
+
+    /// An archive in the layout version 1 of [`ArchiveWriter`] wrote, without checksums and
+    /// dependencies, reads like the same archive written today.
+    #[test]
+    fn test_version_1() {
+        let mut archive = b"helloworldzz".to_vec();
+        archive.extend_from_slice(&2u32.to_le_bytes());
+        for (name, chunks) in [
+            ("a", &[(0u64, 5u32, 5u32), (5, 5, 5)][..]),
+            ("b", &[(10, 2, 100)][..]),
+        ] {
+            archive.extend_from_slice(&1u16.to_le_bytes());
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&(chunks.len() as u32).to_le_bytes());
+            for &(offset, compressed_size, uncompressed_size) in chunks {
+                archive.extend_from_slice(&offset.to_le_bytes());
+                archive.extend_from_slice(&compressed_size.to_le_bytes());
+                archive.extend_from_slice(&uncompressed_size.to_le_bytes());
+                let format = if name == "b" {
+                    DSTORAGE_COMPRESSION_FORMAT_GDEFLATE
+                } else {
+                    DSTORAGE_COMPRESSION_FORMAT_NONE
+                };
+                archive.push(format.0);
+            }
+        }
+        archive.extend_from_slice(&12u64.to_le_bytes());
+        archive.extend_from_slice(&MAGIC);
+        archive.extend_from_slice(&1u32.to_le_bytes());
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_entry("a", [uncompressed(b"hello"), uncompressed(b"world")])
+            .unwrap();
+        writer
+            .add_entry(
+                "b",
+                [ChunkData {
+                    data: b"zz",
+                    uncompressed_size: 100,
+                    compression_format: DSTORAGE_COMPRESSION_FORMAT_GDEFLATE,
+                    checksum: None,
+                }],
+            )
+            .unwrap();
+        let current = writer.finish().unwrap().into_inner();
+
+        let toc = Toc::read_from(Cursor::new(&archive)).unwrap();
+        assert_eq!(toc, Toc::read_from(Cursor::new(&current)).unwrap());
+        assert_eq!(toc.find("a").unwrap().chunks[1].offset, 5);
+        assert_eq!(toc.find("b").unwrap().chunks[0].uncompressed_size, 100);
+    }
+
+    #[test]
+    fn test_version_2() {
+        let mut archive = b"abc".to_vec();
+        archive.extend_from_slice(&1u32.to_le_bytes());
+        archive.extend_from_slice(&1u16.to_le_bytes());
+        archive.push(b'a');
+        archive.extend_from_slice(&1u32.to_le_bytes());
+        archive.extend_from_slice(&0u64.to_le_bytes());
+        archive.extend_from_slice(&3u32.to_le_bytes());
+        archive.extend_from_slice(&3u32.to_le_bytes());
+        archive.push(0);
+        archive.push(2);
+        archive.extend_from_slice(&0x1234u64.to_le_bytes());
+        archive.extend_from_slice(&3u64.to_le_bytes());
+        archive.extend_from_slice(&MAGIC);
+        archive.extend_from_slice(&2u32.to_le_bytes());
+
+        let toc = Toc::read_from(Cursor::new(&archive)).unwrap();
+        let a = toc.find("a").unwrap();
+        assert_eq!(a.chunks[0].checksum, Some(Checksum::Xxh3(0x1234)));
+        assert_eq!(a.dependencies, []);
+    }
+
+    #[test]
+    fn test_corrupt_toc() {
+        let footer = |archive: &mut Vec<u8>, toc_offset: u64| {
+            archive.extend_from_slice(&toc_offset.to_le_bytes());
+            archive.extend_from_slice(&MAGIC);
+            archive.extend_from_slice(&VERSION.to_le_bytes());
+        };
+
+        // Counts far beyond what the TOC can hold must not be trusted for allocations.
+        let mut archive = u32::MAX.to_le_bytes().to_vec();
+        footer(&mut archive, 0);
+        let err = Toc::read_from(Cursor::new(&archive)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut archive = 1u32.to_le_bytes().to_vec();
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        archive.extend_from_slice(&u32::MAX.to_le_bytes());
+        footer(&mut archive, 0);
+        let err = Toc::read_from(Cursor::new(&archive)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // A chunk whose end overflows.
+        let mut archive = 1u32.to_le_bytes().to_vec();
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        archive.extend_from_slice(&1u32.to_le_bytes());
+        archive.extend_from_slice(&u64::MAX.to_le_bytes());
+        archive.extend_from_slice(&1u32.to_le_bytes());
+        archive.extend_from_slice(&1u32.to_le_bytes());
+        archive.extend_from_slice(&[0, 0]);
+        footer(&mut archive, 0);
+        let err = Toc::read_from(Cursor::new(&archive)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_invalid_footer() {
+        let err = Toc::read_from(Cursor::new(vec![0u8; 32])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = Toc::read_from(Cursor::new(vec![0u8; 3])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,457 @@
+//! Optional integrity checksums over the uncompressed data of archive chunks.
+//!
+//! DirectStorage only detects some forms of corruption in compressed data (for example
+//! [`crate::E_DSTORAGE_ZLIB_PARITY_FAIL`] and [`crate::E_DSTORAGE_DECOMPRESSION_ERROR`]), and never
+//! checks uncompressed chunks.  Storing a checksum per chunk allows verifying the destination
+//! memory after a [`crate::DSTORAGE_REQUEST_DESTINATION_MEMORY`] request completed.
+
+use std::{error::Error, fmt};
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::{Chunk, Entry};
+use crate::{IDStorageStatusArray, DSTORAGE_DESTINATION_MEMORY};
+
+/// Checksum of the uncompressed data of a single chunk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Checksum {
+    Crc32c(u32),
+    Xxh3(u64),
+}
+
+impl Checksum {
+    /// Compute a CRC32C (Castagnoli) checksum over `data`.
+    pub fn crc32c(data: &[u8]) -> Self {
+        Self::Crc32c(crc32c::crc32c(data))
+    }
+
+    /// Compute a 64-bit XXH3 checksum over `data`.
+    pub fn xxh3(data: &[u8]) -> Self {
+        Self::Xxh3(xxh3_64(data))
+    }
+
+    /// Compute a checksum of the same kind as `self` over `data`.
+    pub fn recompute(&self, data: &[u8]) -> Self {
+        match self {
+            Self::Crc32c(_) => Self::crc32c(data),
+            Self::Xxh3(_) => Self::xxh3(data),
+        }
+    }
+
+    pub(super) fn kind(&self) -> u8 {
+        match self {
+            Self::Crc32c(_) => 1,
+            Self::Xxh3(_) => 2,
+        }
+    }
+
+    pub(super) fn value(&self) -> u64 {
+        match *self {
+            Self::Crc32c(v) => v as u64,
+            Self::Xxh3(v) => v,
+        }
+    }
+
+    pub(super) fn from_parts(kind: u8, value: u64) -> Option<Option<Self>> {
+        match kind {
+            0 => Some(None),
+            1 => Some(Some(Self::Crc32c(value as u32))),
+            2 => Some(Some(Self::Xxh3(value))),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Crc32c(v) => write!(f, "crc32c:{v:08X}"),
+            Self::Xxh3(v) => write!(f, "xxh3:{v:016X}"),
+        }
+    }
+}
+
+/// The uncompressed data of a chunk does not match the checksum stored in the archive.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChecksumMismatch {
+    /// Name of the [`Entry`] the chunk belongs to.
+    pub entry: String,
+    /// Index of the chunk in [`Entry::chunks`].
+    pub chunk: usize,
+    pub expected: Checksum,
+    pub actual: Checksum,
+}
+
+impl fmt::Display for ChecksumMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "checksum mismatch in chunk {} of `{}`: expected {}, got {}",
+            self.chunk, self.entry, self.expected, self.actual
+        )
+    }
+}
+
+impl Error for ChecksumMismatch {}
+
+/// Error returned by [`Entry::verify_chunk()`] and [`Verifier::track()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChecksumError {
+    /// `chunk` is not an index into [`Entry::chunks`].
+    ChunkIndex {
+        entry: String,
+        chunk: usize,
+    },
+    /// The destination is smaller than the uncompressed data of the chunk.
+    DestinationSize {
+        entry: String,
+        chunk: usize,
+        expected: u32,
+        actual: u32,
+    },
+    Mismatch(ChecksumMismatch),
+}
+
+impl fmt::Display for ChecksumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ChunkIndex { entry, chunk } => write!(f, "`{entry}` has no chunk {chunk}"),
+            Self::DestinationSize {
+                entry,
+                chunk,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "chunk {chunk} of `{entry}` has {expected} uncompressed bytes, the destination \
+                 only {actual}"
+            ),
+            Self::Mismatch(m) => m.fmt(f),
+        }
+    }
+}
+
+impl Error for ChecksumError {}
+
+impl Chunk {
+    /// Verify the uncompressed `data` of this chunk against its stored checksum.  Chunks without a
+    /// checksum always pass.
+    ///
+    /// On failure, the actual checksum of `data` is returned.
+    pub fn verify(&self, data: &[u8]) -> Result<(), Checksum> {
+        match self.checksum {
+            Some(expected) => {
+                let actual = expected.recompute(data);
+                if actual == expected {
+                    Ok(())
+                } else {
+                    Err(actual)
+                }
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Entry {
+    /// Verify the uncompressed `data` of this entry, which is expected to contain all chunks
+    /// back-to-back, against the stored checksums.
+    pub fn verify(&self, data: &[u8]) -> Result<(), VerifyError> {
+        let expected = self.uncompressed_size();
+        if data.len() as u64 != expected {
+            return Err(VerifyError::Length {
+                entry: self.name.clone(),
+                expected,
+                actual: data.len() as u64,
+            });
+        }
+
+        let mut offset = 0;
+        for (index, chunk) in self.chunks.iter().enumerate() {
+            let end = offset + chunk.uncompressed_size as usize;
+            chunk
+                .verify(&data[offset..end])
+                .map_err(|actual| VerifyError::Mismatch(self.mismatch(index, actual)))?;
+            offset = end;
+        }
+        Ok(())
+    }
+
+    /// Verify the uncompressed `data` of the chunk at `index` against its stored checksum.
+    pub fn verify_chunk(&self, index: usize, data: &[u8]) -> Result<(), ChecksumError> {
+        let chunk = self.chunk(index)?;
+        chunk
+            .verify(data)
+            .map_err(|actual| ChecksumError::Mismatch(self.mismatch(index, actual)))
+    }
+
+    fn chunk(&self, index: usize) -> Result<&Chunk, ChecksumError> {
+        self.chunks
+            .get(index)
+            .ok_or_else(|| ChecksumError::ChunkIndex {
+                entry: self.name.clone(),
+                chunk: index,
+            })
+    }
+
+    /// Only called for chunks that failed [`Chunk::verify()`], which have a checksum.
+    fn mismatch(&self, index: usize, actual: Checksum) -> ChecksumMismatch {
+        ChecksumMismatch {
+            entry: self.name.clone(),
+            chunk: index,
+            expected: self.chunks[index].checksum.unwrap(),
+            actual,
+        }
+    }
+}
+
+/// Error returned by [`Verifier::poll()`] and [`Entry::verify()`].
+#[derive(Debug)]
+pub enum VerifyError {
+    /// The request itself failed, the destination memory was not checked.
+    Request {
+        entry: String,
+        error: windows_core::Error,
+    },
+    /// The data passed to [`Entry::verify()`] doesn't have the uncompressed size of the entry.
+    Length {
+        entry: String,
+        expected: u64,
+        actual: u64,
+    },
+    Mismatch(ChecksumMismatch),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Request { entry, error } => write!(f, "request for `{entry}` failed: {error}"),
+            Self::Length {
+                entry,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "`{entry}` has {expected} uncompressed bytes, got {actual}"
+            ),
+            Self::Mismatch(m) => m.fmt(f),
+        }
+    }
+}
+
+impl Error for VerifyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Request { error, .. } => Some(error),
+            Self::Length { .. } | Self::Mismatch(_) => None,
+        }
+    }
+}
+
+struct Pending {
+    status_index: u32,
+    entry: String,
+    chunk_index: usize,
+    chunk: Chunk,
+    destination: DSTORAGE_DESTINATION_MEMORY,
+}
+
+/// Verifies the destination memory of [`crate::DSTORAGE_REQUEST_DESTINATION_MEMORY`] requests
+/// once their status in an [`IDStorageStatusArray`] reports completion.
+#[derive(Default)]
+pub struct Verifier {
+    pending: Vec<Pending>,
+}
+
+impl Verifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Verify chunk `chunk_index` of `entry` once `status_index` completes.  Chunks without a
+    /// checksum are ignored.
+    ///
+    /// Fails if `entry` has no such chunk or `destination` is too small to hold it.
+    ///
+    /// # Safety
+    /// `destination` must stay valid for reads until the request completed and
+    /// [`Verifier::poll()`] has returned its result.
+    pub unsafe fn track(
+        &mut self,
+        status_index: u32,
+        entry: &Entry,
+        chunk_index: usize,
+        destination: DSTORAGE_DESTINATION_MEMORY,
+    ) -> Result<(), ChecksumError> {
+        let chunk = *entry.chunk(chunk_index)?;
+        if destination.Size < chunk.uncompressed_size {
+            return Err(ChecksumError::DestinationSize {
+                entry: entry.name.clone(),
+                chunk: chunk_index,
+                expected: chunk.uncompressed_size,
+                actual: destination.Size,
+            });
+        }
+        if chunk.checksum.is_none() {
+            return Ok(());
+        }
+        self.pending.push(Pending {
+            status_index,
+            entry: entry.name.clone(),
+            chunk_index,
+            chunk,
+            destination,
+        });
+        Ok(())
+    }
+
+    /// Number of tracked chunks that have not completed yet.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Verify all tracked chunks whose request completed.  Returns one result per completed
+    /// chunk; chunks that are still in flight remain tracked.
+    ///
+    /// # Safety
+    /// `status_array` must be the array passed to [`crate::IDStorageQueue::EnqueueStatus()`] for
+    /// the tracked status indices.
+    pub unsafe fn poll(
+        &mut self,
+        status_array: &IDStorageStatusArray,
+    ) -> Vec<Result<String, VerifyError>> {
+        let mut results = Vec::new();
+        self.pending.retain(|p| {
+            if !unsafe { status_array.IsComplete(p.status_index) } {
+                return true;
+            }
+
+            let result = match unsafe { status_array.GetHResult(p.status_index) } {
+                Ok(()) => {
+                    let data = unsafe {
+                        std::slice::from_raw_parts(
+                            p.destination.Buffer.cast::<u8>(),
+                            p.chunk.uncompressed_size as usize,
+                        )
+                    };
+                    match p.chunk.verify(data) {
+                        Ok(()) => Ok(p.entry.clone()),
+                        Err(actual) => Err(VerifyError::Mismatch(ChecksumMismatch {
+                            entry: p.entry.clone(),
+                            chunk: p.chunk_index,
+                            expected: p.chunk.checksum.unwrap(),
+                            actual,
+                        })),
+                    }
+                }
+                Err(error) => Err(VerifyError::Request {
+                    entry: p.entry.clone(),
+                    error,
+                }),
+            };
+            results.push(result);
+            false
+        });
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        archive::{ArchiveWriter, ChunkData, Toc},
+        DSTORAGE_COMPRESSION_FORMAT_NONE,
+    };
+
+    #[test]
+    fn test_known_values() {
+        // Check values from RFC 3720 and the XXH3 reference implementation.
+        assert_eq!(Checksum::crc32c(&[0; 32]), Checksum::Crc32c(0x8A9136AA));
+        assert_eq!(Checksum::xxh3(b""), Checksum::Xxh3(0x2D06800538D394C2));
+    }
+
+    #[test]
+    fn test_verify_entry() {
+        let a = [1u8; 16];
+        let b = [2u8; 8];
+
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_entry(
+                "mesh",
+                [
+                    ChunkData {
+                        data: &a,
+                        uncompressed_size: 16,
+                        compression_format: DSTORAGE_COMPRESSION_FORMAT_NONE,
+                        checksum: Some(Checksum::crc32c(&a)),
+                    },
+                    ChunkData {
+                        data: &b,
+                        uncompressed_size: 8,
+                        compression_format: DSTORAGE_COMPRESSION_FORMAT_NONE,
+                        checksum: Some(Checksum::xxh3(&b)),
+                    },
+                ],
+            )
+            .unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+        let toc = Toc::read_from(Cursor::new(archive)).unwrap();
+        let entry = toc.find("mesh").unwrap();
+
+        let mut loaded = [a.as_slice(), b.as_slice()].concat();
+        entry.verify(&loaded).unwrap();
+
+        let err = entry.verify(&loaded[..20]).unwrap_err();
+        assert!(matches!(
+            err,
+            VerifyError::Length {
+                expected: 24,
+                actual: 20,
+                ..
+            }
+        ));
+
+        loaded[20] ^= 0xFF;
+        let VerifyError::Mismatch(err) = entry.verify(&loaded).unwrap_err() else {
+            panic!("expected a checksum mismatch");
+        };
+        assert_eq!(err.entry, "mesh");
+        assert_eq!(err.chunk, 1);
+        assert_eq!(err.expected, Checksum::xxh3(&b));
+        assert_eq!(err.actual, Checksum::xxh3(&loaded[16..]));
+
+        assert!(matches!(
+            entry.verify_chunk(1, &loaded[16..]),
+            Err(ChecksumError::Mismatch(_))
+        ));
+        assert_eq!(
+            entry.verify_chunk(2, &b),
+            Err(ChecksumError::ChunkIndex {
+                entry: "mesh".to_owned(),
+                chunk: 2
+            })
+        );
+
+        let mut verifier = Verifier::new();
+        let destination = |size| DSTORAGE_DESTINATION_MEMORY {
+            Buffer: std::ptr::null_mut(),
+            Size: size,
+        };
+        let result = unsafe { verifier.track(0, entry, 2, destination(16)) };
+        assert!(matches!(result, Err(ChecksumError::ChunkIndex { .. })));
+        let result = unsafe { verifier.track(0, entry, 0, destination(15)) };
+        assert!(matches!(
+            result,
+            Err(ChecksumError::DestinationSize {
+                expected: 16,
+                actual: 15,
+                ..
+            })
+        ));
+        assert_eq!(verifier.pending(), 0);
+    }
+}
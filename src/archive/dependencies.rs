@@ -0,0 +1,706 @@
+//! Loading an asset together with every asset it depends on.
+//!
+//! [`Toc::load_order()`] resolves the closure of a root asset over [`Entry::dependencies`] into
+//! waves: assets only depend on assets of earlier waves, so all assets of a wave can be read in
+//! parallel.  [`DependencyLoader`] enqueues the reads of a whole closure at once in that order,
+//! followed by one status per asset, and reports the [`LoadHandle`] as resident once every asset
+//! of the closure is.  Assets shared between closures, like a texture used by several materials,
+//! are read only once.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use super::{Entry, Toc};
+use crate::{
+    IDStorageFile, IDStorageQueue, IDStorageStatusArray, DSTORAGE_DESTINATION,
+    DSTORAGE_DESTINATION_MEMORY, DSTORAGE_REQUEST, DSTORAGE_REQUEST_DESTINATION_MEMORY,
+    DSTORAGE_SOURCE,
+};
+
+/// How long dropping a [`DependencyLoader`] waits for assets that are still loading.
+const DROP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Index of an entry in [`Toc::entries`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct AssetId(pub u32);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DependencyError {
+    UnknownAsset(AssetId),
+    /// Assets that depend on each other in a loop, each depending on the next.
+    Cycle(Vec<AssetId>),
+}
+
+impl fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownAsset(id) => write!(f, "archive has no entry with index {}", id.0),
+            Self::Cycle(ids) => {
+                write!(f, "dependency cycle between entries")?;
+                for id in ids {
+                    write!(f, " {}", id.0)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for DependencyError {}
+
+enum Mark {
+    Visiting,
+    /// Length of the longest dependency chain below the asset.
+    Done(usize),
+}
+
+impl Toc {
+    /// Look up the id of an entry by name.
+    pub fn asset(&self, name: &str) -> Option<AssetId> {
+        self.entries
+            .iter()
+            .position(|e| e.name == name)
+            .map(|i| AssetId(i as u32))
+    }
+
+    pub fn entry(&self, id: AssetId) -> Option<&Entry> {
+        self.entries.get(id.0 as usize)
+    }
+
+    /// Resolve `root` and everything it transitively depends on into waves, starting with the
+    /// assets that have no dependencies.  Every asset is placed right after the last of its
+    /// dependencies, and each wave is sorted by id.
+    pub fn load_order(&self, root: AssetId) -> Result<Vec<Vec<AssetId>>, DependencyError> {
+        self.entry(root)
+            .ok_or(DependencyError::UnknownAsset(root))?;
+
+        let mut marks = HashMap::from([(root, Mark::Visiting)]);
+        // Depth-first search, with the index of the next dependency to visit per asset.
+        let mut stack = vec![(root, 0)];
+        while let Some(&(id, next)) = stack.last() {
+            let dependencies = &self.entries[id.0 as usize].dependencies;
+            let Some(&dependency) = dependencies.get(next) else {
+                let depth = dependencies
+                    .iter()
+                    .map(|d| match marks[&AssetId(*d)] {
+                        Mark::Done(depth) => depth + 1,
+                        Mark::Visiting => unreachable!(),
+                    })
+                    .max()
+                    .unwrap_or(0);
+                marks.insert(id, Mark::Done(depth));
+                stack.pop();
+                continue;
+            };
+
+            stack.last_mut().unwrap().1 += 1;
+            let dependency = AssetId(dependency);
+            self.entry(dependency)
+                .ok_or(DependencyError::UnknownAsset(dependency))?;
+            match marks.get(&dependency) {
+                Some(Mark::Visiting) => {
+                    let start = stack.iter().position(|&(id, _)| id == dependency).unwrap();
+                    return Err(DependencyError::Cycle(
+                        stack[start..].iter().map(|&(id, _)| id).collect(),
+                    ));
+                }
+                Some(Mark::Done(_)) => {}
+                None => {
+                    marks.insert(dependency, Mark::Visiting);
+                    stack.push((dependency, 0));
+                }
+            }
+        }
+
+        let mut waves = Vec::new();
+        for (id, mark) in marks {
+            let Mark::Done(depth) = mark else {
+                unreachable!()
+            };
+            if waves.len() <= depth {
+                waves.resize_with(depth + 1, Vec::new);
+            }
+            waves[depth].push(id);
+        }
+        for wave in &mut waves {
+            wave.sort_unstable();
+        }
+        Ok(waves)
+    }
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Dependency(DependencyError),
+    /// Not enough free entries in the status array to track every asset that has to be read.
+    StatusArrayFull {
+        required: usize,
+        free: usize,
+    },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dependency(e) => e.fmt(f),
+            Self::StatusArrayFull { required, free } => write!(
+                f,
+                "loading requires {required} status array entries, but only {free} are free"
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Dependency(e) => Some(e),
+            Self::StatusArrayFull { .. } => None,
+        }
+    }
+}
+
+impl From<DependencyError> for LoadError {
+    fn from(e: DependencyError) -> Self {
+        Self::Dependency(e)
+    }
+}
+
+/// Identifies a closure loaded through [`DependencyLoader::load()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LoadHandle(u64);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoadStatus {
+    Loading,
+    /// Every asset of the closure is resident.
+    Resident,
+    /// Reading `asset` failed.  It is read again by the next [`DependencyLoader::load()`] that
+    /// needs it.
+    Failed {
+        asset: AssetId,
+        error: windows_core::Error,
+    },
+}
+
+enum State {
+    Unloaded,
+    Loading { status_index: u32 },
+    Resident,
+    Failed(windows_core::Error),
+}
+
+struct Asset {
+    state: State,
+    data: Vec<u8>,
+}
+
+/// Reads assets and their dependencies from an archive into memory.
+///
+/// Dropping the loader waits for assets that are still loading, as their requests write into
+/// memory owned by the loader.  The memory of assets that don't complete within ten seconds,
+/// for example because their requests were never submitted, is leaked instead.
+pub struct DependencyLoader {
+    toc: Toc,
+    queue: IDStorageQueue,
+    status_array: IDStorageStatusArray,
+    free_status: Vec<u32>,
+    assets: Vec<Asset>,
+    handles: HashMap<LoadHandle, Vec<AssetId>>,
+    next_handle: u64,
+    drop_timeout: Duration,
+}
+
+impl DependencyLoader {
+    /// Create a loader for the archive described by `toc`, enqueuing on `queue`.  Every asset
+    /// being read occupies one of the `capacity` entries of `status_array`.
+    pub fn new(
+        toc: Toc,
+        queue: IDStorageQueue,
+        status_array: IDStorageStatusArray,
+        capacity: u32,
+    ) -> Self {
+        let assets = toc
+            .entries
+            .iter()
+            .map(|_| Asset {
+                state: State::Unloaded,
+                data: Vec::new(),
+            })
+            .collect();
+        Self {
+            toc,
+            queue,
+            status_array,
+            free_status: (0..capacity).rev().collect(),
+            assets,
+            handles: HashMap::new(),
+            next_handle: 0,
+            drop_timeout: DROP_TIMEOUT,
+        }
+    }
+
+    pub fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    /// Enqueue and submit the reads of `root` and all its dependencies that are not resident or
+    /// loading yet, from `file`.
+    ///
+    /// # Safety
+    /// The queue must read from files into memory, and `file` must be the archive the TOC was
+    /// read from and stay open until the returned handle stopped [`LoadStatus::Loading`].
+    pub unsafe fn load(
+        &mut self,
+        file: &IDStorageFile,
+        root: AssetId,
+    ) -> Result<LoadHandle, LoadError> {
+        let closure: Vec<AssetId> = self.toc.load_order(root)?.concat();
+        let unloaded: Vec<AssetId> = closure
+            .iter()
+            .copied()
+            .filter(|id| {
+                matches!(
+                    self.assets[id.0 as usize].state,
+                    State::Unloaded | State::Failed(_)
+                )
+            })
+            .collect();
+        if unloaded.len() > self.free_status.len() {
+            return Err(LoadError::StatusArrayFull {
+                required: unloaded.len(),
+                free: self.free_status.len(),
+            });
+        }
+
+        for id in unloaded {
+            let entry = &self.toc.entries[id.0 as usize];
+            let asset = &mut self.assets[id.0 as usize];
+            asset.data = vec![0; entry.uncompressed_size() as usize];
+            let mut offset = 0;
+            for chunk in &entry.chunks {
+                let request = DSTORAGE_REQUEST {
+                    Options: chunk.options(DSTORAGE_REQUEST_DESTINATION_MEMORY),
+                    Source: DSTORAGE_SOURCE {
+                        File: std::mem::ManuallyDrop::new(unsafe { chunk.source_file(file) }),
+                    },
+                    Destination: DSTORAGE_DESTINATION {
+                        Memory: DSTORAGE_DESTINATION_MEMORY {
+                            Buffer: asset.data[offset..].as_mut_ptr().cast(),
+                            Size: chunk.uncompressed_size,
+                        },
+                    },
+                    UncompressedSize: chunk.uncompressed_size,
+                    ..Default::default()
+                };
+                unsafe { self.queue.EnqueueRequest(&request) };
+                offset += chunk.uncompressed_size as usize;
+            }
+
+            let status_index = self.free_status.pop().unwrap();
+            unsafe { self.queue.EnqueueStatus(&self.status_array, status_index) };
+            asset.state = State::Loading { status_index };
+        }
+        unsafe { self.queue.Submit() };
+
+        let handle = LoadHandle(self.next_handle);
+        self.next_handle += 1;
+        self.handles.insert(handle, closure);
+        Ok(handle)
+    }
+
+    /// Update the state of loading assets from the status array.
+    pub fn poll(&mut self) {
+        for asset in &mut self.assets {
+            let State::Loading { status_index } = asset.state else {
+                continue;
+            };
+            if !unsafe { self.status_array.IsComplete(status_index) } {
+                continue;
+            }
+            asset.state = match unsafe { self.status_array.GetHResult(status_index) } {
+                Ok(()) => State::Resident,
+                Err(error) => {
+                    asset.data = Vec::new();
+                    State::Failed(error)
+                }
+            };
+            self.free_status.push(status_index);
+        }
+    }
+
+    /// Status of a closure as of the last [`DependencyLoader::poll()`], or [`None`] for a
+    /// released handle.
+    pub fn status(&self, handle: LoadHandle) -> Option<LoadStatus> {
+        let mut status = LoadStatus::Resident;
+        for &id in self.handles.get(&handle)? {
+            match &self.assets[id.0 as usize].state {
+                State::Resident => {}
+                State::Failed(error) => {
+                    return Some(LoadStatus::Failed {
+                        asset: id,
+                        error: error.clone(),
+                    })
+                }
+                State::Unloaded | State::Loading { .. } => status = LoadStatus::Loading,
+            }
+        }
+        Some(status)
+    }
+
+    /// Data of a resident asset.
+    pub fn data(&self, id: AssetId) -> Option<&[u8]> {
+        let asset = self.assets.get(id.0 as usize)?;
+        matches!(asset.state, State::Resident).then_some(&asset.data)
+    }
+
+    /// Stop tracking `handle`.  The data of its assets stays resident.
+    pub fn release(&mut self, handle: LoadHandle) -> bool {
+        self.handles.remove(&handle).is_some()
+    }
+}
+
+impl Drop for DependencyLoader {
+    fn drop(&mut self) {
+        // Requests still write into the asset data, which must outlive them.
+        let deadline = Instant::now() + self.drop_timeout;
+        let is_loading = |a: &Asset| matches!(a.state, State::Loading { .. });
+        while self.assets.iter().any(is_loading) {
+            if Instant::now() >= deadline {
+                for asset in self.assets.iter_mut().filter(|a| is_loading(a)) {
+                    std::mem::forget(std::mem::take(&mut asset.data));
+                }
+                break;
+            }
+            self.poll();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io::Cursor, rc::Rc};
+
+    use windows::Win32::Storage::FileSystem::BY_HANDLE_FILE_INFORMATION;
+    use windows_core::{implement, Ref, HRESULT};
+
+    use super::*;
+    use crate::{
+        archive::{ArchiveWriter, ChunkData},
+        IDStorageFile_Impl, IDStorageQueue_Impl, IDStorageStatusArray_Impl,
+        DSTORAGE_COMPRESSION_FORMAT_NONE, DSTORAGE_ERROR_RECORD, DSTORAGE_QUEUE_INFO,
+        E_DSTORAGE_END_OF_FILE,
+    };
+
+    fn toc(dependencies: &[&[u32]]) -> Toc {
+        Toc {
+            entries: dependencies
+                .iter()
+                .enumerate()
+                .map(|(i, dependencies)| Entry {
+                    name: format!("asset{i}"),
+                    chunks: Vec::new(),
+                    dependencies: dependencies.to_vec(),
+                })
+                .collect(),
+        }
+    }
+
+    fn ids(waves: &[&[u32]]) -> Vec<Vec<AssetId>> {
+        waves
+            .iter()
+            .map(|wave| wave.iter().copied().map(AssetId).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_load_order() {
+        // mesh 0 -> material 1 -> textures 2, 3; mesh 0 -> texture 3; 4 is unrelated.
+        let toc = toc(&[&[1, 3], &[2, 3], &[], &[], &[0]]);
+        assert_eq!(toc.load_order(AssetId(0)), Ok(ids(&[&[2, 3], &[1], &[0]])));
+        assert_eq!(toc.load_order(AssetId(2)), Ok(ids(&[&[2]])));
+        assert_eq!(
+            toc.load_order(AssetId(4)),
+            Ok(ids(&[&[2, 3], &[1], &[0], &[4]]))
+        );
+        assert_eq!(toc.asset("asset1"), Some(AssetId(1)));
+    }
+
+    #[test]
+    fn test_invalid_graph() {
+        let toc = toc(&[&[1], &[2], &[1], &[3], &[7]]);
+        assert_eq!(
+            toc.load_order(AssetId(0)),
+            Err(DependencyError::Cycle(vec![AssetId(1), AssetId(2)]))
+        );
+        assert_eq!(
+            toc.load_order(AssetId(3)),
+            Err(DependencyError::Cycle(vec![AssetId(3)]))
+        );
+        assert_eq!(
+            toc.load_order(AssetId(4)),
+            Err(DependencyError::UnknownAsset(AssetId(7)))
+        );
+        assert_eq!(
+            toc.load_order(AssetId(5)),
+            Err(DependencyError::UnknownAsset(AssetId(5)))
+        );
+    }
+
+    enum Operation {
+        Read {
+            offset: u64,
+            size: u32,
+            destination: *mut u8,
+        },
+        Status(u32),
+    }
+
+    /// Requests enqueued on a [`FakeQueue`], executed by [`Device::run()`].
+    struct Device {
+        archive: Vec<u8>,
+        enqueued: Vec<Operation>,
+        /// Result of every status array entry, [`None`] while incomplete.
+        statuses: Vec<Option<HRESULT>>,
+    }
+
+    impl Device {
+        /// Execute all enqueued operations, failing reads at `fail_offset`.
+        fn run(&mut self, fail_offset: Option<u64>) {
+            let mut result = HRESULT(0);
+            for operation in std::mem::take(&mut self.enqueued) {
+                match operation {
+                    Operation::Read {
+                        offset,
+                        size,
+                        destination,
+                    } => {
+                        if Some(offset) == fail_offset {
+                            result = E_DSTORAGE_END_OF_FILE;
+                            continue;
+                        }
+                        let data = &self.archive[offset as usize..][..size as usize];
+                        unsafe { destination.copy_from_nonoverlapping(data.as_ptr(), data.len()) };
+                    }
+                    Operation::Status(index) => {
+                        self.statuses[index as usize] =
+                            Some(std::mem::replace(&mut result, HRESULT(0)));
+                    }
+                }
+            }
+        }
+    }
+
+    #[implement(IDStorageFile)]
+    struct FakeFile;
+
+    impl IDStorageFile_Impl for FakeFile_Impl {
+        fn Close(&self) {}
+
+        fn GetFileInformation(
+            &self,
+            _info: *mut BY_HANDLE_FILE_INFORMATION,
+        ) -> windows_core::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[implement(IDStorageQueue)]
+    struct FakeQueue(Rc<RefCell<Device>>);
+
+    impl IDStorageQueue_Impl for FakeQueue_Impl {
+        fn EnqueueRequest(&self, request: *const DSTORAGE_REQUEST) {
+            let request = unsafe { &*request };
+            assert_eq!(
+                request.Options.CompressionFormat(),
+                DSTORAGE_COMPRESSION_FORMAT_NONE
+            );
+            let (source, destination) =
+                unsafe { (&request.Source.File, request.Destination.Memory) };
+            assert_eq!(source.Size, destination.Size);
+            self.0.borrow_mut().enqueued.push(Operation::Read {
+                offset: source.Offset,
+                size: source.Size,
+                destination: destination.Buffer.cast(),
+            });
+        }
+
+        fn EnqueueStatus(&self, _status_array: Ref<IDStorageStatusArray>, index: u32) {
+            let mut device = self.0.borrow_mut();
+            device.statuses[index as usize] = None;
+            device.enqueued.push(Operation::Status(index));
+        }
+
+        fn EnqueueSignal(
+            &self,
+            _fence: Ref<windows::Win32::Graphics::Direct3D12::ID3D12Fence>,
+            _value: u64,
+        ) {
+            panic!("not used by DependencyLoader")
+        }
+
+        fn Submit(&self) {}
+
+        fn CancelRequestsWithTag(&self, _mask: u64, _value: u64) {
+            panic!("not used by DependencyLoader")
+        }
+
+        fn Close(&self) {}
+
+        fn GetErrorEvent(&self) -> windows::Win32::Foundation::HANDLE {
+            panic!("not used by DependencyLoader")
+        }
+
+        fn RetrieveErrorRecord(&self, _record: *mut DSTORAGE_ERROR_RECORD) {
+            panic!("not used by DependencyLoader")
+        }
+
+        fn Query(&self, _info: *mut DSTORAGE_QUEUE_INFO) {
+            panic!("not used by DependencyLoader")
+        }
+    }
+
+    #[implement(IDStorageStatusArray)]
+    struct FakeStatusArray(Rc<RefCell<Device>>);
+
+    impl IDStorageStatusArray_Impl for FakeStatusArray_Impl {
+        fn IsComplete(&self, index: u32) -> bool {
+            self.0.borrow().statuses[index as usize].is_some()
+        }
+
+        fn GetHResult(&self, index: u32) -> windows_core::Result<()> {
+            self.0.borrow().statuses[index as usize].unwrap().ok()
+        }
+    }
+
+    /// A texture used by a material, which is used by a mesh together with the texture.
+    fn loader(capacity: u32) -> (DependencyLoader, Rc<RefCell<Device>>) {
+        let chunk = |data| ChunkData {
+            data,
+            uncompressed_size: data.len() as u32,
+            compression_format: DSTORAGE_COMPRESSION_FORMAT_NONE,
+            checksum: None,
+        };
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_entry("texture", [chunk(b"tex"), chunk(b"ture")])
+            .unwrap();
+        writer.add_entry("material", [chunk(b"material")]).unwrap();
+        writer.add_entry("mesh", [chunk(b"mesh")]).unwrap();
+        writer.add_dependency("material", "texture").unwrap();
+        writer.add_dependency("mesh", "material").unwrap();
+        writer.add_dependency("mesh", "texture").unwrap();
+        let archive = writer.finish().unwrap().into_inner();
+
+        let device = Rc::new(RefCell::new(Device {
+            archive: archive.clone(),
+            enqueued: Vec::new(),
+            statuses: vec![None; capacity as usize],
+        }));
+        let loader = DependencyLoader::new(
+            Toc::read_from(Cursor::new(archive)).unwrap(),
+            FakeQueue(device.clone()).into(),
+            FakeStatusArray(device.clone()).into(),
+            capacity,
+        );
+        (loader, device)
+    }
+
+    #[test]
+    fn test_loader() {
+        let (mut loader, device) = loader(4);
+        let file: IDStorageFile = FakeFile.into();
+        let texture = loader.toc().asset("texture").unwrap();
+        let mesh = loader.toc().asset("mesh").unwrap();
+
+        let handle = unsafe { loader.load(&file, mesh) }.unwrap();
+        // Two reads for the texture, one for each other asset, each asset followed by a status.
+        assert_eq!(device.borrow().enqueued.len(), 7);
+        loader.poll();
+        assert_eq!(loader.status(handle), Some(LoadStatus::Loading));
+        assert_eq!(loader.data(texture), None);
+
+        device.borrow_mut().run(None);
+        loader.poll();
+        assert_eq!(loader.status(handle), Some(LoadStatus::Resident));
+        assert_eq!(loader.data(texture), Some(&b"texture"[..]));
+        assert_eq!(loader.data(mesh), Some(&b"mesh"[..]));
+
+        // Resident dependencies are not read again.
+        let material = loader.toc().asset("material").unwrap();
+        let handle = unsafe { loader.load(&file, material) }.unwrap();
+        assert!(device.borrow().enqueued.is_empty());
+        assert_eq!(loader.status(handle), Some(LoadStatus::Resident));
+
+        assert!(loader.release(handle));
+        assert!(!loader.release(handle));
+        assert_eq!(loader.status(handle), None);
+    }
+
+    #[test]
+    fn test_loader_failure() {
+        let (mut loader, device) = loader(4);
+        let file: IDStorageFile = FakeFile.into();
+        let texture = loader.toc().asset("texture").unwrap();
+        let mesh = loader.toc().asset("mesh").unwrap();
+
+        let handle = unsafe { loader.load(&file, mesh) }.unwrap();
+        let texture_offset = loader.toc().entries[texture.0 as usize].chunks[1].offset;
+        device.borrow_mut().run(Some(texture_offset));
+        loader.poll();
+        assert_eq!(
+            loader.status(handle),
+            Some(LoadStatus::Failed {
+                asset: texture,
+                error: E_DSTORAGE_END_OF_FILE.into(),
+            })
+        );
+        assert_eq!(loader.data(texture), None);
+        assert_eq!(loader.data(mesh), Some(&b"mesh"[..]));
+
+        // Only the failed asset is read again.
+        let handle = unsafe { loader.load(&file, mesh) }.unwrap();
+        assert_eq!(device.borrow().enqueued.len(), 3);
+        device.borrow_mut().run(None);
+        loader.poll();
+        assert_eq!(loader.status(handle), Some(LoadStatus::Resident));
+        assert_eq!(loader.data(texture), Some(&b"texture"[..]));
+    }
+
+    #[test]
+    fn test_loader_status_array_full() {
+        let (mut loader, device) = loader(2);
+        let file: IDStorageFile = FakeFile.into();
+        let mesh = loader.toc().asset("mesh").unwrap();
+        assert!(matches!(
+            unsafe { loader.load(&file, mesh) },
+            Err(LoadError::StatusArrayFull {
+                required: 3,
+                free: 2
+            })
+        ));
+        assert!(device.borrow().enqueued.is_empty());
+
+        let texture = loader.toc().asset("texture").unwrap();
+        unsafe { loader.load(&file, texture) }.unwrap();
+        device.borrow_mut().run(None);
+    }
+
+    #[test]
+    fn test_drop_while_loading() {
+        let (mut loader, device) = loader(4);
+        let file: IDStorageFile = FakeFile.into();
+        let mesh = loader.toc().asset("mesh").unwrap();
+        unsafe { loader.load(&file, mesh) }.unwrap();
+
+        // The requests never run, dropping gives up and leaks their destinations.
+        loader.drop_timeout = Duration::from_millis(10);
+        drop(loader);
+        assert!(device.borrow().statuses.iter().all(Option::is_none));
+    }
+}
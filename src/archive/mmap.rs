@@ -0,0 +1,267 @@
+//! Reading archives straight out of memory with [`DSTORAGE_REQUEST_SOURCE_MEMORY`] requests.
+//!
+//! [`MappedArchive`] keeps an archive in memory (typically a memory-mapped file) and hands out
+//! [`SourceMemory`] descriptions that borrow from it.  Requests built from those are only
+//! enqueued through [`MemoryBatch::scope()`], which does not return before all of them
+//! completed, so the mapping can't be unmapped while DirectStorage is still reading from it.
+
+use std::{fs::File, io, marker::PhantomData, path::Path};
+
+use memmap2::Mmap;
+use windows::Win32::System::Threading::{WaitForSingleObject, INFINITE};
+use windows_core::{Interface, PCSTR};
+
+use super::{Chunk, Toc};
+use crate::{
+    waiter::Event, IDStorageFactory, IDStorageQueue, IDStorageQueue1, IDStorageStatusArray,
+    DSTORAGE_DESTINATION, DSTORAGE_DESTINATION_MEMORY, DSTORAGE_REQUEST,
+    DSTORAGE_REQUEST_DESTINATION_MEMORY, DSTORAGE_REQUEST_DESTINATION_TYPE,
+    DSTORAGE_REQUEST_OPTIONS, DSTORAGE_REQUEST_SOURCE_MEMORY, DSTORAGE_SOURCE,
+    DSTORAGE_SOURCE_MEMORY,
+};
+
+/// An archive whose contents are fully accessible in memory.
+pub struct MappedArchive<B = Mmap> {
+    data: B,
+    toc: Toc,
+}
+
+impl MappedArchive<Mmap> {
+    /// Memory-map the archive at `path` and read its table of contents.
+    ///
+    /// # Safety
+    /// The file must not be modified or truncated while it is mapped, see [`Mmap::map()`].
+    pub unsafe fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let map = unsafe { Mmap::map(&file) }?;
+        Self::new(map)
+    }
+}
+
+impl<B: AsRef<[u8]>> MappedArchive<B> {
+    /// Read the table of contents from an archive that is already in memory.
+    pub fn new(data: B) -> io::Result<Self> {
+        let toc = Toc::read_from(io::Cursor::new(data.as_ref()))?;
+        Ok(Self { data, toc })
+    }
+
+    pub fn toc(&self) -> &Toc {
+        &self.toc
+    }
+
+    /// The stored (possibly compressed) payload of `chunk`.
+    pub fn chunk_data(&self, chunk: &Chunk) -> &[u8] {
+        &self.data.as_ref()[chunk.offset as usize..][..chunk.compressed_size as usize]
+    }
+
+    /// Describe `chunk` as the source of a [`DSTORAGE_REQUEST_SOURCE_MEMORY`] request.
+    pub fn source(&self, chunk: &Chunk) -> SourceMemory<'_> {
+        SourceMemory {
+            raw: DSTORAGE_SOURCE_MEMORY {
+                Source: self.chunk_data(chunk).as_ptr().cast(),
+                Size: chunk.compressed_size,
+            },
+            options: chunk.options(DSTORAGE_REQUEST_DESTINATION_MEMORY),
+            uncompressed_size: chunk.uncompressed_size,
+            _data: PhantomData,
+        }
+    }
+}
+
+/// A [`DSTORAGE_SOURCE_MEMORY`] that borrows the memory it points to.
+#[derive(Clone, Copy, Debug)]
+pub struct SourceMemory<'a> {
+    raw: DSTORAGE_SOURCE_MEMORY,
+    options: DSTORAGE_REQUEST_OPTIONS,
+    uncompressed_size: u32,
+    _data: PhantomData<&'a [u8]>,
+}
+
+impl SourceMemory<'_> {
+    pub fn raw(&self) -> &DSTORAGE_SOURCE_MEMORY {
+        &self.raw
+    }
+
+    /// Size of the data after decompression, and thus the minimum size of the destination.
+    pub fn uncompressed_size(&self) -> u32 {
+        self.uncompressed_size
+    }
+}
+
+/// Requests enqueued on a [`DSTORAGE_REQUEST_SOURCE_MEMORY`] queue that borrow their source
+/// and destination memory for `'env`.
+pub struct MemoryBatch<'env> {
+    queue: IDStorageQueue1,
+    status_array: IDStorageStatusArray,
+    event: Event,
+    enqueued: bool,
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'env> MemoryBatch<'env> {
+    /// Run `f` to enqueue requests on `queue`, then submit them and wait until they completed.
+    ///
+    /// Waiting also happens when `f` panics, so no request can outlive the memory it borrows.
+    /// Returns the first error reported for the batch, if any.  Waiting blocks on an event set
+    /// through [`IDStorageQueue1::EnqueueSetEvent()`], which requires DirectStorage 1.1.
+    ///
+    /// # Safety
+    /// `queue` must have been created with [`DSTORAGE_REQUEST_SOURCE_MEMORY`] as source type by
+    /// `factory`.
+    pub unsafe fn scope<R>(
+        factory: &IDStorageFactory,
+        queue: &'env IDStorageQueue,
+        f: impl FnOnce(&mut MemoryBatch<'env>) -> R,
+    ) -> windows_core::Result<R> {
+        let status_array = unsafe { factory.CreateStatusArray(1, PCSTR::null()) }?;
+        let mut batch = Self {
+            queue: queue.cast()?,
+            status_array,
+            event: Event::new()?,
+            enqueued: false,
+            _env: PhantomData,
+        };
+
+        let result = f(&mut batch);
+        batch.wait()?;
+        Ok(result)
+    }
+
+    /// Enqueue a request decompressing `source` into `destination`.
+    ///
+    /// # Panics
+    /// If `destination` is smaller than the uncompressed data or bigger than [`u32::MAX`] bytes.
+    pub fn enqueue_to_memory(&mut self, source: SourceMemory<'env>, destination: &'env mut [u8]) {
+        assert!(destination.len() >= source.uncompressed_size as usize);
+        let size = u32::try_from(destination.len()).expect("destination is bigger than u32::MAX");
+        unsafe {
+            self.enqueue(
+                source,
+                DSTORAGE_REQUEST_DESTINATION_MEMORY,
+                DSTORAGE_DESTINATION {
+                    Memory: DSTORAGE_DESTINATION_MEMORY {
+                        Buffer: destination.as_mut_ptr().cast(),
+                        Size: size,
+                    },
+                },
+            )
+        }
+    }
+
+    /// Enqueue a request reading from `source` into an arbitrary `destination`.
+    ///
+    /// # Safety
+    /// `destination` must be of type `destination_type` and stay valid until this batch has
+    /// completed.
+    pub unsafe fn enqueue(
+        &mut self,
+        source: SourceMemory<'env>,
+        destination_type: DSTORAGE_REQUEST_DESTINATION_TYPE,
+        destination: DSTORAGE_DESTINATION,
+    ) {
+        let mut options = source.options;
+        options.set_SourceType(DSTORAGE_REQUEST_SOURCE_MEMORY);
+        options.set_DestinationType(destination_type);
+
+        let request = DSTORAGE_REQUEST {
+            Options: options,
+            Source: DSTORAGE_SOURCE { Memory: source.raw },
+            Destination: destination,
+            UncompressedSize: source.uncompressed_size,
+            CancellationTag: 0,
+            Name: PCSTR::null(),
+        };
+        unsafe { self.queue.EnqueueRequest(&request) };
+        self.enqueued = true;
+    }
+
+    fn wait(&mut self) -> windows_core::Result<()> {
+        if !std::mem::take(&mut self.enqueued) {
+            return Ok(());
+        }
+
+        unsafe {
+            self.queue.EnqueueStatus(&self.status_array, 0);
+            self.queue.EnqueueSetEvent(self.event.0);
+            self.queue.Submit();
+            WaitForSingleObject(self.event.0, INFINITE);
+            self.status_array.GetHResult(0)
+        }
+    }
+}
+
+impl Drop for MemoryBatch<'_> {
+    fn drop(&mut self) {
+        // Only reached with pending requests when the closure passed to `scope()` panicked.
+        let _ = self.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{
+        archive::{ArchiveWriter, ChunkData},
+        DSTORAGE_COMPRESSION_FORMAT_GDEFLATE, DSTORAGE_COMPRESSION_FORMAT_NONE,
+    };
+
+    fn archive() -> Vec<u8> {
+        let mut writer = ArchiveWriter::new(Cursor::new(Vec::new()));
+        writer
+            .add_entry(
+                "a",
+                [
+                    ChunkData {
+                        data: b"uncompressed",
+                        uncompressed_size: 12,
+                        compression_format: DSTORAGE_COMPRESSION_FORMAT_NONE,
+                        checksum: None,
+                    },
+                    ChunkData {
+                        data: b"gdeflate",
+                        uncompressed_size: 64,
+                        compression_format: DSTORAGE_COMPRESSION_FORMAT_GDEFLATE,
+                        checksum: None,
+                    },
+                ],
+            )
+            .unwrap();
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_source_memory() {
+        let archive = MappedArchive::new(archive()).unwrap();
+        let chunks = &archive.toc().find("a").unwrap().chunks;
+
+        assert_eq!(archive.chunk_data(&chunks[1]), b"gdeflate");
+
+        let source = archive.source(&chunks[1]);
+        assert_eq!(source.raw().Size, 8);
+        assert_eq!(source.uncompressed_size(), 64);
+        assert_eq!(
+            source.options.CompressionFormat(),
+            DSTORAGE_COMPRESSION_FORMAT_GDEFLATE
+        );
+        let data = unsafe { std::slice::from_raw_parts(source.raw().Source.cast::<u8>(), 8) };
+        assert_eq!(data, b"gdeflate");
+    }
+
+    #[test]
+    fn test_open_mapped() {
+        let path = std::env::temp_dir().join(format!(
+            "direct-storage-mmap-test-{}.dsar",
+            std::process::id()
+        ));
+        std::fs::write(&path, archive()).unwrap();
+
+        let archive = unsafe { MappedArchive::open(&path) }.unwrap();
+        let chunk = archive.toc().find("a").unwrap().chunks[0];
+        assert_eq!(archive.chunk_data(&chunk), b"uncompressed");
+
+        drop(archive);
+        std::fs::remove_file(path).unwrap();
+    }
+}
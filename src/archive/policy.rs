@@ -0,0 +1,343 @@
+//! Choosing chunk sizes and whether to compress when packing files into an archive.
+
+use std::{error::Error, fmt, ops::Range};
+
+use super::{Checksum, ChunkData};
+use crate::{
+    DSTORAGE_COMPRESSION_FORMAT, DSTORAGE_COMPRESSION_FORMAT_NONE,
+    DSTORAGE_STAGING_BUFFER_SIZE_32MB,
+};
+
+/// Policy for splitting a file into chunks and deciding per chunk whether compression is worth
+/// it.
+///
+/// GPU decompression is cheap, but not free: a chunk is only stored compressed when doing so
+/// saves at least [`PackPolicy::min_savings`] of its size.  When the first chunk of a file does
+/// not meet that threshold the file is considered incompressible; the remainder is stored
+/// uncompressed in chunks of [`PackPolicy::max_chunk_size`] without attempting to compress it.
+/// Otherwise the compression ratio of the first chunk picks the size of the remaining chunks,
+/// see [`PackPolicy::chunk_size_for_ratio()`].
+///
+/// Every chunk is kept within [`PackPolicy::staging_buffer_size`] (both compressed and
+/// uncompressed), so requests never fail with [`crate::E_DSTORAGE_STAGING_BUFFER_TOO_SMALL`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PackPolicy {
+    /// Chunks are never made smaller than this, unless the file itself is smaller.
+    pub min_chunk_size: u32,
+    /// Chunks are never made larger than this.
+    pub max_chunk_size: u32,
+    /// Staging buffer size the archive will be read with, see
+    /// [`crate::IDStorageFactory::SetStagingBufferSize()`].
+    pub staging_buffer_size: u32,
+    /// Number of chunks a file is split into when that doesn't violate the size limits above.
+    /// More chunks allow more decompression work to happen in parallel.
+    pub target_chunks_per_file: u32,
+    /// Minimum fraction of bytes compression has to save for a chunk to be stored compressed,
+    /// between 0 and 1.
+    pub min_savings: f64,
+}
+
+impl Default for PackPolicy {
+    fn default() -> Self {
+        Self {
+            min_chunk_size: 64 * 1024,
+            max_chunk_size: 16 * 1024 * 1024,
+            staging_buffer_size: DSTORAGE_STAGING_BUFFER_SIZE_32MB.0,
+            target_chunks_per_file: 4,
+            min_savings: 0.05,
+        }
+    }
+}
+
+/// A chunk produced by [`PackPolicy::pack()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PackedChunk {
+    /// Range of the uncompressed input covered by this chunk.
+    pub range: Range<usize>,
+    /// Compressed payload, or [`None`] when the chunk is stored uncompressed.
+    pub compressed: Option<Vec<u8>>,
+    pub compression_format: DSTORAGE_COMPRESSION_FORMAT,
+}
+
+impl PackedChunk {
+    /// Payload to pass to [`super::ArchiveWriter::add_entry()`], `input` being the data passed to
+    /// [`PackPolicy::pack()`].
+    pub fn chunk_data<'a>(&'a self, input: &'a [u8], checksum: Option<Checksum>) -> ChunkData<'a> {
+        let uncompressed = &input[self.range.clone()];
+        ChunkData {
+            data: self.compressed.as_deref().unwrap_or(uncompressed),
+            uncompressed_size: uncompressed.len() as u32,
+            compression_format: self.compression_format,
+            checksum,
+        }
+    }
+}
+
+/// Error of [`PackPolicy::pack()`].
+#[derive(Debug)]
+pub enum PackError<E> {
+    /// The policy is invalid, see [`PackPolicy::validate()`].
+    Policy(&'static str),
+    Compress(E),
+}
+
+impl<E: fmt::Display> fmt::Display for PackError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Policy(e) => write!(f, "invalid pack policy: {e}"),
+            Self::Compress(e) => write!(f, "compression failed: {e}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> Error for PackError<E> {}
+
+impl PackPolicy {
+    /// Check that the settings are in range: [`PackPolicy::min_savings`] between 0 and 1, and
+    /// all sizes and counts other than [`PackPolicy::min_chunk_size`] non-zero.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(0.0..=1.0).contains(&self.min_savings) {
+            return Err("min_savings is not between 0 and 1");
+        }
+        if self.max_chunk_size == 0 {
+            return Err("max_chunk_size is zero");
+        }
+        if self.staging_buffer_size == 0 {
+            return Err("staging_buffer_size is zero");
+        }
+        if self.target_chunks_per_file == 0 {
+            return Err("target_chunks_per_file is zero");
+        }
+        Ok(())
+    }
+
+    /// Largest chunk size that never exceeds the staging buffer.
+    fn chunk_limit(&self) -> u32 {
+        self.max_chunk_size.min(self.staging_buffer_size).max(1)
+    }
+
+    /// Chunk size for a file of `file_size` bytes, before its compression ratio is known.
+    pub fn chunk_size(&self, file_size: u64) -> u32 {
+        self.chunk_size_for_ratio(file_size, 1.0)
+    }
+
+    /// Chunk size for a file of `file_size` bytes that compresses to `ratio` times its size.
+    ///
+    /// [`PackPolicy::min_chunk_size`] applies to the bytes read from disk, so the better a file
+    /// compresses, the larger its uncompressed chunks are, up to the staging buffer size.
+    pub fn chunk_size_for_ratio(&self, file_size: u64, ratio: f64) -> u32 {
+        let limit = self.chunk_limit();
+        let ratio = if ratio > 0.0 { ratio.min(1.0) } else { 1.0 };
+        let min = (f64::from(self.min_chunk_size) / ratio).min(f64::from(limit)) as u32;
+        let min = min.clamp(1, limit);
+
+        let target = file_size.div_ceil(self.target_chunks_per_file.max(1) as u64);
+        let target = target.checked_next_power_of_two().unwrap_or(u64::MAX);
+        target.clamp(min as u64, limit as u64) as u32
+    }
+
+    /// Whether a chunk of `uncompressed_size` compressing to `compressed_size` bytes should be
+    /// stored compressed.
+    pub fn keep_compressed(&self, uncompressed_size: usize, compressed_size: usize) -> bool {
+        compressed_size <= self.staging_buffer_size as usize
+            && (compressed_size as f64) <= uncompressed_size as f64 * (1.0 - self.min_savings)
+    }
+
+    /// Split `data` into chunks, compressing them with `compress` into `compression_format`
+    /// where that meets [`PackPolicy::min_savings`].
+    pub fn pack<E>(
+        &self,
+        data: &[u8],
+        compression_format: DSTORAGE_COMPRESSION_FORMAT,
+        mut compress: impl FnMut(&[u8]) -> Result<Vec<u8>, E>,
+    ) -> Result<Vec<PackedChunk>, PackError<E>> {
+        self.validate().map_err(PackError::Policy)?;
+        let mut chunks = Vec::new();
+        let mut chunk_size = self.chunk_size(data.len() as u64) as usize;
+        let mut incompressible = compression_format == DSTORAGE_COMPRESSION_FORMAT_NONE;
+
+        let mut offset = 0;
+        while offset < data.len() {
+            let range = offset..data.len().min(offset + chunk_size);
+            offset = range.end;
+
+            let compressed = if incompressible {
+                None
+            } else {
+                let compressed = compress(&data[range.clone()]).map_err(PackError::Compress)?;
+                if self.keep_compressed(range.len(), compressed.len()) {
+                    if chunks.is_empty() {
+                        let ratio = compressed.len() as f64 / range.len() as f64;
+                        chunk_size = self.chunk_size_for_ratio(data.len() as u64, ratio) as usize;
+                    }
+                    Some(compressed)
+                } else {
+                    if chunks.is_empty() {
+                        // Don't spend more time on compressing this file, and read it in larger
+                        // requests instead.
+                        incompressible = true;
+                        chunk_size = self.chunk_limit() as usize;
+                    }
+                    None
+                }
+            };
+
+            chunks.push(PackedChunk {
+                range,
+                compression_format: if compressed.is_some() {
+                    compression_format
+                } else {
+                    DSTORAGE_COMPRESSION_FORMAT_NONE
+                },
+                compressed,
+            });
+        }
+
+        Ok(chunks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use super::*;
+    use crate::DSTORAGE_COMPRESSION_FORMAT_GDEFLATE;
+
+    const MIB: u32 = 1024 * 1024;
+
+    /// Run-length encode into (byte, count) pairs, good enough to tell compressible data apart.
+    fn rle(data: &[u8]) -> Result<Vec<u8>, Infallible> {
+        let mut out: Vec<u8> = Vec::new();
+        for &byte in data {
+            match out.len() {
+                n if n >= 2 && out[n - 2] == byte && out[n - 1] < u8::MAX => out[n - 1] += 1,
+                _ => out.extend_from_slice(&[byte, 1]),
+            }
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn test_chunk_size() {
+        let policy = PackPolicy::default();
+        assert_eq!(policy.chunk_size(0), 64 * 1024);
+        assert_eq!(policy.chunk_size(1000), 64 * 1024);
+        assert_eq!(policy.chunk_size(4 * MIB as u64), MIB);
+        assert_eq!(policy.chunk_size(5 * MIB as u64), 2 * MIB);
+        assert_eq!(policy.chunk_size(u64::MAX), 16 * MIB);
+
+        let policy = PackPolicy {
+            staging_buffer_size: 4 * MIB,
+            ..Default::default()
+        };
+        assert_eq!(policy.chunk_size(1 << 40), 4 * MIB);
+
+        let policy = PackPolicy {
+            min_chunk_size: 64 * MIB,
+            staging_buffer_size: 2 * MIB,
+            ..Default::default()
+        };
+        assert_eq!(policy.chunk_size(1000), 2 * MIB);
+    }
+
+    #[test]
+    fn test_chunk_size_for_ratio() {
+        let policy = PackPolicy::default();
+        assert_eq!(policy.chunk_size_for_ratio(1000, 1.0), 64 * 1024);
+        // Every request still reads at least 64 KiB.
+        assert_eq!(policy.chunk_size_for_ratio(1000, 0.25), 256 * 1024);
+        assert_eq!(policy.chunk_size_for_ratio(64 * MIB as u64, 0.25), 16 * MIB);
+        assert_eq!(policy.chunk_size_for_ratio(1000, 0.0), 64 * 1024);
+        assert_eq!(policy.chunk_size_for_ratio(1000, 1e-9), 16 * MIB);
+    }
+
+    #[test]
+    fn test_validate() {
+        PackPolicy::default().validate().unwrap();
+        for min_savings in [-0.1, 1.5, f64::NAN] {
+            let policy = PackPolicy {
+                min_savings,
+                ..Default::default()
+            };
+            assert!(policy.validate().is_err());
+            assert!(matches!(
+                policy.pack(&[0; 16], DSTORAGE_COMPRESSION_FORMAT_GDEFLATE, rle),
+                Err(PackError::Policy(_))
+            ));
+        }
+        let policy = PackPolicy {
+            staging_buffer_size: 0,
+            ..Default::default()
+        };
+        assert_eq!(policy.validate(), Err("staging_buffer_size is zero"));
+    }
+
+    #[test]
+    fn test_keep_compressed() {
+        let policy = PackPolicy {
+            min_savings: 0.25,
+            ..Default::default()
+        };
+        assert!(policy.keep_compressed(100, 75));
+        assert!(!policy.keep_compressed(100, 76));
+        assert!(!policy.keep_compressed(100, 100));
+    }
+
+    #[test]
+    fn test_pack_compressible() {
+        // Compresses to a quarter, so every request after the first one reads 64 KiB instead of
+        // 32 KiB.
+        let data: Vec<u8> = (0..512 * 1024).map(|i| (i / 8 % 251) as u8).collect();
+        let chunks = PackPolicy::default()
+            .pack(&data, DSTORAGE_COMPRESSION_FORMAT_GDEFLATE, rle)
+            .unwrap();
+
+        let k = 1024;
+        let ranges: Vec<_> = chunks.iter().map(|c| c.range.clone()).collect();
+        assert_eq!(ranges, [0..128 * k, 128 * k..384 * k, 384 * k..512 * k]);
+        let sizes: Vec<_> = chunks
+            .iter()
+            .map(|c| c.chunk_data(&data, None).data.len())
+            .collect();
+        assert_eq!(sizes, [32 * k, 64 * k, 32 * k]);
+        assert!(chunks
+            .iter()
+            .all(|c| c.compression_format == DSTORAGE_COMPRESSION_FORMAT_GDEFLATE));
+
+        // Compresses far better, so the remainder is read in a single chunk.
+        let data = vec![0u8; 512 * 1024];
+        let chunks = PackPolicy::default()
+            .pack(&data, DSTORAGE_COMPRESSION_FORMAT_GDEFLATE, rle)
+            .unwrap();
+        let ranges: Vec<_> = chunks.iter().map(|c| c.range.clone()).collect();
+        assert_eq!(ranges, [0..128 * 1024, 128 * 1024..512 * 1024]);
+        assert!(chunks.iter().all(|c| c.compressed.is_some()));
+    }
+
+    #[test]
+    fn test_pack_incompressible() {
+        let data: Vec<u8> = (0..3 * MIB).map(|i| (i % 251) as u8).collect();
+        let mut calls = 0;
+        let chunks = PackPolicy {
+            max_chunk_size: 2 * MIB,
+            ..Default::default()
+        }
+        .pack(&data, DSTORAGE_COMPRESSION_FORMAT_GDEFLATE, |chunk| {
+            calls += 1;
+            rle(chunk)
+        })
+        .unwrap();
+
+        // Only the first chunk is compressed, the rest is stored in chunks of the maximum size.
+        assert_eq!(calls, 1);
+        let ranges: Vec<_> = chunks.iter().map(|c| c.range.clone()).collect();
+        let first = MIB as usize;
+        assert_eq!(ranges, [0..first, first..3 * first]);
+        assert!(chunks
+            .iter()
+            .all(|c| c.compressed.is_none()
+                && c.compression_format == DSTORAGE_COMPRESSION_FORMAT_NONE));
+        assert_eq!(chunks[1].chunk_data(&data, None).data, &data[first..]);
+    }
+}
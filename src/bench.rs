@@ -0,0 +1,886 @@
+//! A harness for benchmarking loads against any replay [`Backend`].
+//!
+//! A [`Sweep`] lists the parameters to vary: chunk size, staging buffer size, queue capacity,
+//! [`PriorityMix`] and compression format.  [`Sweep::run()`] runs every combination of them as a
+//! [`Case`], reading a whole [`Dataset`] split into chunks and timing it from the first enqueued
+//! request until every queue completed.  Every run also measures the CPU time the process spent,
+//! and feeds its requests into a [`Metrics`] registry that yields the bandwidth, compression
+//! ratio and latency per priority of the case.  The results are written as CSV or JSON with
+//! [`write_csv()`] and [`write_json()`], so that runs on different machines can be compared.
+//!
+//! The harness only issues [`Operation`]s, so the same sweep runs on
+//! [`Emulator`](crate::replay::emulator::Emulator) on any platform, optionally in virtual time on
+//! a [`DeviceModel`](crate::replay::simulation::DeviceModel), and on Windows against
+//! DirectStorage itself with `DirectStorageBackend`.  Backends are created per case, which is
+//! where the staging buffer size has to be applied; backends that have none ignore it.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    fmt,
+    io::{self, Write},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    metrics::{Metrics, MetricsSnapshot},
+    priority::Priority,
+    replay::{
+        Backend, Completion, FileId, Operation, QueueDesc, QueueId, Request, Source, SourceType,
+    },
+    scheduler::SimulatedClock,
+    split::split,
+};
+
+/// The maximum capacity of a queue,
+/// [`DSTORAGE_MAX_QUEUE_CAPACITY`](crate::DSTORAGE_MAX_QUEUE_CAPACITY).
+pub const MAX_QUEUE_CAPACITY: u16 = 8192;
+
+/// A range of a [`Dataset`] that is read with a single request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub offset: u64,
+    pub size: u32,
+    pub uncompressed_size: u32,
+    /// Compression format of the chunk, chunks that don't compress well may be stored
+    /// uncompressed in an otherwise compressed dataset.
+    pub compression_format: u8,
+}
+
+/// A file and the chunks it is read in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dataset {
+    /// Path passed to [`Operation::OpenFile`].
+    pub path: String,
+    pub chunks: Vec<Chunk>,
+}
+
+impl Dataset {
+    /// Read `size` bytes of the uncompressed file at `path` in chunks of `chunk_size` bytes.
+    pub fn uncompressed(path: impl Into<String>, size: u64, chunk_size: u32) -> Self {
+        Self {
+            path: path.into(),
+            chunks: split(0, size, chunk_size)
+                .map(|part| Chunk {
+                    offset: part.file_offset,
+                    size: part.size,
+                    uncompressed_size: part.size,
+                    compression_format: 0,
+                })
+                .collect(),
+        }
+    }
+
+    /// Number of bytes read from the file.
+    pub fn size(&self) -> u64 {
+        self.chunks.iter().map(|chunk| u64::from(chunk.size)).sum()
+    }
+
+    /// Number of bytes delivered to destinations.
+    pub fn uncompressed_size(&self) -> u64 {
+        self.chunks
+            .iter()
+            .map(|chunk| u64::from(chunk.uncompressed_size))
+            .sum()
+    }
+}
+
+/// Weights of the priorities requests are spread over, with a queue per entry.
+///
+/// Requests are assigned in turn: with `low:1/high:3`, one request goes to the low priority
+/// queue, then three to the high priority queue, and so on.  Mixes are written and parsed as
+/// `priority:weight` pairs separated by `/`, where a weight of 1 may be left out.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PriorityMix {
+    weights: Vec<(Priority, u32)>,
+}
+
+impl PriorityMix {
+    /// # Panics
+    /// If `weights` is empty or a weight is zero.
+    pub fn new(weights: Vec<(Priority, u32)>) -> Self {
+        assert!(!weights.is_empty(), "a priority mix needs a priority");
+        assert!(
+            weights.iter().all(|&(_, weight)| weight > 0),
+            "weights must be positive"
+        );
+        Self { weights }
+    }
+
+    /// All requests on a single queue of `priority`.
+    pub fn single(priority: Priority) -> Self {
+        Self::new(vec![(priority, 1)])
+    }
+
+    pub fn weights(&self) -> &[(Priority, u32)] {
+        &self.weights
+    }
+
+    /// Index into [`Self::weights()`] of the queue the request at `index` goes to.
+    pub fn queue_of(&self, index: usize) -> usize {
+        let total: u64 = self.weights.iter().map(|&(_, w)| u64::from(w)).sum();
+        let mut position = index as u64 % total;
+        for (queue, &(_, weight)) in self.weights.iter().enumerate() {
+            match position.checked_sub(weight.into()) {
+                Some(rest) => position = rest,
+                None => return queue,
+            }
+        }
+        unreachable!()
+    }
+}
+
+impl Default for PriorityMix {
+    fn default() -> Self {
+        Self::single(Priority::Normal)
+    }
+}
+
+impl fmt::Display for PriorityMix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, &(priority, weight)) in self.weights.iter().enumerate() {
+            if i > 0 {
+                f.write_str("/")?;
+            }
+            f.write_str(priority_name(priority))?;
+            if weight != 1 {
+                write!(f, ":{weight}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Error of parsing a [`PriorityMix`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsePriorityMixError(String);
+
+impl fmt::Display for ParsePriorityMixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid priority mix `{}`", self.0)
+    }
+}
+
+impl std::error::Error for ParsePriorityMixError {}
+
+impl FromStr for PriorityMix {
+    type Err = ParsePriorityMixError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParsePriorityMixError(s.to_owned());
+        let weights = s
+            .split('/')
+            .map(|entry| {
+                let (name, weight) = match entry.split_once(':') {
+                    Some((name, weight)) => (name, weight.parse().map_err(|_| error())?),
+                    None => (entry, 1),
+                };
+                let priority = Priority::ALL
+                    .into_iter()
+                    .find(|&p| priority_name(p) == name)
+                    .ok_or_else(error)?;
+                match weight {
+                    0 => Err(error()),
+                    weight => Ok((priority, weight)),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::new(weights))
+    }
+}
+
+fn priority_name(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Low => "low",
+        Priority::Normal => "normal",
+        Priority::High => "high",
+        Priority::Realtime => "realtime",
+    }
+}
+
+/// The parameters to benchmark, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sweep {
+    /// Chunk sizes in bytes.
+    pub chunk_sizes: Vec<u32>,
+    /// Staging buffer sizes in bytes.  Cases with chunks that don't fit in the staging buffer
+    /// are skipped, since DirectStorage rejects them.
+    pub staging_buffer_sizes: Vec<u32>,
+    pub queue_capacities: Vec<u16>,
+    pub priority_mixes: Vec<PriorityMix>,
+    pub compression_formats: Vec<u8>,
+    /// How often each case is run.
+    pub runs: u32,
+}
+
+impl Default for Sweep {
+    /// 16 MiB chunks with the default staging buffer size of 32 MiB on a single normal priority
+    /// queue of the maximum capacity, uncompressed and run 10 times.
+    fn default() -> Self {
+        Self {
+            chunk_sizes: vec![16 << 20],
+            staging_buffer_sizes: vec![32 << 20],
+            queue_capacities: vec![MAX_QUEUE_CAPACITY],
+            priority_mixes: vec![PriorityMix::default()],
+            compression_formats: vec![0],
+            runs: 10,
+        }
+    }
+}
+
+/// One combination of the parameters of a [`Sweep`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Case {
+    pub chunk_size: u32,
+    pub staging_buffer_size: u32,
+    pub queue_capacity: u16,
+    pub priority_mix: PriorityMix,
+    pub compression_format: u8,
+}
+
+impl fmt::Display for Case {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} chunks, {} staging buffer, capacity {}, {}, format {}",
+            Size(self.chunk_size),
+            Size(self.staging_buffer_size),
+            self.queue_capacity,
+            self.priority_mix,
+            self.compression_format
+        )
+    }
+}
+
+/// Formats a size in bytes in the largest unit it is a multiple of.
+struct Size(u32);
+
+impl fmt::Display for Size {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            0 => write!(f, "0 B"),
+            size if size % (1 << 20) == 0 => write!(f, "{} MiB", size >> 20),
+            size if size % (1 << 10) == 0 => write!(f, "{} KiB", size >> 10),
+            size => write!(f, "{size} B"),
+        }
+    }
+}
+
+/// Error of [`Sweep::run()`] and [`run_case()`].
+#[derive(Debug)]
+pub enum BenchError<E> {
+    /// The dataset could not be prepared.
+    Dataset(io::Error),
+    Backend(E),
+    /// A request failed with this `HRESULT`.
+    Request(i32),
+}
+
+impl<E: fmt::Display> fmt::Display for BenchError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dataset(e) => write!(f, "failed to prepare the dataset: {e}"),
+            Self::Backend(e) => write!(f, "backend failed: {e}"),
+            Self::Request(hresult) => write!(f, "request failed with HRESULT {hresult:#010x}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for BenchError<E> {}
+
+/// Measurements of a [`Case`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaseResult {
+    pub case: Case,
+    /// Number of requests per run.
+    pub requests: usize,
+    /// Bytes read per run.
+    pub bytes_read: u64,
+    /// Bytes delivered per run.
+    pub bytes_delivered: u64,
+    /// Time each run took, in virtual time for backends that run in it.
+    pub elapsed: Vec<Duration>,
+    /// CPU time the process spent in each run, always in real time.  Empty on platforms where
+    /// it can't be queried.
+    pub cpu_time: Vec<Duration>,
+    /// Metrics of all runs, on a timeline that only advances by the time each run took.  The
+    /// latency of a request is the time from the start of its run until the status of its batch
+    /// completed.
+    pub metrics: MetricsSnapshot,
+}
+
+impl CaseResult {
+    pub fn mean(&self) -> Option<Duration> {
+        let runs = u32::try_from(self.elapsed.len()).ok().filter(|&n| n > 0)?;
+        Some(self.elapsed.iter().sum::<Duration>() / runs)
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.elapsed.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.elapsed.iter().max().copied()
+    }
+
+    pub fn mean_cpu_time(&self) -> Option<Duration> {
+        let runs = u32::try_from(self.cpu_time.len()).ok().filter(|&n| n > 0)?;
+        Some(self.cpu_time.iter().sum::<Duration>() / runs)
+    }
+
+    /// Delivered bytes per second over all runs.
+    pub fn bandwidth(&self) -> Option<f64> {
+        self.metrics.bandwidth(None)
+    }
+
+    /// Bytes delivered per byte read.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        self.metrics.compression_ratio()
+    }
+}
+
+impl Sweep {
+    /// Every combination of the parameters, skipping chunks that don't fit in the staging
+    /// buffer.
+    pub fn cases(&self) -> Vec<Case> {
+        let mut cases = Vec::new();
+        for &compression_format in &self.compression_formats {
+            for priority_mix in &self.priority_mixes {
+                for &queue_capacity in &self.queue_capacities {
+                    for &chunk_size in &self.chunk_sizes {
+                        for &staging_buffer_size in &self.staging_buffer_sizes {
+                            if chunk_size <= staging_buffer_size {
+                                cases.push(Case {
+                                    chunk_size,
+                                    staging_buffer_size,
+                                    queue_capacity,
+                                    priority_mix: priority_mix.clone(),
+                                    compression_format,
+                                });
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        cases
+    }
+
+    /// Run every case of the sweep.
+    ///
+    /// `dataset` prepares the data for a chunk size and compression format; it is called once
+    /// per combination.  `backend` creates the backend for each case.
+    pub fn run<B: Backend>(
+        &self,
+        mut dataset: impl FnMut(u32, u8) -> io::Result<Dataset>,
+        mut backend: impl FnMut(&Case) -> Result<B, B::Error>,
+    ) -> Result<Vec<CaseResult>, BenchError<B::Error>> {
+        let mut datasets = HashMap::new();
+        let mut results = Vec::new();
+        for case in self.cases() {
+            let key = (case.chunk_size, case.compression_format);
+            let dataset = match datasets.entry(key) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    dataset(case.chunk_size, case.compression_format)
+                        .map_err(BenchError::Dataset)?,
+                ),
+            };
+            let mut backend = backend(&case).map_err(BenchError::Backend)?;
+            results.push(run_case(&case, dataset, &mut backend, self.runs)?);
+        }
+        Ok(results)
+    }
+}
+
+/// Most statuses enqueued per run.  Datasets with more chunks are read in batches of
+/// requests that share a status.
+const MAX_STATUSES: usize = 1024;
+
+/// How often backends that run in real time are polled for completed statuses.
+const POLL_INTERVAL: Duration = Duration::from_micros(100);
+
+/// Read `dataset` `runs` times as described by `case`.
+///
+/// Each run opens the file and creates a queue per entry of the priority mix, which isn't
+/// timed.  The timed part enqueues every chunk followed by a status per batch on each queue,
+/// submits the queues and polls the backend until every status completed.  The queues and the
+/// file are closed after every run, also when it failed.
+pub fn run_case<B: Backend>(
+    case: &Case,
+    dataset: &Dataset,
+    backend: &mut B,
+    runs: u32,
+) -> Result<CaseResult, BenchError<B::Error>> {
+    let mut result = CaseResult {
+        case: case.clone(),
+        requests: dataset.chunks.len(),
+        bytes_read: dataset.size(),
+        bytes_delivered: dataset.uncompressed_size(),
+        elapsed: Vec::with_capacity(runs as usize),
+        cpu_time: Vec::with_capacity(runs as usize),
+        metrics: MetricsSnapshot::default(),
+    };
+    let mut metrics = Metrics::with_clock(SimulatedClock::new());
+    let file = FileId(0);
+    let queues: Vec<_> = (0..case.priority_mix.weights().len() as u32)
+        .map(QueueId)
+        .collect();
+    let batches = Batches::new(case, dataset, &queues);
+    let execute = |backend: &mut B, operation: Operation| {
+        backend.execute(&operation).map_err(BenchError::Backend)
+    };
+
+    for _ in 0..runs {
+        execute(
+            backend,
+            Operation::OpenFile {
+                file,
+                path: dataset.path.clone(),
+            },
+        )?;
+        let mut created = Vec::with_capacity(queues.len());
+        let mut run = Ok(());
+        for (&queue, &(priority, _)) in queues.iter().zip(case.priority_mix.weights()) {
+            let desc = QueueDesc {
+                name: format!("bench-{}", priority_name(priority)),
+                source_type: SourceType::File,
+                capacity: case.queue_capacity,
+                priority,
+            };
+            run = execute(backend, Operation::CreateQueue { queue, desc });
+            if run.is_err() {
+                break;
+            }
+            created.push(queue);
+        }
+        let run =
+            run.and_then(|()| time_run(case, dataset, &queues, &batches, backend, &mut metrics));
+
+        // Every close is attempted, the first failure is reported.
+        let mut closed = Ok(());
+        for &queue in &created {
+            closed = closed.and(execute(backend, Operation::CloseQueue { queue }));
+        }
+        closed = closed.and(execute(backend, Operation::CloseFile { file }));
+        let (elapsed, cpu_time) = run?;
+        closed?;
+
+        result.elapsed.push(elapsed);
+        result.cpu_time.extend(cpu_time);
+    }
+    result.metrics = metrics.snapshot();
+    Ok(result)
+}
+
+/// The statuses of a run and the requests each of them completes.
+struct Batches {
+    /// Status enqueued after the request at each index.
+    after: HashMap<usize, (QueueId, u32)>,
+    requests: HashMap<(QueueId, u32), Vec<usize>>,
+}
+
+impl Batches {
+    fn new(case: &Case, dataset: &Dataset, queues: &[QueueId]) -> Self {
+        let size = dataset.chunks.len().div_ceil(MAX_STATUSES).max(1);
+        let mut per_queue = vec![Vec::new(); queues.len()];
+        for i in 0..dataset.chunks.len() {
+            per_queue[case.priority_mix.queue_of(i)].push(i);
+        }
+        let mut batches = Self {
+            after: HashMap::new(),
+            requests: HashMap::new(),
+        };
+        for (&queue, requests) in queues.iter().zip(per_queue) {
+            for (index, batch) in (0..).zip(requests.chunks(size)) {
+                batches.after.insert(batch[batch.len() - 1], (queue, index));
+                batches.requests.insert((queue, index), batch.to_vec());
+            }
+        }
+        batches
+    }
+}
+
+/// The timed part of [`run_case()`], returning the time the run took and the CPU time the
+/// process spent in it.
+///
+/// The latency of a request is the time from the start of the run until the status of its
+/// batch was seen complete, which is exact in virtual time and up to [`POLL_INTERVAL`] late in
+/// real time.
+fn time_run<B: Backend>(
+    case: &Case,
+    dataset: &Dataset,
+    queues: &[QueueId],
+    batches: &Batches,
+    backend: &mut B,
+    metrics: &mut Metrics<SimulatedClock>,
+) -> Result<(Duration, Option<Duration>), BenchError<B::Error>> {
+    let execute = |backend: &mut B, operation: Operation| {
+        backend.execute(&operation).map_err(BenchError::Backend)
+    };
+    let weights = case.priority_mix.weights();
+    let start = Instant::now();
+    let cpu_start = process_cpu_time();
+    let virtual_start = backend.virtual_time();
+    let elapsed = |backend: &B| match (virtual_start, backend.virtual_time()) {
+        (Some(start), Some(now)) => now - start,
+        _ => start.elapsed(),
+    };
+    let timeline = metrics.clock().elapsed();
+
+    for (i, chunk) in dataset.chunks.iter().enumerate() {
+        let request = Request {
+            name: None,
+            source: Source::File {
+                file: FileId(0),
+                offset: chunk.offset,
+                size: chunk.size,
+            },
+            compression_format: chunk.compression_format,
+            destination_type: 0,
+            destination_size: chunk.uncompressed_size,
+            uncompressed_size: chunk.uncompressed_size,
+            cancellation_tag: 0,
+        };
+        let queue = queues[case.priority_mix.queue_of(i)];
+        execute(backend, Operation::EnqueueRequest { queue, request })?;
+        if let Some(&(queue, index)) = batches.after.get(&i) {
+            execute(backend, Operation::EnqueueStatus { queue, index })?;
+        }
+    }
+    for &queue in queues {
+        execute(backend, Operation::Submit { queue })?;
+    }
+
+    let mut pending: HashSet<_> = batches.requests.keys().copied().collect();
+    let mut failure = None;
+    while !pending.is_empty() {
+        let completions = backend.poll();
+        let now = elapsed(backend);
+        for completion in completions {
+            let Completion::Status {
+                queue,
+                index,
+                hresult,
+            } = completion
+            else {
+                continue;
+            };
+            if !pending.remove(&(queue, index)) {
+                continue;
+            }
+            if hresult != 0 {
+                failure.get_or_insert(hresult);
+                continue;
+            }
+            let clock = metrics.clock();
+            clock.advance((timeline + now).saturating_sub(clock.elapsed()));
+            for &i in &batches.requests[&(queue, index)] {
+                let chunk = &dataset.chunks[i];
+                let (priority, _) = weights[case.priority_mix.queue_of(i)];
+                metrics.record_request(
+                    priority,
+                    chunk.size.into(),
+                    chunk.uncompressed_size.into(),
+                    now,
+                );
+            }
+        }
+        if pending.is_empty() {
+            break;
+        }
+        match (virtual_start, backend.next_completion()) {
+            (Some(_), Some(at)) => backend.advance_to(at),
+            (Some(_), None) => backend.flush().map_err(BenchError::Backend)?,
+            (None, _) => std::thread::sleep(POLL_INTERVAL),
+        }
+    }
+    let elapsed = elapsed(backend);
+    let cpu_time = match (cpu_start, process_cpu_time()) {
+        (Some(start), Some(now)) => Some(now.saturating_sub(start)),
+        _ => None,
+    };
+    if let Some(hresult) = failure {
+        return Err(BenchError::Request(hresult));
+    }
+    let clock = metrics.clock();
+    clock.advance((timeline + elapsed).saturating_sub(clock.elapsed()));
+    Ok((elapsed, cpu_time))
+}
+
+/// CPU time spent by the process so far, in user and kernel mode.
+#[cfg(windows)]
+fn process_cpu_time() -> Option<Duration> {
+    use windows::Win32::{
+        Foundation::FILETIME,
+        System::Threading::{GetCurrentProcess, GetProcessTimes},
+    };
+
+    let mut times = [FILETIME::default(); 4];
+    let [creation, exit, kernel, user] = &mut times;
+    unsafe { GetProcessTimes(GetCurrentProcess(), creation, exit, kernel, user) }.ok()?;
+    // In units of 100 ns.
+    let ticks =
+        |time: &FILETIME| u64::from(time.dwHighDateTime) << 32 | u64::from(time.dwLowDateTime);
+    Some(Duration::from_nanos(
+        (ticks(kernel) + ticks(user)).saturating_mul(100),
+    ))
+}
+
+/// CPU time spent by the process so far, in user and system mode.
+#[cfg(unix)]
+fn process_cpu_time() -> Option<Duration> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let usage = unsafe { usage.assume_init() };
+    let duration = |time: libc::timeval| {
+        let seconds = u64::try_from(time.tv_sec).ok()?;
+        let micros = u32::try_from(time.tv_usec).ok()?;
+        Some(Duration::from_secs(seconds) + Duration::from_micros(micros.into()))
+    };
+    Some(duration(usage.ru_utime)? + duration(usage.ru_stime)?)
+}
+
+#[cfg(not(any(windows, unix)))]
+fn process_cpu_time() -> Option<Duration> {
+    None
+}
+
+const CSV_HEADER: &str = "chunk_size,staging_buffer_size,queue_capacity,priority_mix,\
+    compression_format,runs,requests,bytes_read,bytes_delivered,mean_seconds,min_seconds,\
+    max_seconds,bandwidth_bytes_per_second,compression_ratio,mean_cpu_seconds";
+
+/// Write `results` as CSV with a header row.  Missing values are left empty.
+pub fn write_csv(results: &[CaseResult], mut writer: impl Write) -> io::Result<()> {
+    writeln!(writer, "{CSV_HEADER}")?;
+    for result in results {
+        let case = &result.case;
+        writeln!(
+            writer,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            case.chunk_size,
+            case.staging_buffer_size,
+            case.queue_capacity,
+            case.priority_mix,
+            case.compression_format,
+            result.elapsed.len(),
+            result.requests,
+            result.bytes_read,
+            result.bytes_delivered,
+            Value(result.mean().map(|d| d.as_secs_f64()), ""),
+            Value(result.min().map(|d| d.as_secs_f64()), ""),
+            Value(result.max().map(|d| d.as_secs_f64()), ""),
+            Value(result.bandwidth(), ""),
+            Value(result.compression_ratio(), ""),
+            Value(result.mean_cpu_time().map(|d| d.as_secs_f64()), ""),
+        )?;
+    }
+    Ok(())
+}
+
+/// Write `results` as a JSON array with an object per case.  Missing values are `null`.
+pub fn write_json(results: &[CaseResult], mut writer: impl Write) -> io::Result<()> {
+    writeln!(writer, "[")?;
+    for (i, result) in results.iter().enumerate() {
+        let case = &result.case;
+        let elapsed: Vec<_> = result
+            .elapsed
+            .iter()
+            .map(|d| d.as_secs_f64().to_string())
+            .collect();
+        // Priority mixes only consist of ASCII letters, digits, `:` and `/`, so they don't need
+        // escaping.
+        write!(
+            writer,
+            "  {{\"chunk_size\": {}, \"staging_buffer_size\": {}, \"queue_capacity\": {}, \
+             \"priority_mix\": \"{}\", \"compression_format\": {}, \"requests\": {}, \
+             \"bytes_read\": {}, \"bytes_delivered\": {}, \"elapsed_seconds\": [{}], \
+             \"mean_seconds\": {}, \"bandwidth_bytes_per_second\": {}, \
+             \"compression_ratio\": {}, \"mean_cpu_seconds\": {}}}",
+            case.chunk_size,
+            case.staging_buffer_size,
+            case.queue_capacity,
+            case.priority_mix,
+            case.compression_format,
+            result.requests,
+            result.bytes_read,
+            result.bytes_delivered,
+            elapsed.join(", "),
+            Value(result.mean().map(|d| d.as_secs_f64()), "null"),
+            Value(result.bandwidth(), "null"),
+            Value(result.compression_ratio(), "null"),
+            Value(result.mean_cpu_time().map(|d| d.as_secs_f64()), "null"),
+        )?;
+        writeln!(writer, "{}", if i + 1 < results.len() { "," } else { "" })?;
+    }
+    writeln!(writer, "]")
+}
+
+/// Formats a finite value, or the placeholder if there is none.
+struct Value(Option<f64>, &'static str);
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(value) if value.is_finite() => write!(f, "{value}"),
+            _ => f.write_str(self.1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ops::Deref,
+        path::{Path, PathBuf},
+        rc::Rc,
+    };
+
+    use super::*;
+    use crate::{
+        replay::{
+            emulator::{Emulator, EmulatorError},
+            simulation::{DeviceModel, ThroughputCurve},
+        },
+        scheduler::SimulatedClock,
+    };
+
+    /// A file in the temporary directory, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl Deref for TempFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn temp_file(name: &str, size: usize) -> TempFile {
+        let path = std::env::temp_dir().join(format!(
+            "direct-storage-bench-{}-{name}",
+            std::process::id()
+        ));
+        std::fs::write(&path, (0..size).map(|i| i as u8).collect::<Vec<_>>()).unwrap();
+        TempFile(path)
+    }
+
+    #[test]
+    fn test_priority_mix() {
+        let mix: PriorityMix = "low/high:3".parse().unwrap();
+        assert_eq!(mix.weights(), [(Priority::Low, 1), (Priority::High, 3)]);
+        assert_eq!(mix.to_string(), "low/high:3");
+        let queues: Vec<_> = (0..6).map(|i| mix.queue_of(i)).collect();
+        assert_eq!(queues, [0, 1, 1, 1, 0, 1]);
+        for invalid in ["", "urgent", "low:0", "low:x"] {
+            assert!(invalid.parse::<PriorityMix>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_sweep() {
+        let path = temp_file("sweep", 10_000);
+        let sweep = Sweep {
+            chunk_sizes: vec![1024, 4096],
+            staging_buffer_sizes: vec![2048, 8192],
+            queue_capacities: vec![2, MAX_QUEUE_CAPACITY],
+            priority_mixes: vec![PriorityMix::default(), "low/high".parse().unwrap()],
+            compression_formats: vec![0],
+            runs: 2,
+        };
+        // The 4 KiB chunks don't fit in the 2 KiB staging buffer.
+        assert_eq!(sweep.cases().len(), 12);
+
+        let mut prepared = 0;
+        let results = sweep
+            .run(
+                |chunk_size, _| {
+                    prepared += 1;
+                    Ok(Dataset::uncompressed(
+                        path.to_str().unwrap(),
+                        10_000,
+                        chunk_size,
+                    ))
+                },
+                |_| Ok::<_, EmulatorError>(Emulator::new()),
+            )
+            .unwrap();
+        assert_eq!(prepared, 2);
+        assert_eq!(results.len(), 12);
+        for result in &results {
+            assert_eq!(result.elapsed.len(), 2);
+            assert_eq!(
+                (result.bytes_read, result.bytes_delivered),
+                (10_000, 10_000)
+            );
+            assert_eq!(result.compression_ratio(), Some(1.0));
+            if cfg!(any(windows, unix)) {
+                assert_eq!(result.cpu_time.len(), 2);
+            }
+        }
+        assert_eq!(results[0].requests, 10);
+
+        let mut csv = Vec::new();
+        write_csv(&results, &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        assert_eq!(csv.lines().count(), 13);
+        assert!(csv.lines().all(|line| line.split(',').count() == 15));
+        assert!(csv
+            .lines()
+            .nth(12)
+            .unwrap()
+            .starts_with("4096,8192,8192,low/high,0,2,3,"));
+
+        let mut json = Vec::new();
+        write_json(&results[..2], &mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("[\n  {\"chunk_size\": 1024, \"staging_buffer_size\": 2048, "));
+        assert_eq!(json.matches("\"priority_mix\": \"normal\"").count(), 2);
+    }
+
+    #[test]
+    fn test_virtual_time() {
+        let path = temp_file("virtual", 4000);
+        let dataset = Dataset::uncompressed(path.to_str().unwrap(), 4000, 1000);
+        let mut emulator = Emulator::new();
+        emulator.set_device(
+            DeviceModel {
+                queue_depth: 4,
+                latency: Duration::from_micros(100),
+                // 1 byte per µs.
+                throughput: ThroughputCurve::constant(1e6),
+                decompression: Vec::new(),
+            },
+            Rc::new(SimulatedClock::new()),
+        );
+        let case = Sweep::default().cases().remove(0);
+        let result = run_case(&case, &dataset, &mut emulator, 3).unwrap();
+        // The latencies overlap, the transfers don't.
+        assert_eq!(result.elapsed, [Duration::from_micros(4100); 3]);
+        assert_eq!(result.bandwidth(), Some(3.0 * 4000.0 / 0.0123));
+        let latency = &result.metrics.latency[&Priority::Normal];
+        assert_eq!(latency.count(), 12);
+        assert_eq!(latency.max(), Duration::from_micros(4100));
+        // Each request has its own status, so each latency ends with its transfer.
+        assert_eq!(
+            latency.sum(),
+            3 * Duration::from_micros(1100 + 2100 + 3100 + 4100)
+        );
+
+        let missing = Dataset::uncompressed(path.to_str().unwrap(), 5000, 1000);
+        assert!(matches!(
+            run_case(&case, &missing, &mut emulator, 1),
+            Err(BenchError::Request(_))
+        ));
+        // The failed run closed its queue and the file.
+        assert!(run_case(&case, &dataset, &mut emulator, 1).is_ok());
+    }
+}
@@ -1,4 +1,5 @@
 // Bindings generated by `windows-bindgen` 0.63.0
+// Post-processed by `api_gen` for DirectStorage 1.3.0, don't edit.
 
 #![allow(
     non_snake_case,
@@ -268,6 +269,12 @@ pub mod Microsoft {
                 pub MultipleSubresourcesRange:
                     core::mem::ManuallyDrop<DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE>,
             }
+            impl core::fmt::Debug for DSTORAGE_DESTINATION {
+                fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                    f.debug_struct("DSTORAGE_DESTINATION")
+                        .finish_non_exhaustive()
+                }
+            }
             impl Clone for DSTORAGE_DESTINATION {
                 fn clone(&self) -> Self {
                     unsafe { core::mem::transmute_copy(self) }
@@ -367,6 +374,12 @@ pub mod Microsoft {
                 pub Signal: core::mem::ManuallyDrop<DSTORAGE_ERROR_PARAMETERS_SIGNAL>,
                 pub Event: DSTORAGE_ERROR_PARAMETERS_EVENT,
             }
+            impl core::fmt::Debug for DSTORAGE_ERROR_FIRST_FAILURE_0 {
+                fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                    f.debug_struct("DSTORAGE_ERROR_FIRST_FAILURE_0")
+                        .finish_non_exhaustive()
+                }
+            }
             impl Clone for DSTORAGE_ERROR_FIRST_FAILURE_0 {
                 fn clone(&self) -> Self {
                     unsafe { core::mem::transmute_copy(self) }
@@ -541,6 +554,19 @@ pub mod Microsoft {
                 pub Reserved1: [u8; 7],
                 pub _bitfield2: u64,
             }
+            bitfield! {
+                /// Accessors of the C bitfields packed into the `_bitfield` fields.
+                impl DSTORAGE_REQUEST_OPTIONS {
+                    _bitfield1: u8 {
+                        CompressionFormat, set_CompressionFormat: DSTORAGE_COMPRESSION_FORMAT(u8) [0..8];
+                    }
+                    _bitfield2: u64 {
+                        SourceType, set_SourceType: DSTORAGE_REQUEST_SOURCE_TYPE(u64) [0..1];
+                        DestinationType, set_DestinationType: DSTORAGE_REQUEST_DESTINATION_TYPE(u64) [1..8];
+                        Reserved, set_Reserved: u64 [8..56];
+                    }
+                }
+            }
             impl Default for DSTORAGE_REQUEST_OPTIONS {
                 fn default() -> Self {
                     unsafe { core::mem::zeroed() }
@@ -559,6 +585,11 @@ pub mod Microsoft {
                 pub Memory: DSTORAGE_SOURCE_MEMORY,
                 pub File: core::mem::ManuallyDrop<DSTORAGE_SOURCE_FILE>,
             }
+            impl core::fmt::Debug for DSTORAGE_SOURCE {
+                fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                    f.debug_struct("DSTORAGE_SOURCE").finish_non_exhaustive()
+                }
+            }
             impl Clone for DSTORAGE_SOURCE {
                 fn clone(&self) -> Self {
                     unsafe { core::mem::transmute_copy(self) }
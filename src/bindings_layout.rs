@@ -0,0 +1,240 @@
+// Bindings layout tests generated by `api_gen` for DirectStorage 1.3.0, don't edit.
+
+use core::mem::{align_of, size_of, MaybeUninit};
+
+use super::*;
+
+/// Asserts the size and alignment of a type and the offsets of its fields.
+macro_rules! layout {
+    ($ty:ident: $size:literal, $align:literal { $($field:ident: $offset:literal),* $(,)? }) => {
+        assert_eq!(size_of::<$ty>(), $size, concat!("size of ", stringify!($ty)));
+        assert_eq!(align_of::<$ty>(), $align, concat!("alignment of ", stringify!($ty)));
+        $(
+            let value = MaybeUninit::<$ty>::uninit();
+            let base = value.as_ptr();
+            // SAFETY: Only computes the address of the field, nothing is read.
+            let field = unsafe { core::ptr::addr_of!((*base).$field) };
+            assert_eq!(
+                field as usize - base as usize,
+                $offset,
+                concat!("offset of ", stringify!($ty), "::", stringify!($field))
+            );
+        )*
+    };
+}
+
+#[cfg(target_pointer_width = "32")]
+#[test]
+fn test_msvc_compat_32bit() {
+    layout!(DSTORAGE_COMMAND_TYPE: 4, 4 {});
+    layout!(DSTORAGE_COMPRESSION: 4, 4 {});
+    layout!(DSTORAGE_COMPRESSION_FORMAT: 1, 1 {});
+    layout!(DSTORAGE_COMPRESSION_SUPPORT: 4, 4 {});
+    layout!(DSTORAGE_CONFIGURATION: 28, 4 {
+        NumSubmitThreads: 0,
+        NumBuiltInCpuDecompressionThreads: 4,
+        ForceMappingLayer: 8,
+        DisableBypassIO: 12,
+        DisableTelemetry: 16,
+        DisableGpuDecompressionMetacommand: 20,
+        DisableGpuDecompression: 24,
+    });
+    layout!(DSTORAGE_CONFIGURATION1: 32, 4 {
+        NumSubmitThreads: 0,
+        NumBuiltInCpuDecompressionThreads: 4,
+        ForceMappingLayer: 8,
+        DisableBypassIO: 12,
+        DisableTelemetry: 16,
+        DisableGpuDecompressionMetacommand: 20,
+        DisableGpuDecompression: 24,
+        ForceFileBuffering: 28,
+    });
+    layout!(DSTORAGE_CUSTOM_DECOMPRESSION_FLAGS: 4, 4 {});
+    layout!(DSTORAGE_CUSTOM_DECOMPRESSION_REQUEST: 48, 8 {
+        Id: 0,
+        CompressionFormat: 8,
+        Reserved: 9,
+        Flags: 12,
+        SrcSize: 16,
+        SrcBuffer: 24,
+        DstSize: 32,
+        DstBuffer: 40,
+    });
+    layout!(DSTORAGE_CUSTOM_DECOMPRESSION_RESULT: 16, 8 { Id: 0, Result: 8 });
+    layout!(DSTORAGE_DEBUG: 4, 4 {});
+    layout!(DSTORAGE_DESTINATION: 40, 8 {
+        Memory: 0,
+        Buffer: 0,
+        Texture: 0,
+        MultipleSubresources: 0,
+        Tiles: 0,
+        MultipleSubresourcesRange: 0,
+    });
+    layout!(DSTORAGE_DESTINATION_BUFFER: 24, 8 { Resource: 0, Offset: 8, Size: 16 });
+    layout!(DSTORAGE_DESTINATION_MEMORY: 8, 4 { Buffer: 0, Size: 4 });
+    layout!(DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES: 8, 4 { Resource: 0, FirstSubresource: 4 });
+    layout!(DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE: 12, 4 {
+        Resource: 0,
+        FirstSubresource: 4,
+        NumSubresources: 8,
+    });
+    layout!(DSTORAGE_DESTINATION_TEXTURE_REGION: 32, 4 {
+        Resource: 0,
+        SubresourceIndex: 4,
+        Region: 8,
+    });
+    layout!(DSTORAGE_DESTINATION_TILES: 36, 4 {
+        Resource: 0,
+        TiledRegionStartCoordinate: 4,
+        TileRegionSize: 20,
+    });
+    layout!(DSTORAGE_ENQUEUE_REQUEST_FLAGS: 4, 4 {});
+    layout!(DSTORAGE_ERROR_FIRST_FAILURE: 696, 8 { HResult: 0, CommandType: 4, Anonymous: 8 });
+    layout!(DSTORAGE_ERROR_FIRST_FAILURE_0: 688, 8 { Request: 0, Status: 0, Signal: 0, Event: 0 });
+    layout!(DSTORAGE_ERROR_PARAMETERS_EVENT: 4, 4 { Handle: 0 });
+    layout!(DSTORAGE_ERROR_PARAMETERS_REQUEST: 688, 8 {
+        Filename: 0,
+        RequestName: 520,
+        Request: 584,
+    });
+    layout!(DSTORAGE_ERROR_PARAMETERS_SIGNAL: 16, 8 { Fence: 0, Value: 8 });
+    layout!(DSTORAGE_ERROR_PARAMETERS_STATUS: 8, 4 { StatusArray: 0, Index: 4 });
+    layout!(DSTORAGE_ERROR_RECORD: 704, 8 { FailureCount: 0, FirstFailure: 8 });
+    layout!(DSTORAGE_GET_REQUEST_FLAGS: 4, 4 {});
+    layout!(DSTORAGE_PRIORITY: 1, 1 {});
+    layout!(DSTORAGE_QUEUE_DESC: 24, 8 {
+        SourceType: 0,
+        Capacity: 8,
+        Priority: 10,
+        Name: 12,
+        Device: 16,
+    });
+    layout!(DSTORAGE_QUEUE_INFO: 32, 8 {
+        Desc: 0,
+        EmptySlotCount: 24,
+        RequestCountUntilAutoSubmit: 26,
+    });
+    layout!(DSTORAGE_REQUEST: 104, 8 {
+        Options: 0,
+        Source: 16,
+        Destination: 40,
+        UncompressedSize: 80,
+        CancellationTag: 88,
+        Name: 96,
+    });
+    layout!(DSTORAGE_REQUEST_DESTINATION_TYPE: 8, 8 {});
+    layout!(DSTORAGE_REQUEST_OPTIONS: 16, 8 { _bitfield1: 0, Reserved1: 1, _bitfield2: 8 });
+    layout!(DSTORAGE_REQUEST_SOURCE_TYPE: 8, 8 {});
+    layout!(DSTORAGE_SOURCE: 24, 8 { Memory: 0, File: 0 });
+    layout!(DSTORAGE_SOURCE_FILE: 24, 8 { Source: 0, Offset: 8, Size: 16 });
+    layout!(DSTORAGE_SOURCE_MEMORY: 8, 4 { Source: 0, Size: 4 });
+    layout!(DSTORAGE_STAGING_BUFFER_SIZE: 4, 4 {});
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn test_msvc_compat_64bit() {
+    layout!(DSTORAGE_COMMAND_TYPE: 4, 4 {});
+    layout!(DSTORAGE_COMPRESSION: 4, 4 {});
+    layout!(DSTORAGE_COMPRESSION_FORMAT: 1, 1 {});
+    layout!(DSTORAGE_COMPRESSION_SUPPORT: 4, 4 {});
+    layout!(DSTORAGE_CONFIGURATION: 28, 4 {
+        NumSubmitThreads: 0,
+        NumBuiltInCpuDecompressionThreads: 4,
+        ForceMappingLayer: 8,
+        DisableBypassIO: 12,
+        DisableTelemetry: 16,
+        DisableGpuDecompressionMetacommand: 20,
+        DisableGpuDecompression: 24,
+    });
+    layout!(DSTORAGE_CONFIGURATION1: 32, 4 {
+        NumSubmitThreads: 0,
+        NumBuiltInCpuDecompressionThreads: 4,
+        ForceMappingLayer: 8,
+        DisableBypassIO: 12,
+        DisableTelemetry: 16,
+        DisableGpuDecompressionMetacommand: 20,
+        DisableGpuDecompression: 24,
+        ForceFileBuffering: 28,
+    });
+    layout!(DSTORAGE_CUSTOM_DECOMPRESSION_FLAGS: 4, 4 {});
+    layout!(DSTORAGE_CUSTOM_DECOMPRESSION_REQUEST: 48, 8 {
+        Id: 0,
+        CompressionFormat: 8,
+        Reserved: 9,
+        Flags: 12,
+        SrcSize: 16,
+        SrcBuffer: 24,
+        DstSize: 32,
+        DstBuffer: 40,
+    });
+    layout!(DSTORAGE_CUSTOM_DECOMPRESSION_RESULT: 16, 8 { Id: 0, Result: 8 });
+    layout!(DSTORAGE_DEBUG: 4, 4 {});
+    layout!(DSTORAGE_DESTINATION: 40, 8 {
+        Memory: 0,
+        Buffer: 0,
+        Texture: 0,
+        MultipleSubresources: 0,
+        Tiles: 0,
+        MultipleSubresourcesRange: 0,
+    });
+    layout!(DSTORAGE_DESTINATION_BUFFER: 24, 8 { Resource: 0, Offset: 8, Size: 16 });
+    layout!(DSTORAGE_DESTINATION_MEMORY: 16, 8 { Buffer: 0, Size: 8 });
+    layout!(DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES: 16, 8 { Resource: 0, FirstSubresource: 8 });
+    layout!(DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE: 16, 8 {
+        Resource: 0,
+        FirstSubresource: 8,
+        NumSubresources: 12,
+    });
+    layout!(DSTORAGE_DESTINATION_TEXTURE_REGION: 40, 8 {
+        Resource: 0,
+        SubresourceIndex: 8,
+        Region: 12,
+    });
+    layout!(DSTORAGE_DESTINATION_TILES: 40, 8 {
+        Resource: 0,
+        TiledRegionStartCoordinate: 8,
+        TileRegionSize: 24,
+    });
+    layout!(DSTORAGE_ENQUEUE_REQUEST_FLAGS: 4, 4 {});
+    layout!(DSTORAGE_ERROR_FIRST_FAILURE: 696, 8 { HResult: 0, CommandType: 4, Anonymous: 8 });
+    layout!(DSTORAGE_ERROR_FIRST_FAILURE_0: 688, 8 { Request: 0, Status: 0, Signal: 0, Event: 0 });
+    layout!(DSTORAGE_ERROR_PARAMETERS_EVENT: 8, 8 { Handle: 0 });
+    layout!(DSTORAGE_ERROR_PARAMETERS_REQUEST: 688, 8 {
+        Filename: 0,
+        RequestName: 520,
+        Request: 584,
+    });
+    layout!(DSTORAGE_ERROR_PARAMETERS_SIGNAL: 16, 8 { Fence: 0, Value: 8 });
+    layout!(DSTORAGE_ERROR_PARAMETERS_STATUS: 16, 8 { StatusArray: 0, Index: 8 });
+    layout!(DSTORAGE_ERROR_RECORD: 704, 8 { FailureCount: 0, FirstFailure: 8 });
+    layout!(DSTORAGE_GET_REQUEST_FLAGS: 4, 4 {});
+    layout!(DSTORAGE_PRIORITY: 1, 1 {});
+    layout!(DSTORAGE_QUEUE_DESC: 32, 8 {
+        SourceType: 0,
+        Capacity: 8,
+        Priority: 10,
+        Name: 16,
+        Device: 24,
+    });
+    layout!(DSTORAGE_QUEUE_INFO: 40, 8 {
+        Desc: 0,
+        EmptySlotCount: 32,
+        RequestCountUntilAutoSubmit: 34,
+    });
+    layout!(DSTORAGE_REQUEST: 104, 8 {
+        Options: 0,
+        Source: 16,
+        Destination: 40,
+        UncompressedSize: 80,
+        CancellationTag: 88,
+        Name: 96,
+    });
+    layout!(DSTORAGE_REQUEST_DESTINATION_TYPE: 8, 8 {});
+    layout!(DSTORAGE_REQUEST_OPTIONS: 16, 8 { _bitfield1: 0, Reserved1: 1, _bitfield2: 8 });
+    layout!(DSTORAGE_REQUEST_SOURCE_TYPE: 8, 8 {});
+    layout!(DSTORAGE_SOURCE: 24, 8 { Memory: 0, File: 0 });
+    layout!(DSTORAGE_SOURCE_FILE: 24, 8 { Source: 0, Offset: 8, Size: 16 });
+    layout!(DSTORAGE_SOURCE_MEMORY: 16, 8 { Source: 0, Size: 8 });
+    layout!(DSTORAGE_STAGING_BUFFER_SIZE: 4, 4 {});
+}
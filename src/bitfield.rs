@@ -0,0 +1,183 @@
+//! Accessors for C bitfields laid out by MSVC.
+//!
+//! windows-rs doesn't generate accessors for bitfields, it only emits their backing fields.
+//! MSVC packs consecutive bitfields into the same backing field as long as they have the same
+//! type and fit, starting at the least significant bit, and starts a new backing field
+//! otherwise.  [`bitfield!`] describes which bits of which backing field a bitfield occupies
+//! and generates a getter and a setter for it:
+//!
+//! ```ignore
+//! bitfield! {
+//!     impl DSTORAGE_REQUEST_OPTIONS {
+//!         _bitfield1: u8 {
+//!             CompressionFormat, set_CompressionFormat: DSTORAGE_COMPRESSION_FORMAT(u8) [0..8];
+//!         }
+//!         _bitfield2: u64 {
+//!             SourceType, set_SourceType: DSTORAGE_REQUEST_SOURCE_TYPE(u64) [0..1];
+//!             // ...
+//!             Reserved, set_Reserved: u64 [8..56];
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! Bitfields either have a plain unsigned integer type or a newtype around one, written as
+//! `Type(Inner)`.  Like assignments in C, setters discard the bits of the value that don't fit
+//! in the bitfield and leave all other bits of the backing field untouched.
+//!
+//! `api_gen` emits the invocations into the bindings from the bitfields recorded in the
+//! metadata, so this module has to be declared before the bindings.
+
+/// Generates accessors for bitfields, see the [module documentation](self).
+// Only the bindings use it outside of the tests.
+#[cfg_attr(not(windows), allow(unused_macros))]
+macro_rules! bitfield {
+    (
+        $(#[$meta:meta])*
+        impl $struct:ident {
+            $(
+                $storage:ident: $storage_ty:ty {
+                    $(
+                        $(#[$attr:meta])*
+                        $get:ident, $set:ident: $field:ident $(($inner:ty))? [$lo:literal..$hi:literal];
+                    )*
+                }
+            )*
+        }
+    ) => {
+        $(#[$meta])*
+        impl $struct {
+            $($(
+                $(#[$attr])*
+                #[allow(clippy::unnecessary_cast)]
+                pub fn $get(&self) -> $field {
+                    const MASK: $storage_ty = bitfield!(@mask $storage_ty, $lo, $hi);
+                    let bits = (self.$storage >> $lo) & MASK;
+                    bitfield!(@wrap bits, $field $(($inner))?)
+                }
+
+                #[allow(clippy::unnecessary_cast)]
+                pub fn $set(&mut self, value: $field) {
+                    const MASK: $storage_ty = bitfield!(@mask $storage_ty, $lo, $hi);
+                    let bits = bitfield!(@unwrap value $(($inner))?) as $storage_ty;
+                    self.$storage = (self.$storage & !(MASK << $lo)) | ((bits & MASK) << $lo);
+                }
+            )*)*
+        }
+    };
+    // The unshifted mask of a bitfield covering bits `lo..hi`.
+    (@mask $storage_ty:ty, $lo:literal, $hi:literal) => {{
+        assert!(
+            $lo < $hi && $hi <= <$storage_ty>::BITS,
+            "bitfield exceeds its backing field"
+        );
+        <$storage_ty>::MAX >> (<$storage_ty>::BITS - ($hi - $lo))
+    }};
+    (@wrap $bits:ident, $field:ident ($inner:ty)) => {
+        $field($bits as $inner)
+    };
+    (@wrap $bits:ident, $field:ident) => {
+        $bits as $field
+    };
+    (@unwrap $value:ident ($inner:ty)) => {
+        $value.0
+    };
+    (@unwrap $value:ident) => {
+        $value
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    /// Mirrors the layout of `DSTORAGE_REQUEST_OPTIONS`, so that it is also tested on other
+    /// platforms.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    struct Options {
+        _bitfield1: u8,
+        _bitfield2: u64,
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct Format(u8);
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    struct Kind(u64);
+
+    bitfield! {
+        impl Options {
+            _bitfield1: u8 {
+                Format, set_Format: Format(u8) [0..8];
+            }
+            _bitfield2: u64 {
+                Source, set_Source: Kind(u64) [0..1];
+                Destination, set_Destination: Kind(u64) [1..8];
+                Reserved, set_Reserved: u64 [8..56];
+            }
+        }
+    }
+
+    /// The fields of `_bitfield2` as `(lo, hi)`.
+    const FIELDS: [(u32, u32); 3] = [(0, 1), (1, 8), (8, 56)];
+
+    fn mask(lo: u32, hi: u32) -> u64 {
+        (u64::MAX >> (64 - (hi - lo))) << lo
+    }
+
+    fn set(options: &mut Options, field: usize, value: u64) {
+        match field {
+            0 => options.set_Source(Kind(value)),
+            1 => options.set_Destination(Kind(value)),
+            _ => options.set_Reserved(value),
+        }
+    }
+
+    fn get(options: &Options, field: usize) -> u64 {
+        match field {
+            0 => options.Source().0,
+            1 => options.Destination().0,
+            _ => options.Reserved(),
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        let mut options = Options::default();
+        options.set_Destination(Kind(0x7F));
+        assert_eq!(options._bitfield2, 0xFE);
+        options.set_Reserved(u64::MAX);
+        assert_eq!(options._bitfield2, 0x00FF_FFFF_FFFF_FFFE);
+        options.set_Source(Kind(3));
+        assert_eq!(options._bitfield2, u64::MAX >> 8);
+        assert_eq!(options.Reserved(), (1 << 48) - 1);
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(bitfield1: u8, bitfield2: u64, field in 0..3usize, value: u64) {
+            let (lo, hi) = FIELDS[field];
+            let mut options = Options { _bitfield1: bitfield1, _bitfield2: bitfield2 };
+            set(&mut options, field, value);
+            prop_assert_eq!(get(&options, field), value & (mask(lo, hi) >> lo));
+            options.set_Format(Format(value as u8));
+            prop_assert_eq!(options.Format(), Format(value as u8));
+        }
+
+        #[test]
+        fn test_no_leaks(bitfield1: u8, bitfield2: u64, field in 0..3usize, value: u64) {
+            let (lo, hi) = FIELDS[field];
+            let before = Options { _bitfield1: bitfield1, _bitfield2: bitfield2 };
+            let mut options = before;
+            set(&mut options, field, value);
+            prop_assert_eq!(options._bitfield1, before._bitfield1);
+            prop_assert_eq!(
+                options._bitfield2 & !mask(lo, hi),
+                before._bitfield2 & !mask(lo, hi)
+            );
+            for other in (0..3).filter(|&other| other != field) {
+                prop_assert_eq!(get(&options, other), get(&before, other));
+            }
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Merging reads of adjacent file ranges into fewer, larger requests.
+//!
+//! Small assets packed next to each other are cheaper to read with one request than with one
+//! request each.  [`CoalescePolicy::coalesce()`] merges reads whose ranges are at most
+//! [`CoalescePolicy::max_gap`] bytes apart into [`MergedRead`]s.  Each merged read is loaded
+//! into a scratch buffer, after which [`MergedRead::scatter()`] copies every part to the
+//! destination of the read it came from.  Bytes in the gaps are read and discarded.
+//!
+//! Only uncompressed data can be coalesced: a merged request has to be read and decompressed as
+//! a whole.
+
+use std::ops::Range;
+
+#[cfg(windows)]
+use crate::{
+    readonly_copy, IDStorageFile, DSTORAGE_COMPRESSION_FORMAT_NONE, DSTORAGE_DESTINATION,
+    DSTORAGE_DESTINATION_MEMORY, DSTORAGE_REQUEST, DSTORAGE_REQUEST_DESTINATION_MEMORY,
+    DSTORAGE_REQUEST_SOURCE_FILE, DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE,
+};
+
+/// A read of `size` bytes at `offset` in a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileRead {
+    pub offset: u64,
+    pub size: u32,
+}
+
+impl FileRead {
+    pub fn end(&self) -> u64 {
+        self.offset + self.size as u64
+    }
+}
+
+/// Limits for merging reads.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoalescePolicy {
+    /// Largest number of unneeded bytes read between two merged reads.
+    pub max_gap: u32,
+    /// Largest merged read, typically the staging buffer size.  Reads that are larger by
+    /// themselves are never merged.
+    pub max_size: u32,
+}
+
+impl Default for CoalescePolicy {
+    fn default() -> Self {
+        Self {
+            max_gap: 64 * 1024,
+            // The default staging buffer size.
+            max_size: 32 * 1024 * 1024,
+        }
+    }
+}
+
+/// Part of a [`MergedRead`] belonging to one of the original reads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    /// Index of the read passed to [`CoalescePolicy::coalesce()`].
+    pub read: usize,
+    /// Range of the data in the merged read.
+    pub range: Range<usize>,
+}
+
+/// A single read covering one or more of the original reads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MergedRead {
+    pub offset: u64,
+    pub size: u32,
+    pub parts: Vec<Part>,
+}
+
+impl CoalescePolicy {
+    /// Merge `reads` that are adjacent, overlapping or at most [`CoalescePolicy::max_gap`]
+    /// bytes apart, without exceeding [`CoalescePolicy::max_size`].  The merged reads are
+    /// sorted by offset.
+    pub fn coalesce(&self, reads: &[FileRead]) -> Vec<MergedRead> {
+        let mut order: Vec<usize> = (0..reads.len()).collect();
+        order.sort_by_key(|&i| (reads[i].offset, reads[i].size));
+
+        let mut merged: Vec<MergedRead> = Vec::new();
+        for index in order {
+            let read = reads[index];
+            if let Some(last) = merged.last_mut() {
+                let last_end = last.offset + last.size as u64;
+                let end = last_end.max(read.end());
+                if read.offset <= last_end.saturating_add(self.max_gap as u64)
+                    && end - last.offset <= self.max_size as u64
+                {
+                    let start = (read.offset - last.offset) as usize;
+                    last.size = (end - last.offset) as u32;
+                    last.parts.push(Part {
+                        read: index,
+                        range: start..start + read.size as usize,
+                    });
+                    continue;
+                }
+            }
+            merged.push(MergedRead {
+                offset: read.offset,
+                size: read.size,
+                parts: vec![Part {
+                    read: index,
+                    range: 0..read.size as usize,
+                }],
+            });
+        }
+        merged
+    }
+}
+
+impl MergedRead {
+    /// Copy the parts of the merged `data` to `destinations`, which is indexed like the reads
+    /// passed to [`CoalescePolicy::coalesce()`].
+    pub fn scatter<D: AsMut<[u8]>>(&self, data: &[u8], destinations: &mut [D]) {
+        assert_eq!(data.len(), self.size as usize);
+        for part in &self.parts {
+            destinations[part.read].as_mut()[..part.range.len()]
+                .copy_from_slice(&data[part.range.clone()]);
+        }
+    }
+
+    /// Build the request reading this range from `file` into `buffer`, to be scattered once it
+    /// completed.
+    ///
+    /// # Safety
+    /// Borrows `file` through [`readonly_copy()`] and writes to `buffer`; both must stay valid
+    /// until the request completed.
+    #[cfg(windows)]
+    pub unsafe fn request(&self, file: &IDStorageFile, buffer: &mut [u8]) -> DSTORAGE_REQUEST {
+        assert!(buffer.len() >= self.size as usize);
+        let mut request = DSTORAGE_REQUEST {
+            Source: DSTORAGE_SOURCE {
+                File: std::mem::ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                    Source: unsafe { readonly_copy(file) },
+                    Offset: self.offset,
+                    Size: self.size,
+                }),
+            },
+            Destination: DSTORAGE_DESTINATION {
+                Memory: DSTORAGE_DESTINATION_MEMORY {
+                    Buffer: buffer.as_mut_ptr().cast(),
+                    Size: self.size,
+                },
+            },
+            UncompressedSize: self.size,
+            ..Default::default()
+        };
+        request
+            .Options
+            .set_CompressionFormat(DSTORAGE_COMPRESSION_FORMAT_NONE);
+        request.Options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+        request
+            .Options
+            .set_DestinationType(DSTORAGE_REQUEST_DESTINATION_MEMORY);
+        request
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(offset: u64, size: u32) -> FileRead {
+        FileRead { offset, size }
+    }
+
+    fn summary(merged: &[MergedRead]) -> Vec<(u64, u32, Vec<usize>)> {
+        merged
+            .iter()
+            .map(|m| (m.offset, m.size, m.parts.iter().map(|p| p.read).collect()))
+            .collect()
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let policy = CoalescePolicy {
+            max_gap: 16,
+            max_size: 800,
+        };
+        let reads = [
+            read(100, 50),
+            read(0, 100),
+            read(166, 10),
+            read(300, 10),
+            read(310, 800),
+        ];
+        assert_eq!(
+            summary(&policy.coalesce(&reads)),
+            [
+                (0, 176, vec![1, 0, 2]),
+                (300, 10, vec![3]),
+                (310, 800, vec![4]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_overlapping() {
+        let merged = CoalescePolicy::default().coalesce(&[read(10, 20), read(0, 40), read(5, 5)]);
+        assert_eq!(summary(&merged), [(0, 40, vec![1, 2, 0])]);
+        assert_eq!(merged[0].parts[2].range, 10..30);
+    }
+
+    #[test]
+    fn test_size_limit() {
+        let policy = CoalescePolicy {
+            max_gap: 0,
+            max_size: u32::MAX,
+        };
+        let merged = policy.coalesce(&[read(0, u32::MAX - 1), read(u32::MAX as u64 - 1, 2)]);
+        assert_eq!(merged.len(), 2);
+    }
+
+    #[test]
+    fn test_end_of_file_range() {
+        let policy = CoalescePolicy {
+            max_gap: u32::MAX,
+            max_size: 64,
+        };
+        let end = u64::MAX - 16;
+        let merged = policy.coalesce(&[read(end - 32, 16), read(end - 8, 8)]);
+        assert_eq!(summary(&merged), [(end - 32, 32, vec![0, 1])]);
+    }
+
+    #[test]
+    fn test_scatter() {
+        let file: Vec<u8> = (0..64).collect();
+        let reads = [read(40, 4), read(8, 8), read(20, 4)];
+        let merged = CoalescePolicy::default().coalesce(&reads);
+        assert_eq!(merged.len(), 1);
+
+        let mut destinations = vec![vec![0u8; 4], vec![0u8; 8], vec![0u8; 4]];
+        let m = &merged[0];
+        m.scatter(
+            &file[m.offset as usize..][..m.size as usize],
+            &mut destinations,
+        );
+        for (read, destination) in reads.iter().zip(&destinations) {
+            assert_eq!(
+                destination,
+                &file[read.offset as usize..read.end() as usize]
+            );
+        }
+    }
+}
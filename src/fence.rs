@@ -0,0 +1,278 @@
+//! Tracking completion of requests through fence signals.
+//!
+//! `IDStorageQueue::EnqueueSignal()` sets a fence to a value once all requests enqueued before it
+//! completed.  A [`FenceTimeline`] hands out increasing values per fence, remembers which
+//! requests each value covers, and answers whether a [`FenceHandle`] completed from the fence's
+//! completed value.
+//!
+//! A fence completes its values in the order they are signaled, so every fence must only be
+//! signaled from a single queue.  Add one fence per queue to the timeline; handles of different
+//! fences can still be waited on together.
+//!
+//! The bookkeeping only relies on the [`Fence`] trait, which is implemented for
+//! `ID3D12Fence` on Windows and by [`FakeFence`] everywhere.
+
+use std::{cell::Cell, collections::VecDeque};
+
+#[cfg(windows)]
+use windows::Win32::{Foundation::HANDLE, Graphics::Direct3D12::ID3D12Fence};
+
+#[cfg(windows)]
+use crate::IDStorageQueue;
+
+/// A monotonically increasing counter that is advanced as work completes.
+pub trait Fence {
+    fn completed_value(&self) -> u64;
+
+    /// Block until the completed value reached `value`.
+    fn wait(&self, value: u64) {
+        while self.completed_value() < value {
+            std::thread::yield_now();
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Fence for ID3D12Fence {
+    fn completed_value(&self) -> u64 {
+        unsafe { self.GetCompletedValue() }
+    }
+
+    fn wait(&self, value: u64) {
+        // Without an event, this only returns once the value has been reached.
+        if unsafe { self.SetEventOnCompletion(value, HANDLE::default()) }.is_err() {
+            while self.completed_value() < value {
+                std::thread::yield_now();
+            }
+        }
+    }
+}
+
+/// A fence that is advanced by calling [`FakeFence::signal()`].
+#[derive(Debug, Default)]
+pub struct FakeFence {
+    value: Cell<u64>,
+}
+
+impl FakeFence {
+    pub fn new(value: u64) -> Self {
+        Self {
+            value: Cell::new(value),
+        }
+    }
+
+    pub fn signal(&self, value: u64) {
+        self.value.set(value);
+    }
+}
+
+impl Fence for FakeFence {
+    fn completed_value(&self) -> u64 {
+        self.value.get()
+    }
+
+    fn wait(&self, value: u64) {
+        // Nothing else could signal it while blocking.
+        assert!(
+            self.value.get() >= value,
+            "waiting for {value} on a fake fence at {}",
+            self.value.get()
+        );
+    }
+}
+
+/// Identifies a fence added to a [`FenceTimeline`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FenceId(pub usize);
+
+/// A value signaled on a fence of a [`FenceTimeline`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FenceHandle {
+    pub fence: FenceId,
+    pub value: u64,
+}
+
+struct Signal<R> {
+    value: u64,
+    requests: Vec<R>,
+}
+
+struct Track<F, R> {
+    fence: F,
+    next_value: u64,
+    unsignaled: Vec<R>,
+    signals: VecDeque<Signal<R>>,
+}
+
+/// Hands out fence values and records the requests `R` covered by each of them.
+pub struct FenceTimeline<F: Fence, R = u64> {
+    tracks: Vec<Track<F, R>>,
+}
+
+impl<F: Fence, R> Default for FenceTimeline<F, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<F: Fence, R> FenceTimeline<F, R> {
+    pub fn new() -> Self {
+        Self { tracks: Vec::new() }
+    }
+
+    /// Add a fence, whose values are handed out starting after its current completed value.
+    ///
+    /// Returns [`None`] if the fence is already at [`u64::MAX`], which is also what an
+    /// `ID3D12Fence` reports once its device was removed.
+    pub fn add_fence(&mut self, fence: F) -> Option<FenceId> {
+        self.tracks.push(Track {
+            next_value: fence.completed_value().checked_add(1)?,
+            fence,
+            unsignaled: Vec::new(),
+            signals: VecDeque::new(),
+        });
+        Some(FenceId(self.tracks.len() - 1))
+    }
+
+    pub fn fence(&self, id: FenceId) -> &F {
+        &self.tracks[id.0].fence
+    }
+
+    /// Record a request enqueued on the queue of `fence`, to be covered by the next
+    /// [`FenceTimeline::signal()`].
+    pub fn track(&mut self, fence: FenceId, request: R) {
+        self.tracks[fence.0].unsignaled.push(request);
+    }
+
+    /// Take the next value of `fence`, covering all requests tracked since the previous one.
+    /// The caller has to enqueue a signal of [`FenceHandle::value`] on the queue the requests
+    /// were enqueued on.
+    pub fn signal(&mut self, fence: FenceId) -> FenceHandle {
+        let track = &mut self.tracks[fence.0];
+        let value = track.next_value;
+        track.next_value = value.checked_add(1).expect("fence values exhausted");
+        track.signals.push_back(Signal {
+            value,
+            requests: std::mem::take(&mut track.unsignaled),
+        });
+        FenceHandle { fence, value }
+    }
+
+    pub fn is_complete(&self, handle: FenceHandle) -> bool {
+        self.fence(handle.fence).completed_value() >= handle.value
+    }
+
+    /// Requests covered by `handle`, or [`None`] if it was retired.
+    pub fn requests(&self, handle: FenceHandle) -> Option<&[R]> {
+        let signals = &self.tracks[handle.fence.0].signals;
+        let first = signals.front()?.value;
+        let signal = signals.get(handle.value.checked_sub(first)? as usize)?;
+        Some(&signal.requests)
+    }
+
+    /// Block until all `handles` completed.  Every fence is waited on once, for the largest
+    /// value among the handles.
+    pub fn wait(&self, handles: impl IntoIterator<Item = FenceHandle>) {
+        let mut values = vec![0; self.tracks.len()];
+        for handle in handles {
+            let value = &mut values[handle.fence.0];
+            *value = (*value).max(handle.value);
+        }
+        for (track, value) in self.tracks.iter().zip(values) {
+            if value > 0 {
+                track.fence.wait(value);
+            }
+        }
+    }
+
+    /// Forget all completed signals, returning their handles and the requests they covered in
+    /// the order they were signaled.
+    pub fn retire(&mut self) -> Vec<(FenceHandle, Vec<R>)> {
+        let mut retired = Vec::new();
+        for (i, track) in self.tracks.iter_mut().enumerate() {
+            let completed = track.fence.completed_value();
+            while track.signals.front().is_some_and(|s| s.value <= completed) {
+                let signal = track.signals.pop_front().unwrap();
+                let handle = FenceHandle {
+                    fence: FenceId(i),
+                    value: signal.value,
+                };
+                retired.push((handle, signal.requests));
+            }
+        }
+        retired
+    }
+}
+
+#[cfg(windows)]
+impl<R> FenceTimeline<ID3D12Fence, R> {
+    /// [`FenceTimeline::signal()`] `fence` and enqueue the signal on `queue`.
+    ///
+    /// # Safety
+    /// `queue` must be the only queue `fence` is signaled from.
+    pub unsafe fn enqueue_signal(&mut self, queue: &IDStorageQueue, fence: FenceId) -> FenceHandle {
+        let handle = self.signal(fence);
+        unsafe { queue.EnqueueSignal(self.fence(fence), handle.value) };
+        handle
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timeline() {
+        let mut timeline = FenceTimeline::new();
+        let fence = timeline.add_fence(FakeFence::new(10)).unwrap();
+        timeline.track(fence, 1);
+        timeline.track(fence, 2);
+        let first = timeline.signal(fence);
+        let empty = timeline.signal(fence);
+        timeline.track(fence, 3);
+        let last = timeline.signal(fence);
+        assert_eq!([first.value, empty.value, last.value], [11, 12, 13]);
+        assert_eq!(timeline.requests(first), Some(&[1, 2][..]));
+        assert_eq!(timeline.requests(empty), Some(&[][..]));
+
+        assert!(!timeline.is_complete(first));
+        timeline.fence(fence).signal(12);
+        assert!(timeline.is_complete(empty));
+        assert!(!timeline.is_complete(last));
+        assert_eq!(timeline.retire(), [(first, vec![1, 2]), (empty, vec![])]);
+        assert_eq!(timeline.requests(first), None);
+        assert_eq!(timeline.requests(last), Some(&[3][..]));
+        assert!(timeline.retire().is_empty());
+    }
+
+    #[test]
+    fn test_multiple_fences() {
+        let mut timeline: FenceTimeline<_> = FenceTimeline::default();
+        let a = timeline.add_fence(FakeFence::new(0)).unwrap();
+        let b = timeline.add_fence(FakeFence::new(100)).unwrap();
+        let handles = [timeline.signal(a), timeline.signal(b), timeline.signal(a)];
+        assert_eq!(handles.map(|h| h.value), [1, 101, 2]);
+
+        timeline.fence(a).signal(2);
+        timeline.wait([handles[0], handles[2]]);
+        assert!(!timeline.is_complete(handles[1]));
+        timeline.fence(b).signal(101);
+        timeline.wait(handles);
+        assert_eq!(timeline.retire().len(), 3);
+    }
+
+    #[test]
+    fn test_removed_device() {
+        let mut timeline: FenceTimeline<_> = FenceTimeline::new();
+        assert_eq!(timeline.add_fence(FakeFence::new(u64::MAX)), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "waiting for")]
+    fn test_wait_incomplete() {
+        let mut timeline: FenceTimeline<_> = FenceTimeline::new();
+        let fence = timeline.add_fence(FakeFence::new(0)).unwrap();
+        let handle = timeline.signal(fence);
+        timeline.wait([handle]);
+    }
+}
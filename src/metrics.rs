@@ -0,0 +1,577 @@
+//! Aggregated metrics of queues.
+//!
+//! A [`Metrics`] registry is fed by queue wrappers as requests complete, fail or as the queue is
+//! queried, and keeps:
+//!
+//! - latency [`Histogram`]s per [`Priority`];
+//! - the bytes read from the source versus the bytes delivered to the destination, whose ratio
+//!   is the effective compression ratio;
+//! - samples of the occupancy of every queue over time, e.g. from `DSTORAGE_QUEUE_INFO`;
+//! - the number of failures per `HRESULT`, usually one of the `E_DSTORAGE_*` codes.
+//!
+//! [`Metrics::snapshot()`] copies the current state, which can be inspected or written in the
+//! Prometheus text format with [`MetricsSnapshot::write_prometheus()`].
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+#[cfg(windows)]
+use crate::DSTORAGE_QUEUE_INFO;
+use crate::{
+    priority::Priority,
+    scheduler::{Clock, SystemClock},
+};
+
+/// Upper bounds of the latency buckets, from 50 µs doubling up to ~1.6 s.
+const LATENCY_BUCKETS: usize = 16;
+const FIRST_BUCKET: Duration = Duration::from_micros(50);
+
+/// Number of occupancy samples kept per queue.
+const OCCUPANCY_SAMPLES: usize = 1024;
+
+/// A histogram of durations with exponentially growing buckets.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    /// Counts per bucket of [`Histogram::bounds()`], followed by the overflow bucket.
+    counts: [u64; LATENCY_BUCKETS + 1],
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    /// Inclusive upper bounds of the buckets, excluding the unbounded last one.
+    pub fn bounds() -> impl Iterator<Item = Duration> {
+        (0..LATENCY_BUCKETS as u32).map(|i| FIRST_BUCKET * (1 << i))
+    }
+
+    pub fn record(&mut self, duration: Duration) {
+        let bucket = Self::bounds()
+            .position(|bound| duration <= bound)
+            .unwrap_or(LATENCY_BUCKETS);
+        self.counts[bucket] += 1;
+        self.count += 1;
+        self.sum += duration;
+        self.max = self.max.max(duration);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        let nanos = self.sum.as_nanos().checked_div(self.count.into())?;
+        Some(Duration::new(
+            (nanos / 1_000_000_000) as u64,
+            (nanos % 1_000_000_000) as u32,
+        ))
+    }
+
+    /// Upper bound of the bucket containing the `q`-quantile, or [`Histogram::max()`] if it is
+    /// in the unbounded bucket.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bound, count) in Self::bounds().zip(self.counts) {
+            seen += count;
+            if seen >= rank {
+                return Some(bound.min(self.max));
+            }
+        }
+        Some(self.max)
+    }
+
+    /// Cumulative counts per bucket bound, as exported to Prometheus.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        Self::bounds().zip(self.counts.iter().scan(0, |seen, count| {
+            *seen += count;
+            Some(*seen)
+        }))
+    }
+}
+
+/// Occupancy of a queue over time.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Occupancy {
+    pub capacity: u32,
+    /// Largest number of occupied slots seen.
+    pub max: u32,
+    /// Number of occupied slots, by time since the registry was created.  Only the most recent
+    /// samples are kept.
+    pub samples: VecDeque<(Duration, u32)>,
+}
+
+impl Occupancy {
+    /// Most recently sampled number of occupied slots.
+    pub fn current(&self) -> Option<u32> {
+        self.samples.back().map(|&(_, used)| used)
+    }
+}
+
+/// A copy of the state of [`Metrics`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Time since the registry was created.
+    pub elapsed: Duration,
+    pub latency: BTreeMap<Priority, Histogram>,
+    pub bytes_read: u64,
+    pub bytes_delivered: u64,
+    /// Occupancy per queue name.
+    pub occupancy: BTreeMap<String, Occupancy>,
+    /// Number of failed requests per `HRESULT`.
+    pub errors: BTreeMap<i32, u64>,
+}
+
+impl MetricsSnapshot {
+    /// Number of completed requests over all priorities.
+    pub fn requests(&self) -> u64 {
+        self.latency.values().map(Histogram::count).sum()
+    }
+
+    /// Bytes delivered per byte read, or [`None`] before anything was read.
+    pub fn compression_ratio(&self) -> Option<f64> {
+        (self.bytes_read > 0).then(|| self.bytes_delivered as f64 / self.bytes_read as f64)
+    }
+
+    /// Bytes delivered per second between `earlier` and this snapshot, or over the lifetime of
+    /// the registry without one.
+    pub fn bandwidth(&self, earlier: Option<&MetricsSnapshot>) -> Option<f64> {
+        let (bytes, elapsed) = match earlier {
+            Some(earlier) => (
+                self.bytes_delivered.checked_sub(earlier.bytes_delivered)?,
+                self.elapsed.checked_sub(earlier.elapsed)?,
+            ),
+            None => (self.bytes_delivered, self.elapsed),
+        };
+        (!elapsed.is_zero()).then(|| bytes as f64 / elapsed.as_secs_f64())
+    }
+
+    /// Write all metrics in the Prometheus text exposition format.
+    pub fn write_prometheus(&self, mut writer: impl Write) -> io::Result<()> {
+        let w = &mut writer;
+        header(
+            w,
+            "dstorage_requests_total",
+            "counter",
+            "Completed requests.",
+        )?;
+        writeln!(w, "dstorage_requests_total {}", self.requests())?;
+        header(
+            w,
+            "dstorage_bytes_read_total",
+            "counter",
+            "Bytes read from sources.",
+        )?;
+        writeln!(w, "dstorage_bytes_read_total {}", self.bytes_read)?;
+        header(
+            w,
+            "dstorage_bytes_delivered_total",
+            "counter",
+            "Bytes written to destinations.",
+        )?;
+        writeln!(w, "dstorage_bytes_delivered_total {}", self.bytes_delivered)?;
+        if let Some(ratio) = self.compression_ratio() {
+            header(
+                w,
+                "dstorage_compression_ratio",
+                "gauge",
+                "Bytes delivered per byte read.",
+            )?;
+            writeln!(w, "dstorage_compression_ratio {ratio}")?;
+        }
+
+        header(
+            w,
+            "dstorage_request_latency_seconds",
+            "histogram",
+            "Time from submitting to completing requests.",
+        )?;
+        for (priority, histogram) in &self.latency {
+            let priority = priority_label(*priority);
+            for (bound, count) in histogram.buckets() {
+                writeln!(
+                    w,
+                    "dstorage_request_latency_seconds_bucket{{priority=\"{priority}\",le=\"{}\"}} {count}",
+                    bound.as_secs_f64()
+                )?;
+            }
+            writeln!(
+                w,
+                "dstorage_request_latency_seconds_bucket{{priority=\"{priority}\",le=\"+Inf\"}} {}",
+                histogram.count()
+            )?;
+            writeln!(
+                w,
+                "dstorage_request_latency_seconds_sum{{priority=\"{priority}\"}} {}",
+                histogram.sum().as_secs_f64()
+            )?;
+            writeln!(
+                w,
+                "dstorage_request_latency_seconds_count{{priority=\"{priority}\"}} {}",
+                histogram.count()
+            )?;
+        }
+
+        if !self.occupancy.is_empty() {
+            header(
+                w,
+                "dstorage_queue_occupancy",
+                "gauge",
+                "Occupied slots of the queue.",
+            )?;
+            for (queue, occupancy) in &self.occupancy {
+                if let Some(used) = occupancy.current() {
+                    writeln!(
+                        w,
+                        "dstorage_queue_occupancy{{queue=\"{}\"}} {used}",
+                        escape(queue)
+                    )?;
+                }
+            }
+            header(
+                w,
+                "dstorage_queue_occupancy_max",
+                "gauge",
+                "Largest number of occupied slots of the queue.",
+            )?;
+            for (queue, occupancy) in &self.occupancy {
+                writeln!(
+                    w,
+                    "dstorage_queue_occupancy_max{{queue=\"{}\"}} {}",
+                    escape(queue),
+                    occupancy.max
+                )?;
+            }
+            header(
+                w,
+                "dstorage_queue_capacity",
+                "gauge",
+                "Number of slots of the queue.",
+            )?;
+            for (queue, occupancy) in &self.occupancy {
+                writeln!(
+                    w,
+                    "dstorage_queue_capacity{{queue=\"{}\"}} {}",
+                    escape(queue),
+                    occupancy.capacity
+                )?;
+            }
+        }
+
+        if !self.errors.is_empty() {
+            header(w, "dstorage_errors_total", "counter", "Failed requests.")?;
+            for (&hresult, count) in &self.errors {
+                write!(
+                    w,
+                    "dstorage_errors_total{{code=\"0x{:08x}\"",
+                    hresult as u32
+                )?;
+                if let Some(name) = error_name(hresult) {
+                    write!(w, ",name=\"{name}\"")?;
+                }
+                writeln!(w, "}} {count}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn header(w: &mut impl Write, name: &str, kind: &str, help: &str) -> io::Result<()> {
+    writeln!(w, "# HELP {name} {help}")?;
+    writeln!(w, "# TYPE {name} {kind}")
+}
+
+fn priority_label(priority: Priority) -> &'static str {
+    match priority {
+        Priority::Low => "low",
+        Priority::Normal => "normal",
+        Priority::High => "high",
+        Priority::Realtime => "realtime",
+    }
+}
+
+/// Escape a Prometheus label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+macro_rules! error_names {
+    ($($name:ident),* $(,)?) => {
+        /// Name of an `E_DSTORAGE_*` code, or [`None`] for other `HRESULT`s.
+        #[cfg(windows)]
+        pub fn error_name(hresult: i32) -> Option<&'static str> {
+            $(
+                if hresult == crate::$name.0 {
+                    return Some(stringify!($name));
+                }
+            )*
+            None
+        }
+    };
+}
+
+error_names!(
+    E_DSTORAGE_ACCESS_VIOLATION,
+    E_DSTORAGE_ALREADY_RUNNING,
+    E_DSTORAGE_BCPACK_BAD_DATA,
+    E_DSTORAGE_BCPACK_BAD_HEADER,
+    E_DSTORAGE_COMPRESSED_DATA_TOO_LARGE,
+    E_DSTORAGE_DECOMPRESSION_ERROR,
+    E_DSTORAGE_DECRYPTION_ERROR,
+    E_DSTORAGE_DEPRECATED_PREVIEW_GDK,
+    E_DSTORAGE_END_OF_FILE,
+    E_DSTORAGE_FILEBUFFERING_REQUIRES_DISABLED_BYPASSIO,
+    E_DSTORAGE_FILE_NOT_OPEN,
+    E_DSTORAGE_FILE_TOO_FRAGMENTED,
+    E_DSTORAGE_INDEX_BOUND,
+    E_DSTORAGE_INVALID_BCPACK_MODE,
+    E_DSTORAGE_INVALID_CLUSTER_SIZE,
+    E_DSTORAGE_INVALID_DESTINATION_SIZE,
+    E_DSTORAGE_INVALID_DESTINATION_TYPE,
+    E_DSTORAGE_INVALID_FENCE,
+    E_DSTORAGE_INVALID_FILE_HANDLE,
+    E_DSTORAGE_INVALID_FILE_OFFSET,
+    E_DSTORAGE_INVALID_INTERMEDIATE_SIZE,
+    E_DSTORAGE_INVALID_MEMORY_QUEUE_PRIORITY,
+    E_DSTORAGE_INVALID_QUEUE_CAPACITY,
+    E_DSTORAGE_INVALID_QUEUE_PRIORITY,
+    E_DSTORAGE_INVALID_SOURCE_TYPE,
+    E_DSTORAGE_INVALID_STAGING_BUFFER_SIZE,
+    E_DSTORAGE_INVALID_STATUS_ARRAY,
+    E_DSTORAGE_INVALID_SWIZZLE_MODE,
+    E_DSTORAGE_IO_TIMEOUT,
+    E_DSTORAGE_NOT_RUNNING,
+    E_DSTORAGE_PASSTHROUGH_ERROR,
+    E_DSTORAGE_QUEUE_CLOSED,
+    E_DSTORAGE_REQUEST_TOO_LARGE,
+    E_DSTORAGE_RESERVED_FIELDS,
+    E_DSTORAGE_STAGING_BUFFER_LOCKED,
+    E_DSTORAGE_STAGING_BUFFER_TOO_SMALL,
+    E_DSTORAGE_SYSTEM_NOT_SUPPORTED,
+    E_DSTORAGE_TOO_MANY_FILES,
+    E_DSTORAGE_TOO_MANY_QUEUES,
+    E_DSTORAGE_UNSUPPORTED_FILE,
+    E_DSTORAGE_UNSUPPORTED_VOLUME,
+    E_DSTORAGE_XVD_DEVICE_NOT_SUPPORTED,
+    E_DSTORAGE_XVD_NOT_REGISTERED,
+    E_DSTORAGE_ZLIB_BAD_DATA,
+    E_DSTORAGE_ZLIB_BAD_HEADER,
+    E_DSTORAGE_ZLIB_PARITY_FAIL,
+);
+
+/// Name of an `E_DSTORAGE_*` code, always [`None`] where the bindings don't exist.
+#[cfg(not(windows))]
+pub fn error_name(_hresult: i32) -> Option<&'static str> {
+    None
+}
+
+/// Collects metrics fed by queue wrappers, see the [module documentation](self).
+#[derive(Debug)]
+pub struct Metrics<C: Clock = SystemClock> {
+    clock: C,
+    start: Instant,
+    snapshot: MetricsSnapshot,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+}
+
+impl<C: Clock> Metrics<C> {
+    pub fn with_clock(clock: C) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            snapshot: MetricsSnapshot::default(),
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Record a completed request that read `bytes_read` bytes from its source and wrote
+    /// `bytes_delivered` bytes to its destination.
+    pub fn record_request(
+        &mut self,
+        priority: Priority,
+        bytes_read: u64,
+        bytes_delivered: u64,
+        latency: Duration,
+    ) {
+        self.snapshot
+            .latency
+            .entry(priority)
+            .or_default()
+            .record(latency);
+        self.snapshot.bytes_read += bytes_read;
+        self.snapshot.bytes_delivered += bytes_delivered;
+    }
+
+    /// Record a failed request.
+    pub fn record_error(&mut self, hresult: i32) {
+        *self.snapshot.errors.entry(hresult).or_default() += 1;
+    }
+
+    /// Record that `used` out of `capacity` slots of `queue` are occupied.
+    pub fn record_occupancy(&mut self, queue: &str, used: u32, capacity: u32) {
+        let elapsed = self.clock.now() - self.start;
+        let occupancy = self.snapshot.occupancy.entry(queue.to_owned()).or_default();
+        occupancy.capacity = capacity;
+        occupancy.max = occupancy.max.max(used);
+        if occupancy.samples.len() == OCCUPANCY_SAMPLES {
+            occupancy.samples.pop_front();
+        }
+        occupancy.samples.push_back((elapsed, used));
+    }
+
+    /// Record the occupancy of `queue` from the result of `IDStorageQueue::Query()`.
+    #[cfg(windows)]
+    pub fn record_queue_info(&mut self, queue: &str, info: &DSTORAGE_QUEUE_INFO) {
+        let capacity = u32::from(info.Desc.Capacity);
+        let used = capacity.saturating_sub(info.EmptySlotCount.into());
+        self.record_occupancy(queue, used, capacity);
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            elapsed: self.clock.now() - self.start,
+            ..self.snapshot.clone()
+        }
+    }
+
+    /// Forget everything recorded so far, restarting the elapsed time.
+    pub fn reset(&mut self) {
+        self.start = self.clock.now();
+        self.snapshot = MetricsSnapshot::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::SimulatedClock;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), None);
+        for latency in [
+            Duration::from_micros(10),
+            MS,
+            MS,
+            3 * MS,
+            Duration::from_secs(5),
+        ] {
+            histogram.record(latency);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(1001002)));
+        assert_eq!(histogram.quantile(0.0), Some(Duration::from_micros(50)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(1600)));
+        assert_eq!(histogram.quantile(0.8), Some(Duration::from_micros(3200)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_secs(5)));
+        let buckets: Vec<_> = histogram.buckets().collect();
+        assert_eq!(buckets.len(), LATENCY_BUCKETS);
+        assert_eq!(buckets[0], (Duration::from_micros(50), 1));
+        assert_eq!(buckets[5], (Duration::from_micros(1600), 3));
+        assert_eq!(buckets[LATENCY_BUCKETS - 1].1, 4);
+
+        let histogram = Histogram {
+            count: 1 << 32,
+            sum: Duration::from_secs(3 << 32),
+            ..Histogram::default()
+        };
+        assert_eq!(histogram.mean(), Some(Duration::from_secs(3)));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut metrics = Metrics::with_clock(SimulatedClock::new());
+        metrics.record_request(Priority::High, 1000, 3000, MS);
+        metrics.record_request(Priority::Low, 1000, 1000, 2 * MS);
+        metrics.record_error(0x89240009_u32 as i32);
+        metrics.record_error(0x89240009_u32 as i32);
+        for used in [4, 10, 2] {
+            metrics.clock().advance(MS);
+            metrics.record_occupancy("textures", used, 16);
+        }
+        metrics.clock().advance(Duration::from_secs(1) - 3 * MS);
+
+        let first = metrics.snapshot();
+        assert_eq!(first.requests(), 2);
+        assert_eq!(first.compression_ratio(), Some(2.0));
+        assert_eq!(first.bandwidth(None), Some(4000.0));
+        assert_eq!(first.errors[&(0x89240009_u32 as i32)], 2);
+        let textures = &first.occupancy["textures"];
+        assert_eq!((textures.current(), textures.max), (Some(2), 10));
+        assert_eq!(textures.samples[0], (MS, 4));
+
+        metrics.record_request(Priority::High, 500, 500, MS);
+        metrics.clock().advance(Duration::from_millis(500));
+        let second = metrics.snapshot();
+        assert_eq!(second.latency[&Priority::High].count(), 2);
+        assert_eq!(second.bandwidth(Some(&first)), Some(1000.0));
+
+        metrics.reset();
+        assert_eq!(metrics.snapshot(), MetricsSnapshot::default());
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let mut metrics = Metrics::with_clock(SimulatedClock::new());
+        metrics.record_request(Priority::Normal, 100, 200, Duration::from_micros(70));
+        metrics.record_occupancy("a \"b\"", 3, 8);
+        metrics.record_error(-1);
+
+        let mut text = Vec::new();
+        metrics.snapshot().write_prometheus(&mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        for line in [
+            "# TYPE dstorage_requests_total counter",
+            "dstorage_requests_total 1",
+            "dstorage_bytes_read_total 100",
+            "dstorage_compression_ratio 2",
+            "# TYPE dstorage_request_latency_seconds histogram",
+            r#"dstorage_request_latency_seconds_bucket{priority="normal",le="0.00005"} 0"#,
+            r#"dstorage_request_latency_seconds_bucket{priority="normal",le="0.0001"} 1"#,
+            r#"dstorage_request_latency_seconds_bucket{priority="normal",le="+Inf"} 1"#,
+            r#"dstorage_request_latency_seconds_sum{priority="normal"} 0.00007"#,
+            r#"dstorage_queue_occupancy{queue="a \"b\""} 3"#,
+            r#"dstorage_queue_capacity{queue="a \"b\""} 8"#,
+            r#"dstorage_errors_total{code="0xffffffff"} 1"#,
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "{line:?} missing in\n{text}"
+            );
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! A platform-independent mirror of [`DSTORAGE_PRIORITY`](crate::DSTORAGE_PRIORITY).
+//!
+//! DirectStorage assigns a priority per queue, so helpers that decide how urgent a request is
+//! return a [`Priority`] and leave it to the caller to enqueue the request on a matching queue.
+
+/// Priority of a queue, ordered from least to most urgent.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+    Realtime,
+}
+
+impl Priority {
+    /// All priorities, from least to most urgent.
+    pub const ALL: [Self; 4] = [Self::Low, Self::Normal, Self::High, Self::Realtime];
+}
+
+#[cfg(windows)]
+impl From<Priority> for crate::DSTORAGE_PRIORITY {
+    fn from(priority: Priority) -> Self {
+        match priority {
+            Priority::Low => crate::DSTORAGE_PRIORITY_LOW,
+            Priority::Normal => crate::DSTORAGE_PRIORITY_NORMAL,
+            Priority::High => crate::DSTORAGE_PRIORITY_HIGH,
+            Priority::Realtime => crate::DSTORAGE_PRIORITY_REALTIME,
+        }
+    }
+}
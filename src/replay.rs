@@ -0,0 +1,1020 @@
+//! Recording streams of queue operations and replaying them against a [`Backend`].
+//!
+//! A [`Recorder`] captures the operations of a session: files opened and closed, queues
+//! created, requests with their source and destination, status and signal writes, submits and
+//! cancellations, each with the time it happened.  They are written to a compact binary log
+//! that [`LogReader`] reads back and [`replay()`] feeds to any [`Backend`], either as fast as
+//! possible or with the recorded timing.
+//!
+//! The log only captures what is needed to issue the same requests again, not the data read:
+//! files are identified by their path, memory sources by their size, and every destination is
+//! replayed into memory of the recorded destination size.
+//!
+//! [`emulator::Emulator`] executes the log in-process with plain file reads on any platform,
+//! which makes it possible to bisect loader regressions on machines without DirectStorage, and
+//! can run in virtual time on a [`simulation::DeviceModel`].  On Windows,
+//! `direct_storage::DirectStorageBackend` replays against DirectStorage itself and
+//! `RecordingQueue` records everything enqueued on an `IDStorageQueue`.
+//! [`faults::FaultInjector`] wraps another backend to inject failures that the real runtime
+//! hardly ever produces.
+//!
+//! # Format
+//!
+//! A log starts with [`MAGIC`] and the [`VERSION`] as a little-endian `u32`, followed by one
+//! record per [`Event`]: a tag byte, the nanoseconds since the previous event and the fields of
+//! the [`Operation`].  Integers other than the version are LEB128 varints, strings are prefixed
+//! with their length.
+
+#[cfg(windows)]
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+    time::{Duration, Instant},
+};
+
+#[cfg(windows)]
+use windows::Win32::Graphics::Direct3D12::ID3D12Fence;
+#[cfg(windows)]
+use windows_core::Interface;
+
+use crate::{
+    priority::Priority,
+    scheduler::{Clock, SystemClock},
+};
+#[cfg(windows)]
+use crate::{
+    IDStorageFile, IDStorageQueue, IDStorageStatusArray, DSTORAGE_REQUEST,
+    DSTORAGE_REQUEST_DESTINATION_BUFFER, DSTORAGE_REQUEST_DESTINATION_MEMORY,
+    DSTORAGE_REQUEST_SOURCE_FILE,
+};
+
+#[cfg(windows)]
+pub mod direct_storage;
+pub mod emulator;
+pub mod faults;
+pub mod simulation;
+
+/// Magic bytes at the start of every log.
+pub const MAGIC: [u8; 4] = *b"DSRL";
+
+/// Version of the log format written by [`LogWriter`].
+pub const VERSION: u32 = 1;
+
+// `HRESULT`s reported by the backends in this module, which also exist where the bindings
+// don't.
+
+/// `E_DSTORAGE_END_OF_FILE`.
+pub const E_END_OF_FILE: i32 = 0x89240007_u32 as i32;
+/// `E_DSTORAGE_IO_TIMEOUT`.
+pub const E_IO_TIMEOUT: i32 = 0x89240016_u32 as i32;
+/// `E_DSTORAGE_QUEUE_CLOSED`.
+pub const E_QUEUE_CLOSED: i32 = 0x89240010_u32 as i32;
+/// `E_DSTORAGE_FILE_NOT_OPEN`.
+pub const E_FILE_NOT_OPEN: i32 = 0x8924000B_u32 as i32;
+/// `E_DSTORAGE_INVALID_DESTINATION_SIZE`.
+pub const E_INVALID_DESTINATION_SIZE: i32 = 0x8924000F_u32 as i32;
+/// `E_DSTORAGE_DECOMPRESSION_ERROR`.
+pub const E_DECOMPRESSION_ERROR: i32 = 0x89240030_u32 as i32;
+
+/// Identifies a file opened during a recording.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FileId(pub u32);
+
+/// Identifies a queue created during a recording.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QueueId(pub u32);
+
+/// Mirrors `DSTORAGE_REQUEST_SOURCE_TYPE`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SourceType {
+    #[default]
+    File,
+    Memory,
+}
+
+/// What is recorded about the creation of a queue.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueDesc {
+    pub name: String,
+    pub source_type: SourceType,
+    pub capacity: u16,
+    pub priority: Priority,
+}
+
+/// Where a recorded request reads from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Source {
+    File {
+        file: FileId,
+        offset: u64,
+        size: u32,
+    },
+    /// The contents of memory sources are not recorded.
+    Memory { size: u32 },
+}
+
+impl Source {
+    pub fn size(&self) -> u32 {
+        match *self {
+            Self::File { size, .. } | Self::Memory { size } => size,
+        }
+    }
+}
+
+/// What is recorded about a request.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    /// `DSTORAGE_REQUEST::Name`.
+    pub name: Option<String>,
+    pub source: Source,
+    pub compression_format: u8,
+    /// `DSTORAGE_REQUEST_DESTINATION_TYPE` of the original request.
+    pub destination_type: u64,
+    /// Number of bytes written to the destination: the size of memory and buffer
+    /// destinations, the uncompressed size otherwise.
+    pub destination_size: u32,
+    pub uncompressed_size: u32,
+    pub cancellation_tag: u64,
+}
+
+/// A recorded operation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+    OpenFile {
+        file: FileId,
+        path: String,
+    },
+    CloseFile {
+        file: FileId,
+    },
+    CreateQueue {
+        queue: QueueId,
+        desc: QueueDesc,
+    },
+    CloseQueue {
+        queue: QueueId,
+    },
+    EnqueueRequest {
+        queue: QueueId,
+        request: Request,
+    },
+    EnqueueStatus {
+        queue: QueueId,
+        index: u32,
+    },
+    EnqueueSignal {
+        queue: QueueId,
+        value: u64,
+    },
+    Submit {
+        queue: QueueId,
+    },
+    Cancel {
+        queue: QueueId,
+        mask: u64,
+        value: u64,
+    },
+}
+
+/// An [`Operation`] and when it happened, relative to the start of the recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub at: Duration,
+    pub operation: Operation,
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn write_varint(w: &mut impl Write, mut value: u64) -> io::Result<()> {
+    let mut buf = [0; 10];
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        if value == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+fn write_string(w: &mut impl Write, value: &str) -> io::Result<()> {
+    write_varint(w, value.len() as u64)?;
+    w.write_all(value.as_bytes())
+}
+
+/// Read a single byte, or [`None`] at the end of the log.
+fn read_byte(r: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut b = [0; 1];
+    loop {
+        match r.read(&mut b) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(b[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    read_byte(r)?.ok_or_else(|| io::ErrorKind::UnexpectedEof.into())
+}
+
+fn read_varint(r: &mut impl Read) -> io::Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(r)?;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid_data("varint is longer than 64 bits"))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    u32::try_from(read_varint(r)?).map_err(|_| invalid_data("value does not fit in 32 bits"))
+}
+
+fn read_string(r: &mut impl Read) -> io::Result<String> {
+    let len = read_varint(r)?;
+    let mut bytes = Vec::new();
+    r.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    String::from_utf8(bytes).map_err(|_| invalid_data("string is not UTF-8"))
+}
+
+fn priority_to_u8(priority: Priority) -> u8 {
+    match priority {
+        Priority::Low => 0,
+        Priority::Normal => 1,
+        Priority::High => 2,
+        Priority::Realtime => 3,
+    }
+}
+
+mod tag {
+    pub const OPEN_FILE: u8 = 0;
+    pub const CLOSE_FILE: u8 = 1;
+    pub const CREATE_QUEUE: u8 = 2;
+    pub const CLOSE_QUEUE: u8 = 3;
+    pub const ENQUEUE_FILE_REQUEST: u8 = 4;
+    pub const ENQUEUE_MEMORY_REQUEST: u8 = 5;
+    pub const ENQUEUE_STATUS: u8 = 6;
+    pub const ENQUEUE_SIGNAL: u8 = 7;
+    pub const SUBMIT: u8 = 8;
+    pub const CANCEL: u8 = 9;
+}
+
+/// Writes [`Event`]s to a log.
+pub struct LogWriter<W: Write> {
+    writer: W,
+    last: Duration,
+}
+
+impl<W: Write> LogWriter<W> {
+    /// Write the header of a log to `writer`.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(&MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        Ok(Self {
+            writer,
+            last: Duration::ZERO,
+        })
+    }
+
+    /// Append `event`.  Events are stored relative to the previous one, so an event earlier
+    /// than the previous one is stored at the same time.
+    pub fn write(&mut self, event: &Event) -> io::Result<()> {
+        let w = &mut self.writer;
+        let at = event.at.max(self.last);
+        let delta = (at - self.last).as_nanos().min(u64::MAX.into()) as u64;
+        self.last = at;
+
+        let tag = match &event.operation {
+            Operation::OpenFile { .. } => tag::OPEN_FILE,
+            Operation::CloseFile { .. } => tag::CLOSE_FILE,
+            Operation::CreateQueue { .. } => tag::CREATE_QUEUE,
+            Operation::CloseQueue { .. } => tag::CLOSE_QUEUE,
+            Operation::EnqueueRequest { request, .. } => match request.source {
+                Source::File { .. } => tag::ENQUEUE_FILE_REQUEST,
+                Source::Memory { .. } => tag::ENQUEUE_MEMORY_REQUEST,
+            },
+            Operation::EnqueueStatus { .. } => tag::ENQUEUE_STATUS,
+            Operation::EnqueueSignal { .. } => tag::ENQUEUE_SIGNAL,
+            Operation::Submit { .. } => tag::SUBMIT,
+            Operation::Cancel { .. } => tag::CANCEL,
+        };
+        w.write_all(&[tag])?;
+        write_varint(w, delta)?;
+
+        match &event.operation {
+            Operation::OpenFile { file, path } => {
+                write_varint(w, file.0.into())?;
+                write_string(w, path)?;
+            }
+            Operation::CloseFile { file } => write_varint(w, file.0.into())?,
+            Operation::CreateQueue { queue, desc } => {
+                write_varint(w, queue.0.into())?;
+                write_string(w, &desc.name)?;
+                w.write_all(&[
+                    (desc.source_type == SourceType::Memory) as u8,
+                    priority_to_u8(desc.priority),
+                ])?;
+                write_varint(w, desc.capacity.into())?;
+            }
+            Operation::CloseQueue { queue } | Operation::Submit { queue } => {
+                write_varint(w, queue.0.into())?
+            }
+            Operation::EnqueueRequest { queue, request } => {
+                write_varint(w, queue.0.into())?;
+                match request.source {
+                    Source::File { file, offset, size } => {
+                        write_varint(w, file.0.into())?;
+                        write_varint(w, offset)?;
+                        write_varint(w, size.into())?;
+                    }
+                    Source::Memory { size } => write_varint(w, size.into())?,
+                }
+                w.write_all(&[request.compression_format])?;
+                write_varint(w, request.destination_type)?;
+                write_varint(w, request.destination_size.into())?;
+                write_varint(w, request.uncompressed_size.into())?;
+                write_varint(w, request.cancellation_tag)?;
+                write_string(w, request.name.as_deref().unwrap_or(""))?;
+            }
+            Operation::EnqueueStatus { queue, index } => {
+                write_varint(w, queue.0.into())?;
+                write_varint(w, (*index).into())?;
+            }
+            Operation::EnqueueSignal { queue, value } => {
+                write_varint(w, queue.0.into())?;
+                write_varint(w, *value)?;
+            }
+            Operation::Cancel { queue, mask, value } => {
+                write_varint(w, queue.0.into())?;
+                write_varint(w, *mask)?;
+                write_varint(w, *value)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads [`Event`]s from a log written by [`LogWriter`].
+pub struct LogReader<R: Read> {
+    reader: R,
+    at: Duration,
+    failed: bool,
+}
+
+impl<R: Read> LogReader<R> {
+    /// Read and validate the header of a log from `reader`.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 8];
+        reader.read_exact(&mut header)?;
+        if header[..4] != MAGIC {
+            return Err(invalid_data("log has an invalid magic"));
+        }
+        let version = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported log version {version}")));
+        }
+        Ok(Self {
+            reader,
+            at: Duration::ZERO,
+            failed: false,
+        })
+    }
+
+    /// Read the next event, or [`None`] at the end of the log.
+    pub fn read(&mut self) -> io::Result<Option<Event>> {
+        let r = &mut self.reader;
+        let Some(tag) = read_byte(r)? else {
+            return Ok(None);
+        };
+        self.at += Duration::from_nanos(read_varint(r)?);
+
+        let operation = match tag {
+            tag::OPEN_FILE => Operation::OpenFile {
+                file: FileId(read_u32(r)?),
+                path: read_string(r)?,
+            },
+            tag::CLOSE_FILE => Operation::CloseFile {
+                file: FileId(read_u32(r)?),
+            },
+            tag::CREATE_QUEUE => {
+                let queue = QueueId(read_u32(r)?);
+                let name = read_string(r)?;
+                let source_type = match read_u8(r)? {
+                    0 => SourceType::File,
+                    1 => SourceType::Memory,
+                    other => return Err(invalid_data(format!("unknown source type {other}"))),
+                };
+                let priority = *Priority::ALL
+                    .get(read_u8(r)? as usize)
+                    .ok_or_else(|| invalid_data("unknown priority"))?;
+                let capacity = u16::try_from(read_varint(r)?)
+                    .map_err(|_| invalid_data("queue capacity does not fit in 16 bits"))?;
+                Operation::CreateQueue {
+                    queue,
+                    desc: QueueDesc {
+                        name,
+                        source_type,
+                        capacity,
+                        priority,
+                    },
+                }
+            }
+            tag::CLOSE_QUEUE => Operation::CloseQueue {
+                queue: QueueId(read_u32(r)?),
+            },
+            tag::ENQUEUE_FILE_REQUEST | tag::ENQUEUE_MEMORY_REQUEST => {
+                let queue = QueueId(read_u32(r)?);
+                let source = if tag == tag::ENQUEUE_FILE_REQUEST {
+                    Source::File {
+                        file: FileId(read_u32(r)?),
+                        offset: read_varint(r)?,
+                        size: read_u32(r)?,
+                    }
+                } else {
+                    Source::Memory { size: read_u32(r)? }
+                };
+                let compression_format = read_u8(r)?;
+                let destination_type = read_varint(r)?;
+                let destination_size = read_u32(r)?;
+                let uncompressed_size = read_u32(r)?;
+                let cancellation_tag = read_varint(r)?;
+                let name = read_string(r)?;
+                Operation::EnqueueRequest {
+                    queue,
+                    request: Request {
+                        name: (!name.is_empty()).then_some(name),
+                        source,
+                        compression_format,
+                        destination_type,
+                        destination_size,
+                        uncompressed_size,
+                        cancellation_tag,
+                    },
+                }
+            }
+            tag::ENQUEUE_STATUS => Operation::EnqueueStatus {
+                queue: QueueId(read_u32(r)?),
+                index: read_u32(r)?,
+            },
+            tag::ENQUEUE_SIGNAL => Operation::EnqueueSignal {
+                queue: QueueId(read_u32(r)?),
+                value: read_varint(r)?,
+            },
+            tag::SUBMIT => Operation::Submit {
+                queue: QueueId(read_u32(r)?),
+            },
+            tag::CANCEL => Operation::Cancel {
+                queue: QueueId(read_u32(r)?),
+                mask: read_varint(r)?,
+                value: read_varint(r)?,
+            },
+            other => return Err(invalid_data(format!("unknown event tag {other}"))),
+        };
+        Ok(Some(Event {
+            at: self.at,
+            operation,
+        }))
+    }
+}
+
+impl<R: Read> Iterator for LogReader<R> {
+    type Item = io::Result<Event>;
+
+    /// Stops after the first error.
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.read().transpose();
+        self.failed = matches!(result, Some(Err(_)));
+        result
+    }
+}
+
+/// Records operations into a log, timestamped with a [`Clock`].
+pub struct Recorder<W: Write, C: Clock = SystemClock> {
+    writer: LogWriter<W>,
+    clock: C,
+    start: Instant,
+    next_file: u32,
+    next_queue: u32,
+    /// Files opened through [`Recorder::open_dstorage_file()`], keyed by interface pointer.
+    #[cfg(windows)]
+    files: HashMap<usize, FileId>,
+}
+
+impl<W: Write> Recorder<W> {
+    pub fn new(writer: W) -> io::Result<Self> {
+        Self::with_clock(writer, SystemClock)
+    }
+}
+
+impl<W: Write, C: Clock> Recorder<W, C> {
+    pub fn with_clock(writer: W, clock: C) -> io::Result<Self> {
+        Ok(Self {
+            writer: LogWriter::new(writer)?,
+            start: clock.now(),
+            clock,
+            next_file: 0,
+            next_queue: 0,
+            #[cfg(windows)]
+            files: HashMap::new(),
+        })
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// Record `operation` as happening now.
+    pub fn record(&mut self, operation: Operation) -> io::Result<()> {
+        self.writer.write(&Event {
+            at: self.clock.now() - self.start,
+            operation,
+        })
+    }
+
+    /// Record opening the file at `path`.
+    pub fn open_file(&mut self, path: &str) -> io::Result<FileId> {
+        let file = FileId(self.next_file);
+        self.next_file += 1;
+        self.record(Operation::OpenFile {
+            file,
+            path: path.to_owned(),
+        })?;
+        Ok(file)
+    }
+
+    pub fn close_file(&mut self, file: FileId) -> io::Result<()> {
+        self.record(Operation::CloseFile { file })
+    }
+
+    pub fn create_queue(&mut self, desc: QueueDesc) -> io::Result<QueueId> {
+        let queue = QueueId(self.next_queue);
+        self.next_queue += 1;
+        self.record(Operation::CreateQueue { queue, desc })?;
+        Ok(queue)
+    }
+
+    pub fn close_queue(&mut self, queue: QueueId) -> io::Result<()> {
+        self.record(Operation::CloseQueue { queue })
+    }
+
+    pub fn enqueue_request(&mut self, queue: QueueId, request: Request) -> io::Result<()> {
+        self.record(Operation::EnqueueRequest { queue, request })
+    }
+
+    pub fn enqueue_status(&mut self, queue: QueueId, index: u32) -> io::Result<()> {
+        self.record(Operation::EnqueueStatus { queue, index })
+    }
+
+    pub fn enqueue_signal(&mut self, queue: QueueId, value: u64) -> io::Result<()> {
+        self.record(Operation::EnqueueSignal { queue, value })
+    }
+
+    pub fn submit(&mut self, queue: QueueId) -> io::Result<()> {
+        self.record(Operation::Submit { queue })
+    }
+
+    pub fn cancel(&mut self, queue: QueueId, mask: u64, value: u64) -> io::Result<()> {
+        self.record(Operation::Cancel { queue, mask, value })
+    }
+
+    /// Flush and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer.into_inner())
+    }
+}
+
+#[cfg(windows)]
+impl<W: Write, C: Clock> Recorder<W, C> {
+    /// Record opening `file` from `path`, so that requests reading from it can be recorded.
+    pub fn open_dstorage_file(&mut self, file: &IDStorageFile, path: &str) -> io::Result<FileId> {
+        let id = self.open_file(path)?;
+        self.files.insert(file.as_raw() as usize, id);
+        Ok(id)
+    }
+
+    /// Record closing a file opened through [`Recorder::open_dstorage_file()`].
+    pub fn close_dstorage_file(&mut self, file: &IDStorageFile) -> io::Result<()> {
+        match self.files.remove(&(file.as_raw() as usize)) {
+            Some(id) => self.close_file(id),
+            None => Ok(()),
+        }
+    }
+
+    /// Describe `request` for recording.
+    ///
+    /// # Safety
+    /// `request.Name` must be null or point to a null-terminated string, and the source and
+    /// destination must match `request.Options`.
+    pub unsafe fn describe_request(&self, request: &DSTORAGE_REQUEST) -> io::Result<Request> {
+        unsafe {
+            Request::from_dstorage(request, |file| {
+                self.files.get(&(file?.as_raw() as usize)).copied()
+            })
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "request reads from a file that was not recorded",
+            )
+        })
+    }
+}
+
+#[cfg(windows)]
+impl Request {
+    /// Describe `request`, identifying the file it reads from with `file_id`.  Returns [`None`]
+    /// when `file_id` does.
+    ///
+    /// # Safety
+    /// See [`Recorder::describe_request()`].
+    pub(crate) unsafe fn from_dstorage(
+        request: &DSTORAGE_REQUEST,
+        file_id: impl FnOnce(Option<&IDStorageFile>) -> Option<FileId>,
+    ) -> Option<Self> {
+        let source = if request.Options.SourceType() == DSTORAGE_REQUEST_SOURCE_FILE {
+            let source = unsafe { &request.Source.File };
+            Source::File {
+                file: file_id(source.Source.as_ref())?,
+                offset: source.Offset,
+                size: source.Size,
+            }
+        } else {
+            Source::Memory {
+                size: unsafe { request.Source.Memory.Size },
+            }
+        };
+        let destination_type = request.Options.DestinationType();
+        let destination_size = if destination_type == DSTORAGE_REQUEST_DESTINATION_MEMORY {
+            unsafe { request.Destination.Memory.Size }
+        } else if destination_type == DSTORAGE_REQUEST_DESTINATION_BUFFER {
+            unsafe { request.Destination.Buffer.Size }
+        } else {
+            request.UncompressedSize
+        };
+        Some(Self {
+            name: (!request.Name.is_null())
+                .then(|| unsafe { request.Name.to_string() }.ok())
+                .flatten(),
+            source,
+            compression_format: request.Options.CompressionFormat().0,
+            destination_type: destination_type.0,
+            destination_size,
+            uncompressed_size: request.UncompressedSize,
+            cancellation_tag: request.CancellationTag,
+        })
+    }
+}
+
+/// An `IDStorageQueue` that records everything enqueued on it into a shared [`Recorder`].
+///
+/// Requests may only read from files opened through [`Recorder::open_dstorage_file()`].
+#[cfg(windows)]
+pub struct RecordingQueue<W: Write, C: Clock = SystemClock> {
+    queue: IDStorageQueue,
+    id: QueueId,
+    recorder: Arc<Mutex<Recorder<W, C>>>,
+}
+
+#[cfg(windows)]
+impl<W: Write, C: Clock> RecordingQueue<W, C> {
+    /// Record the creation of `queue`, which was created as described by `desc`.
+    pub fn new(
+        queue: IDStorageQueue,
+        desc: QueueDesc,
+        recorder: Arc<Mutex<Recorder<W, C>>>,
+    ) -> io::Result<Self> {
+        let id = recorder.lock().unwrap().create_queue(desc)?;
+        Ok(Self {
+            queue,
+            id,
+            recorder,
+        })
+    }
+
+    pub fn queue(&self) -> &IDStorageQueue {
+        &self.queue
+    }
+
+    pub fn id(&self) -> QueueId {
+        self.id
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::EnqueueRequest()` and [`Recorder::describe_request()`].
+    pub unsafe fn enqueue_request(&self, request: &DSTORAGE_REQUEST) -> io::Result<()> {
+        let mut recorder = self.recorder.lock().unwrap();
+        let described = unsafe { recorder.describe_request(request) }?;
+        unsafe { self.queue.EnqueueRequest(request) };
+        recorder.enqueue_request(self.id, described)
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::EnqueueStatus()`.
+    pub unsafe fn enqueue_status(
+        &self,
+        status_array: &IDStorageStatusArray,
+        index: u32,
+    ) -> io::Result<()> {
+        unsafe { self.queue.EnqueueStatus(status_array, index) };
+        self.recorder.lock().unwrap().enqueue_status(self.id, index)
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::EnqueueSignal()`.
+    pub unsafe fn enqueue_signal(&self, fence: &ID3D12Fence, value: u64) -> io::Result<()> {
+        unsafe { self.queue.EnqueueSignal(fence, value) };
+        self.recorder.lock().unwrap().enqueue_signal(self.id, value)
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::Submit()`.
+    pub unsafe fn submit(&self) -> io::Result<()> {
+        self.recorder.lock().unwrap().submit(self.id)?;
+        unsafe { self.queue.Submit() };
+        Ok(())
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::CancelRequestsWithTag()`.
+    pub unsafe fn cancel_requests_with_tag(&self, mask: u64, value: u64) -> io::Result<()> {
+        unsafe { self.queue.CancelRequestsWithTag(mask, value) };
+        self.recorder.lock().unwrap().cancel(self.id, mask, value)
+    }
+
+    /// Close the queue and record it.
+    ///
+    /// # Safety
+    /// See `IDStorageQueue::Close()`.
+    pub unsafe fn close(self) -> io::Result<()> {
+        unsafe { self.queue.Close() };
+        self.recorder.lock().unwrap().close_queue(self.id)
+    }
+}
+
+/// A status or signal write that completed during a replay.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Completion {
+    /// The `HRESULT` is the first failure of any request enqueued before the status.
+    Status {
+        queue: QueueId,
+        index: u32,
+        hresult: i32,
+    },
+    Signal {
+        queue: QueueId,
+        value: u64,
+    },
+}
+
+/// Something operations can be replayed against.
+pub trait Backend {
+    type Error;
+
+    /// Perform `operation`.  Requests only have to be executed once their queue is submitted.
+    fn execute(&mut self, operation: &Operation) -> Result<(), Self::Error>;
+
+    /// Return the statuses and signals that completed since the previous call.
+    fn poll(&mut self) -> Vec<Completion>;
+
+    /// Block until everything that was submitted completed.
+    fn flush(&mut self) -> Result<(), Self::Error>;
+
+    /// Current time of backends that run in virtual time, or [`None`] for real time.
+    fn virtual_time(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Advance virtual time to `at`, if it is later than the current one.
+    fn advance_to(&mut self, _at: Duration) {}
+
+    /// Time the earliest submitted status or signal that hasn't been polled yet completes, for
+    /// backends that run in virtual time.
+    fn next_completion(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// How [`replay()`] paces the events.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Pacing {
+    /// Execute every event immediately.
+    #[default]
+    AsFastAsPossible,
+    /// Sleep until each event is due relative to the start of the replay, or advance virtual
+    /// time to it for backends that run in virtual time.
+    Recorded,
+}
+
+/// Error of [`replay()`].
+#[derive(Debug)]
+pub enum ReplayError<E> {
+    /// The log could not be read.
+    Log(io::Error),
+    /// The backend failed to execute the event at `index`.
+    Backend { index: usize, error: E },
+}
+
+impl<E: fmt::Display> fmt::Display for ReplayError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Log(e) => write!(f, "failed to read the log: {e}"),
+            Self::Backend { index, error } => write!(f, "failed to replay event {index}: {error}"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for ReplayError<E> {}
+
+/// Result of [`replay()`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Number of events replayed.
+    pub events: usize,
+    /// Completed statuses and signals, with the time since the start of the replay they were
+    /// observed at.  Backends running in virtual time report it instead of real time.
+    pub completions: Vec<(Duration, Completion)>,
+    pub elapsed: Duration,
+}
+
+/// Replay `events` against `backend` and wait for everything to complete.
+pub fn replay<B: Backend>(
+    events: impl IntoIterator<Item = io::Result<Event>>,
+    backend: &mut B,
+    pacing: Pacing,
+) -> Result<ReplayReport, ReplayError<B::Error>> {
+    let start = Instant::now();
+    let virtual_start = backend.virtual_time();
+    // Time since the start of the replay, in virtual time if the backend runs in it.
+    let elapsed = |backend: &B| match (virtual_start, backend.virtual_time()) {
+        (Some(start), Some(now)) => now - start,
+        _ => start.elapsed(),
+    };
+    let mut report = ReplayReport::default();
+    let collect = |backend: &mut B, report: &mut ReplayReport| {
+        let at = elapsed(backend);
+        report
+            .completions
+            .extend(backend.poll().into_iter().map(|c| (at, c)));
+    };
+
+    for (index, event) in events.into_iter().enumerate() {
+        let event = event.map_err(ReplayError::Log)?;
+        if pacing == Pacing::Recorded {
+            match virtual_start {
+                Some(virtual_start) => backend.advance_to(virtual_start + event.at),
+                None => {
+                    if let Some(wait) = event.at.checked_sub(start.elapsed()) {
+                        std::thread::sleep(wait);
+                    }
+                }
+            }
+        }
+        backend
+            .execute(&event.operation)
+            .map_err(|error| ReplayError::Backend { index, error })?;
+        report.events += 1;
+        collect(backend, &mut report);
+    }
+
+    backend.flush().map_err(|error| ReplayError::Backend {
+        index: report.events,
+        error,
+    })?;
+    collect(backend, &mut report);
+    report.elapsed = elapsed(backend);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::SimulatedClock;
+
+    pub(crate) fn session() -> Vec<u8> {
+        let mut recorder = Recorder::with_clock(Vec::new(), SimulatedClock::new()).unwrap();
+        let file = recorder.open_file("assets/a.bin").unwrap();
+        let queue = recorder
+            .create_queue(QueueDesc {
+                name: "textures".into(),
+                capacity: 128,
+                priority: Priority::High,
+                ..Default::default()
+            })
+            .unwrap();
+        recorder.clock().advance(Duration::from_micros(1500));
+        recorder
+            .enqueue_request(
+                queue,
+                Request {
+                    name: Some("first".into()),
+                    source: Source::File {
+                        file,
+                        offset: 4,
+                        size: 8,
+                    },
+                    compression_format: 0,
+                    destination_type: 0,
+                    destination_size: 8,
+                    uncompressed_size: 8,
+                    cancellation_tag: 1,
+                },
+            )
+            .unwrap();
+        recorder.enqueue_status(queue, 0).unwrap();
+        recorder.clock().advance(Duration::from_millis(2));
+        recorder.submit(queue).unwrap();
+        recorder.cancel(queue, !0, 1).unwrap();
+        recorder.enqueue_signal(queue, u64::MAX).unwrap();
+        recorder.close_queue(queue).unwrap();
+        recorder.close_file(file).unwrap();
+        recorder.finish().unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let log = session();
+        let events: Vec<_> = LogReader::new(&log[..])
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(events.len(), 9);
+        assert_eq!(
+            events[0].operation,
+            Operation::OpenFile {
+                file: FileId(0),
+                path: "assets/a.bin".into()
+            }
+        );
+        let Operation::CreateQueue { desc, .. } = &events[1].operation else {
+            panic!("{:?}", events[1]);
+        };
+        assert_eq!(
+            (desc.name.as_str(), desc.priority),
+            ("textures", Priority::High)
+        );
+        let Operation::EnqueueRequest { request, .. } = &events[2].operation else {
+            panic!("{:?}", events[2]);
+        };
+        assert_eq!(request.name.as_deref(), Some("first"));
+        assert_eq!(events[2].at, Duration::from_micros(1500));
+        assert_eq!(events[4].at, Duration::from_micros(3500));
+        assert_eq!(
+            events[6].operation,
+            Operation::EnqueueSignal {
+                queue: QueueId(0),
+                value: u64::MAX
+            }
+        );
+
+        // Rewriting the events produces the same log.
+        let mut writer = LogWriter::new(Vec::new()).unwrap();
+        for event in &events {
+            writer.write(event).unwrap();
+        }
+        assert_eq!(writer.into_inner(), log);
+    }
+
+    #[test]
+    fn test_invalid_log() {
+        assert!(LogReader::new(&b"DSRX\x01\0\0\0"[..]).is_err());
+        assert!(LogReader::new(&b"DSRL\x02\0\0\0"[..]).is_err());
+
+        let log = session();
+        let mut reader = LogReader::new(&log[..log.len() - 1]).unwrap();
+        assert_eq!(reader.by_ref().filter(Result::is_ok).count(), 8);
+        let mut reader = LogReader::new(&log[..log.len() - 1]).unwrap();
+        assert!(reader.nth(8).unwrap().is_err());
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,452 @@
+//! A [`Backend`] replaying against DirectStorage.
+//!
+//! Every destination is replayed into memory owned by the backend, so queues are created
+//! without a device, unless [`DirectStorageBackend::set_destination_buffer()`] was called: then
+//! queues are created on its device and requests are read into the D3D12 buffer instead, which
+//! lets DirectStorage decompress GDeflate on the GPU.  Recorded statuses and signals are both
+//! enqueued as statuses on a status array per queue, whose [`STATUS_SLOTS`] entries are reused
+//! round-robin; a slot must have completed before it is reused.  Requests still pending when a
+//! queue is closed are cancelled.
+//!
+//! Draining a queue blocks on an event set through `IDStorageQueue1::EnqueueSetEvent()`, so the
+//! backend requires DirectStorage 1.1.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    ffi::CString,
+    fmt,
+    mem::ManuallyDrop,
+    path::PathBuf,
+};
+
+use windows::Win32::{
+    Graphics::Direct3D12::{ID3D12Device, ID3D12Resource},
+    System::Threading::{WaitForSingleObject, INFINITE},
+};
+use windows_core::{Interface, HSTRING, PCSTR};
+
+use super::{
+    Backend, Completion, FileId, Operation, QueueDesc, QueueId, Request, Source, SourceType,
+};
+use crate::{
+    readonly_copy, waiter::Event, IDStorageFactory, IDStorageFile, IDStorageQueue, IDStorageQueue1,
+    IDStorageStatusArray, DSTORAGE_COMPRESSION_FORMAT, DSTORAGE_DESTINATION,
+    DSTORAGE_DESTINATION_BUFFER, DSTORAGE_DESTINATION_MEMORY, DSTORAGE_QUEUE_DESC,
+    DSTORAGE_REQUEST, DSTORAGE_REQUEST_DESTINATION_BUFFER, DSTORAGE_REQUEST_DESTINATION_MEMORY,
+    DSTORAGE_REQUEST_OPTIONS, DSTORAGE_REQUEST_SOURCE_FILE, DSTORAGE_REQUEST_SOURCE_MEMORY,
+    DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE, DSTORAGE_SOURCE_MEMORY,
+};
+
+/// Number of entries in the status array of every queue.
+pub const STATUS_SLOTS: u32 = 4096;
+
+/// Error of [`DirectStorageBackend`].
+#[derive(Debug)]
+pub enum DirectStorageError {
+    UnknownFile(FileId),
+    UnknownQueue(QueueId),
+    DuplicateQueue(QueueId),
+    /// More than [`STATUS_SLOTS`] statuses and signals of a queue are in flight.
+    StatusSlotsExhausted(QueueId),
+    /// A request writes more bytes than the destination buffer holds.
+    DestinationTooLarge(u32),
+    Windows(windows_core::Error),
+}
+
+impl fmt::Display for DirectStorageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownFile(file) => write!(f, "file {} is not open", file.0),
+            Self::UnknownQueue(queue) => write!(f, "queue {} does not exist", queue.0),
+            Self::DuplicateQueue(queue) => write!(f, "queue {} already exists", queue.0),
+            Self::StatusSlotsExhausted(queue) => write!(
+                f,
+                "more than {STATUS_SLOTS} statuses and signals are in flight on queue {}",
+                queue.0
+            ),
+            Self::DestinationTooLarge(size) => write!(
+                f,
+                "a destination of {size} bytes does not fit in the destination buffer"
+            ),
+            Self::Windows(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for DirectStorageError {}
+
+impl From<windows_core::Error> for DirectStorageError {
+    fn from(e: windows_core::Error) -> Self {
+        Self::Windows(e)
+    }
+}
+
+struct ReplayQueue {
+    queue: IDStorageQueue1,
+    status_array: IDStorageStatusArray,
+    /// Set once everything enqueued before [`ReplayQueue::drain()`] completed.
+    event: Event,
+    next_slot: u32,
+    /// Statuses in flight, with the completion to report or [`None`] for internal ones.
+    markers: VecDeque<(u32, Option<Completion>)>,
+    /// Number of statuses enqueued and completed since the queue was created.
+    enqueued: u64,
+    completed: u64,
+    /// Source and destination memory of requests, with the number of statuses enqueued before
+    /// them.  They are freed once the next status completed.
+    buffers: VecDeque<(u64, Vec<u8>)>,
+}
+
+impl ReplayQueue {
+    fn enqueue_status(
+        &mut self,
+        id: QueueId,
+        completion: Option<Completion>,
+    ) -> Result<(), DirectStorageError> {
+        let slot = self.next_slot;
+        if self.markers.iter().any(|&(s, _)| s == slot) {
+            return Err(DirectStorageError::StatusSlotsExhausted(id));
+        }
+        self.next_slot = (slot + 1) % STATUS_SLOTS;
+        unsafe { self.queue.EnqueueStatus(&self.status_array, slot) };
+        self.markers.push_back((slot, completion));
+        self.enqueued += 1;
+        Ok(())
+    }
+
+    /// Report completed statuses in order.
+    fn poll(&mut self, completions: &mut Vec<Completion>) {
+        while let Some(&(slot, completion)) = self.markers.front() {
+            if !unsafe { self.status_array.IsComplete(slot) } {
+                break;
+            }
+            let hresult = match unsafe { self.status_array.GetHResult(slot) } {
+                Ok(()) => 0,
+                Err(e) => e.code().0,
+            };
+            self.markers.pop_front();
+            self.completed += 1;
+            completions.extend(completion.map(|completion| match completion {
+                Completion::Status { queue, index, .. } => Completion::Status {
+                    queue,
+                    index,
+                    hresult,
+                },
+                signal @ Completion::Signal { .. } => signal,
+            }));
+        }
+        while self
+            .buffers
+            .front()
+            .is_some_and(|&(status, _)| status < self.completed)
+        {
+            self.buffers.pop_front();
+        }
+    }
+
+    /// Submit and block until everything enqueued completed.
+    ///
+    /// This doesn't need a free status slot, so it can't fail and always leaves no request
+    /// behind that could still write into `buffers`.
+    fn drain(&mut self, completions: &mut Vec<Completion>) {
+        unsafe {
+            self.queue.EnqueueSetEvent(self.event.0);
+            self.queue.Submit();
+            WaitForSingleObject(self.event.0, INFINITE);
+        }
+        self.poll(completions);
+        debug_assert!(self.markers.is_empty());
+        self.buffers.clear();
+    }
+}
+
+/// A D3D12 buffer that requests are read into back to back, wrapping around at its end.
+struct DestinationBuffer {
+    device: ID3D12Device,
+    buffer: ID3D12Resource,
+    size: u64,
+    /// Where the next request is read to.
+    offset: u64,
+}
+
+/// Replays operations against DirectStorage, see the [module documentation](self).
+pub struct DirectStorageBackend {
+    factory: IDStorageFactory,
+    map_path: Box<dyn FnMut(&str) -> PathBuf>,
+    destination: Option<DestinationBuffer>,
+    files: HashMap<FileId, IDStorageFile>,
+    queues: HashMap<QueueId, ReplayQueue>,
+    completions: Vec<Completion>,
+}
+
+impl DirectStorageBackend {
+    pub fn new(factory: IDStorageFactory) -> Self {
+        Self {
+            factory,
+            map_path: Box::new(|path: &str| PathBuf::from(path)),
+            destination: None,
+            files: HashMap::new(),
+            queues: HashMap::new(),
+            completions: Vec::new(),
+        }
+    }
+
+    /// Map recorded paths to the files opened instead.
+    pub fn set_path_map(&mut self, map_path: impl FnMut(&str) -> PathBuf + 'static) {
+        self.map_path = Box::new(map_path);
+    }
+
+    /// Create queues on `device` and read all requests into `buffer` instead of memory, starting
+    /// with the queues created next.
+    ///
+    /// Requests are placed back to back and wrap around at the end of `buffer`, so the data of
+    /// requests in flight at the same time only stays intact if `buffer` holds all of them.
+    /// Requests bigger than `buffer` fail with [`DirectStorageError::DestinationTooLarge`].
+    ///
+    /// # Safety
+    /// `buffer` must be a buffer of at least `size` bytes created on `device`.
+    pub unsafe fn set_destination_buffer(
+        &mut self,
+        device: ID3D12Device,
+        buffer: ID3D12Resource,
+        size: u64,
+    ) {
+        self.destination = Some(DestinationBuffer {
+            device,
+            buffer,
+            size,
+            offset: 0,
+        });
+    }
+
+    fn queue(&mut self, queue: QueueId) -> Result<&mut ReplayQueue, DirectStorageError> {
+        self.queues
+            .get_mut(&queue)
+            .ok_or(DirectStorageError::UnknownQueue(queue))
+    }
+
+    fn create_queue(&mut self, id: QueueId, desc: &QueueDesc) -> Result<(), DirectStorageError> {
+        if self.queues.contains_key(&id) {
+            return Err(DirectStorageError::DuplicateQueue(id));
+        }
+        let name = CString::new(desc.name.replace('\0', "")).unwrap();
+        let queue: IDStorageQueue = unsafe {
+            self.factory.CreateQueue(&DSTORAGE_QUEUE_DESC {
+                SourceType: match desc.source_type {
+                    SourceType::File => DSTORAGE_REQUEST_SOURCE_FILE,
+                    SourceType::Memory => DSTORAGE_REQUEST_SOURCE_MEMORY,
+                },
+                Capacity: desc.capacity,
+                Priority: desc.priority.into(),
+                Name: PCSTR::from_raw(name.as_ptr().cast()),
+                Device: match &self.destination {
+                    Some(destination) => readonly_copy(&destination.device),
+                    None => Default::default(),
+                },
+            })
+        }?;
+        let status_array = unsafe { self.factory.CreateStatusArray(STATUS_SLOTS, PCSTR::null()) }?;
+        self.queues.insert(
+            id,
+            ReplayQueue {
+                queue: queue.cast()?,
+                status_array,
+                event: Event::new()?,
+                next_slot: 0,
+                markers: VecDeque::new(),
+                enqueued: 0,
+                completed: 0,
+                buffers: VecDeque::new(),
+            },
+        );
+        Ok(())
+    }
+
+    fn enqueue_request(
+        &mut self,
+        id: QueueId,
+        request: &Request,
+    ) -> Result<(), DirectStorageError> {
+        let file = match request.source {
+            Source::File { file, .. } => Some(
+                self.files
+                    .get(&file)
+                    .ok_or(DirectStorageError::UnknownFile(file))?
+                    .clone(),
+            ),
+            Source::Memory { .. } => None,
+        };
+        let mut memory = Vec::new();
+        let (destination_type, destination) = match &mut self.destination {
+            Some(buffer) => {
+                let size = u64::from(request.destination_size);
+                if size > buffer.size {
+                    return Err(DirectStorageError::DestinationTooLarge(
+                        request.destination_size,
+                    ));
+                }
+                if buffer.offset + size > buffer.size {
+                    buffer.offset = 0;
+                }
+                let offset = buffer.offset;
+                buffer.offset += size;
+                let destination = DSTORAGE_DESTINATION_BUFFER {
+                    Resource: unsafe { readonly_copy(&buffer.buffer) },
+                    Offset: offset,
+                    Size: request.destination_size,
+                };
+                (
+                    DSTORAGE_REQUEST_DESTINATION_BUFFER,
+                    DSTORAGE_DESTINATION {
+                        Buffer: ManuallyDrop::new(destination),
+                    },
+                )
+            }
+            None => {
+                memory.resize(request.destination_size as usize, 0u8);
+                let destination = DSTORAGE_DESTINATION_MEMORY {
+                    Buffer: memory.as_mut_ptr().cast(),
+                    Size: request.destination_size,
+                };
+                (
+                    DSTORAGE_REQUEST_DESTINATION_MEMORY,
+                    DSTORAGE_DESTINATION {
+                        Memory: destination,
+                    },
+                )
+            }
+        };
+        let queue = self.queue(id)?;
+
+        let mut options = DSTORAGE_REQUEST_OPTIONS::default();
+        options.set_CompressionFormat(DSTORAGE_COMPRESSION_FORMAT(request.compression_format));
+        options.set_DestinationType(destination_type);
+        let source = match (request.source, &file) {
+            (Source::File { offset, size, .. }, Some(file)) => {
+                options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+                DSTORAGE_SOURCE {
+                    File: ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                        Source: unsafe { readonly_copy(file) },
+                        Offset: offset,
+                        Size: size,
+                    }),
+                }
+            }
+            (source, _) => {
+                options.set_SourceType(DSTORAGE_REQUEST_SOURCE_MEMORY);
+                let memory = vec![0u8; source.size() as usize];
+                let source = DSTORAGE_SOURCE {
+                    Memory: DSTORAGE_SOURCE_MEMORY {
+                        Source: memory.as_ptr().cast(),
+                        Size: source.size(),
+                    },
+                };
+                // Moving the vector doesn't move its allocation.
+                queue.buffers.push_back((queue.enqueued, memory));
+                source
+            }
+        };
+        let name = request
+            .name
+            .as_ref()
+            .and_then(|name| CString::new(name.as_str()).ok());
+        unsafe {
+            queue.queue.EnqueueRequest(&DSTORAGE_REQUEST {
+                Options: options,
+                Source: source,
+                Destination: destination,
+                UncompressedSize: request.uncompressed_size,
+                CancellationTag: request.cancellation_tag,
+                Name: name
+                    .as_ref()
+                    .map_or(PCSTR::null(), |name| PCSTR::from_raw(name.as_ptr().cast())),
+            })
+        };
+        if !memory.is_empty() {
+            queue.buffers.push_back((queue.enqueued, memory));
+        }
+        Ok(())
+    }
+}
+
+impl Backend for DirectStorageBackend {
+    type Error = DirectStorageError;
+
+    fn execute(&mut self, operation: &Operation) -> Result<(), DirectStorageError> {
+        match operation {
+            Operation::OpenFile { file, path } => {
+                let path = (self.map_path)(path);
+                let opened = unsafe { self.factory.OpenFile(&HSTRING::from(path.as_path())) }?;
+                self.files.insert(*file, opened);
+            }
+            Operation::CloseFile { file } => {
+                let file = self
+                    .files
+                    .remove(file)
+                    .ok_or(DirectStorageError::UnknownFile(*file))?;
+                unsafe { file.Close() };
+            }
+            Operation::CreateQueue { queue, desc } => self.create_queue(*queue, desc)?,
+            Operation::CloseQueue { queue: id } => {
+                let queue = self
+                    .queues
+                    .get_mut(id)
+                    .ok_or(DirectStorageError::UnknownQueue(*id))?;
+                unsafe { queue.queue.CancelRequestsWithTag(0, 0) };
+                queue.drain(&mut self.completions);
+                unsafe { queue.queue.Close() };
+                self.queues.remove(id);
+            }
+            Operation::EnqueueRequest { queue, request } => {
+                self.enqueue_request(*queue, request)?
+            }
+            Operation::EnqueueStatus { queue: id, index } => {
+                let completion = Completion::Status {
+                    queue: *id,
+                    index: *index,
+                    hresult: 0,
+                };
+                self.queue(*id)?.enqueue_status(*id, Some(completion))?
+            }
+            Operation::EnqueueSignal { queue: id, value } => {
+                let completion = Completion::Signal {
+                    queue: *id,
+                    value: *value,
+                };
+                self.queue(*id)?.enqueue_status(*id, Some(completion))?
+            }
+            Operation::Submit { queue } => unsafe { self.queue(*queue)?.queue.Submit() },
+            Operation::Cancel { queue, mask, value } => unsafe {
+                self.queue(*queue)?
+                    .queue
+                    .CancelRequestsWithTag(*mask, *value)
+            },
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Vec<Completion> {
+        for queue in self.queues.values_mut() {
+            queue.poll(&mut self.completions);
+        }
+        std::mem::take(&mut self.completions)
+    }
+
+    /// Submits every queue, including requests the recording never submitted.
+    fn flush(&mut self) -> Result<(), DirectStorageError> {
+        for queue in self.queues.values_mut() {
+            queue.drain(&mut self.completions);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DirectStorageBackend {
+    fn drop(&mut self) {
+        // DirectStorage may still write into the buffers of pending requests.
+        for queue in self.queues.values_mut() {
+            unsafe { queue.queue.CancelRequestsWithTag(0, 0) };
+            queue.drain(&mut self.completions);
+        }
+    }
+}
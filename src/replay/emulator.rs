@@ -0,0 +1,660 @@
+//! An in-process [`Backend`] that executes requests with plain file reads.
+//!
+//! Requests are executed in order when their queue is submitted, or once the number of
+//! enqueued entries reaches the capacity of the queue, like DirectStorage does automatically.
+//! Statuses complete with the first failure of a request since the previous status, and
+//! signals always complete.  Cancellation only drops requests that were not submitted yet.
+//!
+//! Uncompressed data is copied to the destination, other compression formats have to be
+//! registered with [`Emulator::set_decompressor()`].  Memory sources read zeros, since their
+//! contents are not recorded.  Every executed request is reported as a [`Delivery`] with a
+//! digest of the delivered bytes, so that replays of different builds can be compared.
+//!
+//! With [`Emulator::set_device()`], the emulator runs in virtual time: requests take as long as
+//! a [`DeviceModel`] says, and statuses and signals are only reported by [`Backend::poll()`] once
+//! the shared [`SimulatedClock`] reached their completion.  The same clock can drive a
+//! [`Scheduler`](crate::scheduler::Scheduler), so that streaming code can be evaluated against
+//! frame budgets without real hardware.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::File,
+    io::{self, Read, Seek, SeekFrom},
+    path::PathBuf,
+    rc::Rc,
+    time::Duration,
+};
+
+use super::{
+    simulation::{Device, DeviceModel},
+    Backend, Completion, FileId, Operation, QueueDesc, QueueId, Request, Source,
+    E_DECOMPRESSION_ERROR, E_END_OF_FILE, E_FILE_NOT_OPEN, E_INVALID_DESTINATION_SIZE,
+};
+use crate::scheduler::SimulatedClock;
+
+/// Decompresses a source into a destination of the uncompressed size.
+pub type Decompressor = Box<dyn FnMut(&[u8], &mut [u8]) -> io::Result<()>>;
+
+/// Error of [`Emulator`], for operations that fail synchronously in DirectStorage.
+#[derive(Debug)]
+pub enum EmulatorError {
+    OpenFile { path: PathBuf, error: io::Error },
+    UnknownFile(FileId),
+    UnknownQueue(QueueId),
+    DuplicateQueue(QueueId),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OpenFile { path, error } => {
+                write!(f, "failed to open {}: {error}", path.display())
+            }
+            Self::UnknownFile(file) => write!(f, "file {} is not open", file.0),
+            Self::UnknownQueue(queue) => write!(f, "queue {} does not exist", queue.0),
+            Self::DuplicateQueue(queue) => write!(f, "queue {} already exists", queue.0),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {}
+
+/// A request executed by the [`Emulator`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Delivery {
+    pub queue: QueueId,
+    pub name: Option<String>,
+    /// `0` if the request succeeded.
+    pub hresult: i32,
+    /// Number of bytes written to the destination.
+    pub bytes: u32,
+    /// FNV-1a hash of the delivered bytes.
+    pub digest: u64,
+    /// When the data was delivered in virtual time, if the emulator has a device.
+    pub delivered: Option<Duration>,
+}
+
+enum Entry {
+    Request(Request),
+    Status(u32),
+    Signal(u64),
+}
+
+struct EmulatedQueue {
+    desc: QueueDesc,
+    pending: Vec<Entry>,
+    /// First failure since the previous status.
+    error: Option<i32>,
+    /// When the last request submitted on this queue is delivered in virtual time.
+    delivered: Duration,
+}
+
+struct Simulation {
+    device: Device,
+    clock: Rc<SimulatedClock>,
+    /// Completions that are reported once the clock reaches them.
+    scheduled: Vec<(Duration, Completion)>,
+}
+
+/// Executes replayed operations in-process, see the [module documentation](self).
+pub struct Emulator {
+    map_path: Box<dyn FnMut(&str) -> PathBuf>,
+    decompressors: HashMap<u8, Decompressor>,
+    files: HashMap<FileId, File>,
+    queues: HashMap<QueueId, EmulatedQueue>,
+    completions: Vec<Completion>,
+    deliveries: Vec<Delivery>,
+    simulation: Option<Simulation>,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    /// An emulator that opens recorded paths as they are.
+    pub fn new() -> Self {
+        Self {
+            map_path: Box::new(|path: &str| PathBuf::from(path)),
+            decompressors: HashMap::new(),
+            files: HashMap::new(),
+            queues: HashMap::new(),
+            completions: Vec::new(),
+            deliveries: Vec::new(),
+            simulation: None,
+        }
+    }
+
+    /// Run in virtual time on `clock`, with requests taking as long as `model` says.
+    pub fn set_device(&mut self, model: DeviceModel, clock: Rc<SimulatedClock>) {
+        self.simulation = Some(Simulation {
+            device: Device::new(model),
+            clock,
+            scheduled: Vec::new(),
+        });
+    }
+
+    /// The simulated device, if any.
+    pub fn device(&self) -> Option<&Device> {
+        self.simulation.as_ref().map(|s| &s.device)
+    }
+
+    /// Map recorded paths to the files opened instead, e.g. to replay a log recorded on
+    /// another machine.
+    pub fn set_path_map(&mut self, map_path: impl FnMut(&str) -> PathBuf + 'static) {
+        self.map_path = Box::new(map_path);
+    }
+
+    /// Decompress requests using `compression_format` with `decompressor`.
+    pub fn set_decompressor(
+        &mut self,
+        compression_format: u8,
+        decompressor: impl FnMut(&[u8], &mut [u8]) -> io::Result<()> + 'static,
+    ) {
+        self.decompressors
+            .insert(compression_format, Box::new(decompressor));
+    }
+
+    /// Requests executed so far, in order.
+    pub fn deliveries(&self) -> &[Delivery] {
+        &self.deliveries
+    }
+
+    fn queue(&mut self, queue: QueueId) -> Result<&mut EmulatedQueue, EmulatorError> {
+        self.queues
+            .get_mut(&queue)
+            .ok_or(EmulatorError::UnknownQueue(queue))
+    }
+
+    fn enqueue(&mut self, queue: QueueId, entry: Entry) -> Result<(), EmulatorError> {
+        let emulated = self.queue(queue)?;
+        emulated.pending.push(entry);
+        if emulated.pending.len() >= emulated.desc.capacity.max(1).into() {
+            self.submit(queue)?;
+        }
+        Ok(())
+    }
+
+    fn submit(&mut self, queue: QueueId) -> Result<(), EmulatorError> {
+        let pending = std::mem::take(&mut self.queue(queue)?.pending);
+        for entry in pending {
+            match entry {
+                Entry::Request(request) => {
+                    let (hresult, data) = match self.execute_request(&request) {
+                        Ok(data) => (0, data),
+                        Err(hresult) => (hresult, Vec::new()),
+                    };
+                    let delivered = self.simulation.as_mut().map(|simulation| {
+                        simulation
+                            .device
+                            .schedule(
+                                simulation.clock.elapsed(),
+                                request.source.size(),
+                                request.uncompressed_size,
+                                request.compression_format,
+                            )
+                            .delivered
+                    });
+                    let emulated = self.queue(queue)?;
+                    if hresult != 0 {
+                        emulated.error.get_or_insert(hresult);
+                    }
+                    if let Some(delivered) = delivered {
+                        emulated.delivered = emulated.delivered.max(delivered);
+                    }
+                    self.deliveries.push(Delivery {
+                        queue,
+                        name: request.name,
+                        hresult,
+                        bytes: data.len() as u32,
+                        digest: fnv1a(&data),
+                        delivered,
+                    });
+                }
+                Entry::Status(index) => {
+                    let hresult = self.queue(queue)?.error.take().unwrap_or(0);
+                    self.complete(
+                        queue,
+                        Completion::Status {
+                            queue,
+                            index,
+                            hresult,
+                        },
+                    )?;
+                }
+                Entry::Signal(value) => {
+                    self.complete(queue, Completion::Signal { queue, value })?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Report `completion` once all requests submitted before it on `queue` were delivered.
+    fn complete(&mut self, queue: QueueId, completion: Completion) -> Result<(), EmulatorError> {
+        let delivered = self.queue(queue)?.delivered;
+        match &mut self.simulation {
+            Some(simulation) => {
+                let at = delivered.max(simulation.clock.elapsed());
+                simulation.scheduled.push((at, completion));
+            }
+            None => self.completions.push(completion),
+        }
+        Ok(())
+    }
+
+    /// Read and decompress `request`, returning the data written to its destination.
+    fn execute_request(&mut self, request: &Request) -> Result<Vec<u8>, i32> {
+        let source = match request.source {
+            Source::File { file, offset, size } => {
+                let file = self.files.get(&file).ok_or(E_FILE_NOT_OPEN)?;
+                read_at(file, offset, size as usize).map_err(|_| E_END_OF_FILE)?
+            }
+            Source::Memory { size } => vec![0; size as usize],
+        };
+        if request.destination_size != request.uncompressed_size {
+            return Err(E_INVALID_DESTINATION_SIZE);
+        }
+        let mut destination = vec![0; request.uncompressed_size as usize];
+        match self.decompressors.get_mut(&request.compression_format) {
+            Some(decompress) => {
+                decompress(&source, &mut destination).map_err(|_| E_DECOMPRESSION_ERROR)?
+            }
+            None if request.compression_format == 0 => {
+                if source.len() != destination.len() {
+                    return Err(E_INVALID_DESTINATION_SIZE);
+                }
+                destination.copy_from_slice(&source);
+            }
+            None => return Err(E_DECOMPRESSION_ERROR),
+        }
+        Ok(destination)
+    }
+}
+
+impl Backend for Emulator {
+    type Error = EmulatorError;
+
+    fn execute(&mut self, operation: &Operation) -> Result<(), EmulatorError> {
+        match operation {
+            Operation::OpenFile { file, path } => {
+                let path = (self.map_path)(path);
+                let opened =
+                    File::open(&path).map_err(|error| EmulatorError::OpenFile { path, error })?;
+                self.files.insert(*file, opened);
+            }
+            Operation::CloseFile { file } => {
+                self.files
+                    .remove(file)
+                    .ok_or(EmulatorError::UnknownFile(*file))?;
+            }
+            Operation::CreateQueue { queue, desc } => {
+                if self.queues.contains_key(queue) {
+                    return Err(EmulatorError::DuplicateQueue(*queue));
+                }
+                self.queues.insert(
+                    *queue,
+                    EmulatedQueue {
+                        desc: desc.clone(),
+                        pending: Vec::new(),
+                        error: None,
+                        delivered: Duration::ZERO,
+                    },
+                );
+            }
+            Operation::CloseQueue { queue } => {
+                // Closing drops requests that were never submitted.
+                self.queues
+                    .remove(queue)
+                    .ok_or(EmulatorError::UnknownQueue(*queue))?;
+            }
+            Operation::EnqueueRequest { queue, request } => {
+                self.enqueue(*queue, Entry::Request(request.clone()))?
+            }
+            Operation::EnqueueStatus { queue, index } => {
+                self.enqueue(*queue, Entry::Status(*index))?
+            }
+            Operation::EnqueueSignal { queue, value } => {
+                self.enqueue(*queue, Entry::Signal(*value))?
+            }
+            Operation::Submit { queue } => self.submit(*queue)?,
+            Operation::Cancel { queue, mask, value } => {
+                self.queue(*queue)?.pending.retain(|entry| match entry {
+                    Entry::Request(request) => request.cancellation_tag & mask != *value,
+                    Entry::Status(_) | Entry::Signal(_) => true,
+                })
+            }
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> Vec<Completion> {
+        if let Some(simulation) = &mut self.simulation {
+            let now = simulation.clock.elapsed();
+            let (mut due, scheduled) = std::mem::take(&mut simulation.scheduled)
+                .into_iter()
+                .partition::<Vec<_>, _>(|&(at, _)| at <= now);
+            simulation.scheduled = scheduled;
+            due.sort_by_key(|&(at, _)| at);
+            self.completions
+                .extend(due.into_iter().map(|(_, completion)| completion));
+        }
+        std::mem::take(&mut self.completions)
+    }
+
+    /// Requests are executed on submit, so this only advances virtual time until everything
+    /// completed.
+    fn flush(&mut self) -> Result<(), EmulatorError> {
+        if let Some(simulation) = &self.simulation {
+            if let Some(last) = simulation.scheduled.iter().map(|&(at, _)| at).max() {
+                self.advance_to(last);
+            }
+        }
+        Ok(())
+    }
+
+    fn virtual_time(&self) -> Option<Duration> {
+        Some(self.simulation.as_ref()?.clock.elapsed())
+    }
+
+    fn advance_to(&mut self, at: Duration) {
+        if let Some(simulation) = &self.simulation {
+            let clock = &simulation.clock;
+            clock.advance(at.saturating_sub(clock.elapsed()));
+        }
+    }
+
+    fn next_completion(&self) -> Option<Duration> {
+        let simulation = self.simulation.as_ref()?;
+        simulation.scheduled.iter().map(|&(at, _)| at).min()
+    }
+}
+
+fn read_at(mut file: &File, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; size];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// 64-bit FNV-1a.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xCBF29CE484222325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001B3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{ops::Deref, path::Path, time::Duration};
+
+    use super::*;
+    use crate::{
+        priority::Priority,
+        replay::{
+            replay, simulation::ThroughputCurve, tests::session, Event, LogReader, Pacing,
+            SourceType,
+        },
+        scheduler::{Deadline, Job, Scheduler, SchedulerPolicy},
+    };
+
+    /// A file in the temporary directory, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl Deref for TempFile {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn temp_file(name: &str, contents: &[u8]) -> TempFile {
+        let path = std::env::temp_dir().join(format!(
+            "direct-storage-emulator-{}-{name}",
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        TempFile(path)
+    }
+
+    #[test]
+    fn test_replay_session() {
+        let file = temp_file("session", b"0123456789abcdef");
+        let path = file.to_path_buf();
+        let mut emulator = Emulator::new();
+        emulator.set_path_map(move |recorded| {
+            assert_eq!(recorded, "assets/a.bin");
+            path.clone()
+        });
+
+        let log = session();
+        let reader = LogReader::new(&log[..]).unwrap();
+        let report = replay(reader, &mut emulator, Pacing::AsFastAsPossible).unwrap();
+        assert_eq!(report.events, 9);
+        let completions: Vec<_> = report.completions.iter().map(|(_, c)| *c).collect();
+        // The signal is dropped with the queue, since it was never submitted.
+        assert_eq!(
+            completions,
+            [Completion::Status {
+                queue: QueueId(0),
+                index: 0,
+                hresult: 0
+            }]
+        );
+        assert_eq!(emulator.deliveries().len(), 1);
+        assert_eq!(emulator.deliveries()[0].digest, fnv1a(b"456789ab"));
+    }
+
+    #[test]
+    fn test_replay_in_virtual_time() {
+        let file = temp_file("virtual", b"0123456789abcdef");
+        let path = file.to_path_buf();
+        let clock = Rc::new(SimulatedClock::new());
+        let mut emulator = Emulator::new();
+        emulator.set_path_map(move |_| path.clone());
+        emulator.set_device(
+            DeviceModel {
+                queue_depth: 1,
+                latency: Duration::from_micros(100),
+                throughput: ThroughputCurve::constant(1e6),
+                decompression: Vec::new(),
+            },
+            clock.clone(),
+        );
+
+        let log = session();
+        let reader = LogReader::new(&log[..]).unwrap();
+        let report = replay(reader, &mut emulator, Pacing::Recorded).unwrap();
+        // Submitted at 3.5 ms, then 100 µs of latency and 8 µs of transfer.
+        let delivered = Duration::from_micros(3608);
+        assert_eq!(report.completions.len(), 1);
+        assert_eq!(report.completions[0].0, delivered);
+        assert_eq!(report.elapsed, delivered);
+        assert_eq!(clock.elapsed(), delivered);
+        assert_eq!(emulator.deliveries()[0].delivered, Some(delivered));
+    }
+
+    #[test]
+    fn test_frame_budget() {
+        let clock = Rc::new(SimulatedClock::new());
+        let policy = SchedulerPolicy {
+            bytes_per_frame: 4 << 20,
+            ..Default::default()
+        };
+        let mut scheduler = Scheduler::new(policy, clock.clone());
+        let mut emulator = Emulator::new();
+        emulator.set_device(DeviceModel::nvme(), clock.clone());
+        let queue = QueueId(0);
+        emulator
+            .execute(&Operation::CreateQueue {
+                queue,
+                desc: QueueDesc {
+                    source_type: SourceType::Memory,
+                    capacity: 1024,
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+
+        // 16 MiB in 256 KiB jobs, for at most 4 MiB per frame.
+        for _ in 0..64 {
+            scheduler.submit(Job {
+                size: 256 << 10,
+                deadline: Deadline::Frame(10),
+                priority: Priority::Normal,
+                cancellation_tag: 0,
+            });
+        }
+        let frame_time = scheduler.policy().frame_time;
+        let mut completed = 0;
+        for frame in 0..8u32 {
+            let plan = scheduler.schedule();
+            for (index, dispatch) in plan.dispatched.iter().enumerate() {
+                let size = dispatch.size as u32;
+                let request = Request {
+                    name: None,
+                    source: Source::Memory { size },
+                    compression_format: 0,
+                    destination_type: 0,
+                    destination_size: size,
+                    uncompressed_size: size,
+                    cancellation_tag: 0,
+                };
+                emulator
+                    .execute(&Operation::EnqueueRequest { queue, request })
+                    .unwrap();
+                emulator
+                    .execute(&Operation::EnqueueStatus {
+                        queue,
+                        index: index as u32,
+                    })
+                    .unwrap();
+            }
+            emulator.execute(&Operation::Submit { queue }).unwrap();
+            emulator.advance_to(frame_time * (frame + 1));
+            // 4 MiB take about 1 ms at ~4 GB/s, so every frame's loads finish within the frame.
+            let completions = emulator.poll();
+            assert_eq!(completions.len(), plan.dispatched.len());
+            for completion in completions {
+                let Completion::Status { index, hresult, .. } = completion else {
+                    unreachable!();
+                };
+                assert_eq!(hresult, 0);
+                assert!(scheduler.complete(plan.dispatched[index as usize].id));
+                completed += 1;
+            }
+        }
+        assert_eq!(completed, 64);
+        let device = emulator.device().unwrap();
+        assert_eq!(device.bytes_read(), 16 << 20);
+        assert!(device.busy() < frame_time / 4);
+    }
+
+    #[test]
+    fn test_errors_and_cancellation() {
+        let path = temp_file("errors", &[7; 16]);
+        let mut emulator = Emulator::new();
+        emulator.set_decompressor(1, |source, destination| {
+            for (chunk, &byte) in destination.chunks_mut(2).zip(source) {
+                chunk.fill(byte);
+            }
+            Ok(())
+        });
+
+        let file = FileId(3);
+        let queue = QueueId(0);
+        let request = |offset, size, compression_format, tag| Request {
+            name: None,
+            source: Source::File { file, offset, size },
+            compression_format,
+            destination_type: 0,
+            destination_size: size * (1 + compression_format as u32),
+            uncompressed_size: size * (1 + compression_format as u32),
+            cancellation_tag: tag,
+        };
+        let mut recorder =
+            crate::replay::Recorder::with_clock(Vec::new(), SimulatedClock::new()).unwrap();
+        let operations = [
+            Operation::OpenFile {
+                file,
+                path: path.to_str().unwrap().into(),
+            },
+            Operation::CreateQueue {
+                queue,
+                desc: QueueDesc {
+                    capacity: 64,
+                    ..Default::default()
+                },
+            },
+            Operation::EnqueueRequest {
+                queue,
+                request: request(12, 8, 0, 0),
+            },
+            Operation::EnqueueRequest {
+                queue,
+                request: request(0, 4, 1, 0),
+            },
+            Operation::EnqueueStatus { queue, index: 5 },
+            Operation::EnqueueRequest {
+                queue,
+                request: request(0, 4, 2, 1),
+            },
+            Operation::EnqueueStatus { queue, index: 6 },
+            Operation::EnqueueRequest {
+                queue,
+                request: request(0, 4, 2, 2),
+            },
+            Operation::EnqueueStatus { queue, index: 7 },
+            Operation::Cancel {
+                queue,
+                mask: 2,
+                value: 2,
+            },
+            Operation::Submit { queue },
+        ];
+        for operation in operations {
+            recorder.record(operation).unwrap();
+        }
+        let log = recorder.finish().unwrap();
+        let events = LogReader::new(&log[..]).unwrap();
+        let report = replay(events, &mut emulator, Pacing::Recorded).unwrap();
+
+        let hresults: Vec<_> = report
+            .completions
+            .iter()
+            .map(|(_, c)| match c {
+                Completion::Status { hresult, .. } => *hresult,
+                Completion::Signal { .. } => unreachable!(),
+            })
+            .collect();
+        assert_eq!(hresults, [E_END_OF_FILE, E_DECOMPRESSION_ERROR, 0]);
+        let deliveries = emulator.deliveries();
+        assert_eq!(deliveries.len(), 3);
+        assert_eq!(
+            (deliveries[1].bytes, deliveries[1].digest),
+            (8, fnv1a(&[7; 8]))
+        );
+
+        let unknown = Event {
+            at: Duration::ZERO,
+            operation: Operation::Submit { queue: QueueId(9) },
+        };
+        assert!(matches!(
+            replay([Ok(unknown)], &mut emulator, Pacing::AsFastAsPossible),
+            Err(crate::replay::ReplayError::Backend {
+                index: 0,
+                error: EmulatorError::UnknownQueue(QueueId(9))
+            })
+        ));
+    }
+}
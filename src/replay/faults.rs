@@ -0,0 +1,987 @@
+//! A [`Backend`] wrapper injecting failures for testing error handling.
+//!
+//! [`FaultInjector`] forwards operations to another backend, usually an
+//! [`Emulator`](super::emulator::Emulator), and fails requests according to its
+//! [`FaultRule`]s: the Nth request, or every request with some probability drawn from a seeded
+//! generator, so that runs are reproducible.  A failed request is not forwarded, and the next
+//! status enqueued on its queue completes with the injected `HRESULT`.  An injected
+//! [`Fault::QueueClosed`] fails every later request and status of the queue as well.
+//!
+//! Like DirectStorage, the injector keeps an [`ErrorRecord`] per queue with the first injected
+//! failure and signals the queue's error event when it happens.  On Windows, the record can be
+//! converted to a `DSTORAGE_ERROR_RECORD` and the event is a real event handle.
+//!
+//! Code using the DirectStorage interfaces directly can be tested with a `QueueFaultInjector`
+//! instead, which applies the same rules to the `IDStorageQueue1` and `IDStorageStatusArray` it
+//! wraps.  Its queues don't forward failed requests either, report the injected `HRESULT` through
+//! the wrapped status arrays, fill `RetrieveErrorRecord()` and signal `GetErrorEvent()`.
+
+#[cfg(windows)]
+use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+#[cfg(windows)]
+use windows::Win32::{
+    Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0},
+    Graphics::Direct3D12::ID3D12Fence,
+    System::Threading::{CreateEventW, SetEvent, WaitForSingleObject},
+};
+#[cfg(windows)]
+use windows_core::{implement, Interface, Ref, HRESULT, PCWSTR};
+
+use super::{
+    Backend, Completion, FileId, Operation, QueueId, Request, Source, E_DECOMPRESSION_ERROR,
+    E_END_OF_FILE, E_IO_TIMEOUT, E_QUEUE_CLOSED,
+};
+#[cfg(windows)]
+use crate::{
+    IDStorageFile, IDStorageQueue, IDStorageQueue1, IDStorageQueue1_Impl, IDStorageQueue_Impl,
+    IDStorageStatusArray, IDStorageStatusArray_Impl, DSTORAGE_COMMAND_TYPE_REQUEST,
+    DSTORAGE_COMPRESSION_FORMAT, DSTORAGE_ERROR_RECORD, DSTORAGE_QUEUE_INFO, DSTORAGE_REQUEST,
+    DSTORAGE_REQUEST_DESTINATION_TYPE, DSTORAGE_REQUEST_OPTIONS, DSTORAGE_REQUEST_SOURCE_FILE,
+    DSTORAGE_REQUEST_SOURCE_MEMORY, DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE, DSTORAGE_SOURCE_MEMORY,
+};
+
+/// A failure that can be injected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Fault {
+    IoTimeout,
+    EndOfFile,
+    DecompressionError,
+    /// Fails the request and closes its queue.
+    QueueClosed,
+}
+
+impl Fault {
+    pub fn hresult(self) -> i32 {
+        match self {
+            Self::IoTimeout => E_IO_TIMEOUT,
+            Self::EndOfFile => E_END_OF_FILE,
+            Self::DecompressionError => E_DECOMPRESSION_ERROR,
+            Self::QueueClosed => E_QUEUE_CLOSED,
+        }
+    }
+}
+
+/// When a [`FaultRule`] fires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    /// On the Nth request matched by the rule, counting from 1.
+    Nth(u64),
+    /// On every request matched by the rule with this probability.
+    Probability(f64),
+}
+
+/// Injects `fault` into requests that match `queue` when `trigger` fires.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaultRule {
+    pub trigger: Trigger,
+    pub fault: Fault,
+    /// Only match requests on this queue, or requests of all queues.
+    pub queue: Option<QueueId>,
+}
+
+impl FaultRule {
+    pub fn nth(n: u64, fault: Fault) -> Self {
+        Self {
+            trigger: Trigger::Nth(n),
+            fault,
+            queue: None,
+        }
+    }
+
+    pub fn probability(probability: f64, fault: Fault) -> Self {
+        Self {
+            trigger: Trigger::Probability(probability),
+            fault,
+            queue: None,
+        }
+    }
+
+    pub fn on_queue(self, queue: QueueId) -> Self {
+        Self {
+            queue: Some(queue),
+            ..self
+        }
+    }
+}
+
+/// An injected failure.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InjectedFault {
+    pub queue: QueueId,
+    pub fault: Fault,
+    /// The failed request.
+    pub request: Request,
+    /// Path of the file the request read from.
+    pub filename: Option<String>,
+}
+
+/// Mirrors `DSTORAGE_ERROR_RECORD` for injected failures.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorRecord {
+    /// Number of failures on the queue.
+    pub failure_count: u32,
+    pub first_failure: InjectedFault,
+}
+
+#[cfg(windows)]
+impl ErrorRecord {
+    /// Fill a `DSTORAGE_ERROR_RECORD` as DirectStorage would for the first failure.  The request
+    /// in it references neither a file nor memory, and its name is only stored inline.
+    pub fn to_dstorage(&self) -> DSTORAGE_ERROR_RECORD {
+        let failure = &self.first_failure;
+        let request = &failure.request;
+        let mut options = DSTORAGE_REQUEST_OPTIONS::default();
+        options.set_CompressionFormat(DSTORAGE_COMPRESSION_FORMAT(request.compression_format));
+        options.set_DestinationType(DSTORAGE_REQUEST_DESTINATION_TYPE(request.destination_type));
+        let source = match request.source {
+            Source::File { offset, size, .. } => {
+                options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+                DSTORAGE_SOURCE {
+                    File: std::mem::ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                        Source: Default::default(),
+                        Offset: offset,
+                        Size: size,
+                    }),
+                }
+            }
+            Source::Memory { size } => {
+                options.set_SourceType(DSTORAGE_REQUEST_SOURCE_MEMORY);
+                DSTORAGE_SOURCE {
+                    Memory: DSTORAGE_SOURCE_MEMORY {
+                        Source: std::ptr::null(),
+                        Size: size,
+                    },
+                }
+            }
+        };
+
+        let mut record = DSTORAGE_ERROR_RECORD {
+            FailureCount: self.failure_count,
+            ..Default::default()
+        };
+        record.FirstFailure.HResult = HRESULT(failure.fault.hresult());
+        record.FirstFailure.CommandType = DSTORAGE_COMMAND_TYPE_REQUEST;
+        let parameters = unsafe { &mut record.FirstFailure.Anonymous.Request };
+        // Both names are truncated to leave room for the null terminator.
+        let filename = failure.filename.as_deref().unwrap_or("");
+        let capacity = parameters.Filename.len() - 1;
+        for (dst, src) in parameters.Filename[..capacity]
+            .iter_mut()
+            .zip(filename.encode_utf16())
+        {
+            *dst = src;
+        }
+        let name = request.name.as_deref().unwrap_or("");
+        let capacity = parameters.RequestName.len() - 1;
+        for (dst, &src) in parameters.RequestName[..capacity]
+            .iter_mut()
+            .zip(name.as_bytes())
+        {
+            *dst = src as i8;
+        }
+        parameters.Request = DSTORAGE_REQUEST {
+            Options: options,
+            Source: source,
+            UncompressedSize: request.uncompressed_size,
+            CancellationTag: request.cancellation_tag,
+            ..Default::default()
+        };
+        record
+    }
+}
+
+/// The SplitMix64 generator, which is small and good enough for deciding about faults.
+#[derive(Clone, Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniformly distributed in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// A manual-reset event, set on the first failure of a queue.
+#[cfg(windows)]
+struct ErrorEvent(HANDLE);
+
+// SAFETY: Event handles can be used from any thread.
+#[cfg(windows)]
+unsafe impl Send for ErrorEvent {}
+
+#[cfg(windows)]
+impl Drop for ErrorEvent {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.0) };
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    closed: bool,
+    /// First injected failure since the previous status.
+    error: Option<i32>,
+    record: Option<ErrorRecord>,
+    #[cfg(windows)]
+    event: Option<ErrorEvent>,
+}
+
+/// The rules, and the failures they injected per queue.
+struct Faults {
+    rng: SplitMix64,
+    /// Rules with the number of requests they matched.
+    rules: Vec<(FaultRule, u64)>,
+    /// Paths of open files, for [`InjectedFault::filename`].
+    files: HashMap<FileId, String>,
+    queues: HashMap<QueueId, QueueState>,
+    injected: Vec<InjectedFault>,
+}
+
+impl Faults {
+    fn new(seed: u64) -> Self {
+        Self {
+            rng: SplitMix64(seed),
+            rules: Vec::new(),
+            files: HashMap::new(),
+            queues: HashMap::new(),
+            injected: Vec::new(),
+        }
+    }
+
+    fn error_record(&self, queue: QueueId) -> Option<&ErrorRecord> {
+        self.queues.get(&queue)?.record.as_ref()
+    }
+
+    /// Checks the event once it was created, which is set exactly when the record is.
+    fn error_signaled(&self, queue: QueueId) -> bool {
+        let Some(state) = self.queues.get(&queue) else {
+            return false;
+        };
+        #[cfg(windows)]
+        if let Some(event) = &state.event {
+            return unsafe { WaitForSingleObject(event.0, 0) } == WAIT_OBJECT_0;
+        }
+        state.record.is_some()
+    }
+
+    #[cfg(windows)]
+    fn error_event(&mut self, queue: QueueId) -> windows_core::Result<HANDLE> {
+        let state = self.queues.entry(queue).or_default();
+        if state.event.is_none() {
+            let event = ErrorEvent(unsafe { CreateEventW(None, true, false, PCWSTR::null()) }?);
+            if state.record.is_some() {
+                unsafe { SetEvent(event.0) }?;
+            }
+            state.event = Some(event);
+        }
+        Ok(state.event.as_ref().unwrap().0)
+    }
+
+    /// Decide whether the next request on `queue` fails.
+    fn fault(&mut self, queue: QueueId) -> Option<Fault> {
+        if self.queues.get(&queue).is_some_and(|state| state.closed) {
+            return Some(Fault::QueueClosed);
+        }
+        let mut fault = None;
+        for (rule, matched) in &mut self.rules {
+            if rule.queue.is_some_and(|q| q != queue) {
+                continue;
+            }
+            *matched += 1;
+            // Every probabilistic rule draws for every request it matches, so that adding a
+            // rule doesn't change the decisions of the others.
+            let fires = match rule.trigger {
+                Trigger::Nth(n) => *matched == n,
+                Trigger::Probability(p) => self.rng.next_f64() < p,
+            };
+            if fires && fault.is_none() {
+                fault = Some(rule.fault);
+            }
+        }
+        fault
+    }
+
+    fn inject(&mut self, queue: QueueId, request: &Request, fault: Fault) {
+        let filename = match request.source {
+            Source::File { file, .. } => self.files.get(&file).cloned(),
+            Source::Memory { .. } => None,
+        };
+        let injected = InjectedFault {
+            queue,
+            fault,
+            request: request.clone(),
+            filename,
+        };
+        let state = self.queues.entry(queue).or_default();
+        state.closed |= fault == Fault::QueueClosed;
+        state.error.get_or_insert(fault.hresult());
+        match &mut state.record {
+            Some(record) => record.failure_count += 1,
+            None => {
+                state.record = Some(ErrorRecord {
+                    failure_count: 1,
+                    first_failure: injected.clone(),
+                });
+                #[cfg(windows)]
+                if let Some(event) = &state.event {
+                    let _ = unsafe { SetEvent(event.0) };
+                }
+            }
+        }
+        self.injected.push(injected);
+    }
+
+    /// The `HRESULT` a status enqueued on `queue` now reports instead of its own, if any.
+    fn enqueue_status(&mut self, queue: QueueId) -> Option<i32> {
+        let state = self.queues.entry(queue).or_default();
+        if state.closed {
+            Some(E_QUEUE_CLOSED)
+        } else {
+            state.error.take()
+        }
+    }
+}
+
+/// Injects failures into operations forwarded to `B`, see the [module documentation](self).
+pub struct FaultInjector<B> {
+    inner: B,
+    faults: Faults,
+    /// Statuses forwarded to the inner backend per queue, with the `HRESULT` to report instead.
+    statuses: HashMap<QueueId, VecDeque<(u32, Option<i32>)>>,
+}
+
+impl<B: Backend> FaultInjector<B> {
+    /// Wrap `inner`, deciding probabilistic faults with a generator seeded by `seed`.
+    pub fn new(inner: B, seed: u64) -> Self {
+        Self {
+            inner,
+            faults: Faults::new(seed),
+            statuses: HashMap::new(),
+        }
+    }
+
+    /// Add a rule.  Rules are checked in the order they were added, and the first one that fires
+    /// decides the fault.
+    pub fn add_rule(&mut self, rule: FaultRule) {
+        self.faults.rules.push((rule, 0));
+    }
+
+    pub fn inner(&self) -> &B {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut B {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    /// Failures injected so far, in order.
+    pub fn injected(&self) -> &[InjectedFault] {
+        &self.faults.injected
+    }
+
+    /// The error record of `queue`, once a failure was injected into it.
+    pub fn error_record(&self, queue: QueueId) -> Option<&ErrorRecord> {
+        self.faults.error_record(queue)
+    }
+
+    /// Whether the error event of `queue` was signaled.
+    pub fn error_signaled(&self, queue: QueueId) -> bool {
+        self.faults.error_signaled(queue)
+    }
+
+    /// The manual-reset event signaled on the first failure of `queue`, like
+    /// `IDStorageQueue::GetErrorEvent()`.  It is owned by the injector.
+    #[cfg(windows)]
+    pub fn error_event(&mut self, queue: QueueId) -> windows_core::Result<HANDLE> {
+        self.faults.error_event(queue)
+    }
+}
+
+impl<B: Backend> Backend for FaultInjector<B> {
+    type Error = B::Error;
+
+    fn execute(&mut self, operation: &Operation) -> Result<(), B::Error> {
+        match operation {
+            Operation::OpenFile { file, path } => {
+                self.faults.files.insert(*file, path.clone());
+            }
+            Operation::CloseFile { file } => {
+                self.faults.files.remove(file);
+            }
+            Operation::EnqueueRequest { queue, request } => {
+                if let Some(fault) = self.faults.fault(*queue) {
+                    self.faults.inject(*queue, request, fault);
+                    return Ok(());
+                }
+            }
+            Operation::EnqueueStatus { queue, index } => {
+                let error = self.faults.enqueue_status(*queue);
+                self.statuses
+                    .entry(*queue)
+                    .or_default()
+                    .push_back((*index, error));
+            }
+            _ => {}
+        }
+        self.inner.execute(operation)
+    }
+
+    /// Injected failures take precedence over failures reported by the inner backend.
+    fn poll(&mut self) -> Vec<Completion> {
+        let mut completions = self.inner.poll();
+        for completion in &mut completions {
+            if let Completion::Status {
+                queue,
+                index,
+                hresult,
+            } = completion
+            {
+                let Some(statuses) = self.statuses.get_mut(queue) else {
+                    continue;
+                };
+                if let Some(position) = statuses.iter().position(|&(i, _)| i == *index) {
+                    if let Some(error) = statuses.remove(position).unwrap().1 {
+                        *hresult = error;
+                    }
+                }
+            }
+        }
+        completions
+    }
+
+    fn flush(&mut self) -> Result<(), B::Error> {
+        self.inner.flush()
+    }
+
+    fn virtual_time(&self) -> Option<Duration> {
+        self.inner.virtual_time()
+    }
+
+    fn advance_to(&mut self, at: Duration) {
+        self.inner.advance_to(at);
+    }
+
+    fn next_completion(&self) -> Option<Duration> {
+        self.inner.next_completion()
+    }
+}
+
+/// State shared by a [`QueueFaultInjector`] and the queues it wrapped.
+#[cfg(windows)]
+struct Shared {
+    faults: Faults,
+    /// Ids of the files requests read from, keyed by interface pointer.
+    files: HashMap<usize, FileId>,
+    next_queue: u32,
+}
+
+#[cfg(windows)]
+impl Shared {
+    fn file_id(&mut self, file: &IDStorageFile) -> FileId {
+        let next = FileId(self.files.len() as u32);
+        *self.files.entry(file.as_raw() as usize).or_insert(next)
+    }
+}
+
+/// Injects failures into DirectStorage queues by [`FaultRule`]s, see the
+/// [module documentation](self).
+///
+/// Status arrays must be wrapped with [`QueueFaultInjector::wrap_status_array()`] to report
+/// injected failures; statuses written to other arrays only report those of the wrapped queue.
+#[cfg(windows)]
+#[derive(Clone)]
+pub struct QueueFaultInjector(Arc<Mutex<Shared>>);
+
+#[cfg(windows)]
+impl QueueFaultInjector {
+    /// Decide probabilistic faults with a generator seeded by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(Shared {
+            faults: Faults::new(seed),
+            files: HashMap::new(),
+            next_queue: 0,
+        })))
+    }
+
+    /// Add a rule, see [`FaultInjector::add_rule()`].
+    pub fn add_rule(&self, rule: FaultRule) {
+        self.0.lock().unwrap().faults.rules.push((rule, 0));
+    }
+
+    /// Name `file` in the error records of requests reading from it.
+    pub fn name_file(&self, file: &IDStorageFile, path: &str) {
+        let mut shared = self.0.lock().unwrap();
+        let id = shared.file_id(file);
+        shared.faults.files.insert(id, path.to_owned());
+    }
+
+    /// Wrap `queue`, returning the id rules refer to it by.
+    pub fn wrap_queue(&self, queue: IDStorageQueue1) -> (QueueId, IDStorageQueue1) {
+        let mut shared = self.0.lock().unwrap();
+        let id = QueueId(shared.next_queue);
+        shared.next_queue += 1;
+        let queue = FaultyQueue {
+            inner: queue,
+            id,
+            shared: self.0.clone(),
+        };
+        (id, queue.into())
+    }
+
+    pub fn wrap_status_array(&self, status_array: IDStorageStatusArray) -> IDStorageStatusArray {
+        FaultyStatusArray {
+            inner: status_array,
+            errors: Mutex::default(),
+        }
+        .into()
+    }
+
+    /// Failures injected so far, in order.
+    pub fn injected(&self) -> Vec<InjectedFault> {
+        self.0.lock().unwrap().faults.injected.clone()
+    }
+
+    /// The error record of `queue`, once a failure was injected into it.
+    pub fn error_record(&self, queue: QueueId) -> Option<ErrorRecord> {
+        self.0.lock().unwrap().faults.error_record(queue).cloned()
+    }
+
+    /// Whether the error event of `queue` was signaled.
+    pub fn error_signaled(&self, queue: QueueId) -> bool {
+        self.0.lock().unwrap().faults.error_signaled(queue)
+    }
+}
+
+/// A queue created by [`QueueFaultInjector::wrap_queue()`].
+#[cfg(windows)]
+#[implement(IDStorageQueue, IDStorageQueue1)]
+struct FaultyQueue {
+    inner: IDStorageQueue1,
+    id: QueueId,
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[cfg(windows)]
+impl IDStorageQueue_Impl for FaultyQueue_Impl {
+    fn EnqueueRequest(&self, request: *const DSTORAGE_REQUEST) {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(fault) = shared.faults.fault(self.id) {
+            let request = unsafe {
+                Request::from_dstorage(&*request, |file| file.map(|file| shared.file_id(file)))
+            };
+            // Requests without a file are invalid, DirectStorage fails those on its own.
+            if let Some(request) = request {
+                shared.faults.inject(self.id, &request, fault);
+                return;
+            }
+        }
+        drop(shared);
+        unsafe { self.inner.EnqueueRequest(request) };
+    }
+
+    fn EnqueueStatus(&self, status_array: Ref<IDStorageStatusArray>, index: u32) {
+        let error = self.shared.lock().unwrap().faults.enqueue_status(self.id);
+        match status_array
+            .as_ref()
+            .map(|status_array| status_array.cast_object_ref::<FaultyStatusArray>())
+        {
+            Some(Ok(faulty)) => {
+                faulty.errors.lock().unwrap().insert(index, error);
+                unsafe { self.inner.EnqueueStatus(&faulty.inner, index) };
+            }
+            _ => unsafe { self.inner.EnqueueStatus(status_array.as_ref(), index) },
+        }
+    }
+
+    fn EnqueueSignal(&self, fence: Ref<ID3D12Fence>, value: u64) {
+        unsafe { self.inner.EnqueueSignal(fence.as_ref(), value) };
+    }
+
+    fn Submit(&self) {
+        unsafe { self.inner.Submit() };
+    }
+
+    fn CancelRequestsWithTag(&self, mask: u64, value: u64) {
+        unsafe { self.inner.CancelRequestsWithTag(mask, value) };
+    }
+
+    fn Close(&self) {
+        unsafe { self.inner.Close() };
+    }
+
+    fn GetErrorEvent(&self) -> HANDLE {
+        let mut shared = self.shared.lock().unwrap();
+        shared.faults.error_event(self.id).unwrap_or_default()
+    }
+
+    /// Injected failures are reported instead of failures of the wrapped queue.
+    fn RetrieveErrorRecord(&self, record: *mut DSTORAGE_ERROR_RECORD) {
+        let injected = self
+            .shared
+            .lock()
+            .unwrap()
+            .faults
+            .error_record(self.id)
+            .map(ErrorRecord::to_dstorage);
+        let record = unsafe { &mut *record };
+        *record = injected.unwrap_or_else(|| unsafe { self.inner.RetrieveErrorRecord() });
+    }
+
+    fn Query(&self, info: *mut DSTORAGE_QUEUE_INFO) {
+        unsafe { *info = self.inner.Query() };
+    }
+}
+
+#[cfg(windows)]
+impl IDStorageQueue1_Impl for FaultyQueue_Impl {
+    fn EnqueueSetEvent(&self, handle: HANDLE) {
+        unsafe { self.inner.EnqueueSetEvent(handle) };
+    }
+}
+
+/// A status array created by [`QueueFaultInjector::wrap_status_array()`].
+#[cfg(windows)]
+#[implement(IDStorageStatusArray)]
+struct FaultyStatusArray {
+    inner: IDStorageStatusArray,
+    /// Injected `HRESULT` of every entry a wrapped queue enqueued a status for.
+    errors: Mutex<HashMap<u32, Option<i32>>>,
+}
+
+#[cfg(windows)]
+impl IDStorageStatusArray_Impl for FaultyStatusArray_Impl {
+    fn IsComplete(&self, index: u32) -> bool {
+        unsafe { self.inner.IsComplete(index) }
+    }
+
+    /// Injected failures take precedence over failures reported by the wrapped status array.
+    fn GetHResult(&self, index: u32) -> windows_core::Result<()> {
+        if unsafe { self.inner.IsComplete(index) } {
+            if let Some(&Some(error)) = self.errors.lock().unwrap().get(&index) {
+                return HRESULT(error).ok();
+            }
+        }
+        unsafe { self.inner.GetHResult(index) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::replay::{emulator::Emulator, QueueDesc};
+
+    fn request(name: &str) -> Request {
+        Request {
+            name: Some(name.into()),
+            source: Source::Memory { size: 4 },
+            compression_format: 0,
+            destination_type: 0,
+            destination_size: 4,
+            uncompressed_size: 4,
+            cancellation_tag: 0,
+        }
+    }
+
+    /// Run `requests` requests with a status after each on a fresh queue, returning the
+    /// `HRESULT` of every status.
+    fn run(injector: &mut FaultInjector<Emulator>, queue: QueueId, requests: u32) -> Vec<i32> {
+        injector
+            .execute(&Operation::CreateQueue {
+                queue,
+                desc: QueueDesc {
+                    capacity: 16,
+                    ..Default::default()
+                },
+            })
+            .unwrap();
+        for index in 0..requests {
+            let request = request(&format!("r{index}"));
+            injector
+                .execute(&Operation::EnqueueRequest { queue, request })
+                .unwrap();
+            injector
+                .execute(&Operation::EnqueueStatus { queue, index })
+                .unwrap();
+        }
+        injector.execute(&Operation::Submit { queue }).unwrap();
+        injector
+            .poll()
+            .into_iter()
+            .map(|completion| match completion {
+                Completion::Status { hresult, .. } => hresult,
+                Completion::Signal { .. } => unreachable!(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nth() {
+        let mut injector = FaultInjector::new(Emulator::new(), 0);
+        injector.add_rule(FaultRule::nth(2, Fault::IoTimeout));
+        injector.add_rule(FaultRule::nth(2, Fault::EndOfFile).on_queue(QueueId(1)));
+        assert_eq!(run(&mut injector, QueueId(0), 3), [0, E_IO_TIMEOUT, 0]);
+        assert!(!injector.error_signaled(QueueId(1)));
+        // The first rule already fired, the second one counts requests of queue 1 only.
+        assert_eq!(run(&mut injector, QueueId(1), 3), [0, E_END_OF_FILE, 0]);
+        assert_eq!(injector.inner().deliveries().len(), 4);
+
+        let record = injector.error_record(QueueId(0)).unwrap();
+        assert_eq!(record.failure_count, 1);
+        assert_eq!(record.first_failure.request.name.as_deref(), Some("r1"));
+        assert!(injector.error_signaled(QueueId(1)));
+    }
+
+    #[test]
+    fn test_probability() {
+        let hresults = |seed| {
+            let mut injector = FaultInjector::new(Emulator::new(), seed);
+            injector.add_rule(FaultRule::probability(0.25, Fault::DecompressionError));
+            run(&mut injector, QueueId(0), 400)
+        };
+        let first = hresults(7);
+        assert_eq!(first, hresults(7));
+        assert_ne!(first, hresults(8));
+        let failures = first.iter().filter(|&&h| h != 0).count();
+        assert!((60..140).contains(&failures), "{failures} failures");
+    }
+
+    #[test]
+    fn test_queue_closed() {
+        let mut injector = FaultInjector::new(Emulator::new(), 0);
+        injector.add_rule(FaultRule::nth(2, Fault::QueueClosed));
+        injector.add_rule(FaultRule::nth(3, Fault::IoTimeout));
+        assert_eq!(
+            run(&mut injector, QueueId(0), 4),
+            [0, E_QUEUE_CLOSED, E_QUEUE_CLOSED, E_QUEUE_CLOSED]
+        );
+        let record = injector.error_record(QueueId(0)).unwrap();
+        assert_eq!(record.failure_count, 3);
+        assert_eq!(record.first_failure.fault, Fault::QueueClosed);
+        assert_eq!(injector.injected().len(), 3);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_to_dstorage() {
+        let mut injector = FaultInjector::new(Emulator::new(), 0);
+        injector.add_rule(FaultRule::nth(1, Fault::EndOfFile));
+        run(&mut injector, QueueId(0), 1);
+        let record = injector.error_record(QueueId(0)).unwrap().to_dstorage();
+        assert_eq!(record.FailureCount, 1);
+        assert_eq!(record.FirstFailure.HResult.0, E_END_OF_FILE);
+        assert_eq!(
+            record.FirstFailure.CommandType,
+            DSTORAGE_COMMAND_TYPE_REQUEST
+        );
+        let parameters = unsafe { &record.FirstFailure.Anonymous.Request };
+        assert_eq!(parameters.RequestName[..3], [b'r' as i8, b'0' as i8, 0]);
+        assert_eq!(parameters.Filename[0], 0);
+        assert_eq!(unsafe { parameters.Request.Source.Memory.Size }, 4);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn test_error_event() {
+        use windows::Win32::System::Threading::ResetEvent;
+
+        let mut injector = FaultInjector::new(Emulator::new(), 0);
+        injector.add_rule(FaultRule::nth(2, Fault::IoTimeout));
+        let event = injector.error_event(QueueId(0)).unwrap();
+        assert!(!injector.error_signaled(QueueId(0)));
+        run(&mut injector, QueueId(0), 2);
+        assert!(injector.error_signaled(QueueId(0)));
+        assert_eq!(unsafe { WaitForSingleObject(event, 0) }, WAIT_OBJECT_0);
+
+        // The state of the event is reported, not whether a failure was recorded.
+        unsafe { ResetEvent(event) }.unwrap();
+        assert!(!injector.error_signaled(QueueId(0)));
+    }
+
+    #[cfg(windows)]
+    mod queue_fault_injector {
+        use std::{cell::RefCell, rc::Rc};
+
+        use windows::Win32::{
+            Foundation::WAIT_TIMEOUT, Storage::FileSystem::BY_HANDLE_FILE_INFORMATION,
+        };
+
+        use super::*;
+        use crate::{readonly_copy, IDStorageFile_Impl};
+
+        /// Operations forwarded to a [`FakeQueue`].
+        struct Device {
+            /// Offsets of the forwarded requests.
+            offsets: Vec<u64>,
+            /// Statuses enqueued since the last submit.
+            enqueued: Vec<u32>,
+            /// Result of every status array entry, [`None`] while incomplete.
+            statuses: Vec<Option<HRESULT>>,
+        }
+
+        #[implement(IDStorageFile)]
+        struct FakeFile;
+
+        impl IDStorageFile_Impl for FakeFile_Impl {
+            fn Close(&self) {}
+
+            fn GetFileInformation(
+                &self,
+                _info: *mut BY_HANDLE_FILE_INFORMATION,
+            ) -> windows_core::Result<()> {
+                Ok(())
+            }
+        }
+
+        /// Completes every status enqueued on it successfully once submitted.
+        #[implement(IDStorageQueue, IDStorageQueue1)]
+        struct FakeQueue(Rc<RefCell<Device>>);
+
+        impl IDStorageQueue_Impl for FakeQueue_Impl {
+            fn EnqueueRequest(&self, request: *const DSTORAGE_REQUEST) {
+                let request = unsafe { &*request };
+                let offset = unsafe { request.Source.File.Offset };
+                self.0.borrow_mut().offsets.push(offset);
+            }
+
+            fn EnqueueStatus(&self, status_array: Ref<IDStorageStatusArray>, index: u32) {
+                // The wrapped queue only sees the wrapped status array.
+                assert!(status_array
+                    .unwrap()
+                    .cast_object_ref::<FakeStatusArray>()
+                    .is_ok());
+                self.0.borrow_mut().enqueued.push(index);
+            }
+
+            fn EnqueueSignal(&self, _fence: Ref<ID3D12Fence>, _value: u64) {
+                panic!("not used by the test")
+            }
+
+            fn Submit(&self) {
+                let mut device = self.0.borrow_mut();
+                for index in std::mem::take(&mut device.enqueued) {
+                    device.statuses[index as usize] = Some(HRESULT(0));
+                }
+            }
+
+            fn CancelRequestsWithTag(&self, _mask: u64, _value: u64) {
+                panic!("not used by the test")
+            }
+
+            fn Close(&self) {}
+
+            fn GetErrorEvent(&self) -> HANDLE {
+                panic!("errors are injected")
+            }
+
+            fn RetrieveErrorRecord(&self, _record: *mut DSTORAGE_ERROR_RECORD) {
+                panic!("errors are injected")
+            }
+
+            fn Query(&self, _info: *mut DSTORAGE_QUEUE_INFO) {
+                panic!("not used by the test")
+            }
+        }
+
+        impl IDStorageQueue1_Impl for FakeQueue_Impl {
+            fn EnqueueSetEvent(&self, _handle: HANDLE) {
+                panic!("not used by the test")
+            }
+        }
+
+        #[implement(IDStorageStatusArray)]
+        struct FakeStatusArray(Rc<RefCell<Device>>);
+
+        impl IDStorageStatusArray_Impl for FakeStatusArray_Impl {
+            fn IsComplete(&self, index: u32) -> bool {
+                self.0.borrow().statuses[index as usize].is_some()
+            }
+
+            fn GetHResult(&self, index: u32) -> windows_core::Result<()> {
+                self.0.borrow().statuses[index as usize].unwrap().ok()
+            }
+        }
+
+        fn request(file: &IDStorageFile, offset: u64) -> DSTORAGE_REQUEST {
+            let mut request = DSTORAGE_REQUEST {
+                Source: DSTORAGE_SOURCE {
+                    File: std::mem::ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                        Source: unsafe { readonly_copy(file) },
+                        Offset: offset,
+                        Size: 16,
+                    }),
+                },
+                UncompressedSize: 16,
+                ..Default::default()
+            };
+            request.Options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+            request
+        }
+
+        #[test]
+        fn test_wrapped_queue() {
+            let device = Rc::new(RefCell::new(Device {
+                offsets: Vec::new(),
+                enqueued: Vec::new(),
+                statuses: vec![None; 3],
+            }));
+            let injector = QueueFaultInjector::new(0);
+            injector.add_rule(FaultRule::nth(2, Fault::EndOfFile));
+            let (id, queue) = injector.wrap_queue(FakeQueue(device.clone()).into());
+            let status_array = injector.wrap_status_array(FakeStatusArray(device.clone()).into());
+            let file: IDStorageFile = FakeFile.into();
+            injector.name_file(&file, "assets/a.bin");
+
+            let error_event = unsafe { queue.GetErrorEvent() };
+            assert_eq!(unsafe { WaitForSingleObject(error_event, 0) }, WAIT_TIMEOUT);
+            for (index, offset) in [0, 16, 32].into_iter().enumerate() {
+                unsafe {
+                    queue.EnqueueRequest(&request(&file, offset));
+                    queue.EnqueueStatus(&status_array, index as u32);
+                }
+            }
+            // The failed request is not forwarded.
+            assert_eq!(device.borrow().offsets, [0, 32]);
+            assert!(!unsafe { status_array.IsComplete(1) });
+
+            unsafe { queue.Submit() };
+            let hresults: Vec<_> = (0..3)
+                .map(|index| unsafe { status_array.GetHResult(index) }.map_err(|e| e.code()))
+                .collect();
+            assert_eq!(hresults, [Ok(()), Err(HRESULT(E_END_OF_FILE)), Ok(())]);
+
+            assert_eq!(
+                unsafe { WaitForSingleObject(error_event, 0) },
+                WAIT_OBJECT_0
+            );
+            assert!(injector.error_signaled(id));
+            let record = unsafe { queue.RetrieveErrorRecord() };
+            assert_eq!(record.FailureCount, 1);
+            assert_eq!(record.FirstFailure.HResult, HRESULT(E_END_OF_FILE));
+            let parameters = unsafe { &record.FirstFailure.Anonymous.Request };
+            let filename: Vec<u16> = "assets/a.bin\0".encode_utf16().collect();
+            assert_eq!(parameters.Filename[..filename.len()], filename);
+            assert_eq!(unsafe { parameters.Request.Source.File.Offset }, 16);
+            assert_eq!(
+                injector.injected()[0].filename.as_deref(),
+                Some("assets/a.bin")
+            );
+        }
+    }
+}
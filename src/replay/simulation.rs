@@ -0,0 +1,267 @@
+//! A model of a storage device for running the [`Emulator`](super::emulator::Emulator) in
+//! virtual time.
+//!
+//! A [`DeviceModel`] describes how many requests a device works on at once, the latency until a
+//! request starts transferring, the transfer throughput depending on the request size and the
+//! decompression throughput per compression format.  [`Device`] applies it to requests in the
+//! order they are issued and computes when each of them is delivered:
+//!
+//! 1. a request waits for one of [`DeviceModel::queue_depth`] slots,
+//! 2. spends [`DeviceModel::latency`] before its data is ready,
+//! 3. transfers its data over a single channel shared by all requests, and
+//! 4. is decompressed by a decompressor per format, which processes one request at a time.
+//!
+//! All times are [`Duration`]s on the virtual timeline of a
+//! [`SimulatedClock`](crate::scheduler::SimulatedClock), so results are deterministic.
+
+use std::{cmp::Reverse, collections::BinaryHeap, time::Duration};
+
+/// Throughput in bytes per second depending on the request size, interpolated linearly between
+/// points and clamped outside of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThroughputCurve {
+    points: Vec<(u32, f64)>,
+}
+
+impl ThroughputCurve {
+    /// # Panics
+    /// If `points` is empty or a throughput isn't positive.
+    pub fn new(mut points: Vec<(u32, f64)>) -> Self {
+        assert!(!points.is_empty(), "a throughput curve needs a point");
+        assert!(
+            points.iter().all(|&(_, throughput)| throughput > 0.0),
+            "throughput must be positive"
+        );
+        points.sort_by_key(|&(size, _)| size);
+        Self { points }
+    }
+
+    pub fn constant(throughput: f64) -> Self {
+        Self::new(vec![(0, throughput)])
+    }
+
+    pub fn at(&self, size: u32) -> f64 {
+        let i = self.points.partition_point(|&(s, _)| s <= size);
+        if i == 0 {
+            return self.points[0].1;
+        }
+        let (s0, t0) = self.points[i - 1];
+        let Some(&(s1, t1)) = self.points.get(i) else {
+            return t0;
+        };
+        t0 + (t1 - t0) * f64::from(size - s0) / f64::from(s1 - s0)
+    }
+
+    /// Time to transfer `size` bytes in a single request.
+    pub fn transfer_time(&self, size: u32) -> Duration {
+        Duration::from_secs_f64(f64::from(size) / self.at(size))
+    }
+}
+
+/// Performance characteristics of a storage device, see the [module documentation](self).
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceModel {
+    /// Number of requests the device works on concurrently.
+    pub queue_depth: u32,
+    /// Time from issuing a request until its data is ready to be transferred.
+    pub latency: Duration,
+    pub throughput: ThroughputCurve,
+    /// Decompression throughput in uncompressed bytes per second by compression format.
+    /// Formats that aren't listed, including uncompressed data, take no time.
+    pub decompression: Vec<(u8, f64)>,
+}
+
+impl DeviceModel {
+    /// A PCIe 4.0 NVMe SSD with GPU GDeflate decompression.
+    pub fn nvme() -> Self {
+        Self {
+            queue_depth: 32,
+            latency: Duration::from_micros(80),
+            throughput: ThroughputCurve::new(vec![
+                (4 << 10, 0.8e9),
+                (64 << 10, 3.5e9),
+                (1 << 20, 6.5e9),
+            ]),
+            decompression: vec![(1, 12e9)],
+        }
+    }
+
+    /// A SATA SSD with CPU GDeflate decompression.
+    pub fn sata_ssd() -> Self {
+        Self {
+            queue_depth: 32,
+            latency: Duration::from_micros(150),
+            throughput: ThroughputCurve::new(vec![
+                (4 << 10, 0.2e9),
+                (64 << 10, 0.45e9),
+                (1 << 20, 0.55e9),
+            ]),
+            decompression: vec![(1, 2e9)],
+        }
+    }
+
+    fn decompression_throughput(&self, compression_format: u8) -> Option<f64> {
+        self.decompression
+            .iter()
+            .find(|&&(format, _)| format == compression_format)
+            .map(|&(_, throughput)| throughput)
+    }
+}
+
+/// Timeline of a request scheduled on a [`Device`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timing {
+    /// When the request got a slot of the queue depth.
+    pub started: Duration,
+    /// When its data was transferred.
+    pub transferred: Duration,
+    /// When its data was decompressed and delivered.
+    pub delivered: Duration,
+}
+
+/// State of a simulated device, see the [module documentation](self).
+#[derive(Clone, Debug)]
+pub struct Device {
+    model: DeviceModel,
+    /// When each slot of the queue depth becomes free.
+    slots: BinaryHeap<Reverse<Duration>>,
+    channel_free: Duration,
+    decompressors_free: Vec<(u8, Duration)>,
+    bytes_read: u64,
+    busy: Duration,
+}
+
+impl Device {
+    /// # Panics
+    /// If a decompression throughput of `model` isn't positive.
+    pub fn new(model: DeviceModel) -> Self {
+        assert!(
+            model
+                .decompression
+                .iter()
+                .all(|&(_, throughput)| throughput > 0.0),
+            "decompression throughput must be positive"
+        );
+        Self {
+            slots: (0..model.queue_depth.max(1))
+                .map(|_| Reverse(Duration::ZERO))
+                .collect(),
+            model,
+            channel_free: Duration::ZERO,
+            decompressors_free: Vec::new(),
+            bytes_read: 0,
+            busy: Duration::ZERO,
+        }
+    }
+
+    pub fn model(&self) -> &DeviceModel {
+        &self.model
+    }
+
+    /// Total number of bytes transferred.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Total time the transfer channel was busy.
+    pub fn busy(&self) -> Duration {
+        self.busy
+    }
+
+    /// Schedule a request issued at `issued` that reads `size` bytes and decompresses them to
+    /// `uncompressed_size` bytes.
+    pub fn schedule(
+        &mut self,
+        issued: Duration,
+        size: u32,
+        uncompressed_size: u32,
+        compression_format: u8,
+    ) -> Timing {
+        let Reverse(slot_free) = self.slots.pop().unwrap();
+        let started = issued.max(slot_free);
+        let ready = started + self.model.latency;
+        let transfer = self.model.throughput.transfer_time(size);
+        let transferred = ready.max(self.channel_free) + transfer;
+        self.channel_free = transferred;
+        self.slots.push(Reverse(transferred));
+        self.bytes_read += u64::from(size);
+        self.busy += transfer;
+
+        let delivered = match self.model.decompression_throughput(compression_format) {
+            Some(throughput) if compression_format != 0 => {
+                let free = match self
+                    .decompressors_free
+                    .iter_mut()
+                    .find(|(format, _)| *format == compression_format)
+                {
+                    Some((_, free)) => free,
+                    None => {
+                        self.decompressors_free
+                            .push((compression_format, Duration::ZERO));
+                        &mut self.decompressors_free.last_mut().unwrap().1
+                    }
+                };
+                *free = transferred.max(*free)
+                    + Duration::from_secs_f64(f64::from(uncompressed_size) / throughput);
+                *free
+            }
+            _ => transferred,
+        };
+        Timing {
+            started,
+            transferred,
+            delivered,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const US: Duration = Duration::from_micros(1);
+
+    #[test]
+    fn test_curve() {
+        let curve = ThroughputCurve::new(vec![(2000, 4.0), (1000, 2.0)]);
+        assert_eq!(curve.at(0), 2.0);
+        assert_eq!(curve.at(1500), 3.0);
+        assert_eq!(curve.at(5000), 4.0);
+        assert_eq!(curve.transfer_time(2000), Duration::from_secs(500));
+    }
+
+    #[test]
+    fn test_device() {
+        let mut device = Device::new(DeviceModel {
+            queue_depth: 2,
+            latency: 100 * US,
+            // 1 byte per µs.
+            throughput: ThroughputCurve::constant(1e6),
+            decompression: vec![(1, 0.5e6)],
+        });
+        let a = device.schedule(Duration::ZERO, 50, 50, 0);
+        assert_eq!((a.started, a.delivered), (Duration::ZERO, 150 * US));
+        // Latencies overlap, transfers are serialized.
+        let b = device.schedule(Duration::ZERO, 50, 100, 1);
+        assert_eq!(b.transferred, 200 * US);
+        assert_eq!(b.delivered, 400 * US);
+        // Waits for the slot of `a`.
+        let c = device.schedule(10 * US, 10, 10, 0);
+        assert_eq!((c.started, c.transferred), (150 * US, 260 * US));
+        // Gets the slot of `b`, but waits for the decompression of `b`.
+        let d = device.schedule(200 * US, 10, 10, 1);
+        assert_eq!((d.started, d.transferred), (200 * US, 310 * US));
+        assert_eq!(d.delivered, 420 * US);
+        assert_eq!(device.bytes_read(), 120);
+        assert_eq!(device.busy(), 120 * US);
+    }
+
+    #[test]
+    #[should_panic(expected = "decompression throughput must be positive")]
+    fn test_zero_decompression_throughput() {
+        Device::new(DeviceModel {
+            decompression: vec![(1, 0.0)],
+            ..DeviceModel::nvme()
+        });
+    }
+}
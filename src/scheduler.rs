@@ -0,0 +1,539 @@
+//! Deciding each frame which load jobs to enqueue.
+//!
+//! A [`Scheduler`] holds load jobs that have a deadline, a size and a base [`Priority`].  Every
+//! frame, [`Scheduler::schedule()`] picks the most urgent jobs that fit in
+//! [`SchedulerPolicy::bytes_per_frame`] and leaves the rest for later frames.  Jobs are promoted
+//! while they wait and as their deadline approaches, so that they end up on the
+//! [`Priority::High`] or [`Priority::Realtime`] queue in time.
+//!
+//! Jobs that are no longer needed are removed with [`Scheduler::cancel()`], which matches them by
+//! cancellation tag in the same way as `IDStorageQueue::CancelRequestsWithTag()`.  Requests of
+//! dispatched jobs have to be built with [`Dispatch::cancellation_tag`] for that to reach them.
+//!
+//! Time is read from a [`Clock`], so the policy can be tested deterministically with a
+//! [`SimulatedClock`].
+
+use std::{
+    cell::Cell,
+    cmp::Reverse,
+    collections::BTreeMap,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+
+use crate::priority::Priority;
+#[cfg(windows)]
+use crate::IDStorageQueue;
+
+/// Source of the current time for [`Deadline::Instant`].
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+/// Reads [`Instant::now()`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that only moves when [`SimulatedClock::advance()`] is called.
+#[derive(Clone, Debug)]
+pub struct SimulatedClock {
+    start: Instant,
+    elapsed: Cell<Duration>,
+}
+
+impl SimulatedClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            elapsed: Cell::new(Duration::ZERO),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.elapsed.set(self.elapsed.get() + duration);
+    }
+
+    /// Time since the clock was created.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed.get()
+    }
+}
+
+impl Default for SimulatedClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed.get()
+    }
+}
+
+/// Shares a clock, e.g. a [`SimulatedClock`] between a [`Scheduler`] and a simulated device.
+impl<C: Clock + ?Sized> Clock for Rc<C> {
+    fn now(&self) -> Instant {
+        (**self).now()
+    }
+}
+
+/// When the data of a job has to be resident.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deadline {
+    /// Before the given frame, as counted by [`Scheduler::frame()`], is rendered.
+    Frame(u64),
+    Instant(Instant),
+}
+
+/// Identifies a job submitted to a [`Scheduler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JobId(pub u64);
+
+/// A load job to be scheduled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Job {
+    /// Number of bytes read from storage, counted against the bandwidth budget.
+    pub size: u64,
+    pub deadline: Deadline,
+    pub priority: Priority,
+    /// Tag to set on every request of the job, see [`Scheduler::cancel()`].
+    pub cancellation_tag: u64,
+}
+
+/// Tuning of a [`Scheduler`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SchedulerPolicy {
+    /// Bandwidth budget per frame.  The most urgent job is dispatched even if it exceeds the
+    /// budget by itself, and [`Priority::Realtime`] jobs are never held back.
+    pub bytes_per_frame: u64,
+    /// Duration of a frame, used to convert [`Deadline::Instant`] into frames.
+    pub frame_time: Duration,
+    /// Jobs due within this many frames are promoted to at least [`Priority::High`].
+    pub high_within_frames: u64,
+    /// Jobs due within this many frames are promoted to [`Priority::Realtime`].
+    pub realtime_within_frames: u64,
+    /// Jobs are promoted by one priority, up to [`Priority::High`], for every this many frames
+    /// they wait.  `0` disables aging.
+    pub aging_frames: u64,
+    /// Drop jobs whose deadline passed before they were dispatched instead of loading them as
+    /// [`Priority::Realtime`].
+    pub drop_expired: bool,
+}
+
+impl Default for SchedulerPolicy {
+    fn default() -> Self {
+        Self {
+            bytes_per_frame: 16 * 1024 * 1024,
+            frame_time: Duration::from_micros(16_667),
+            high_within_frames: 4,
+            realtime_within_frames: 1,
+            aging_frames: 30,
+            drop_expired: false,
+        }
+    }
+}
+
+/// A job that should be enqueued now.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Dispatch {
+    pub id: JobId,
+    pub size: u64,
+    /// Priority of the queue to enqueue the requests of the job on.
+    pub priority: Priority,
+    pub cancellation_tag: u64,
+}
+
+/// Result of [`Scheduler::schedule()`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FramePlan {
+    pub frame: u64,
+    /// Jobs to enqueue, most urgent first.  They stay in flight until
+    /// [`Scheduler::complete()`] is called.
+    pub dispatched: Vec<Dispatch>,
+    /// Jobs dropped because of [`SchedulerPolicy::drop_expired`].
+    pub expired: Vec<JobId>,
+    /// Bytes of [`FramePlan::dispatched`].
+    pub bytes: u64,
+}
+
+/// Jobs removed by [`Scheduler::cancel()`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Cancellation {
+    pub mask: u64,
+    pub value: u64,
+    /// Jobs that were not dispatched yet.
+    pub pending: Vec<JobId>,
+    /// Dispatched jobs whose requests still have to be cancelled on their queues.
+    pub in_flight: Vec<JobId>,
+}
+
+impl Cancellation {
+    /// Cancel the requests of [`Cancellation::in_flight`] jobs on `queue`.  Has to be called for
+    /// every queue the jobs were enqueued on.
+    ///
+    /// # Safety
+    /// Calls into DirectStorage; see `IDStorageQueue::CancelRequestsWithTag()`.
+    #[cfg(windows)]
+    pub unsafe fn apply(&self, queue: &IDStorageQueue) {
+        if !self.in_flight.is_empty() {
+            unsafe { queue.CancelRequestsWithTag(self.mask, self.value) }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Pending {
+    job: Job,
+    submitted: u64,
+}
+
+/// Schedules load jobs under a per-frame bandwidth budget.
+#[derive(Debug)]
+pub struct Scheduler<C: Clock = SystemClock> {
+    policy: SchedulerPolicy,
+    clock: C,
+    frame: u64,
+    next_id: u64,
+    pending: BTreeMap<JobId, Pending>,
+    in_flight: BTreeMap<JobId, u64>,
+}
+
+impl<C: Clock> Scheduler<C> {
+    pub fn new(policy: SchedulerPolicy, clock: C) -> Self {
+        Self {
+            policy,
+            clock,
+            frame: 0,
+            next_id: 0,
+            pending: BTreeMap::new(),
+            in_flight: BTreeMap::new(),
+        }
+    }
+
+    pub fn policy(&self) -> &SchedulerPolicy {
+        &self.policy
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    /// The frame planned by the next call to [`Scheduler::schedule()`].
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn submit(&mut self, job: Job) -> JobId {
+        let id = JobId(self.next_id);
+        self.next_id += 1;
+        self.pending.insert(
+            id,
+            Pending {
+                job,
+                submitted: self.frame,
+            },
+        );
+        id
+    }
+
+    /// Remove all pending and in-flight jobs whose tag matches `tag & mask == value`.
+    pub fn cancel(&mut self, mask: u64, value: u64) -> Cancellation {
+        let matches = |tag: u64| tag & mask == value;
+        let pending = self
+            .pending
+            .iter()
+            .filter(|(_, p)| matches(p.job.cancellation_tag))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in &pending {
+            self.pending.remove(id);
+        }
+        let in_flight = self
+            .in_flight
+            .iter()
+            .filter(|(_, &tag)| matches(tag))
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        for id in &in_flight {
+            self.in_flight.remove(id);
+        }
+        Cancellation {
+            mask,
+            value,
+            pending,
+            in_flight,
+        }
+    }
+
+    /// Mark a dispatched job as finished.  Returns `false` if it wasn't in flight.
+    pub fn complete(&mut self, id: JobId) -> bool {
+        self.in_flight.remove(&id).is_some()
+    }
+
+    /// Number of frames left until `deadline`, or [`None`] if it has passed.
+    fn frames_left(&self, deadline: Deadline, now: Instant) -> Option<u64> {
+        match deadline {
+            Deadline::Frame(frame) => frame.checked_sub(self.frame),
+            Deadline::Instant(instant) => {
+                let left = instant.checked_duration_since(now)?;
+                let frame_time = self.policy.frame_time.as_nanos().max(1);
+                Some(
+                    (left.as_nanos() / frame_time)
+                        .try_into()
+                        .unwrap_or(u64::MAX),
+                )
+            }
+        }
+    }
+
+    fn effective_priority(&self, pending: &Pending, frames_left: u64) -> Priority {
+        if frames_left <= self.policy.realtime_within_frames {
+            return Priority::Realtime;
+        }
+        let mut priority = pending.job.priority;
+        if let Some(steps) = (self.frame - pending.submitted).checked_div(self.policy.aging_frames)
+        {
+            let aged = (priority as u64)
+                .saturating_add(steps)
+                .min(Priority::High as u64);
+            priority = priority.max(Priority::ALL[aged as usize]);
+        }
+        if frames_left <= self.policy.high_within_frames {
+            priority = priority.max(Priority::High);
+        }
+        priority
+    }
+
+    /// Plan the current frame and advance to the next one.
+    ///
+    /// Jobs are ordered by effective priority, then by deadline and then by submission order,
+    /// and dispatched as long as they fit in the budget.
+    pub fn schedule(&mut self) -> FramePlan {
+        let now = self.clock.now();
+        let mut plan = FramePlan {
+            frame: self.frame,
+            ..Default::default()
+        };
+
+        let mut candidates = Vec::with_capacity(self.pending.len());
+        for (&id, pending) in &self.pending {
+            match self.frames_left(pending.job.deadline, now) {
+                Some(frames_left) => {
+                    let priority = self.effective_priority(pending, frames_left);
+                    candidates.push((Reverse(priority), frames_left, id));
+                }
+                None if self.policy.drop_expired => plan.expired.push(id),
+                None => candidates.push((Reverse(Priority::Realtime), 0, id)),
+            }
+        }
+        for id in &plan.expired {
+            self.pending.remove(id);
+        }
+        candidates.sort_unstable();
+
+        for (Reverse(priority), _, id) in candidates {
+            let job = self.pending[&id].job;
+            let fits = plan.bytes + job.size <= self.policy.bytes_per_frame;
+            if priority == Priority::Realtime || fits || plan.dispatched.is_empty() {
+                self.pending.remove(&id);
+                self.in_flight.insert(id, job.cancellation_tag);
+                plan.bytes += job.size;
+                plan.dispatched.push(Dispatch {
+                    id,
+                    size: job.size,
+                    priority,
+                    cancellation_tag: job.cancellation_tag,
+                });
+            }
+        }
+
+        self.frame += 1;
+        plan
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: Duration = Duration::from_millis(10);
+
+    fn scheduler(bytes_per_frame: u64) -> Scheduler<SimulatedClock> {
+        let policy = SchedulerPolicy {
+            bytes_per_frame,
+            frame_time: FRAME,
+            high_within_frames: 4,
+            realtime_within_frames: 1,
+            aging_frames: 10,
+            drop_expired: false,
+        };
+        Scheduler::new(policy, SimulatedClock::new())
+    }
+
+    fn job(size: u64, frame: u64, priority: Priority) -> Job {
+        Job {
+            size,
+            deadline: Deadline::Frame(frame),
+            priority,
+            cancellation_tag: 0,
+        }
+    }
+
+    fn dispatched(plan: &FramePlan) -> Vec<(JobId, Priority)> {
+        plan.dispatched.iter().map(|d| (d.id, d.priority)).collect()
+    }
+
+    #[test]
+    fn test_budget_and_order() {
+        let mut scheduler = scheduler(100);
+        let low = scheduler.submit(job(60, 100, Priority::Low));
+        let normal = scheduler.submit(job(60, 100, Priority::Normal));
+        let small = scheduler.submit(job(40, 100, Priority::Low));
+        let early = scheduler.submit(job(60, 50, Priority::Normal));
+
+        let plan = scheduler.schedule();
+        assert_eq!(plan.frame, 0);
+        assert_eq!(
+            dispatched(&plan),
+            [(early, Priority::Normal), (small, Priority::Low)]
+        );
+        assert_eq!(plan.bytes, 100);
+        assert_eq!(
+            dispatched(&scheduler.schedule()),
+            [(normal, Priority::Normal)]
+        );
+        assert_eq!(dispatched(&scheduler.schedule()), [(low, Priority::Low)]);
+        assert_eq!(scheduler.pending(), 0);
+        assert_eq!(scheduler.in_flight(), 4);
+        assert!(scheduler.complete(low));
+        assert!(!scheduler.complete(low));
+    }
+
+    #[test]
+    fn test_oversized_job() {
+        let mut scheduler = scheduler(100);
+        let big = scheduler.submit(job(500, 100, Priority::High));
+        let small = scheduler.submit(job(10, 100, Priority::Low));
+        assert_eq!(dispatched(&scheduler.schedule()), [(big, Priority::High)]);
+        assert_eq!(dispatched(&scheduler.schedule()), [(small, Priority::Low)]);
+    }
+
+    #[test]
+    fn test_deadline_promotion() {
+        let mut scheduler = scheduler(0);
+        let blocker = scheduler.submit(job(1, 1000, Priority::High));
+        let id = scheduler.submit(job(10, 6, Priority::Low));
+        assert_eq!(
+            dispatched(&scheduler.schedule()),
+            [(blocker, Priority::High)]
+        );
+
+        // Every frame, a realtime job leaves no room for jobs that aren't realtime themselves.
+        let mut priorities = Vec::new();
+        for _ in 1..6 {
+            scheduler.submit(job(1, scheduler.frame() + 1, Priority::Low));
+            let plan = scheduler.schedule();
+            priorities.push(
+                plan.dispatched
+                    .iter()
+                    .find(|d| d.id == id)
+                    .map(|d| d.priority),
+            );
+        }
+        assert_eq!(
+            priorities,
+            [None, None, None, None, Some(Priority::Realtime)]
+        );
+    }
+
+    #[test]
+    fn test_instant_deadline() {
+        let mut scheduler = scheduler(0);
+        let deadline = scheduler.clock().now() + FRAME * 8;
+        scheduler.submit(job(1, 1000, Priority::Normal));
+        let id = scheduler.submit(Job {
+            size: 1,
+            deadline: Deadline::Instant(deadline),
+            priority: Priority::Low,
+            cancellation_tag: 0,
+        });
+        assert_eq!(
+            dispatched(&scheduler.schedule()),
+            [(JobId(0), Priority::Normal)]
+        );
+        scheduler.clock().advance(FRAME * 7);
+        assert_eq!(
+            dispatched(&scheduler.schedule()),
+            [(id, Priority::Realtime)]
+        );
+    }
+
+    #[test]
+    fn test_aging() {
+        let mut scheduler = scheduler(10);
+        let old = scheduler.submit(job(10, 1000, Priority::Low));
+        let mut last = None;
+        for frame in 0..25 {
+            let new = scheduler.submit(job(10, 1000, Priority::Normal));
+            let plan = scheduler.schedule();
+            if plan.dispatched[0].id == old {
+                last = Some((frame, plan.dispatched[0].priority));
+                break;
+            }
+            assert_eq!(plan.dispatched[0].id, new);
+        }
+        // Normal after 10 frames ties with the new normal jobs and wins by submission order.
+        assert_eq!(last, Some((10, Priority::Normal)));
+    }
+
+    #[test]
+    fn test_expired() {
+        let mut scheduler = scheduler(0);
+        scheduler.schedule();
+        let id = scheduler.submit(job(1, 0, Priority::Low));
+        let plan = scheduler.schedule();
+        assert_eq!(dispatched(&plan), [(id, Priority::Realtime)]);
+
+        scheduler.policy.drop_expired = true;
+        let id = scheduler.submit(job(1, 0, Priority::Low));
+        let plan = scheduler.schedule();
+        assert!(plan.dispatched.is_empty());
+        assert_eq!(plan.expired, [id]);
+        assert_eq!(scheduler.pending(), 0);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let mut scheduler = scheduler(10);
+        let tagged = |size, tag| Job {
+            cancellation_tag: tag,
+            ..job(size, 100, Priority::Normal)
+        };
+        let level_1 = scheduler.submit(tagged(10, 0x1_0001));
+        let level_1_pending = scheduler.submit(tagged(10, 0x1_0002));
+        let level_2 = scheduler.submit(tagged(10, 0x2_0001));
+        assert_eq!(scheduler.schedule().dispatched[0].id, level_1);
+
+        let cancellation = scheduler.cancel(0xFFFF_0000, 0x1_0000);
+        assert_eq!(cancellation.pending, [level_1_pending]);
+        assert_eq!(cancellation.in_flight, [level_1]);
+        assert!(!scheduler.complete(level_1));
+        assert_eq!(scheduler.schedule().dispatched[0].id, level_2);
+        assert!(scheduler.cancel(0xFFFF_0000, 0x3_0000).in_flight.is_empty());
+    }
+}
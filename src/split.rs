@@ -0,0 +1,200 @@
+//! Splitting reads that exceed the runtime limits into multiple requests.
+//!
+//! [`DSTORAGE_SOURCE_FILE::Size`](crate::DSTORAGE_SOURCE_FILE) and
+//! [`DSTORAGE_REQUEST::UncompressedSize`](crate::DSTORAGE_REQUEST) are `u32`, and every request
+//! has to fit in the staging buffer.  [`split()`] divides a 64-bit file range into parts that
+//! respect both limits; on Windows, `enqueue_split_read()` enqueues them together with a single
+//! status entry that completes when every part has completed.
+
+#[cfg(windows)]
+use windows::Win32::Graphics::Direct3D12::ID3D12Resource;
+
+#[cfg(windows)]
+use crate::{
+    readonly_copy, IDStorageFile, IDStorageQueue, IDStorageStatusArray,
+    DSTORAGE_COMPRESSION_FORMAT_NONE, DSTORAGE_DESTINATION, DSTORAGE_DESTINATION_BUFFER,
+    DSTORAGE_DESTINATION_MEMORY, DSTORAGE_REQUEST, DSTORAGE_REQUEST_DESTINATION_BUFFER,
+    DSTORAGE_REQUEST_DESTINATION_MEMORY, DSTORAGE_REQUEST_SOURCE_FILE, DSTORAGE_SOURCE,
+    DSTORAGE_SOURCE_FILE,
+};
+
+/// One request-sized part of a larger read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SplitPart {
+    pub file_offset: u64,
+    /// Offset relative to the start of the destination.
+    pub destination_offset: u64,
+    pub size: u32,
+}
+
+/// Split reading `size` bytes at `file_offset` into parts of at most `max_request_size` bytes,
+/// typically the staging buffer size.
+pub fn split(
+    file_offset: u64,
+    size: u64,
+    max_request_size: u32,
+) -> impl Iterator<Item = SplitPart> {
+    let max_request_size = max_request_size.max(1) as u64;
+    (0..size.div_ceil(max_request_size)).map(move |i| {
+        let destination_offset = i * max_request_size;
+        SplitPart {
+            file_offset: file_offset + destination_offset,
+            destination_offset,
+            size: (size - destination_offset).min(max_request_size) as u32,
+        }
+    })
+}
+
+/// Destination of a read enqueued with [`enqueue_split_read()`].
+#[cfg(windows)]
+pub enum SplitDestination<'a> {
+    Memory(&'a mut [u8]),
+    /// A buffer resource, written starting at `offset`.
+    Buffer {
+        resource: &'a ID3D12Resource,
+        offset: u64,
+    },
+}
+
+/// Completion handle shared by all parts of a read enqueued with [`enqueue_split_read()`].
+#[cfg(windows)]
+pub struct SplitCompletion {
+    status_array: IDStorageStatusArray,
+    index: u32,
+    requests: usize,
+}
+
+#[cfg(windows)]
+impl SplitCompletion {
+    /// Number of requests the read was split into.
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    pub fn is_complete(&self) -> bool {
+        unsafe { self.status_array.IsComplete(self.index) }
+    }
+
+    /// The first error of any part, or [`None`] while parts are still in flight.
+    pub fn result(&self) -> Option<windows_core::Result<()>> {
+        self.is_complete()
+            .then(|| unsafe { self.status_array.GetHResult(self.index) })
+    }
+}
+
+/// Enqueue uncompressed requests reading `size` bytes at `file_offset` into `destination`,
+/// followed by a status write to `status_index` of `status_array` that completes once all of
+/// them did.
+///
+/// # Safety
+/// `queue` must read from files, and `file`, `destination` and `status_array` must stay valid
+/// until the returned completion reports that the read has finished.
+#[cfg(windows)]
+#[allow(clippy::too_many_arguments)]
+pub unsafe fn enqueue_split_read(
+    queue: &IDStorageQueue,
+    file: &IDStorageFile,
+    file_offset: u64,
+    size: u64,
+    mut destination: SplitDestination<'_>,
+    max_request_size: u32,
+    status_array: &IDStorageStatusArray,
+    status_index: u32,
+) -> SplitCompletion {
+    let (destination_type, buffer) = match &mut destination {
+        SplitDestination::Memory(memory) => {
+            assert!(memory.len() as u64 >= size);
+            (DSTORAGE_REQUEST_DESTINATION_MEMORY, memory.as_mut_ptr())
+        }
+        SplitDestination::Buffer { .. } => {
+            (DSTORAGE_REQUEST_DESTINATION_BUFFER, std::ptr::null_mut())
+        }
+    };
+
+    let mut requests = 0;
+    for part in split(file_offset, size, max_request_size) {
+        let destination = match &destination {
+            SplitDestination::Memory(_) => DSTORAGE_DESTINATION {
+                Memory: DSTORAGE_DESTINATION_MEMORY {
+                    Buffer: unsafe { buffer.add(part.destination_offset as usize) }.cast(),
+                    Size: part.size,
+                },
+            },
+            SplitDestination::Buffer { resource, offset } => DSTORAGE_DESTINATION {
+                Buffer: std::mem::ManuallyDrop::new(DSTORAGE_DESTINATION_BUFFER {
+                    Resource: unsafe { readonly_copy(*resource) },
+                    Offset: offset + part.destination_offset,
+                    Size: part.size,
+                }),
+            },
+        };
+        let mut request = DSTORAGE_REQUEST {
+            Source: DSTORAGE_SOURCE {
+                File: std::mem::ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                    Source: unsafe { readonly_copy(file) },
+                    Offset: part.file_offset,
+                    Size: part.size,
+                }),
+            },
+            Destination: destination,
+            UncompressedSize: part.size,
+            ..Default::default()
+        };
+        request
+            .Options
+            .set_CompressionFormat(DSTORAGE_COMPRESSION_FORMAT_NONE);
+        request.Options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+        request.Options.set_DestinationType(destination_type);
+        unsafe { queue.EnqueueRequest(&request) };
+        requests += 1;
+    }
+
+    unsafe { queue.EnqueueStatus(status_array, status_index) };
+    SplitCompletion {
+        status_array: status_array.clone(),
+        index: status_index,
+        requests,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let parts: Vec<_> = split(100, 250, 100).collect();
+        assert_eq!(
+            parts,
+            [
+                SplitPart {
+                    file_offset: 100,
+                    destination_offset: 0,
+                    size: 100
+                },
+                SplitPart {
+                    file_offset: 200,
+                    destination_offset: 100,
+                    size: 100
+                },
+                SplitPart {
+                    file_offset: 300,
+                    destination_offset: 200,
+                    size: 50
+                },
+            ]
+        );
+        assert_eq!(split(0, 0, 100).count(), 0);
+    }
+
+    #[test]
+    fn test_split_huge() {
+        // A 10 GiB file read with the largest possible requests.
+        let size = 10 << 30;
+        let parts: Vec<_> = split(1 << 40, size, u32::MAX).collect();
+        assert_eq!(parts.len(), 3);
+        assert_eq!(parts.iter().map(|p| p.size as u64).sum::<u64>(), size);
+        let last = parts.last().unwrap();
+        assert_eq!(last.file_offset + last.size as u64, (1 << 40) + size);
+    }
+}
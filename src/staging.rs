@@ -0,0 +1,237 @@
+//! Planning the size of the DirectStorage staging buffer.
+//!
+//! Every request has to fit in the staging buffer, otherwise it fails with
+//! [`E_DSTORAGE_STAGING_BUFFER_TOO_SMALL`](crate::E_DSTORAGE_STAGING_BUFFER_TOO_SMALL).  The size
+//! can only be changed with [`IDStorageFactory::SetStagingBufferSize()`] before the first queue is
+//! created, after which the runtime returns [`E_DSTORAGE_STAGING_BUFFER_LOCKED`].
+//! [`StagingFactory`] enforces that ordering on the Rust side.
+
+use std::{sync::Mutex, time::Duration};
+
+use windows_core::Interface;
+
+use crate::{
+    IDStorageFactory, DSTORAGE_QUEUE_DESC, DSTORAGE_REQUEST, DSTORAGE_REQUEST_SOURCE_MEMORY,
+    E_DSTORAGE_STAGING_BUFFER_LOCKED,
+};
+
+const MIB: u64 = 1024 * 1024;
+
+/// Staging buffer sizes computed by [`StagingPlanner::plan()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StagingPlan {
+    /// Smallest staging buffer that fits every planned request.
+    pub minimum: u32,
+    /// Suggested size, at least [`StagingPlan::minimum`] and rounded up to a power-of-two number
+    /// of MiB.  Larger than the minimum when a throughput target was given.
+    pub recommended: u32,
+}
+
+/// Collects request sizes and computes the staging buffer size needed to execute them.
+#[derive(Clone, Debug, Default)]
+pub struct StagingPlanner {
+    largest: u32,
+    throughput: Option<(u64, Duration)>,
+}
+
+impl StagingPlanner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Recommend a staging buffer that can hold the data read at `bytes_per_second` during
+    /// `latency`, so the device is kept busy while earlier requests are being completed.
+    pub fn with_throughput(mut self, bytes_per_second: u64, latency: Duration) -> Self {
+        self.throughput = Some((bytes_per_second, latency));
+        self
+    }
+
+    /// Account for a request reading `source_size` bytes that decompress to `uncompressed_size`.
+    pub fn add_size(&mut self, source_size: u32, uncompressed_size: u32) {
+        self.largest = self.largest.max(source_size).max(uncompressed_size);
+    }
+
+    /// Account for `request`.
+    pub fn add_request(&mut self, request: &DSTORAGE_REQUEST) {
+        let source_size = if request.Options.SourceType() == DSTORAGE_REQUEST_SOURCE_MEMORY {
+            unsafe { request.Source.Memory.Size }
+        } else {
+            unsafe { request.Source.File.Size }
+        };
+        self.add_size(source_size, request.UncompressedSize);
+    }
+
+    /// Account for every chunk in an archive.
+    #[cfg(feature = "archive")]
+    pub fn add_toc(&mut self, toc: &crate::archive::Toc) {
+        for chunk in toc.entries.iter().flat_map(|e| &e.chunks) {
+            self.add_size(chunk.compressed_size, chunk.uncompressed_size);
+        }
+    }
+
+    pub fn plan(&self) -> StagingPlan {
+        let minimum = self.largest;
+
+        let throughput = self.throughput.map_or(0, |(bytes_per_second, latency)| {
+            (bytes_per_second as f64 * latency.as_secs_f64()).ceil() as u64
+        });
+        let recommended = (minimum as u64)
+            .max(throughput)
+            .div_ceil(MIB)
+            .max(1)
+            .next_power_of_two()
+            * MIB;
+
+        StagingPlan {
+            minimum,
+            recommended: recommended.min(u32::MAX as u64).max(minimum as u64) as u32,
+        }
+    }
+}
+
+/// Wraps an [`IDStorageFactory`] and rejects changes to the staging buffer size after a queue has
+/// been created through it.
+pub struct StagingFactory {
+    factory: IDStorageFactory,
+    /// Whether a queue was created.  Held while changing the size or creating a queue, so the
+    /// two can't interleave.
+    locked: Mutex<bool>,
+}
+
+impl StagingFactory {
+    pub fn new(factory: IDStorageFactory) -> Self {
+        Self {
+            factory,
+            locked: Mutex::new(false),
+        }
+    }
+
+    /// The wrapped factory.  Queues created directly on it are not tracked by this wrapper.
+    pub fn factory(&self) -> &IDStorageFactory {
+        &self.factory
+    }
+
+    /// Whether a queue has been created, after which the staging buffer size can't be changed.
+    pub fn is_locked(&self) -> bool {
+        *self.locked.lock().unwrap()
+    }
+
+    /// Calls [`IDStorageFactory::SetStagingBufferSize()`], or returns
+    /// [`E_DSTORAGE_STAGING_BUFFER_LOCKED`] when a queue was already created.
+    ///
+    /// # Safety
+    /// Calls into the DirectStorage runtime.
+    pub unsafe fn set_staging_buffer_size(&self, size: u32) -> windows_core::Result<()> {
+        let locked = self.locked.lock().unwrap();
+        if *locked {
+            return E_DSTORAGE_STAGING_BUFFER_LOCKED.ok();
+        }
+        unsafe { self.factory.SetStagingBufferSize(size) }
+    }
+
+    /// Applies [`StagingPlan::recommended`] through [`StagingFactory::set_staging_buffer_size()`].
+    ///
+    /// # Safety
+    /// Calls into the DirectStorage runtime.
+    pub unsafe fn apply(&self, plan: &StagingPlan) -> windows_core::Result<()> {
+        unsafe { self.set_staging_buffer_size(plan.recommended) }
+    }
+
+    /// Calls [`IDStorageFactory::CreateQueue()`] and locks the staging buffer size if that
+    /// succeeded.
+    ///
+    /// # Safety
+    /// Calls into the DirectStorage runtime, `desc` must be valid.
+    pub unsafe fn create_queue<T: Interface>(
+        &self,
+        desc: &DSTORAGE_QUEUE_DESC,
+    ) -> windows_core::Result<T> {
+        let mut locked = self.locked.lock().unwrap();
+        let queue = unsafe { self.factory.CreateQueue(desc) }?;
+        *locked = true;
+        Ok(queue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::mem::ManuallyDrop;
+
+    use super::*;
+    use crate::{
+        DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE, DSTORAGE_SOURCE_MEMORY,
+        DSTORAGE_STAGING_BUFFER_SIZE_32MB,
+    };
+
+    #[test]
+    fn test_minimum() {
+        let mut planner = StagingPlanner::new();
+        assert_eq!(
+            planner.plan(),
+            StagingPlan {
+                minimum: 0,
+                recommended: MIB as u32
+            }
+        );
+
+        planner.add_size(1000, 3 * MIB as u32 + 1);
+        planner.add_size(2 * MIB as u32, 2 * MIB as u32);
+        assert_eq!(
+            planner.plan(),
+            StagingPlan {
+                minimum: 3 * MIB as u32 + 1,
+                recommended: 4 * MIB as u32
+            }
+        );
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut planner = StagingPlanner::new();
+
+        let mut request = DSTORAGE_REQUEST {
+            Source: DSTORAGE_SOURCE {
+                File: ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                    Size: 5000,
+                    ..Default::default()
+                }),
+            },
+            UncompressedSize: 100,
+            ..Default::default()
+        };
+        planner.add_request(&request);
+        assert_eq!(planner.plan().minimum, 5000);
+
+        request
+            .Options
+            .set_SourceType(DSTORAGE_REQUEST_SOURCE_MEMORY);
+        request.Source = DSTORAGE_SOURCE {
+            Memory: DSTORAGE_SOURCE_MEMORY {
+                Size: 1000,
+                ..Default::default()
+            },
+        };
+        request.UncompressedSize = 7000;
+        planner.add_request(&request);
+        assert_eq!(planner.plan().minimum, 7000);
+    }
+
+    #[test]
+    fn test_throughput() {
+        let mut planner =
+            StagingPlanner::new().with_throughput(7_000_000_000, Duration::from_millis(4));
+        planner.add_size(MIB as u32, MIB as u32);
+
+        // 28 MB in flight rounds up to the default 32 MiB staging buffer.
+        let plan = planner.plan();
+        assert_eq!(plan.minimum, MIB as u32);
+        assert_eq!(plan.recommended, DSTORAGE_STAGING_BUFFER_SIZE_32MB.0);
+    }
+
+    #[test]
+    fn test_huge_request() {
+        let mut planner = StagingPlanner::new();
+        planner.add_size(u32::MAX, u32::MAX);
+        assert_eq!(planner.plan().recommended, u32::MAX);
+    }
+}
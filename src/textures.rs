@@ -0,0 +1,578 @@
+//! Texture containers and the D3D12 subresource layouts needed to stream them with
+//! DirectStorage.
+//!
+//! Parsers in this module produce a [`TextureLayout`], which describes where every subresource is
+//! stored in the container.  DirectStorage expects texture data in the layout returned by
+//! `ID3D12Device::GetCopyableFootprints()`, with rows aligned to
+//! [`TEXTURE_DATA_PITCH_ALIGNMENT`] and subresources aligned to
+//! [`TEXTURE_DATA_PLACEMENT_ALIGNMENT`].  [`TextureLayout::footprints()`] computes that layout
+//! and [`TextureLayout::repack()`] converts tightly packed container data into it.
+//!
+//! The parsing and layout math is platform-independent, building request destinations is only
+//! available on Windows.
+
+use std::{error::Error, fmt, ops::Range};
+
+#[cfg(windows)]
+use windows::Win32::Graphics::Direct3D12::{ID3D12Resource, D3D12_BOX};
+
+#[cfg(windows)]
+use crate::{
+    readonly_copy, IDStorageFile, DSTORAGE_COMPRESSION_FORMAT_NONE, DSTORAGE_DESTINATION,
+    DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES, DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE,
+    DSTORAGE_DESTINATION_TEXTURE_REGION, DSTORAGE_REQUEST,
+    DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES,
+    DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE,
+    DSTORAGE_REQUEST_DESTINATION_TEXTURE_REGION, DSTORAGE_REQUEST_DESTINATION_TYPE,
+    DSTORAGE_REQUEST_SOURCE_FILE, DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE,
+};
+
+pub mod dds;
+pub mod ktx2;
+
+/// `D3D12_TEXTURE_DATA_PITCH_ALIGNMENT`
+pub const TEXTURE_DATA_PITCH_ALIGNMENT: u32 = 256;
+/// `D3D12_TEXTURE_DATA_PLACEMENT_ALIGNMENT`
+pub const TEXTURE_DATA_PLACEMENT_ALIGNMENT: u64 = 512;
+/// `D3D12_REQ_SUBRESOURCES`, the maximum number of subresources of a resource.
+pub const REQ_SUBRESOURCES: u32 = 30720;
+
+/// Block dimensions and size of a `DXGI_FORMAT`.  Uncompressed formats have 1x1 blocks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatInfo {
+    pub block_width: u32,
+    pub block_height: u32,
+    pub bytes_per_block: u32,
+}
+
+impl FormatInfo {
+    const fn pixel(bytes: u32) -> Self {
+        Self {
+            block_width: 1,
+            block_height: 1,
+            bytes_per_block: bytes,
+        }
+    }
+
+    const fn bc(bytes: u32) -> Self {
+        Self {
+            block_width: 4,
+            block_height: 4,
+            bytes_per_block: bytes,
+        }
+    }
+
+    /// Look up the layout of a `DXGI_FORMAT` value.  Returns [`None`] for unknown formats and for
+    /// formats whose layout can't be described by a block size (planar, packed YUV and 1-bit
+    /// formats).
+    pub fn from_dxgi_format(format: u32) -> Option<Self> {
+        Some(match format {
+            1..=4 => Self::pixel(16),
+            5..=8 => Self::pixel(12),
+            9..=22 => Self::pixel(8),
+            23..=47 | 67 | 87..=93 => Self::pixel(4),
+            48..=59 | 85 | 86 | 115 => Self::pixel(2),
+            60..=65 => Self::pixel(1),
+            70..=72 | 79..=81 => Self::bc(8),
+            73..=78 | 82..=84 | 94..=99 => Self::bc(16),
+            _ => return None,
+        })
+    }
+
+    pub fn is_block_compressed(&self) -> bool {
+        self.block_width > 1 || self.block_height > 1
+    }
+
+    /// Number of bytes in one tightly packed row of blocks of a surface `width` texels wide, or
+    /// [`None`] if it doesn't fit in a [`u32`].
+    pub fn row_size(&self, width: u32) -> Option<u32> {
+        width
+            .div_ceil(self.block_width)
+            .max(1)
+            .checked_mul(self.bytes_per_block)
+    }
+
+    /// Number of rows of blocks in a surface `height` texels high.
+    pub fn num_rows(&self, height: u32) -> u32 {
+        height.div_ceil(self.block_height).max(1)
+    }
+}
+
+/// Dimensionality of a texture resource.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dimension {
+    Texture1D,
+    Texture2D,
+    Texture3D,
+}
+
+/// Location of a single subresource (one mip level of one array slice or cube face) in a
+/// texture container.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Subresource {
+    /// D3D12 subresource index, `mip_level + array_slice * mip_levels`.
+    pub index: u32,
+    pub mip_level: u32,
+    pub array_slice: u32,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    /// Size of a tightly packed row of blocks.
+    pub row_size: u32,
+    /// Number of rows of blocks per depth slice.
+    pub num_rows: u32,
+    /// Offset of the tightly packed data in the container.
+    pub offset: u64,
+    /// Size of the tightly packed data in the container.
+    pub size: u64,
+}
+
+impl Subresource {
+    /// Row pitch of this subresource in the layout DirectStorage expects.
+    pub fn footprint_row_pitch(&self) -> u32 {
+        self.row_size.next_multiple_of(TEXTURE_DATA_PITCH_ALIGNMENT)
+    }
+
+    /// Size of this subresource in the layout DirectStorage expects.  The last row is not
+    /// padded, matching `GetCopyableFootprints()`.
+    pub fn footprint_size(&self) -> u64 {
+        let rows = self.num_rows as u64 * self.depth as u64;
+        (rows - 1) * self.footprint_row_pitch() as u64 + self.row_size as u64
+    }
+
+    /// Whether the tightly packed data in the container already is in the layout DirectStorage
+    /// expects, so it can be read straight from the container.
+    pub fn is_footprint_compatible(&self) -> bool {
+        self.footprint_size() == self.size
+    }
+
+    /// The full extent of this subresource, for [`DSTORAGE_DESTINATION_TEXTURE_REGION::Region`].
+    #[cfg(windows)]
+    pub fn region(&self) -> D3D12_BOX {
+        D3D12_BOX {
+            left: 0,
+            top: 0,
+            front: 0,
+            right: self.width,
+            bottom: self.height,
+            back: self.depth,
+        }
+    }
+
+    /// Describe this subresource as a [`DSTORAGE_REQUEST_DESTINATION_TEXTURE_REGION`].
+    ///
+    /// The source data must be in the layout given by [`Subresource::footprint_size()`], which
+    /// is also the returned [`TextureDestination::uncompressed_size`].  Fails when that size
+    /// doesn't fit in a request.
+    ///
+    /// # Safety
+    /// Borrows `resource` through [`readonly_copy()`], the returned destination must not outlive
+    /// it.
+    #[cfg(windows)]
+    pub unsafe fn texture_region(
+        &self,
+        resource: &ID3D12Resource,
+    ) -> Result<TextureDestination, RequestTooLarge> {
+        Ok(TextureDestination {
+            destination_type: DSTORAGE_REQUEST_DESTINATION_TEXTURE_REGION,
+            destination: DSTORAGE_DESTINATION {
+                Texture: std::mem::ManuallyDrop::new(DSTORAGE_DESTINATION_TEXTURE_REGION {
+                    Resource: unsafe { readonly_copy(resource) },
+                    SubresourceIndex: self.index,
+                    Region: self.region(),
+                }),
+            },
+            uncompressed_size: request_size(self.footprint_size())?,
+        })
+    }
+}
+
+/// Offsets of a range of subresources in the layout DirectStorage expects, as returned by
+/// [`TextureLayout::footprints()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Footprints {
+    /// Offset of every subresource in the range, relative to the start of the first.
+    pub offsets: Vec<u64>,
+    /// Total size of the range, the `UncompressedSize` of a request loading it.
+    pub total_size: u64,
+}
+
+/// The destination half of a texture request.
+#[cfg(windows)]
+pub struct TextureDestination {
+    pub destination_type: DSTORAGE_REQUEST_DESTINATION_TYPE,
+    pub destination: DSTORAGE_DESTINATION,
+    /// Required `UncompressedSize` of the request.
+    pub uncompressed_size: u32,
+}
+
+/// Error returned when a request would have to load more than [`u32::MAX`] bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestTooLarge {
+    /// Size the request would need.
+    pub size: u64,
+}
+
+impl fmt::Display for RequestTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "request is too large: {} bytes", self.size)
+    }
+}
+
+impl Error for RequestTooLarge {}
+
+fn request_size(size: u64) -> Result<u32, RequestTooLarge> {
+    u32::try_from(size).map_err(|_| RequestTooLarge { size })
+}
+
+/// Number of subresources of a texture with `mip_levels` and `array_size`, or [`None`] if there
+/// are more than [`REQ_SUBRESOURCES`].
+pub(crate) fn num_subresources(mip_levels: u32, array_size: u32) -> Option<u32> {
+    mip_levels
+        .checked_mul(array_size)
+        .filter(|&n| n <= REQ_SUBRESOURCES)
+}
+
+/// Shape of a texture and the location of its subresources in a container.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextureLayout {
+    /// `DXGI_FORMAT` of the texture data.
+    pub dxgi_format: u32,
+    pub dimension: Dimension,
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+    pub mip_levels: u32,
+    /// Number of array slices, including the six faces of every cube.
+    pub array_size: u32,
+    pub is_cube: bool,
+    /// All subresources, ordered by their D3D12 subresource index.
+    pub subresources: Vec<Subresource>,
+}
+
+impl TextureLayout {
+    /// Compute the mip chain of a texture whose subresources are stored tightly packed and
+    /// back-to-back (array slice major, mip level minor) starting at `data_offset`.
+    ///
+    /// Fails when the texture has too many subresources or its size overflows.  Callers parsing
+    /// untrusted headers should check that the container is large enough to hold
+    /// [`num_subresources()`] subresources first, as this allocates one [`Subresource`] each.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn packed(
+        dxgi_format: u32,
+        format: FormatInfo,
+        dimension: Dimension,
+        (width, height, depth): (u32, u32, u32),
+        mip_levels: u32,
+        array_size: u32,
+        is_cube: bool,
+        data_offset: u64,
+    ) -> Result<Self, &'static str> {
+        let too_large = "texture is too large";
+        let count = num_subresources(mip_levels, array_size).ok_or("too many subresources")?;
+        let mut subresources = Vec::with_capacity(count as usize);
+        let mut offset = data_offset;
+        for array_slice in 0..array_size {
+            for mip_level in 0..mip_levels {
+                let width = (width >> mip_level).max(1);
+                let height = (height >> mip_level).max(1);
+                let depth = (depth >> mip_level).max(1);
+                let row_size = format.row_size(width).ok_or(too_large)?;
+                let num_rows = format.num_rows(height);
+                let size = (row_size as u64 * num_rows as u64)
+                    .checked_mul(depth as u64)
+                    .ok_or(too_large)?;
+                subresources.push(Subresource {
+                    index: mip_level + array_slice * mip_levels,
+                    mip_level,
+                    array_slice,
+                    width,
+                    height,
+                    depth,
+                    row_size,
+                    num_rows,
+                    offset,
+                    size,
+                });
+                offset = offset.checked_add(size).ok_or(too_large)?;
+            }
+        }
+
+        Ok(Self {
+            dxgi_format,
+            dimension,
+            width,
+            height,
+            depth,
+            mip_levels,
+            array_size,
+            is_cube,
+            subresources,
+        })
+    }
+
+    pub fn subresource(&self, mip_level: u32, array_slice: u32) -> Option<&Subresource> {
+        if mip_level >= self.mip_levels {
+            return None;
+        }
+        self.subresources
+            .get((mip_level + array_slice * self.mip_levels) as usize)
+    }
+
+    /// Compute the layout of `range` of subresources like `GetCopyableFootprints()` does with a
+    /// base offset of zero.
+    pub fn footprints(&self, range: Range<u32>) -> Footprints {
+        let mut offsets = Vec::with_capacity(range.len());
+        let mut offset = 0u64;
+        let mut total_size = 0;
+        for subresource in &self.subresources[range.start as usize..range.end as usize] {
+            offset = offset.next_multiple_of(TEXTURE_DATA_PLACEMENT_ALIGNMENT);
+            offsets.push(offset);
+            total_size = offset + subresource.footprint_size();
+            offset += subresource.footprint_row_pitch() as u64
+                * subresource.num_rows as u64
+                * subresource.depth as u64;
+        }
+        Footprints {
+            offsets,
+            total_size,
+        }
+    }
+
+    /// Copy `range` of subresources from the tightly packed `container` into the layout
+    /// DirectStorage expects, for example to store it in an [archive](crate::archive).
+    ///
+    /// Padding bytes are zeroed.
+    pub fn repack(&self, range: Range<u32>, container: &[u8]) -> Vec<u8> {
+        let footprints = self.footprints(range.clone());
+        let mut out = vec![0; footprints.total_size as usize];
+        for (subresource, &dst_offset) in self.subresources
+            [range.start as usize..range.end as usize]
+            .iter()
+            .zip(&footprints.offsets)
+        {
+            let pitch = subresource.footprint_row_pitch() as usize;
+            let row_size = subresource.row_size as usize;
+            let rows = (subresource.num_rows * subresource.depth) as usize;
+            let src = &container[subresource.offset as usize..][..subresource.size as usize];
+            for (row, src_row) in src.chunks_exact(row_size).take(rows).enumerate() {
+                let dst = dst_offset as usize + row * pitch;
+                out[dst..dst + row_size].copy_from_slice(src_row);
+            }
+        }
+        out
+    }
+
+    /// Whether `range` of subresources is stored in the container exactly in the layout returned
+    /// by [`TextureLayout::footprints()`], so it can be loaded with a single request.
+    pub fn is_footprint_compatible(&self, range: Range<u32>) -> bool {
+        let subresources = &self.subresources[range.start as usize..range.end as usize];
+        let Some(first) = subresources.first() else {
+            return false;
+        };
+        subresources
+            .iter()
+            .zip(self.footprints(range).offsets)
+            .all(|(s, offset)| s.is_footprint_compatible() && s.offset == first.offset + offset)
+    }
+
+    /// The `UncompressedSize` of a request loading `range` of subresources, the
+    /// [`Footprints::total_size`], or [`RequestTooLarge`] when it doesn't fit in a request.
+    pub fn request_size(&self, range: Range<u32>) -> Result<u32, RequestTooLarge> {
+        request_size(self.footprints(range).total_size)
+    }
+
+    /// Build a request loading `range` of subresources straight from the container in `file`
+    /// into `resource`.  Returns [`None`] when the data in the container is not in the layout
+    /// DirectStorage expects, see [`TextureLayout::is_footprint_compatible()`]; those
+    /// subresources have to be [repacked](TextureLayout::repack()) first.
+    ///
+    /// A single subresource is loaded as a [`DSTORAGE_REQUEST_DESTINATION_TEXTURE_REGION`],
+    /// larger ranges through [`TextureLayout::multiple_subresources()`].  Fails with
+    /// [`RequestTooLarge`] when the range is 4 GiB or larger, split it into smaller requests.
+    ///
+    /// # Safety
+    /// Borrows `file` and `resource` through [`readonly_copy()`], the returned request must not
+    /// outlive them.
+    #[cfg(windows)]
+    pub unsafe fn file_request(
+        &self,
+        file: &IDStorageFile,
+        resource: &ID3D12Resource,
+        range: Range<u32>,
+    ) -> Result<Option<DSTORAGE_REQUEST>, RequestTooLarge> {
+        if !self.is_footprint_compatible(range.clone()) {
+            return Ok(None);
+        }
+
+        let destination = if range.len() == 1 {
+            unsafe { self.subresources[range.start as usize].texture_region(resource)? }
+        } else {
+            unsafe { self.multiple_subresources(resource, range.clone())? }
+        };
+
+        let mut request = DSTORAGE_REQUEST {
+            Source: DSTORAGE_SOURCE {
+                File: std::mem::ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                    Source: unsafe { readonly_copy(file) },
+                    Offset: self.subresources[range.start as usize].offset,
+                    Size: destination.uncompressed_size,
+                }),
+            },
+            Destination: destination.destination,
+            UncompressedSize: destination.uncompressed_size,
+            ..Default::default()
+        };
+        request
+            .Options
+            .set_CompressionFormat(DSTORAGE_COMPRESSION_FORMAT_NONE);
+        request.Options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+        request
+            .Options
+            .set_DestinationType(destination.destination_type);
+        Ok(Some(request))
+    }
+
+    /// Describe `range` of subresources as a [`DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES`]
+    /// destination when it extends to the last subresource, or as a
+    /// [`DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE`] otherwise.
+    ///
+    /// The source data must be in the layout returned by [`TextureLayout::footprints()`].  Fails
+    /// when its [`TextureLayout::request_size()`] doesn't fit in a request.
+    ///
+    /// # Safety
+    /// Borrows `resource` through [`readonly_copy()`], the returned destination must not outlive
+    /// it.
+    #[cfg(windows)]
+    pub unsafe fn multiple_subresources(
+        &self,
+        resource: &ID3D12Resource,
+        range: Range<u32>,
+    ) -> Result<TextureDestination, RequestTooLarge> {
+        let uncompressed_size = self.request_size(range.clone())?;
+        let (destination_type, destination) = if range.end as usize == self.subresources.len() {
+            (
+                DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES,
+                DSTORAGE_DESTINATION {
+                    MultipleSubresources: std::mem::ManuallyDrop::new(
+                        DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES {
+                            Resource: unsafe { readonly_copy(resource) },
+                            FirstSubresource: range.start,
+                        },
+                    ),
+                },
+            )
+        } else {
+            (
+                DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE,
+                DSTORAGE_DESTINATION {
+                    MultipleSubresourcesRange: std::mem::ManuallyDrop::new(
+                        DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE {
+                            Resource: unsafe { readonly_copy(resource) },
+                            FirstSubresource: range.start,
+                            NumSubresources: range.len() as u32,
+                        },
+                    ),
+                },
+            )
+        };
+        Ok(TextureDestination {
+            destination_type,
+            destination,
+            uncompressed_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_info() {
+        // DXGI_FORMAT_BC1_UNORM
+        let bc1 = FormatInfo::from_dxgi_format(71).unwrap();
+        assert!(bc1.is_block_compressed());
+        assert_eq!(bc1.row_size(1), Some(8));
+        assert_eq!(bc1.row_size(17), Some(40));
+        assert_eq!(bc1.num_rows(2), 1);
+
+        // DXGI_FORMAT_R8G8B8A8_UNORM
+        let rgba8 = FormatInfo::from_dxgi_format(28).unwrap();
+        assert!(!rgba8.is_block_compressed());
+        assert_eq!(rgba8.row_size(3), Some(12));
+        assert_eq!(rgba8.row_size(u32::MAX), None);
+        assert_eq!(rgba8.num_rows(3), 3);
+
+        // DXGI_FORMAT_NV12
+        assert_eq!(FormatInfo::from_dxgi_format(103), None);
+    }
+
+    #[test]
+    fn test_footprints() {
+        // 256x4 RGBA8 with 3 mips: 1024-byte rows, then 512-byte and 256-byte rows.
+        let rgba8 = FormatInfo::from_dxgi_format(28).unwrap();
+        let layout =
+            TextureLayout::packed(28, rgba8, Dimension::Texture2D, (256, 4, 1), 3, 1, false, 0)
+                .unwrap();
+        assert!(layout
+            .subresources
+            .iter()
+            .all(|s| s.is_footprint_compatible()));
+
+        let footprints = layout.footprints(0..3);
+        assert_eq!(footprints.offsets, [0, 4096, 5120]);
+        assert_eq!(footprints.total_size, 5120 + 256);
+
+        let footprints = layout.footprints(1..3);
+        assert_eq!(footprints.offsets, [0, 1024]);
+        assert_eq!(footprints.total_size, 1024 + 256);
+    }
+
+    #[test]
+    fn test_repack() {
+        // 8x2 R8 texture: 8-byte rows need padding to 256.
+        let r8 = FormatInfo::from_dxgi_format(61).unwrap();
+        let layout =
+            TextureLayout::packed(61, r8, Dimension::Texture2D, (8, 2, 1), 2, 1, false, 4).unwrap();
+        let mip0 = layout.subresource(0, 0).unwrap();
+        assert!(!mip0.is_footprint_compatible());
+        assert_eq!(mip0.footprint_size(), 256 + 8);
+
+        let container: Vec<u8> = (0..4 + 16 + 4).collect();
+        let repacked = layout.repack(0..2, &container);
+        assert_eq!(repacked.len(), 512 + 4);
+        assert_eq!(&repacked[..8], &container[4..12]);
+        assert!(repacked[8..256].iter().all(|&b| b == 0));
+        assert_eq!(&repacked[256..264], &container[12..20]);
+        assert_eq!(&repacked[512..], &container[20..24]);
+    }
+
+    #[test]
+    fn test_request_size() {
+        // 32768x32768 RGBA8 with 2 mips: 4 GiB, then 1 GiB.
+        let rgba8 = FormatInfo::from_dxgi_format(28).unwrap();
+        let layout = TextureLayout::packed(
+            28,
+            rgba8,
+            Dimension::Texture2D,
+            (32768, 32768, 1),
+            2,
+            1,
+            false,
+            0,
+        )
+        .unwrap();
+        assert_eq!(layout.request_size(1..2), Ok(1 << 30));
+        assert_eq!(
+            layout.request_size(0..2),
+            Err(RequestTooLarge {
+                size: (4 << 30) + (1 << 30)
+            })
+        );
+        assert_eq!(
+            layout.request_size(0..1),
+            Err(RequestTooLarge { size: 4 << 30 })
+        );
+    }
+}
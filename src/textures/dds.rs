@@ -0,0 +1,460 @@
+//! Parsing DirectDraw Surface (`.dds`) files.
+//!
+//! Supports the legacy header with the common FourCC and RGB(A) pixel formats, as well as the
+//! `DX10` extension header carrying a `DXGI_FORMAT` directly.  Textures may be 1D, 2D (arrays),
+//! cube maps (arrays) or volumes, each with a mip chain.  DDS stores every subresource tightly
+//! packed, ordered by array slice and then mip level, which is exactly the D3D12 subresource
+//! order.
+//!
+//! Only the header is needed to compute the [`TextureLayout`], so files can be streamed with
+//! [`TextureLayout::file_request()`] after reading the first [`MAX_HEADER_SIZE`] bytes.  Ranges
+//! of 4 GiB or more don't fit in a request, its [`RequestTooLarge`] error converts into
+//! [`DdsError::RequestTooLarge`].
+
+use std::{error::Error, fmt};
+
+use super::{num_subresources, Dimension, FormatInfo, RequestTooLarge, TextureLayout};
+
+pub const MAGIC: [u8; 4] = *b"DDS ";
+/// Size of the magic, `DDS_HEADER` and `DDS_HEADER_DXT10`.
+pub const MAX_HEADER_SIZE: usize = 4 + HEADER_SIZE + DX10_HEADER_SIZE;
+
+const HEADER_SIZE: usize = 124;
+const DX10_HEADER_SIZE: usize = 20;
+
+const DDSD_MIPMAPCOUNT: u32 = 0x20000;
+const DDSD_DEPTH: u32 = 0x800000;
+
+const DDPF_ALPHAPIXELS: u32 = 0x1;
+const DDPF_ALPHA: u32 = 0x2;
+const DDPF_FOURCC: u32 = 0x4;
+const DDPF_RGB: u32 = 0x40;
+const DDPF_LUMINANCE: u32 = 0x20000;
+
+const DDSCAPS2_CUBEMAP: u32 = 0x200;
+const DDSCAPS2_CUBEMAP_ALLFACES: u32 = 0xFC00;
+const DDSCAPS2_VOLUME: u32 = 0x200000;
+
+const D3D10_RESOURCE_DIMENSION_TEXTURE1D: u32 = 2;
+const D3D10_RESOURCE_DIMENSION_TEXTURE2D: u32 = 3;
+const D3D10_RESOURCE_DIMENSION_TEXTURE3D: u32 = 4;
+const D3D10_RESOURCE_MISC_TEXTURECUBE: u32 = 0x4;
+
+/// Error returned when parsing a DDS file fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DdsError {
+    /// The data does not start with [`MAGIC`].
+    Magic,
+    /// A header field has an invalid value.
+    Header(&'static str),
+    /// The legacy pixel format has no equivalent `DXGI_FORMAT`.
+    UnsupportedPixelFormat { flags: u32, four_cc: [u8; 4] },
+    /// The `DXGI_FORMAT` is not supported by [`FormatInfo`].
+    UnsupportedDxgiFormat(u32),
+    /// The data is shorter than the header, or the file is shorter than its subresources.
+    Truncated { required: u64, len: u64 },
+    /// A request built from the layout would load more than [`u32::MAX`] bytes.
+    RequestTooLarge { size: u64 },
+}
+
+impl fmt::Display for DdsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Magic => f.write_str("not a DDS file"),
+            Self::Header(msg) => write!(f, "invalid DDS header: {msg}"),
+            Self::UnsupportedPixelFormat { flags, four_cc } => write!(
+                f,
+                "unsupported DDS pixel format (flags {flags:#x}, FourCC {:?})",
+                String::from_utf8_lossy(four_cc)
+            ),
+            Self::UnsupportedDxgiFormat(format) => write!(f, "unsupported DXGI_FORMAT {format}"),
+            Self::Truncated { required, len } => {
+                write!(f, "DDS data truncated: need {required} bytes, got {len}")
+            }
+            Self::RequestTooLarge { size } => write!(f, "request is too large: {size} bytes"),
+        }
+    }
+}
+
+impl Error for DdsError {}
+
+impl From<RequestTooLarge> for DdsError {
+    fn from(RequestTooLarge { size }: RequestTooLarge) -> Self {
+        Self::RequestTooLarge { size }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Map a legacy `DDS_PIXELFORMAT` to a `DXGI_FORMAT`.
+fn legacy_dxgi_format(pf: &[u8]) -> Result<u32, DdsError> {
+    let flags = read_u32(pf, 4);
+    let four_cc: [u8; 4] = pf[8..12].try_into().unwrap();
+    let bits = read_u32(pf, 12);
+    let masks = [
+        read_u32(pf, 16),
+        read_u32(pf, 20),
+        read_u32(pf, 24),
+        read_u32(pf, 28),
+    ];
+    let unsupported = DdsError::UnsupportedPixelFormat { flags, four_cc };
+
+    let format = if flags & DDPF_FOURCC != 0 {
+        match &four_cc {
+            b"DXT1" => 71,
+            b"DXT2" | b"DXT3" => 74,
+            b"DXT4" | b"DXT5" => 77,
+            b"ATI1" | b"BC4U" => 80,
+            b"BC4S" => 81,
+            b"ATI2" | b"BC5U" => 83,
+            b"BC5S" => 84,
+            // Some writers store a D3DFORMAT value instead of characters.
+            _ => match u32::from_le_bytes(four_cc) {
+                36 => 11,
+                110 => 13,
+                111 => 54,
+                112 => 34,
+                113 => 10,
+                114 => 41,
+                115 => 16,
+                116 => 2,
+                _ => return Err(unsupported),
+            },
+        }
+    } else if flags & DDPF_RGB != 0 {
+        let alpha = if flags & DDPF_ALPHAPIXELS != 0 {
+            masks[3]
+        } else {
+            0
+        };
+        match (bits, [masks[0], masks[1], masks[2], alpha]) {
+            (32, [0xFF, 0xFF00, 0xFF0000, 0xFF000000]) => 28,
+            (32, [0xFF0000, 0xFF00, 0xFF, 0xFF000000]) => 87,
+            (32, [0xFF0000, 0xFF00, 0xFF, 0]) => 88,
+            (32, [0x3FF, 0xFFC00, 0x3FF00000, 0xC0000000]) => 24,
+            (32, [0xFFFF, 0xFFFF0000, 0, 0]) => 35,
+            (32, [0xFFFFFFFF, 0, 0, 0]) => 41,
+            (16, [0xF800, 0x7E0, 0x1F, 0]) => 85,
+            (16, [0x7C00, 0x3E0, 0x1F, 0x8000]) => 86,
+            (16, [0xF00, 0xF0, 0xF, 0xF000]) => 115,
+            _ => return Err(unsupported),
+        }
+    } else if flags & DDPF_LUMINANCE != 0 {
+        match (bits, masks[0]) {
+            (8, 0xFF) => 61,
+            (16, 0xFFFF) => 56,
+            (16, 0xFF) if flags & DDPF_ALPHAPIXELS != 0 => 49,
+            _ => return Err(unsupported),
+        }
+    } else if flags & DDPF_ALPHA != 0 && bits == 8 {
+        65
+    } else {
+        return Err(unsupported);
+    };
+    Ok(format)
+}
+
+/// Parse the headers at the start of a DDS file of `file_size` bytes.  `header` must contain at
+/// least the first [`MAX_HEADER_SIZE`] bytes of the file, or the whole file if it is smaller.
+pub fn parse_header(header: &[u8], file_size: u64) -> Result<TextureLayout, DdsError> {
+    let truncated = |required: usize| DdsError::Truncated {
+        required: required as u64,
+        len: header.len() as u64,
+    };
+
+    if header.len() < MAGIC.len() || header[..4] != MAGIC {
+        return Err(DdsError::Magic);
+    }
+    if header.len() < 4 + HEADER_SIZE {
+        return Err(truncated(4 + HEADER_SIZE));
+    }
+    let h = &header[4..4 + HEADER_SIZE];
+    if read_u32(h, 0) != HEADER_SIZE as u32 {
+        return Err(DdsError::Header("dwSize is not 124"));
+    }
+    let flags = read_u32(h, 4);
+    let height = read_u32(h, 8);
+    let width = read_u32(h, 12);
+    let depth = read_u32(h, 20);
+    let mip_levels = if flags & DDSD_MIPMAPCOUNT != 0 {
+        read_u32(h, 24).max(1)
+    } else {
+        1
+    };
+    let pf = &h[72..104];
+    let caps2 = read_u32(h, 108);
+
+    let mut data_offset = 4 + HEADER_SIZE;
+    let (dxgi_format, dimension, array_size, is_cube) =
+        if pf[8..12] == *b"DX10" && read_u32(pf, 4) & DDPF_FOURCC != 0 {
+            if header.len() < MAX_HEADER_SIZE {
+                return Err(truncated(MAX_HEADER_SIZE));
+            }
+            let dx10 = &header[data_offset..MAX_HEADER_SIZE];
+            data_offset = MAX_HEADER_SIZE;
+
+            let dimension = match read_u32(dx10, 4) {
+                D3D10_RESOURCE_DIMENSION_TEXTURE1D => Dimension::Texture1D,
+                D3D10_RESOURCE_DIMENSION_TEXTURE2D => Dimension::Texture2D,
+                D3D10_RESOURCE_DIMENSION_TEXTURE3D => Dimension::Texture3D,
+                _ => return Err(DdsError::Header("invalid resourceDimension")),
+            };
+            let is_cube = read_u32(dx10, 8) & D3D10_RESOURCE_MISC_TEXTURECUBE != 0;
+            if is_cube && dimension != Dimension::Texture2D {
+                return Err(DdsError::Header("cube map is not 2D"));
+            }
+            let array_size = read_u32(dx10, 12);
+            if array_size == 0 || (dimension == Dimension::Texture3D && array_size != 1) {
+                return Err(DdsError::Header("invalid arraySize"));
+            }
+            let array_size = if is_cube {
+                array_size
+                    .checked_mul(6)
+                    .ok_or(DdsError::Header("invalid arraySize"))?
+            } else {
+                array_size
+            };
+            (read_u32(dx10, 0), dimension, array_size, is_cube)
+        } else {
+            let format = legacy_dxgi_format(pf)?;
+            if caps2 & DDSCAPS2_CUBEMAP != 0 {
+                if caps2 & DDSCAPS2_CUBEMAP_ALLFACES != DDSCAPS2_CUBEMAP_ALLFACES {
+                    return Err(DdsError::Header("partial cube maps are not supported"));
+                }
+                (format, Dimension::Texture2D, 6, true)
+            } else if caps2 & DDSCAPS2_VOLUME != 0 || flags & DDSD_DEPTH != 0 {
+                (format, Dimension::Texture3D, 1, false)
+            } else {
+                (format, Dimension::Texture2D, 1, false)
+            }
+        };
+
+    let (height, depth) = match dimension {
+        Dimension::Texture1D => (1, 1),
+        Dimension::Texture2D => (height, 1),
+        Dimension::Texture3D => (height, depth),
+    };
+    if width == 0 || height == 0 || depth == 0 {
+        return Err(DdsError::Header("zero-sized texture"));
+    }
+    if mip_levels > 32 - width.max(height).max(depth).leading_zeros() {
+        return Err(DdsError::Header("too many mip levels"));
+    }
+    if is_cube && width != height {
+        return Err(DdsError::Header("cube map faces are not square"));
+    }
+
+    let format = FormatInfo::from_dxgi_format(dxgi_format)
+        .ok_or(DdsError::UnsupportedDxgiFormat(dxgi_format))?;
+    // Every subresource takes at least one block, reject headers describing more than the file
+    // can hold before allocating their layout.
+    let count = num_subresources(mip_levels, array_size)
+        .ok_or(DdsError::Header("too many subresources"))?;
+    let required = data_offset as u64 + count as u64 * format.bytes_per_block as u64;
+    if required > file_size {
+        return Err(DdsError::Truncated {
+            required,
+            len: file_size,
+        });
+    }
+    let layout = TextureLayout::packed(
+        dxgi_format,
+        format,
+        dimension,
+        (width, height, depth),
+        mip_levels,
+        array_size,
+        is_cube,
+        data_offset as u64,
+    )
+    .map_err(DdsError::Header)?;
+
+    let end = layout
+        .subresources
+        .last()
+        .map_or(data_offset as u64, |s| s.offset + s.size);
+    if end > file_size {
+        return Err(DdsError::Truncated {
+            required: end,
+            len: file_size,
+        });
+    }
+    Ok(layout)
+}
+
+/// Parse a DDS file that is completely in memory.
+pub fn parse(data: &[u8]) -> Result<TextureLayout, DdsError> {
+    parse_header(data, data.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Legacy `DXT1` header, 16x8 with 4 mips.
+    const BC1_MIPS: &[u8] = include_bytes!("testdata/bc1_mips.dds");
+    /// `DX10` header with `DXGI_FORMAT_BC7_UNORM`, 8x8 cube with 2 mips.
+    const BC7_CUBE: &[u8] = include_bytes!("testdata/bc7_cube.dds");
+    /// Legacy RGBA8 header, 64x4x4 volume with 2 mips.
+    const RGBA8_VOLUME: &[u8] = include_bytes!("testdata/rgba8_volume.dds");
+    /// `DX10` header with `DXGI_FORMAT_BC4_UNORM`, 4x4 array of 3 slices without mips.
+    const BC4_ARRAY: &[u8] = include_bytes!("testdata/bc4_array.dds");
+
+    fn sizes(layout: &TextureLayout) -> Vec<(u32, u32, u32, u64, u64)> {
+        layout
+            .subresources
+            .iter()
+            .map(|s| (s.width, s.height, s.depth, s.offset, s.size))
+            .collect()
+    }
+
+    #[test]
+    fn test_bc1_mips() {
+        let layout = parse(BC1_MIPS).unwrap();
+        assert_eq!(layout.dxgi_format, 71);
+        assert_eq!(layout.dimension, Dimension::Texture2D);
+        assert_eq!((layout.mip_levels, layout.array_size), (4, 1));
+        assert_eq!(
+            sizes(&layout),
+            [
+                (16, 8, 1, 128, 64),
+                (8, 4, 1, 192, 16),
+                (4, 2, 1, 208, 8),
+                (2, 1, 1, 216, 8),
+            ]
+        );
+        let mip0 = layout.subresource(0, 0).unwrap();
+        assert_eq!((mip0.row_size, mip0.num_rows), (32, 2));
+        // Only the mips consisting of a single row of blocks can be read as-is.
+        assert!(!layout.is_footprint_compatible(0..1));
+        assert!(layout.is_footprint_compatible(1..2));
+        assert!(!layout.is_footprint_compatible(1..3));
+    }
+
+    #[test]
+    fn test_bc7_cube() {
+        let layout = parse(BC7_CUBE).unwrap();
+        assert_eq!(layout.dxgi_format, 98);
+        assert!(layout.is_cube);
+        assert_eq!((layout.mip_levels, layout.array_size), (2, 6));
+        assert_eq!(layout.subresources.len(), 12);
+
+        let face = layout.subresource(1, 4).unwrap();
+        assert_eq!(face.index, 9);
+        assert_eq!((face.width, face.height), (4, 4));
+        assert_eq!(face.offset, 148 + 4 * 80 + 64);
+        assert_eq!(face.size, 16);
+        assert_eq!(layout.subresources.last().unwrap().offset + 16, 628);
+    }
+
+    #[test]
+    fn test_rgba8_volume() {
+        let layout = parse(RGBA8_VOLUME).unwrap();
+        assert_eq!(layout.dxgi_format, 28);
+        assert_eq!(layout.dimension, Dimension::Texture3D);
+        assert_eq!(
+            sizes(&layout),
+            [(64, 4, 4, 128, 4096), (32, 2, 2, 4224, 512)]
+        );
+        assert!(layout.is_footprint_compatible(0..1));
+        assert!(!layout.is_footprint_compatible(0..2));
+
+        let footprints = layout.footprints(0..2);
+        assert_eq!(footprints.offsets, [0, 4096]);
+        assert_eq!(footprints.total_size, 4096 + 3 * 256 + 128);
+        let repacked = layout.repack(0..2, RGBA8_VOLUME);
+        assert_eq!(&repacked[..4096], &RGBA8_VOLUME[128..4224]);
+        assert_eq!(
+            &repacked[4096 + 256..][..128],
+            &RGBA8_VOLUME[4224 + 128..][..128]
+        );
+    }
+
+    #[test]
+    fn test_bc4_array() {
+        let layout = parse(BC4_ARRAY).unwrap();
+        assert_eq!(layout.dxgi_format, 80);
+        assert!(!layout.is_cube);
+        assert_eq!((layout.mip_levels, layout.array_size), (1, 3));
+        assert_eq!(
+            sizes(&layout),
+            [(4, 4, 1, 148, 8), (4, 4, 1, 156, 8), (4, 4, 1, 164, 8)]
+        );
+        assert!(layout.is_footprint_compatible(0..1));
+        // Each slice is padded to 512 bytes in the footprint layout.
+        assert!(!layout.is_footprint_compatible(0..3));
+        assert_eq!(layout.footprints(0..3).total_size, 1024 + 8);
+    }
+
+    #[test]
+    fn test_header_only() {
+        let layout = parse_header(&BC7_CUBE[..MAX_HEADER_SIZE], BC7_CUBE.len() as u64).unwrap();
+        assert_eq!(layout, parse(BC7_CUBE).unwrap());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(b"DDX "), Err(DdsError::Magic));
+        assert_eq!(
+            parse(&BC1_MIPS[..100]),
+            Err(DdsError::Truncated {
+                required: 128,
+                len: 100
+            })
+        );
+        assert_eq!(
+            parse(&BC1_MIPS[..200]),
+            Err(DdsError::Truncated {
+                required: 224,
+                len: 200
+            })
+        );
+
+        let mut data = BC1_MIPS.to_vec();
+        data[84..88].copy_from_slice(b"DXT9");
+        assert!(matches!(
+            parse(&data),
+            Err(DdsError::UnsupportedPixelFormat { four_cc, .. }) if &four_cc == b"DXT9"
+        ));
+
+        let mut data = BC4_ARRAY.to_vec();
+        // DXGI_FORMAT_NV12
+        data[128..132].copy_from_slice(&103u32.to_le_bytes());
+        assert_eq!(parse(&data), Err(DdsError::UnsupportedDxgiFormat(103)));
+    }
+
+    #[test]
+    fn test_hostile_header() {
+        // 2^19 x 2^19 with 20 mips and the given number of array slices.
+        let header = |array_size: u32| {
+            let mut data = BC4_ARRAY.to_vec();
+            let flags = read_u32(&data, 8) | DDSD_MIPMAPCOUNT;
+            data[8..12].copy_from_slice(&flags.to_le_bytes());
+            data[12..16].copy_from_slice(&(1u32 << 19).to_le_bytes());
+            data[16..20].copy_from_slice(&(1u32 << 19).to_le_bytes());
+            data[28..32].copy_from_slice(&20u32.to_le_bytes());
+            data[140..144].copy_from_slice(&array_size.to_le_bytes());
+            data
+        };
+
+        // 20 * 0x10000000 subresources overflow a u32.
+        assert_eq!(
+            parse(&header(0x10000000)),
+            Err(DdsError::Header("too many subresources"))
+        );
+        assert_eq!(
+            parse(&header(2048)),
+            Err(DdsError::Header("too many subresources"))
+        );
+        // Within the subresource limit, but the file can't hold a block per subresource.
+        assert_eq!(
+            parse(&header(1536)),
+            Err(DdsError::Truncated {
+                required: 148 + 20 * 1536 * 8,
+                len: 172
+            })
+        );
+    }
+}
@@ -0,0 +1,637 @@
+//! Parsing Khronos Texture 2.0 (`.ktx2`) files.
+//!
+//! KTX2 stores a texture level by level, each level containing every layer and cube face.  The
+//! levels can be supercompressed individually; Zstandard-supercompressed levels are read with
+//! a custom [`DSTORAGE_COMPRESSION_FORMAT`] so that they are routed through
+//! `IDStorageCustomDecompressionQueue` and decompressed by the application.
+//!
+//! D3D12 orders subresources by array slice first, so the layers of a single level are only
+//! adjacent subresources when the texture has no mip chain.  [`Ktx2Texture::range_requests()`]
+//! groups the images of every level into as few
+//! [`DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE`] requests as that allows.
+//!
+//! [`DSTORAGE_COMPRESSION_FORMAT`]: crate::DSTORAGE_COMPRESSION_FORMAT
+//! [`DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE`]: crate::DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE
+
+use std::{error::Error, fmt, ops::Range};
+
+#[cfg(windows)]
+use windows::Win32::Graphics::Direct3D12::ID3D12Resource;
+
+use super::{num_subresources, Dimension, FormatInfo, Subresource, TextureLayout};
+#[cfg(windows)]
+use crate::{
+    readonly_copy, IDStorageFile, DSTORAGE_COMPRESSION_FORMAT, DSTORAGE_COMPRESSION_FORMAT_NONE,
+    DSTORAGE_DESTINATION, DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE, DSTORAGE_REQUEST,
+    DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE, DSTORAGE_REQUEST_SOURCE_FILE,
+    DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE,
+};
+
+pub const IDENTIFIER: [u8; 12] = [
+    0xAB, 0x4B, 0x54, 0x58, 0x20, 0x32, 0x30, 0xBB, 0x0D, 0x0A, 0x1A, 0x0A,
+];
+/// Size of the header and index, up to the level index.
+pub const HEADER_SIZE: usize = 80;
+const LEVEL_INDEX_ENTRY_SIZE: usize = 24;
+
+/// Compression format suggested for Zstandard-supercompressed levels, to be passed to
+/// [`RangeRequest::request()`].
+#[cfg(windows)]
+pub const ZSTD_COMPRESSION_FORMAT: DSTORAGE_COMPRESSION_FORMAT =
+    crate::DSTORAGE_CUSTOM_COMPRESSION_0;
+
+/// `supercompressionScheme` of a KTX2 file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Supercompression {
+    None,
+    BasisLz,
+    Zstandard,
+    Zlib,
+    Other(u32),
+}
+
+impl From<u32> for Supercompression {
+    fn from(scheme: u32) -> Self {
+        match scheme {
+            0 => Self::None,
+            1 => Self::BasisLz,
+            2 => Self::Zstandard,
+            3 => Self::Zlib,
+            scheme => Self::Other(scheme),
+        }
+    }
+}
+
+/// Error returned when parsing a KTX2 file or grouping its levels into requests fails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Ktx2Error {
+    /// The data does not start with [`IDENTIFIER`].
+    Identifier,
+    /// A header or level index field has an invalid value.
+    Header(&'static str),
+    /// The `VkFormat` has no equivalent `DXGI_FORMAT`.
+    UnsupportedVkFormat(u32),
+    /// Only uncompressed and Zstandard-supercompressed levels can be streamed.
+    UnsupportedSupercompression(Supercompression),
+    /// A supercompressed level covers subresources that are not adjacent in D3D12, so it can't
+    /// be decompressed by a single request.
+    SplitSupercompressedLevel { level: u32 },
+    /// A request would read or decompress more than [`u32::MAX`] bytes, the limit of
+    /// DirectStorage requests.
+    RequestTooLarge { level: u32, size: u64 },
+    /// The data is shorter than the header, or the file is shorter than its levels.
+    Truncated { required: u64, len: u64 },
+}
+
+impl fmt::Display for Ktx2Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Identifier => f.write_str("not a KTX2 file"),
+            Self::Header(msg) => write!(f, "invalid KTX2 header: {msg}"),
+            Self::UnsupportedVkFormat(format) => write!(f, "unsupported VkFormat {format}"),
+            Self::UnsupportedSupercompression(scheme) => {
+                write!(f, "unsupported KTX2 supercompression scheme {scheme:?}")
+            }
+            Self::SplitSupercompressedLevel { level } => write!(
+                f,
+                "supercompressed level {level} spans non-adjacent subresources"
+            ),
+            Self::RequestTooLarge { level, size } => {
+                write!(f, "request for level {level} is too large: {size} bytes")
+            }
+            Self::Truncated { required, len } => {
+                write!(f, "KTX2 data truncated: need {required} bytes, got {len}")
+            }
+        }
+    }
+}
+
+impl Error for Ktx2Error {}
+
+/// Map a `VkFormat` to a `DXGI_FORMAT`.
+pub fn dxgi_format(vk_format: u32) -> Option<u32> {
+    Some(match vk_format {
+        9 => 61,         // R8_UNORM
+        10 => 63,        // R8_SNORM
+        13 => 62,        // R8_UINT
+        14 => 64,        // R8_SINT
+        16 => 49,        // R8G8_UNORM
+        37 => 28,        // R8G8B8A8_UNORM
+        38 => 31,        // R8G8B8A8_SNORM
+        41 => 30,        // R8G8B8A8_UINT
+        42 => 32,        // R8G8B8A8_SINT
+        43 => 29,        // R8G8B8A8_SRGB
+        44 => 87,        // B8G8R8A8_UNORM
+        50 => 91,        // B8G8R8A8_SRGB
+        64 => 24,        // A2B10G10R10_UNORM_PACK32
+        70 => 56,        // R16_UNORM
+        76 => 54,        // R16_SFLOAT
+        77 => 35,        // R16G16_UNORM
+        83 => 34,        // R16G16_SFLOAT
+        91 => 11,        // R16G16B16A16_UNORM
+        97 => 10,        // R16G16B16A16_SFLOAT
+        100 => 41,       // R32_SFLOAT
+        103 => 16,       // R32G32_SFLOAT
+        109 => 2,        // R32G32B32A32_SFLOAT
+        122 => 26,       // B10G11R11_UFLOAT_PACK32
+        123 => 67,       // E5B9G9R9_UFLOAT_PACK32
+        131 | 133 => 71, // BC1_RGB(A)_UNORM_BLOCK
+        132 | 134 => 72, // BC1_RGB(A)_SRGB_BLOCK
+        135 => 74,       // BC2_UNORM_BLOCK
+        136 => 75,       // BC2_SRGB_BLOCK
+        137 => 77,       // BC3_UNORM_BLOCK
+        138 => 78,       // BC3_SRGB_BLOCK
+        139 => 80,       // BC4_UNORM_BLOCK
+        140 => 81,       // BC4_SNORM_BLOCK
+        141 => 83,       // BC5_UNORM_BLOCK
+        142 => 84,       // BC5_SNORM_BLOCK
+        143 => 95,       // BC6H_UFLOAT_BLOCK
+        144 => 96,       // BC6H_SFLOAT_BLOCK
+        145 => 98,       // BC7_UNORM_BLOCK
+        146 => 99,       // BC7_SRGB_BLOCK
+        _ => return None,
+    })
+}
+
+/// Location of one level in the file, from the level index.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Level {
+    pub offset: u64,
+    /// Size in the file, after supercompression.
+    pub size: u64,
+    /// Size of the tightly packed images of all layers and faces.
+    pub uncompressed_size: u64,
+}
+
+/// A parsed KTX2 file.
+///
+/// For uncompressed files [`Subresource::offset`] in [`Ktx2Texture::layout`] is the offset in
+/// the file.  For supercompressed files it is the offset in the uncompressed data of its level.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ktx2Texture {
+    pub vk_format: u32,
+    pub supercompression: Supercompression,
+    /// Indexed by mip level.
+    pub levels: Vec<Level>,
+    pub layout: TextureLayout,
+}
+
+/// A group of subresources of a single level that can be loaded by one
+/// [`DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE`] request.
+///
+/// [`DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE`]: crate::DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RangeRequest {
+    pub level: u32,
+    pub subresources: Range<u32>,
+    pub file_offset: u64,
+    pub file_size: u64,
+    /// Size of the subresources in the layout DirectStorage expects, see
+    /// [`TextureLayout::footprints()`].
+    pub uncompressed_size: u64,
+    /// The file range is Zstandard-supercompressed.  The custom decompressor has to write the
+    /// decompressed level in the layout DirectStorage expects, for example with
+    /// [`TextureLayout::repack()`].
+    pub zstd: bool,
+    /// The file range is uncompressed and already in the layout DirectStorage expects.
+    pub footprint_compatible: bool,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Size of the header and level index of a file with `level_count` levels.
+pub fn header_size(level_count: u32) -> usize {
+    HEADER_SIZE + level_count.max(1) as usize * LEVEL_INDEX_ENTRY_SIZE
+}
+
+/// Parse the header and level index at the start of a KTX2 file of `file_size` bytes.  `header`
+/// must contain at least the first [`header_size()`] bytes of the file.
+pub fn parse_header(header: &[u8], file_size: u64) -> Result<Ktx2Texture, Ktx2Error> {
+    let truncated = |required: usize| Ktx2Error::Truncated {
+        required: required as u64,
+        len: header.len() as u64,
+    };
+
+    if header.len() < IDENTIFIER.len() || header[..12] != IDENTIFIER {
+        return Err(Ktx2Error::Identifier);
+    }
+    if header.len() < HEADER_SIZE {
+        return Err(truncated(HEADER_SIZE));
+    }
+    let vk_format = read_u32(header, 12);
+    let width = read_u32(header, 20);
+    let height = read_u32(header, 24);
+    let depth = read_u32(header, 28);
+    let layer_count = read_u32(header, 32);
+    let face_count = read_u32(header, 36);
+    let level_count = read_u32(header, 40);
+    let supercompression = Supercompression::from(read_u32(header, 44));
+
+    match supercompression {
+        Supercompression::None | Supercompression::Zstandard => {}
+        scheme => return Err(Ktx2Error::UnsupportedSupercompression(scheme)),
+    }
+    let dxgi_format = dxgi_format(vk_format).ok_or(Ktx2Error::UnsupportedVkFormat(vk_format))?;
+    let format = FormatInfo::from_dxgi_format(dxgi_format)
+        .ok_or(Ktx2Error::UnsupportedVkFormat(vk_format))?;
+
+    let dimension = match (height, depth) {
+        (0, 0) => Dimension::Texture1D,
+        (_, 0) => Dimension::Texture2D,
+        _ => Dimension::Texture3D,
+    };
+    let (height, depth) = (height.max(1), depth.max(1));
+    if width == 0 {
+        return Err(Ktx2Error::Header("zero-sized texture"));
+    }
+    let is_cube = match face_count {
+        1 => false,
+        6 if dimension == Dimension::Texture2D && width == height => true,
+        _ => return Err(Ktx2Error::Header("invalid faceCount")),
+    };
+    if dimension == Dimension::Texture3D && layer_count > 0 {
+        return Err(Ktx2Error::Header("3D textures can't have layers"));
+    }
+    // A level count of zero asks the loader to generate mips, only the base level is stored.
+    let mip_levels = level_count.max(1);
+    if mip_levels > 32 - width.max(height).max(depth).leading_zeros() {
+        return Err(Ktx2Error::Header("too many levels"));
+    }
+    let array_size = layer_count
+        .max(1)
+        .checked_mul(face_count)
+        .ok_or(Ktx2Error::Header("invalid layerCount"))?;
+    let count = num_subresources(mip_levels, array_size)
+        .ok_or(Ktx2Error::Header("too many subresources"))?;
+
+    let index_end = header_size(level_count);
+    if header.len() < index_end {
+        return Err(truncated(index_end));
+    }
+    let levels: Vec<Level> = (0..mip_levels as usize)
+        .map(|i| {
+            let entry = HEADER_SIZE + i * LEVEL_INDEX_ENTRY_SIZE;
+            Level {
+                offset: read_u64(header, entry),
+                size: read_u64(header, entry + 8),
+                uncompressed_size: read_u64(header, entry + 16),
+            }
+        })
+        .collect();
+
+    // Check the level index against the images it should hold before allocating their layout.
+    let mut images = Vec::with_capacity(levels.len());
+    for (mip_level, level) in (0..mip_levels).zip(&levels) {
+        let width = (width >> mip_level).max(1);
+        let height = (height >> mip_level).max(1);
+        let depth = (depth >> mip_level).max(1);
+        let row_size = format
+            .row_size(width)
+            .ok_or(Ktx2Error::Header("texture is too large"))?;
+        let num_rows = format.num_rows(height);
+        let size = (row_size as u64 * num_rows as u64)
+            .checked_mul(depth as u64)
+            .ok_or(Ktx2Error::Header("texture is too large"))?;
+        if size.checked_mul(array_size as u64) != Some(level.uncompressed_size)
+            || (supercompression == Supercompression::None && level.size != level.uncompressed_size)
+        {
+            return Err(Ktx2Error::Header("level size does not match its images"));
+        }
+        let end = level.offset.saturating_add(level.size);
+        if end > file_size {
+            return Err(Ktx2Error::Truncated {
+                required: end,
+                len: file_size,
+            });
+        }
+        images.push((width, height, depth, row_size, num_rows, size));
+    }
+
+    let mut subresources = Vec::with_capacity(count as usize);
+    for array_slice in 0..array_size {
+        for ((mip_level, level), &(width, height, depth, row_size, num_rows, size)) in
+            (0..mip_levels).zip(&levels).zip(&images)
+        {
+            let base = match supercompression {
+                Supercompression::None => level.offset,
+                _ => 0,
+            };
+            subresources.push(Subresource {
+                index: mip_level + array_slice * mip_levels,
+                mip_level,
+                array_slice,
+                width,
+                height,
+                depth,
+                row_size,
+                num_rows,
+                offset: base + array_slice as u64 * size,
+                size,
+            });
+        }
+    }
+
+    Ok(Ktx2Texture {
+        vk_format,
+        supercompression,
+        levels,
+        layout: TextureLayout {
+            dxgi_format,
+            dimension,
+            width,
+            height,
+            depth,
+            mip_levels,
+            array_size,
+            is_cube,
+            subresources,
+        },
+    })
+}
+
+/// Parse a KTX2 file that is completely in memory.
+pub fn parse(data: &[u8]) -> Result<Ktx2Texture, Ktx2Error> {
+    parse_header(data, data.len() as u64)
+}
+
+impl Ktx2Texture {
+    /// Group the images of every level into requests, in order of mip level.
+    ///
+    /// Without a mip chain all layers and faces of the only level form one request; otherwise
+    /// every image becomes its own request.  Supercompressed levels can't be split up, so they
+    /// return [`Ktx2Error::SplitSupercompressedLevel`] when the texture has both a mip chain
+    /// and multiple array slices.
+    pub fn range_requests(&self) -> Result<Vec<RangeRequest>, Ktx2Error> {
+        let layout = &self.layout;
+        let zstd = self.supercompression == Supercompression::Zstandard;
+        let mut requests = Vec::new();
+        for (mip_level, level) in (0..layout.mip_levels).zip(&self.levels) {
+            let ranges: Vec<Range<u32>> = if layout.mip_levels == 1 {
+                std::iter::once(0..layout.array_size).collect()
+            } else if layout.array_size == 1 || !zstd {
+                (0..layout.array_size)
+                    .map(|slice| {
+                        let index = mip_level + slice * layout.mip_levels;
+                        index..index + 1
+                    })
+                    .collect()
+            } else {
+                return Err(Ktx2Error::SplitSupercompressedLevel { level: mip_level });
+            };
+
+            for subresources in ranges {
+                let uncompressed_size = layout.footprints(subresources.clone()).total_size;
+                let request = if zstd {
+                    RangeRequest {
+                        level: mip_level,
+                        subresources,
+                        file_offset: level.offset,
+                        file_size: level.size,
+                        uncompressed_size,
+                        zstd,
+                        footprint_compatible: false,
+                    }
+                } else {
+                    let first = &layout.subresources[subresources.start as usize];
+                    let last = &layout.subresources[subresources.end as usize - 1];
+                    RangeRequest {
+                        level: mip_level,
+                        file_offset: first.offset,
+                        file_size: last.offset + last.size - first.offset,
+                        uncompressed_size,
+                        zstd,
+                        footprint_compatible: layout.is_footprint_compatible(subresources.clone()),
+                        subresources,
+                    }
+                };
+                requests.push(request);
+            }
+        }
+        Ok(requests)
+    }
+}
+
+impl RangeRequest {
+    /// Build the request loading this range from `file` into `resource`.  Zstandard
+    /// supercompressed data is tagged with `zstd_format`, usually [`ZSTD_COMPRESSION_FORMAT`].
+    ///
+    /// Returns [`None`] for uncompressed data that is not
+    /// [footprint compatible](RangeRequest::footprint_compatible); it has to be read into memory
+    /// and [repacked](TextureLayout::repack()) instead.  Fails with
+    /// [`Ktx2Error::RequestTooLarge`] when the file range or its uncompressed size doesn't fit in
+    /// a single request.
+    ///
+    /// # Safety
+    /// Borrows `file` and `resource` through [`readonly_copy()`], the returned request must not
+    /// outlive them.
+    #[cfg(windows)]
+    pub unsafe fn request(
+        &self,
+        file: &IDStorageFile,
+        resource: &ID3D12Resource,
+        zstd_format: DSTORAGE_COMPRESSION_FORMAT,
+    ) -> Result<Option<DSTORAGE_REQUEST>, Ktx2Error> {
+        if !self.zstd && !self.footprint_compatible {
+            return Ok(None);
+        }
+        let size = |size: u64| {
+            u32::try_from(size).map_err(|_| Ktx2Error::RequestTooLarge {
+                level: self.level,
+                size,
+            })
+        };
+        let file_size = size(self.file_size)?;
+        let uncompressed_size = size(self.uncompressed_size)?;
+
+        let mut request = DSTORAGE_REQUEST {
+            Source: DSTORAGE_SOURCE {
+                File: std::mem::ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                    Source: unsafe { readonly_copy(file) },
+                    Offset: self.file_offset,
+                    Size: file_size,
+                }),
+            },
+            Destination: DSTORAGE_DESTINATION {
+                MultipleSubresourcesRange: std::mem::ManuallyDrop::new(
+                    DSTORAGE_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE {
+                        Resource: unsafe { readonly_copy(resource) },
+                        FirstSubresource: self.subresources.start,
+                        NumSubresources: self.subresources.len() as u32,
+                    },
+                ),
+            },
+            UncompressedSize: uncompressed_size,
+            ..Default::default()
+        };
+        request.Options.set_CompressionFormat(if self.zstd {
+            zstd_format
+        } else {
+            DSTORAGE_COMPRESSION_FORMAT_NONE
+        });
+        request.Options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+        request
+            .Options
+            .set_DestinationType(DSTORAGE_REQUEST_DESTINATION_MULTIPLE_SUBRESOURCES_RANGE);
+        Ok(Some(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `VK_FORMAT_R8G8B8A8_UNORM`, 64x2 with 2 layers and 2 levels, smallest level first.
+    const RGBA8_ARRAY: &[u8] = include_bytes!("testdata/rgba8_array.ktx2");
+    /// `VK_FORMAT_BC7_UNORM_BLOCK`, 16x16 with 3 Zstandard-supercompressed levels.
+    const BC7_ZSTD: &[u8] = include_bytes!("testdata/bc7_zstd.ktx2");
+
+    #[test]
+    fn test_rgba8_array() {
+        let texture = parse(RGBA8_ARRAY).unwrap();
+        assert_eq!(texture.supercompression, Supercompression::None);
+        assert_eq!(
+            texture.levels,
+            [
+                Level {
+                    offset: 384,
+                    size: 1024,
+                    uncompressed_size: 1024
+                },
+                Level {
+                    offset: 128,
+                    size: 256,
+                    uncompressed_size: 256
+                },
+            ]
+        );
+
+        let layout = &texture.layout;
+        assert_eq!(layout.dxgi_format, 28);
+        assert_eq!((layout.mip_levels, layout.array_size), (2, 2));
+        let offsets: Vec<_> = layout.subresources.iter().map(|s| s.offset).collect();
+        assert_eq!(offsets, [384, 128, 384 + 512, 128 + 128]);
+
+        let requests = texture.range_requests().unwrap();
+        let ranges: Vec<_> = requests
+            .iter()
+            .map(|r| (r.subresources.clone(), r.file_offset, r.file_size))
+            .collect();
+        assert_eq!(
+            ranges,
+            [
+                (0..1, 384, 512),
+                (2..3, 896, 512),
+                (1..2, 128, 128),
+                (3..4, 256, 128),
+            ]
+        );
+        assert!(requests[0].footprint_compatible);
+        assert_eq!(requests[0].uncompressed_size, 512);
+        assert!(requests[2].footprint_compatible);
+    }
+
+    #[test]
+    fn test_bc7_zstd() {
+        let texture = parse(BC7_ZSTD).unwrap();
+        assert_eq!(texture.supercompression, Supercompression::Zstandard);
+        assert_eq!(texture.layout.dxgi_format, 98);
+        assert_eq!(texture.layout.subresources[1].offset, 0);
+
+        let requests = texture.range_requests().unwrap();
+        assert_eq!(
+            requests[0],
+            RangeRequest {
+                level: 0,
+                subresources: 0..1,
+                file_offset: 192,
+                file_size: 24,
+                // 4 rows of 64 bytes, padded to 256.
+                uncompressed_size: 3 * 256 + 64,
+                zstd: true,
+                footprint_compatible: false,
+            }
+        );
+        assert_eq!(requests[2].file_offset, 160);
+        assert_eq!(requests[2].uncompressed_size, 16);
+    }
+
+    #[test]
+    fn test_split_supercompressed_level() {
+        let mut data = RGBA8_ARRAY.to_vec();
+        data[44..48].copy_from_slice(&2u32.to_le_bytes());
+        let texture = parse(&data).unwrap();
+        assert_eq!(
+            texture.range_requests(),
+            Err(Ktx2Error::SplitSupercompressedLevel { level: 0 })
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse(&RGBA8_ARRAY[1..]), Err(Ktx2Error::Identifier));
+        assert_eq!(
+            parse(&RGBA8_ARRAY[..100]),
+            Err(Ktx2Error::Truncated {
+                required: 128,
+                len: 100
+            })
+        );
+        assert_eq!(
+            parse(&RGBA8_ARRAY[..1000]),
+            Err(Ktx2Error::Truncated {
+                required: 1408,
+                len: 1000
+            })
+        );
+
+        let mut data = RGBA8_ARRAY.to_vec();
+        data[44..48].copy_from_slice(&1u32.to_le_bytes());
+        assert_eq!(
+            parse(&data),
+            Err(Ktx2Error::UnsupportedSupercompression(
+                Supercompression::BasisLz
+            ))
+        );
+
+        data[44..48].copy_from_slice(&0u32.to_le_bytes());
+        // VK_FORMAT_ASTC_4x4_UNORM_BLOCK
+        data[12..16].copy_from_slice(&157u32.to_le_bytes());
+        assert_eq!(parse(&data), Err(Ktx2Error::UnsupportedVkFormat(157)));
+    }
+
+    #[test]
+    fn test_hostile_level_index() {
+        let mut data = RGBA8_ARRAY.to_vec();
+        data[32..36].copy_from_slice(&0x10000000u32.to_le_bytes());
+        assert_eq!(
+            parse(&data),
+            Err(Ktx2Error::Header("too many subresources"))
+        );
+
+        // 2^29 x 2^29 RGBA8 images of 16 layers overflow the level size.
+        let mut data = RGBA8_ARRAY.to_vec();
+        data[20..24].copy_from_slice(&(1u32 << 29).to_le_bytes());
+        data[24..28].copy_from_slice(&(1u32 << 29).to_le_bytes());
+        data[32..36].copy_from_slice(&16u32.to_le_bytes());
+        assert_eq!(
+            parse(&data),
+            Err(Ktx2Error::Header("level size does not match its images"))
+        );
+
+        let mut data = RGBA8_ARRAY.to_vec();
+        data[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(
+            parse(&data),
+            Err(Ktx2Error::Truncated {
+                required: u64::MAX,
+                len: RGBA8_ARRAY.len() as u64
+            })
+        );
+    }
+}
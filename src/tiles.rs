@@ -0,0 +1,538 @@
+//! Tile grids of reserved (tiled) resources and [`DSTORAGE_REQUEST_DESTINATION_TILES`]
+//! requests.
+//!
+//! Reserved resources are divided into 64 KiB tiles whose shape depends on the format and
+//! dimension of the resource.  The smallest mips, which don't fill a single tile, are packed
+//! into a mip tail that can only be loaded as a whole.  [`TileGrid`] computes the standard
+//! layout; on hardware that packs differently it can be corrected with the values reported by
+//! `ID3D12Device::GetResourceTiling()` through [`TileGrid::with_packed_mips()`].
+//!
+//! Tiles are assumed to be stored in the file per array slice, then per standard mip, in
+//! X-major, then Y, then Z order, followed by the packed mip tail of that slice.  This matches
+//! the order in which DirectStorage consumes a [`D3D12_TILE_REGION_SIZE`] without `UseBox`, so
+//! consecutive tiles can be loaded with a single request.
+//!
+//! [`DSTORAGE_REQUEST_DESTINATION_TILES`]: crate::DSTORAGE_REQUEST_DESTINATION_TILES
+//! [`D3D12_TILE_REGION_SIZE`]: windows::Win32::Graphics::Direct3D12::D3D12_TILE_REGION_SIZE
+
+#[cfg(windows)]
+use windows::Win32::Graphics::Direct3D12::{
+    ID3D12Resource, D3D12_TILED_RESOURCE_COORDINATE, D3D12_TILE_REGION_SIZE,
+};
+
+use crate::textures::{Dimension, FormatInfo};
+#[cfg(windows)]
+use crate::{
+    readonly_copy, textures::RequestTooLarge, IDStorageFile, DSTORAGE_DESTINATION,
+    DSTORAGE_DESTINATION_TILES, DSTORAGE_REQUEST, DSTORAGE_REQUEST_DESTINATION_TILES,
+    DSTORAGE_REQUEST_SOURCE_FILE, DSTORAGE_SOURCE, DSTORAGE_SOURCE_FILE,
+};
+
+pub mod residency;
+pub use residency::{FrameBatch, ResidencyManager, ResourceId, TileLoad, TileMapping};
+
+/// `D3D12_TILED_RESOURCE_TILE_SIZE_IN_BYTES`
+pub const TILE_SIZE: u32 = 64 * 1024;
+
+/// Size of a single tile in texels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileShape {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+impl TileShape {
+    /// Standard tile shape for `format`, as given by `D3D12_TILE_SHAPE` for standard swizzle.
+    /// Returns [`None`] for 1D textures, which can't be tiled, and for block sizes that don't
+    /// divide a tile.
+    pub fn new(format: FormatInfo, dimension: Dimension) -> Option<Self> {
+        // Shape in blocks, a tile always holds 64 KiB of blocks.
+        let (width, height, depth) = match (dimension, format.bytes_per_block) {
+            (Dimension::Texture1D, _) => return None,
+            (Dimension::Texture2D, 1) => (256, 256, 1),
+            (Dimension::Texture2D, 2) => (256, 128, 1),
+            (Dimension::Texture2D, 4) => (128, 128, 1),
+            (Dimension::Texture2D, 8) => (128, 64, 1),
+            (Dimension::Texture2D, 16) => (64, 64, 1),
+            (Dimension::Texture3D, 1) => (64, 32, 32),
+            (Dimension::Texture3D, 2) => (32, 32, 32),
+            (Dimension::Texture3D, 4) => (32, 32, 16),
+            (Dimension::Texture3D, 8) => (32, 16, 16),
+            (Dimension::Texture3D, 16) => (16, 16, 16),
+            _ => return None,
+        };
+        Some(Self {
+            width: width * format.block_width,
+            height: height * format.block_height,
+            depth,
+        })
+    }
+}
+
+/// Number of tiles covering a standard (not packed) mip level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MipTiles {
+    pub width: u32,
+    pub height: u32,
+    pub depth: u32,
+}
+
+impl MipTiles {
+    pub fn count(&self) -> u64 {
+        self.width as u64 * self.height as u64 * self.depth as u64
+    }
+}
+
+/// A single tile of a reserved resource.  Any tile with a `mip_level` at or past
+/// [`TileGrid::standard_mips()`] refers to the packed mip tail of its array slice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TileCoord {
+    pub array_slice: u32,
+    pub mip_level: u32,
+    pub z: u32,
+    pub y: u32,
+    pub x: u32,
+}
+
+/// A run of tiles that are consecutive in both the resource and the file, loaded by one
+/// [`DSTORAGE_REQUEST_DESTINATION_TILES`](crate::DSTORAGE_REQUEST_DESTINATION_TILES) request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TileRequest {
+    /// Subresource of the first tile, `D3D12_TILED_RESOURCE_COORDINATE::Subresource`.
+    pub subresource: u32,
+    pub x: u32,
+    pub y: u32,
+    pub z: u32,
+    pub num_tiles: u32,
+    /// Offset of the first tile relative to the start of the tile data in the file.
+    pub file_offset: u64,
+}
+
+impl TileRequest {
+    pub fn size(&self) -> u64 {
+        self.num_tiles as u64 * TILE_SIZE as u64
+    }
+
+    /// Describe this run of tiles as a [`DSTORAGE_DESTINATION_TILES`].
+    ///
+    /// # Safety
+    /// Borrows `resource` through [`readonly_copy()`], the returned destination must not outlive
+    /// it.
+    #[cfg(windows)]
+    pub unsafe fn destination(&self, resource: &ID3D12Resource) -> DSTORAGE_DESTINATION_TILES {
+        DSTORAGE_DESTINATION_TILES {
+            Resource: unsafe { readonly_copy(resource) },
+            TiledRegionStartCoordinate: D3D12_TILED_RESOURCE_COORDINATE {
+                X: self.x,
+                Y: self.y,
+                Z: self.z,
+                Subresource: self.subresource,
+            },
+            TileRegionSize: D3D12_TILE_REGION_SIZE {
+                NumTiles: self.num_tiles,
+                UseBox: false.into(),
+                Width: 0,
+                Height: 0,
+                Depth: 0,
+            },
+        }
+    }
+
+    /// Build a request loading this run of tiles from `file`, whose tile data starts at
+    /// `base_offset`.  Fails with [`RequestTooLarge`] for runs of 4 GiB or more, which need to
+    /// be split.
+    ///
+    /// # Safety
+    /// Borrows `file` and `resource` through [`readonly_copy()`], the returned request must not
+    /// outlive them.
+    #[cfg(windows)]
+    pub unsafe fn request(
+        &self,
+        file: &IDStorageFile,
+        base_offset: u64,
+        resource: &ID3D12Resource,
+    ) -> Result<DSTORAGE_REQUEST, RequestTooLarge> {
+        let size = self.size();
+        let size = u32::try_from(size).map_err(|_| RequestTooLarge { size })?;
+        let mut request = DSTORAGE_REQUEST {
+            Source: DSTORAGE_SOURCE {
+                File: std::mem::ManuallyDrop::new(DSTORAGE_SOURCE_FILE {
+                    Source: unsafe { readonly_copy(file) },
+                    Offset: base_offset + self.file_offset,
+                    Size: size,
+                }),
+            },
+            Destination: DSTORAGE_DESTINATION {
+                Tiles: std::mem::ManuallyDrop::new(unsafe { self.destination(resource) }),
+            },
+            UncompressedSize: size,
+            ..Default::default()
+        };
+        request.Options.set_SourceType(DSTORAGE_REQUEST_SOURCE_FILE);
+        request
+            .Options
+            .set_DestinationType(DSTORAGE_REQUEST_DESTINATION_TILES);
+        Ok(request)
+    }
+}
+
+/// Tile layout of a reserved 2D (array) or 3D texture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileGrid {
+    pub shape: TileShape,
+    /// Tile counts of the standard mips, indexed by mip level.
+    pub mips: Vec<MipTiles>,
+    pub mip_levels: u32,
+    pub array_size: u32,
+    /// Number of tiles holding the packed mip tail of one array slice.
+    pub packed_tiles: u32,
+}
+
+impl TileGrid {
+    /// Compute the standard tile layout.  A mip is packed once it no longer fills a whole tile
+    /// in every dimension.
+    pub fn new(
+        format: FormatInfo,
+        dimension: Dimension,
+        (width, height, depth): (u32, u32, u32),
+        mip_levels: u32,
+        array_size: u32,
+    ) -> Option<Self> {
+        let shape = TileShape::new(format, dimension)?;
+        let mut mips = Vec::new();
+        // Estimate the packed mip tail from the size of its tightly packed mips.
+        let mut packed_size = 0;
+        for mip_level in 0..mip_levels {
+            let width = (width >> mip_level).max(1);
+            let height = (height >> mip_level).max(1);
+            let depth = (depth >> mip_level).max(1);
+            if mips.len() == mip_level as usize
+                && width >= shape.width
+                && height >= shape.height
+                && depth >= shape.depth
+            {
+                mips.push(MipTiles {
+                    width: width.div_ceil(shape.width),
+                    height: height.div_ceil(shape.height),
+                    depth: depth.div_ceil(shape.depth),
+                });
+            } else {
+                packed_size +=
+                    format.row_size(width)? as u64 * format.num_rows(height) as u64 * depth as u64;
+            }
+        }
+
+        Some(Self {
+            shape,
+            mips,
+            mip_levels,
+            array_size,
+            packed_tiles: packed_size.div_ceil(TILE_SIZE as u64) as u32,
+        })
+    }
+
+    /// Override the number of standard mips and tiles of the packed mip tail, using
+    /// `D3D12_PACKED_MIP_INFO::NumStandardMips` and `NumTilesForPackedMips`.
+    pub fn with_packed_mips(mut self, standard_mips: u32, packed_tiles: u32) -> Self {
+        self.mips.truncate(standard_mips as usize);
+        self.packed_tiles = packed_tiles;
+        self
+    }
+
+    pub fn standard_mips(&self) -> u32 {
+        self.mips.len() as u32
+    }
+
+    pub fn has_packed_mips(&self) -> bool {
+        self.standard_mips() < self.mip_levels
+    }
+
+    /// D3D12 subresource index of `mip_level` in `array_slice`.
+    pub fn subresource(&self, mip_level: u32, array_slice: u32) -> u32 {
+        mip_level + array_slice * self.mip_levels
+    }
+
+    /// Number of tiles of one array slice, including its packed mip tail.
+    pub fn tiles_per_slice(&self) -> u64 {
+        self.mips.iter().map(MipTiles::count).sum::<u64>() + self.packed_tiles as u64
+    }
+
+    pub fn total_tiles(&self) -> u64 {
+        self.tiles_per_slice() * self.array_size as u64
+    }
+
+    /// Index of `tile` in the file, see the [module documentation](self) for the order.  Tiles
+    /// of the packed mip tail return the index of the first tile of the tail.
+    pub fn tile_index(&self, tile: TileCoord) -> Option<u64> {
+        if tile.array_slice >= self.array_size || tile.mip_level >= self.mip_levels {
+            return None;
+        }
+        let slice_start = tile.array_slice as u64 * self.tiles_per_slice();
+        let mip_start: u64 = self
+            .mips
+            .iter()
+            .take(tile.mip_level as usize)
+            .map(MipTiles::count)
+            .sum();
+        let Some(mip) = self.mips.get(tile.mip_level as usize) else {
+            return Some(slice_start + mip_start);
+        };
+        if tile.x >= mip.width || tile.y >= mip.height || tile.z >= mip.depth {
+            return None;
+        }
+        let linear = tile.x as u64
+            + tile.y as u64 * mip.width as u64
+            + tile.z as u64 * mip.width as u64 * mip.height as u64;
+        Some(slice_start + mip_start + linear)
+    }
+
+    /// Offset of `tile` relative to the start of the tile data in the file.
+    pub fn file_offset(&self, tile: TileCoord) -> Option<u64> {
+        self.tile_index(tile).map(|i| i * TILE_SIZE as u64)
+    }
+
+    /// The coordinate all tiles of the packed mip tail of `array_slice` are normalized to.
+    pub fn packed_tail(&self, array_slice: u32) -> TileCoord {
+        TileCoord {
+            array_slice,
+            mip_level: self.standard_mips(),
+            z: 0,
+            y: 0,
+            x: 0,
+        }
+    }
+
+    /// The tiles loaded by `request`, which must have been created by [`TileGrid::requests()`].
+    /// Requests for (part of) a packed mip tail return [`TileGrid::packed_tail()`].
+    pub fn request_tiles(&self, request: &TileRequest) -> Vec<TileCoord> {
+        let mip_level = request.subresource % self.mip_levels;
+        let array_slice = request.subresource / self.mip_levels;
+        let Some(mip) = self.mips.get(mip_level as usize) else {
+            return vec![self.packed_tail(array_slice)];
+        };
+
+        let start = request.x + (request.y + request.z * mip.height) * mip.width;
+        (start..start + request.num_tiles)
+            .map(|i| TileCoord {
+                array_slice,
+                mip_level,
+                z: i / (mip.width * mip.height),
+                y: i / mip.width % mip.height,
+                x: i % mip.width,
+            })
+            .collect()
+    }
+
+    /// Coalesce `tiles` into the fewest requests of at most `max_tiles_per_request` tiles each,
+    /// typically the staging buffer size divided by [`TILE_SIZE`].
+    ///
+    /// Duplicates and tiles outside the grid are ignored.  Requesting any tile of a packed mip
+    /// loads the whole packed mip tail of that array slice.
+    pub fn requests(
+        &self,
+        tiles: impl IntoIterator<Item = TileCoord>,
+        max_tiles_per_request: u32,
+    ) -> Vec<TileRequest> {
+        let max_tiles_per_request = max_tiles_per_request.max(1);
+        let standard_mips = self.standard_mips();
+
+        let mut tiles: Vec<TileCoord> = tiles
+            .into_iter()
+            .filter(|t| self.tile_index(*t).is_some())
+            .map(|t| {
+                if t.mip_level >= standard_mips {
+                    self.packed_tail(t.array_slice)
+                } else {
+                    t
+                }
+            })
+            .collect();
+        // Sorting by (slice, mip, z, y, x) is the same as sorting by file order.
+        tiles.sort_unstable();
+        tiles.dedup();
+
+        let mut requests: Vec<TileRequest> = Vec::new();
+        let mut previous: Option<(u64, TileCoord)> = None;
+        for tile in tiles {
+            let index = self.tile_index(tile).unwrap();
+
+            if tile.mip_level == standard_mips {
+                // The packed mip tail is addressed as a linear run of tiles of its first mip.
+                let mut remaining = self.packed_tiles;
+                let mut x = 0;
+                while remaining > 0 {
+                    let num_tiles = remaining.min(max_tiles_per_request);
+                    requests.push(TileRequest {
+                        subresource: self.subresource(tile.mip_level, tile.array_slice),
+                        x,
+                        y: 0,
+                        z: 0,
+                        num_tiles,
+                        file_offset: (index + x as u64) * TILE_SIZE as u64,
+                    });
+                    x += num_tiles;
+                    remaining -= num_tiles;
+                }
+                previous = None;
+                continue;
+            }
+
+            let extends = previous.is_some_and(|(prev_index, prev)| {
+                prev_index + 1 == index
+                    && prev.array_slice == tile.array_slice
+                    && prev.mip_level == tile.mip_level
+            });
+            match requests.last_mut() {
+                Some(last) if extends && last.num_tiles < max_tiles_per_request => {
+                    last.num_tiles += 1;
+                }
+                _ => requests.push(TileRequest {
+                    subresource: self.subresource(tile.mip_level, tile.array_slice),
+                    x: tile.x,
+                    y: tile.y,
+                    z: tile.z,
+                    num_tiles: 1,
+                    file_offset: index * TILE_SIZE as u64,
+                }),
+            }
+            previous = Some((index, tile));
+        }
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bc7() -> FormatInfo {
+        FormatInfo::from_dxgi_format(98).unwrap()
+    }
+
+    fn tile(mip_level: u32, x: u32, y: u32) -> TileCoord {
+        TileCoord {
+            array_slice: 0,
+            mip_level,
+            z: 0,
+            y,
+            x,
+        }
+    }
+
+    #[test]
+    fn test_tile_shape() {
+        let rgba8 = FormatInfo::from_dxgi_format(28).unwrap();
+        let shape = |format, dimension| {
+            let s = TileShape::new(format, dimension).unwrap();
+            (s.width, s.height, s.depth)
+        };
+        assert_eq!(shape(rgba8, Dimension::Texture2D), (128, 128, 1));
+        assert_eq!(shape(rgba8, Dimension::Texture3D), (32, 32, 16));
+        assert_eq!(shape(bc7(), Dimension::Texture2D), (256, 256, 1));
+        // DXGI_FORMAT_BC1_UNORM
+        let bc1 = FormatInfo::from_dxgi_format(71).unwrap();
+        assert_eq!(shape(bc1, Dimension::Texture2D), (512, 256, 1));
+        assert_eq!(TileShape::new(rgba8, Dimension::Texture1D), None);
+    }
+
+    #[test]
+    fn test_grid() {
+        // 1024x512 BC7 with a full mip chain: mips 0 and 1 are standard.
+        let grid = TileGrid::new(bc7(), Dimension::Texture2D, (1024, 512, 1), 11, 2).unwrap();
+        assert_eq!(
+            grid.mips,
+            [
+                MipTiles {
+                    width: 4,
+                    height: 2,
+                    depth: 1
+                },
+                MipTiles {
+                    width: 2,
+                    height: 1,
+                    depth: 1
+                },
+            ]
+        );
+        assert!(grid.has_packed_mips());
+        // The 256x128 mip and smaller need 32 KiB + 8 KiB + ... of blocks, which fits one tile.
+        assert_eq!(grid.packed_tiles, 1);
+        assert_eq!(grid.tiles_per_slice(), 11);
+        assert_eq!(grid.total_tiles(), 22);
+
+        assert_eq!(grid.tile_index(tile(0, 3, 1)), Some(7));
+        assert_eq!(grid.tile_index(tile(1, 1, 0)), Some(9));
+        assert_eq!(grid.tile_index(tile(5, 0, 0)), Some(10));
+        assert_eq!(grid.tile_index(tile(0, 4, 0)), None);
+        let mut second_slice = tile(1, 0, 0);
+        second_slice.array_slice = 1;
+        assert_eq!(grid.file_offset(second_slice), Some(19 * TILE_SIZE as u64));
+
+        let grid = grid.with_packed_mips(1, 3);
+        assert_eq!(grid.tiles_per_slice(), 11);
+        assert_eq!(grid.tile_index(tile(1, 0, 0)), Some(8));
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let grid = TileGrid::new(bc7(), Dimension::Texture2D, (1024, 512, 1), 11, 2).unwrap();
+        let requests = grid.requests(
+            [
+                tile(0, 3, 0),
+                tile(0, 0, 1),
+                tile(0, 1, 1),
+                tile(0, 2, 0),
+                tile(0, 2, 0),
+                tile(1, 0, 0),
+                tile(7, 0, 0),
+                tile(9, 0, 0),
+                tile(0, 9, 9),
+            ],
+            2,
+        );
+        let summary: Vec<_> = requests
+            .iter()
+            .map(|r| {
+                (
+                    r.subresource,
+                    r.x,
+                    r.y,
+                    r.num_tiles,
+                    r.file_offset / TILE_SIZE as u64,
+                )
+            })
+            .collect();
+        // Row 0 wraps into row 1, and is split at the request size limit.  Mip 1 follows mip 0
+        // in the file, but is a different subresource.
+        assert_eq!(
+            summary,
+            [
+                (0, 2, 0, 2, 2),
+                (0, 0, 1, 2, 4),
+                (1, 0, 0, 1, 8),
+                (2, 0, 0, 1, 10),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_volume() {
+        // DXGI_FORMAT_R16_FLOAT, 32x32x32 tiles.
+        let r16 = FormatInfo::from_dxgi_format(54).unwrap();
+        let grid = TileGrid::new(r16, Dimension::Texture3D, (64, 64, 64), 1, 1).unwrap();
+        assert_eq!(grid.tiles_per_slice(), 8);
+        assert!(!grid.has_packed_mips());
+
+        let tiles = (0..2).map(|z| TileCoord {
+            array_slice: 0,
+            mip_level: 0,
+            z,
+            y: 1,
+            x: 1,
+        });
+        let requests = grid.requests(tiles, 16);
+        assert_eq!(requests.len(), 2);
+        assert_eq!((requests[1].z, requests[1].file_offset), (1, 7 * 65536));
+    }
+}
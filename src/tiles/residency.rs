@@ -0,0 +1,515 @@
+//! Residency management for sparse virtual textures.
+//!
+//! [`ResidencyManager`] keeps a page table per reserved resource, mapping tiles to tiles of a
+//! shared tile pool (a `D3D12_HEAP` of [`ResidencyManager::pool_tiles()`] tiles).  Every frame,
+//! GPU feedback reports which tiles were sampled; [`ResidencyManager::update()`] then evicts the
+//! least recently used tiles that weren't needed this frame to make room, and returns a
+//! [`FrameBatch`] describing the tile mappings to update and the tile requests to enqueue.
+//!
+//! Packed mip tails are the fallback for every other tile, they are loaded at
+//! [`Priority::High`] and before any other tile.  Tiles from feedback are loaded at
+//! [`Priority::Normal`], and tiles passed to [`ResidencyManager::prefetch()`] at
+//! [`Priority::Low`].
+
+use std::collections::HashMap;
+
+use super::{TileCoord, TileGrid, TileRequest};
+use crate::priority::Priority;
+
+/// Identifies a resource added to a [`ResidencyManager`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ResourceId(pub usize);
+
+/// Tile pool tiles mapped to, or unmapped from, a tile of a resource.  Packed mip tails map to
+/// [`TileGrid::packed_tiles`] pool tiles, starting at [`TileGrid::packed_tail()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileMapping {
+    pub resource: ResourceId,
+    pub tile: TileCoord,
+    pub pool_tiles: Vec<u32>,
+}
+
+/// A tile request to enqueue on a queue with [`TileLoad::priority`].  Pass it to
+/// [`ResidencyManager::complete()`] once it has finished.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TileLoad {
+    pub resource: ResourceId,
+    pub request: TileRequest,
+    pub priority: Priority,
+}
+
+/// Work produced by [`ResidencyManager::update()`] for a single frame.
+///
+/// Evicted tiles must be unmapped and new tiles mapped (with
+/// `ID3D12CommandQueue::UpdateTileMappings()`) before the loads are enqueued.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameBatch {
+    pub evicted: Vec<TileMapping>,
+    pub mapped: Vec<TileMapping>,
+    /// Ordered from most to least urgent.
+    pub loads: Vec<TileLoad>,
+    /// Requested tiles that need more tiles than the whole pool holds.  They are dropped
+    /// instead of being retried every frame.
+    pub rejected: Vec<(ResourceId, TileCoord)>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Number of [`TileLoad`]s that haven't completed yet.
+    Loading(u32),
+    Resident,
+}
+
+#[derive(Debug)]
+struct Page {
+    pool_tiles: Vec<u32>,
+    last_used: u64,
+    state: State,
+}
+
+#[derive(Debug)]
+struct Resource {
+    grid: TileGrid,
+    pages: HashMap<TileCoord, Page>,
+    /// Tiles that are not resident yet, with the highest priority they were requested at.
+    requested: HashMap<TileCoord, Priority>,
+}
+
+/// Tracks the residency of tiles of reserved resources within a fixed tile pool budget.
+#[derive(Debug)]
+pub struct ResidencyManager {
+    frame: u64,
+    pool_tiles: u32,
+    free: Vec<u32>,
+    max_tiles_per_request: u32,
+    resources: Vec<Resource>,
+}
+
+impl ResidencyManager {
+    /// Manage a tile pool of `pool_tiles` tiles.  Runs of tiles are split into requests of at
+    /// most `max_tiles_per_request` tiles, see [`TileGrid::requests()`].
+    pub fn new(pool_tiles: u32, max_tiles_per_request: u32) -> Self {
+        Self {
+            frame: 0,
+            pool_tiles,
+            free: (0..pool_tiles).rev().collect(),
+            max_tiles_per_request,
+            resources: Vec::new(),
+        }
+    }
+
+    pub fn add_resource(&mut self, grid: TileGrid) -> ResourceId {
+        self.resources.push(Resource {
+            grid,
+            pages: HashMap::new(),
+            requested: HashMap::new(),
+        });
+        ResourceId(self.resources.len() - 1)
+    }
+
+    pub fn grid(&self, resource: ResourceId) -> &TileGrid {
+        &self.resources[resource.0].grid
+    }
+
+    /// Number of frames completed with [`ResidencyManager::update()`].
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    pub fn pool_tiles(&self) -> u32 {
+        self.pool_tiles
+    }
+
+    /// Number of pool tiles that are not mapped to any resource.
+    pub fn free_tiles(&self) -> u32 {
+        self.free.len() as u32
+    }
+
+    /// Whether `tile` has been loaded and can be sampled.
+    pub fn is_resident(&self, resource: ResourceId, tile: TileCoord) -> bool {
+        let resource = &self.resources[resource.0];
+        let tile = normalize(&resource.grid, tile);
+        resource
+            .pages
+            .get(&tile)
+            .is_some_and(|p| p.state == State::Resident)
+    }
+
+    /// Report tiles sampled by the GPU this frame.  Resident tiles are marked as used, missing
+    /// tiles are requested.  Tiles outside the grid are ignored.
+    pub fn feedback(&mut self, resource: ResourceId, tiles: impl IntoIterator<Item = TileCoord>) {
+        for tile in tiles {
+            self.request(resource, tile, Priority::Normal);
+        }
+    }
+
+    /// Request tiles that are likely to be needed soon, at [`Priority::Low`].
+    pub fn prefetch(&mut self, resource: ResourceId, tiles: impl IntoIterator<Item = TileCoord>) {
+        for tile in tiles {
+            self.request(resource, tile, Priority::Low);
+        }
+    }
+
+    fn request(&mut self, resource_id: ResourceId, tile: TileCoord, priority: Priority) {
+        let frame = self.frame;
+        let resource = &mut self.resources[resource_id.0];
+        if resource.grid.tile_index(tile).is_none() {
+            return;
+        }
+        let tile = normalize(&resource.grid, tile);
+        let priority = if tile.mip_level == resource.grid.standard_mips() {
+            Priority::High
+        } else {
+            priority
+        };
+
+        match resource.pages.get_mut(&tile) {
+            Some(page) => {
+                if priority > Priority::Low {
+                    page.last_used = frame;
+                }
+            }
+            None => {
+                let requested = resource.requested.entry(tile).or_insert(priority);
+                *requested = (*requested).max(priority);
+            }
+        }
+    }
+
+    /// Finish the current frame: allocate pool tiles for requested tiles, evicting least
+    /// recently used tiles that weren't used this frame, and build the loads for them.
+    ///
+    /// Tiles are only evicted for requests that fit once they are, other requests stay pending
+    /// and are retried next frame.  Requests that can never fit are dropped and returned in
+    /// [`FrameBatch::rejected`].
+    pub fn update(&mut self) -> FrameBatch {
+        let mut batch = FrameBatch::default();
+
+        // Most urgent first, coarser mips first as they are the fallback for finer ones.
+        let mut requested: Vec<(Priority, ResourceId, TileCoord)> = self
+            .resources
+            .iter_mut()
+            .enumerate()
+            .flat_map(|(i, r)| {
+                r.requested
+                    .drain()
+                    .map(move |(tile, priority)| (priority, ResourceId(i), tile))
+            })
+            .collect();
+        requested.sort_unstable_by(|a, b| {
+            (b.0, b.2.mip_level, a.1, a.2).cmp(&(a.0, a.2.mip_level, b.1, b.2))
+        });
+
+        // Eviction candidates, least recently used and finest mips first.
+        let mut candidates: Vec<(u64, u32, ResourceId, TileCoord, u32)> = self
+            .resources
+            .iter()
+            .enumerate()
+            .flat_map(|(i, r)| {
+                r.pages
+                    .iter()
+                    .filter(|(_, p)| p.state == State::Resident && p.last_used < self.frame)
+                    .map(move |(tile, p)| {
+                        let tiles = p.pool_tiles.len() as u32;
+                        (p.last_used, tile.mip_level, ResourceId(i), *tile, tiles)
+                    })
+            })
+            .collect();
+        candidates.sort_unstable();
+        let mut evictable: u32 = candidates.iter().map(|c| c.4).sum();
+        let mut candidates = candidates.into_iter();
+
+        let mut allocated: Vec<(Priority, ResourceId, TileCoord)> = Vec::new();
+        let mut pending = Vec::new();
+        for (priority, resource_id, tile) in requested {
+            let needed = self.page_tiles(resource_id, tile);
+            if needed > self.pool_tiles {
+                batch.rejected.push((resource_id, tile));
+                continue;
+            }
+            if self.free.len() as u32 + evictable < needed {
+                pending.push((priority, resource_id, tile));
+                continue;
+            }
+
+            while (self.free.len() as u32) < needed {
+                let (_, _, evict_id, evict_tile, tiles) = candidates.next().unwrap();
+                evictable -= tiles;
+                let page = self.resources[evict_id.0]
+                    .pages
+                    .remove(&evict_tile)
+                    .unwrap();
+                self.free.extend(page.pool_tiles.iter().rev());
+                batch.evicted.push(TileMapping {
+                    resource: evict_id,
+                    tile: evict_tile,
+                    pool_tiles: page.pool_tiles,
+                });
+            }
+
+            let pool_tiles: Vec<u32> = (0..needed).map(|_| self.free.pop().unwrap()).collect();
+            self.resources[resource_id.0].pages.insert(
+                tile,
+                Page {
+                    pool_tiles: pool_tiles.clone(),
+                    last_used: self.frame,
+                    state: State::Loading(0),
+                },
+            );
+            batch.mapped.push(TileMapping {
+                resource: resource_id,
+                tile,
+                pool_tiles,
+            });
+            allocated.push((priority, resource_id, tile));
+        }
+
+        for (priority, resource_id, tile) in pending {
+            self.resources[resource_id.0]
+                .requested
+                .insert(tile, priority);
+        }
+
+        // Coalesce the allocated tiles per priority and resource, keeping the urgent ones first.
+        for priority in Priority::ALL.into_iter().rev() {
+            for (index, resource) in self.resources.iter_mut().enumerate() {
+                let resource_id = ResourceId(index);
+                let tiles = allocated
+                    .iter()
+                    .filter(|(p, r, _)| *p == priority && *r == resource_id)
+                    .map(|(_, _, tile)| *tile);
+                for request in resource.grid.requests(tiles, self.max_tiles_per_request) {
+                    for tile in resource.grid.request_tiles(&request) {
+                        if let Some(Page {
+                            state: State::Loading(n),
+                            ..
+                        }) = resource.pages.get_mut(&tile)
+                        {
+                            *n += 1;
+                        }
+                    }
+                    batch.loads.push(TileLoad {
+                        resource: resource_id,
+                        request,
+                        priority,
+                    });
+                }
+            }
+        }
+
+        self.frame += 1;
+        batch
+    }
+
+    /// Mark the tiles of `load` as resident once its request has completed.
+    pub fn complete(&mut self, load: &TileLoad) {
+        let resource = &mut self.resources[load.resource.0];
+        for tile in resource.grid.request_tiles(&load.request) {
+            if let Some(page) = resource.pages.get_mut(&tile) {
+                if let State::Loading(n) = page.state {
+                    page.state = if n > 1 {
+                        State::Loading(n - 1)
+                    } else {
+                        State::Resident
+                    };
+                }
+            }
+        }
+    }
+
+    fn page_tiles(&self, resource: ResourceId, tile: TileCoord) -> u32 {
+        let grid = &self.resources[resource.0].grid;
+        if tile.mip_level == grid.standard_mips() {
+            grid.packed_tiles
+        } else {
+            1
+        }
+    }
+}
+
+fn normalize(grid: &TileGrid, tile: TileCoord) -> TileCoord {
+    if tile.mip_level >= grid.standard_mips() {
+        grid.packed_tail(tile.array_slice)
+    } else {
+        tile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::textures::{Dimension, FormatInfo};
+
+    /// 1024x1024 BC7 with a full mip chain: 4x4 + 2x2 + 1x1 standard tiles, 1 packed tile.
+    fn grid() -> TileGrid {
+        let bc7 = FormatInfo::from_dxgi_format(98).unwrap();
+        TileGrid::new(bc7, Dimension::Texture2D, (1024, 1024, 1), 11, 1).unwrap()
+    }
+
+    fn tile(mip_level: u32, x: u32, y: u32) -> TileCoord {
+        TileCoord {
+            array_slice: 0,
+            mip_level,
+            z: 0,
+            y,
+            x,
+        }
+    }
+
+    fn complete_all(manager: &mut ResidencyManager, batch: &FrameBatch) {
+        for load in &batch.loads {
+            manager.complete(load);
+        }
+    }
+
+    #[test]
+    fn test_load_order() {
+        let mut manager = ResidencyManager::new(16, 4);
+        let texture = manager.add_resource(grid());
+        manager.prefetch(texture, [tile(1, 1, 1)]);
+        manager.feedback(texture, [tile(0, 0, 0), tile(0, 1, 0), tile(9, 0, 0)]);
+
+        let batch = manager.update();
+        let loads: Vec<_> = batch
+            .loads
+            .iter()
+            .map(|l| (l.priority, l.request.subresource, l.request.num_tiles))
+            .collect();
+        assert_eq!(
+            loads,
+            [
+                (Priority::High, 3, 1),
+                (Priority::Normal, 0, 2),
+                (Priority::Low, 1, 1),
+            ]
+        );
+        assert_eq!(batch.mapped.len(), 4);
+        assert_eq!(manager.free_tiles(), 12);
+
+        assert!(!manager.is_resident(texture, tile(0, 0, 0)));
+        complete_all(&mut manager, &batch);
+        assert!(manager.is_resident(texture, tile(0, 0, 0)));
+        // Any mip of the packed tail is resident once the tail is.
+        assert!(manager.is_resident(texture, tile(10, 0, 0)));
+
+        // Resident tiles are not requested again.
+        manager.feedback(texture, [tile(0, 0, 0)]);
+        assert_eq!(manager.update(), FrameBatch::default());
+    }
+
+    #[test]
+    fn test_simulated_feedback() {
+        // Room for the packed tail and 4 more tiles.
+        let mut manager = ResidencyManager::new(5, 16);
+        let texture = manager.add_resource(grid());
+
+        // A camera panning across mip 0, sampling a 2x1 window of tiles every frame, always
+        // with the packed tail as fallback.
+        let mut evicted = Vec::new();
+        for frame in 0..3 {
+            let window = [tile(0, frame, 0), tile(0, frame + 1, 0), tile(10, 0, 0)];
+            manager.feedback(texture, window);
+            let batch = manager.update();
+            complete_all(&mut manager, &batch);
+            for tile in &window {
+                assert!(manager.is_resident(texture, *tile), "frame {frame}");
+            }
+            evicted.extend(batch.evicted.iter().map(|m| m.tile));
+        }
+        // The whole pan fits in the pool.
+        assert_eq!(evicted, []);
+
+        manager.feedback(texture, [tile(0, 0, 1), tile(0, 1, 1), tile(10, 0, 0)]);
+        let batch = manager.update();
+        let evicted: Vec<_> = batch.evicted.iter().map(|m| m.tile).collect();
+        assert_eq!(evicted, [tile(0, 0, 0), tile(0, 1, 0)]);
+        // Evicted pool tiles are reused for the new tiles.
+        let mut reused: Vec<_> = batch
+            .mapped
+            .iter()
+            .flat_map(|m| m.pool_tiles.clone())
+            .collect();
+        let mut freed: Vec<_> = batch
+            .evicted
+            .iter()
+            .flat_map(|m| m.pool_tiles.clone())
+            .collect();
+        reused.sort();
+        freed.sort();
+        assert_eq!(reused, freed);
+    }
+
+    #[test]
+    fn test_over_budget() {
+        let mut manager = ResidencyManager::new(2, 16);
+        let texture = manager.add_resource(grid());
+
+        // Tiles used this frame are never evicted, so the third request has to wait.
+        manager.feedback(texture, [tile(0, 0, 0), tile(0, 1, 0), tile(0, 2, 0)]);
+        let batch = manager.update();
+        assert_eq!(batch.mapped.len(), 2);
+        assert!(batch.evicted.is_empty());
+        complete_all(&mut manager, &batch);
+
+        // Next frame only the pending tile is needed, so it replaces the least recently used.
+        let batch = manager.update();
+        assert_eq!(batch.evicted.len(), 1);
+        assert_eq!(batch.mapped[0].tile, tile(0, 2, 0));
+    }
+
+    #[test]
+    fn test_no_eviction_without_room() {
+        // A packed tail of 3 tiles in a pool of 4.
+        let mut manager = ResidencyManager::new(4, 16);
+        let texture = manager.add_resource(grid().with_packed_mips(3, 3));
+        manager.feedback(texture, [tile(0, 0, 0), tile(0, 1, 0), tile(0, 2, 0)]);
+        let batch = manager.update();
+        complete_all(&mut manager, &batch);
+
+        // Evicting the only unused tile doesn't make room for the tail, so nothing is evicted.
+        manager.feedback(texture, [tile(0, 0, 0), tile(0, 1, 0), tile(3, 0, 0)]);
+        let batch = manager.update();
+        assert!(batch.evicted.is_empty());
+        assert!(batch.mapped.is_empty());
+        assert!(batch.rejected.is_empty());
+
+        // Once enough tiles are unused the tail replaces them.
+        let batch = manager.update();
+        assert_eq!(batch.evicted.len(), 2);
+        assert_eq!(batch.mapped[0].tile, tile(3, 0, 0));
+    }
+
+    #[test]
+    fn test_larger_than_pool() {
+        let mut manager = ResidencyManager::new(2, 16);
+        let texture = manager.add_resource(grid().with_packed_mips(3, 3));
+        manager.feedback(texture, [tile(0, 0, 0)]);
+        let batch = manager.update();
+        complete_all(&mut manager, &batch);
+
+        manager.feedback(texture, [tile(3, 0, 0)]);
+        let batch = manager.update();
+        assert!(batch.evicted.is_empty());
+        assert_eq!(batch.rejected, [(texture, tile(3, 0, 0))]);
+        assert!(manager.is_resident(texture, tile(0, 0, 0)));
+        // The rejected tail is not retried.
+        assert_eq!(manager.update(), FrameBatch::default());
+    }
+
+    #[test]
+    fn test_loading_tiles_are_not_evicted() {
+        let mut manager = ResidencyManager::new(1, 16);
+        let texture = manager.add_resource(grid());
+
+        manager.feedback(texture, [tile(0, 0, 0)]);
+        let first = manager.update();
+        manager.feedback(texture, [tile(0, 1, 0)]);
+        let batch = manager.update();
+        assert!(batch.mapped.is_empty());
+
+        complete_all(&mut manager, &first);
+        let batch = manager.update();
+        assert_eq!(batch.evicted[0].tile, tile(0, 0, 0));
+        assert_eq!(batch.mapped[0].tile, tile(0, 1, 0));
+    }
+}
@@ -0,0 +1,1295 @@
+//! Recording queue activity and exporting it as a timeline.
+//!
+//! A [`TraceRecorder`] records requests, submits, status and signal writes and cancellations per
+//! queue.  Requests are considered finished when a status or signal enqueued after them completes,
+//! or when they are cancelled, which gives their submit-to-complete latency.  Recordings can be
+//! summarized with [`TraceRecorder::stats()`] or exported for viewing in
+//! [Perfetto](https://ui.perfetto.dev) or `chrome://tracing`, either as Chrome trace JSON
+//! ([`TraceRecorder::write_chrome_json()`]) or as a Perfetto protobuf trace
+//! ([`TraceRecorder::write_perfetto()`]).
+//!
+//! Overlapping requests of a queue are spread over multiple lanes in the exported timeline, and
+//! the number of completed bytes per queue is exported as a counter.
+//!
+//! On Windows, `TracedQueue` wraps an `IDStorageQueue` and records everything enqueued on it.
+//! With the `tracing` feature enabled, it also opens a `dstorage_request` span for every request,
+//! which closes once the request finished and records its `HRESULT`.  Finished requests can
+//! also be fed into a shared [`Metrics`] registry with `TracedQueue::set_metrics()`.
+
+#[cfg(all(windows, feature = "tracing"))]
+use std::collections::HashMap;
+#[cfg(windows)]
+use std::sync::{Arc, Mutex};
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+
+#[cfg(windows)]
+use windows::Win32::Graphics::Direct3D12::ID3D12Fence;
+#[cfg(all(windows, feature = "tracing"))]
+use windows_core::Interface;
+
+#[cfg(all(windows, feature = "tracing"))]
+use crate::IDStorageFile;
+use crate::{
+    metrics::Metrics,
+    priority::Priority,
+    scheduler::{Clock, SystemClock},
+};
+#[cfg(windows)]
+use crate::{IDStorageQueue, IDStorageStatusArray, DSTORAGE_REQUEST, DSTORAGE_REQUEST_SOURCE_FILE};
+
+/// Name of a `DSTORAGE_COMPRESSION_FORMAT` value.
+pub fn compression_format_name(format: u8) -> &'static str {
+    match format {
+        0 => "none",
+        1 => "gdeflate",
+        128.. => "custom",
+        _ => "unknown",
+    }
+}
+
+/// Name of a `DSTORAGE_REQUEST_DESTINATION_TYPE` value.
+pub fn destination_type_name(destination_type: u64) -> &'static str {
+    match destination_type {
+        0 => "memory",
+        1 => "buffer",
+        2 => "texture_region",
+        3 => "multiple_subresources",
+        4 => "tiles",
+        5 => "multiple_subresources_range",
+        _ => "unknown",
+    }
+}
+
+/// Identifies a queue added to a [`TraceRecorder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct QueueId(pub usize);
+
+/// Index of a request in [`TraceRecorder::requests()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RequestId(pub usize);
+
+/// What is recorded about a request when it is enqueued.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RequestInfo {
+    /// `DSTORAGE_REQUEST::Name`.
+    pub name: Option<String>,
+    /// Number of bytes read from the source.
+    pub size: u32,
+    pub uncompressed_size: u32,
+    pub compression_format: u8,
+    pub destination_type: u64,
+    pub cancellation_tag: u64,
+}
+
+#[cfg(windows)]
+impl RequestInfo {
+    /// # Safety
+    /// `request.Name` must be null or point to a null-terminated string, and the source must
+    /// match `request.Options.SourceType()`.
+    pub unsafe fn from_request(request: &DSTORAGE_REQUEST) -> Self {
+        let size = if request.Options.SourceType() == DSTORAGE_REQUEST_SOURCE_FILE {
+            unsafe { request.Source.File.Size }
+        } else {
+            unsafe { request.Source.Memory.Size }
+        };
+        Self {
+            name: (!request.Name.is_null())
+                .then(|| unsafe { request.Name.to_string() }.ok())
+                .flatten(),
+            size,
+            uncompressed_size: request.UncompressedSize,
+            compression_format: request.Options.CompressionFormat().0,
+            destination_type: request.Options.DestinationType().0,
+            cancellation_tag: request.CancellationTag,
+        }
+    }
+}
+
+/// How a request finished.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Covered by a completed status or signal.  The `HRESULT` of a status is the first failure
+    /// of any request before it, so it can't be attributed to a single request; signals always
+    /// report `0`.
+    Completed {
+        hresult: i32,
+    },
+    Cancelled,
+}
+
+/// A recorded request.  Times are relative to the creation of the recorder.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RequestRecord {
+    pub queue: QueueId,
+    pub info: RequestInfo,
+    pub enqueued: Duration,
+    pub submitted: Option<Duration>,
+    pub finished: Option<(Duration, Outcome)>,
+}
+
+impl RequestRecord {
+    /// Time from submit to completion, for requests that completed.
+    pub fn latency(&self) -> Option<Duration> {
+        match self.finished? {
+            (finished, Outcome::Completed { .. }) => Some(finished.saturating_sub(self.submitted?)),
+            (_, Outcome::Cancelled) => None,
+        }
+    }
+}
+
+/// A recorded queue operation other than enqueuing a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Submit,
+    EnqueueStatus { index: u32 },
+    EnqueueSignal { value: u64 },
+    StatusComplete { index: u32, hresult: i32 },
+    SignalComplete { value: u64 },
+    Cancel { mask: u64, value: u64 },
+}
+
+impl EventKind {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Submit => "Submit",
+            Self::EnqueueStatus { .. } => "EnqueueStatus",
+            Self::EnqueueSignal { .. } => "EnqueueSignal",
+            Self::StatusComplete { .. } => "StatusComplete",
+            Self::SignalComplete { .. } => "SignalComplete",
+            Self::Cancel { .. } => "CancelRequestsWithTag",
+        }
+    }
+
+    fn args(&self) -> Vec<(&'static str, Arg)> {
+        match *self {
+            Self::Submit => vec![],
+            Self::EnqueueStatus { index } => vec![("index", Arg::Uint(index.into()))],
+            Self::EnqueueSignal { value } | Self::SignalComplete { value } => {
+                vec![("value", Arg::Uint(value))]
+            }
+            Self::StatusComplete { index, hresult } => vec![
+                ("index", Arg::Uint(index.into())),
+                ("hresult", Arg::Hresult(hresult)),
+            ],
+            Self::Cancel { mask, value } => {
+                vec![("mask", Arg::Uint(mask)), ("value", Arg::Uint(value))]
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueEvent {
+    pub queue: QueueId,
+    pub at: Duration,
+    pub kind: EventKind,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Marker {
+    Status(u32),
+    Signal(u64),
+}
+
+struct QueueTrack {
+    name: String,
+    priority: Priority,
+    requests: Vec<RequestId>,
+    /// Pending markers with the number of requests enqueued before them.
+    markers: Vec<(Marker, usize)>,
+}
+
+/// Summary of the requests of a queue.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QueueStats {
+    pub queue: QueueId,
+    pub name: String,
+    pub completed: usize,
+    pub failed: usize,
+    pub cancelled: usize,
+    /// Source bytes of successfully completed requests.
+    pub bytes: u64,
+    pub mean_latency: Duration,
+    pub max_latency: Duration,
+    /// [`QueueStats::bytes`] per second, from the first submit to the last completion.
+    pub bandwidth: f64,
+}
+
+/// Records queue activity, see the [module documentation](self).
+pub struct TraceRecorder<C: Clock = SystemClock> {
+    clock: C,
+    start: Instant,
+    queues: Vec<QueueTrack>,
+    requests: Vec<RequestRecord>,
+    events: Vec<QueueEvent>,
+}
+
+impl Default for TraceRecorder {
+    fn default() -> Self {
+        Self::new(SystemClock)
+    }
+}
+
+impl<C: Clock> TraceRecorder<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            start: clock.now(),
+            clock,
+            queues: Vec::new(),
+            requests: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
+
+    fn now(&self) -> Duration {
+        self.clock.now().saturating_duration_since(self.start)
+    }
+
+    pub fn add_queue(&mut self, name: &str, priority: Priority) -> QueueId {
+        self.queues.push(QueueTrack {
+            name: name.to_owned(),
+            priority,
+            requests: Vec::new(),
+            markers: Vec::new(),
+        });
+        QueueId(self.queues.len() - 1)
+    }
+
+    pub fn requests(&self) -> &[RequestRecord] {
+        &self.requests
+    }
+
+    /// Record request `id` in `metrics` if it finished, and return whether it did.  Completed
+    /// requests add their latency and sizes.  Cancelled and failed requests are not recorded:
+    /// the `HRESULT` of a failed status covers every request before it, so it should be passed
+    /// to [`Metrics::record_error()`] once per status instead.
+    pub fn record_metrics<M: Clock>(&self, id: RequestId, metrics: &mut Metrics<M>) -> bool {
+        let request = &self.requests[id.0];
+        match request.finished {
+            None => return false,
+            Some((_, Outcome::Cancelled)) => {}
+            Some((_, Outcome::Completed { hresult })) if hresult < 0 => {}
+            Some((_, Outcome::Completed { .. })) => metrics.record_request(
+                self.queues[request.queue.0].priority,
+                request.info.size.into(),
+                request.info.uncompressed_size.into(),
+                request.latency().unwrap_or_default(),
+            ),
+        }
+        true
+    }
+
+    pub fn events(&self) -> &[QueueEvent] {
+        &self.events
+    }
+
+    fn event(&mut self, queue: QueueId, kind: EventKind) {
+        let at = self.now();
+        self.events.push(QueueEvent { queue, at, kind });
+    }
+
+    pub fn enqueue_request(&mut self, queue: QueueId, info: RequestInfo) -> RequestId {
+        let id = RequestId(self.requests.len());
+        self.requests.push(RequestRecord {
+            queue,
+            info,
+            enqueued: self.now(),
+            submitted: None,
+            finished: None,
+        });
+        self.queues[queue.0].requests.push(id);
+        id
+    }
+
+    pub fn submit(&mut self, queue: QueueId) {
+        let now = self.now();
+        for id in &self.queues[queue.0].requests {
+            let request = &mut self.requests[id.0];
+            // Requests cancelled before the submit never reach the hardware.
+            if request.finished.is_none() {
+                request.submitted.get_or_insert(now);
+            }
+        }
+        self.event(queue, EventKind::Submit);
+    }
+
+    fn enqueue_marker(&mut self, queue: QueueId, marker: Marker) {
+        let track = &mut self.queues[queue.0];
+        track.markers.push((marker, track.requests.len()));
+    }
+
+    pub fn enqueue_status(&mut self, queue: QueueId, index: u32) {
+        self.enqueue_marker(queue, Marker::Status(index));
+        self.event(queue, EventKind::EnqueueStatus { index });
+    }
+
+    pub fn enqueue_signal(&mut self, queue: QueueId, value: u64) {
+        self.enqueue_marker(queue, Marker::Signal(value));
+        self.event(queue, EventKind::EnqueueSignal { value });
+    }
+
+    /// Finish all unfinished requests of `queue` enqueued before `marker`.
+    fn complete_marker(&mut self, queue: QueueId, marker: Marker, outcome: Outcome) {
+        let now = self.now();
+        let track = &mut self.queues[queue.0];
+        let Some(position) = track.markers.iter().position(|(m, _)| *m == marker) else {
+            return;
+        };
+        let (_, end) = track.markers.remove(position);
+        for id in &track.requests[..end] {
+            self.requests[id.0].finished.get_or_insert((now, outcome));
+        }
+    }
+
+    /// Record that status `index` of `queue` completed with `hresult`.
+    pub fn complete_status(&mut self, queue: QueueId, index: u32, hresult: i32) {
+        self.complete_marker(queue, Marker::Status(index), Outcome::Completed { hresult });
+        self.event(queue, EventKind::StatusComplete { index, hresult });
+    }
+
+    /// Record that the fence signaled with `value` on `queue` reached it.
+    pub fn complete_signal(&mut self, queue: QueueId, value: u64) {
+        self.complete_marker(
+            queue,
+            Marker::Signal(value),
+            Outcome::Completed { hresult: 0 },
+        );
+        self.event(queue, EventKind::SignalComplete { value });
+    }
+
+    /// Record `CancelRequestsWithTag(mask, value)` on `queue`, finishing all matching requests.
+    pub fn cancel(&mut self, queue: QueueId, mask: u64, value: u64) {
+        let now = self.now();
+        for id in &self.queues[queue.0].requests {
+            let request = &mut self.requests[id.0];
+            if request.info.cancellation_tag & mask == value {
+                request.finished.get_or_insert((now, Outcome::Cancelled));
+            }
+        }
+        self.event(queue, EventKind::Cancel { mask, value });
+    }
+
+    pub fn stats(&self) -> Vec<QueueStats> {
+        self.queues
+            .iter()
+            .enumerate()
+            .map(|(i, track)| {
+                let mut stats = QueueStats {
+                    queue: QueueId(i),
+                    name: track.name.clone(),
+                    completed: 0,
+                    failed: 0,
+                    cancelled: 0,
+                    bytes: 0,
+                    mean_latency: Duration::ZERO,
+                    max_latency: Duration::ZERO,
+                    bandwidth: 0.0,
+                };
+                let mut total_latency = Duration::ZERO;
+                let mut first_submit: Option<Duration> = None;
+                let mut last_completion = Duration::ZERO;
+                for request in track.requests.iter().map(|id| &self.requests[id.0]) {
+                    let Some((finished, outcome)) = request.finished else {
+                        continue;
+                    };
+                    match outcome {
+                        Outcome::Cancelled => stats.cancelled += 1,
+                        Outcome::Completed { hresult } if hresult < 0 => stats.failed += 1,
+                        Outcome::Completed { .. } => {
+                            stats.completed += 1;
+                            stats.bytes += request.info.size as u64;
+                            let latency = request.latency().unwrap_or_default();
+                            total_latency += latency;
+                            stats.max_latency = stats.max_latency.max(latency);
+                            if let Some(submitted) = request.submitted {
+                                first_submit =
+                                    Some(first_submit.map_or(submitted, |f| f.min(submitted)));
+                            }
+                            last_completion = last_completion.max(finished);
+                        }
+                    }
+                }
+                if stats.completed > 0 {
+                    stats.mean_latency = total_latency / stats.completed as u32;
+                }
+                let elapsed = last_completion.saturating_sub(first_submit.unwrap_or_default());
+                if !elapsed.is_zero() {
+                    stats.bandwidth = stats.bytes as f64 / elapsed.as_secs_f64();
+                }
+                stats
+            })
+            .collect()
+    }
+
+    /// Assign tracks: one per queue for its events, followed by as many lanes as needed for its
+    /// overlapping requests.
+    fn layout(&self) -> Layout {
+        let mut layout = Layout {
+            tracks: Vec::new(),
+            event_tracks: Vec::new(),
+            request_tracks: vec![None; self.requests.len()],
+        };
+        for track in &self.queues {
+            layout.event_tracks.push(layout.tracks.len());
+            layout
+                .tracks
+                .push(format!("{} ({:?})", track.name, track.priority));
+
+            let mut spans: Vec<(Duration, Duration, RequestId)> = track
+                .requests
+                .iter()
+                .filter_map(|&id| {
+                    let request = &self.requests[id.0];
+                    Some((request.submitted?, request.finished?.0, id))
+                })
+                .collect();
+            spans.sort();
+            // End of the last span on every lane.
+            let mut lanes: Vec<(Duration, usize)> = Vec::new();
+            for (start, end, id) in spans {
+                let lane = match lanes.iter().position(|&(lane_end, _)| lane_end <= start) {
+                    Some(lane) => lane,
+                    None => {
+                        lanes.push((Duration::ZERO, layout.tracks.len()));
+                        layout
+                            .tracks
+                            .push(format!("{} requests {}", track.name, lanes.len()));
+                        lanes.len() - 1
+                    }
+                };
+                lanes[lane].0 = end;
+                layout.request_tracks[id.0] = Some(lanes[lane].1);
+            }
+        }
+        layout
+    }
+
+    fn request_args(&self, request: &RequestRecord) -> Vec<(&'static str, Arg)> {
+        let info = &request.info;
+        let mut args = vec![
+            ("size", Arg::Uint(info.size.into())),
+            (
+                "uncompressed_size",
+                Arg::Uint(info.uncompressed_size.into()),
+            ),
+            (
+                "compression",
+                Arg::Str(compression_format_name(info.compression_format).into()),
+            ),
+            (
+                "destination",
+                Arg::Str(destination_type_name(info.destination_type).into()),
+            ),
+            (
+                "priority",
+                Arg::Str(format!("{:?}", self.queues[request.queue.0].priority)),
+            ),
+            ("cancellation_tag", Arg::Uint(info.cancellation_tag)),
+        ];
+        match request.finished {
+            Some((_, Outcome::Completed { hresult })) => {
+                args.push(("hresult", Arg::Hresult(hresult)))
+            }
+            Some((_, Outcome::Cancelled)) => args.push(("cancelled", Arg::Uint(1))),
+            None => {}
+        }
+        args
+    }
+
+    /// Completed bytes per queue after each completion, as `(queue, time, bytes)`.
+    fn byte_counters(&self) -> Vec<(QueueId, Duration, u64)> {
+        let mut completions: Vec<(Duration, QueueId, u64)> = self
+            .requests
+            .iter()
+            .filter_map(|r| match r.finished? {
+                (at, Outcome::Completed { hresult }) if hresult >= 0 => {
+                    Some((at, r.queue, r.info.size as u64))
+                }
+                _ => None,
+            })
+            .collect();
+        completions.sort();
+        let mut totals = vec![0; self.queues.len()];
+        let mut counters: Vec<(QueueId, Duration, u64)> = Vec::new();
+        for (at, queue, size) in completions {
+            totals[queue.0] += size;
+            // Requests finished by the same marker share one sample.
+            match counters.last_mut() {
+                Some(last) if last.0 == queue && last.1 == at => last.2 = totals[queue.0],
+                _ => counters.push((queue, at, totals[queue.0])),
+            }
+        }
+        counters
+    }
+
+    /// Write the recording in the Chrome trace event JSON format.
+    pub fn write_chrome_json(&self, mut writer: impl Write) -> io::Result<()> {
+        let layout = self.layout();
+        let mut events = vec![
+            r#"{"ph":"M","pid":1,"name":"process_name","args":{"name":"DirectStorage"}}"#
+                .to_owned(),
+        ];
+        for (tid, name) in layout.tracks.iter().enumerate() {
+            events.push(format!(
+                r#"{{"ph":"M","pid":1,"tid":{tid},"name":"thread_name","args":{{"name":{}}}}}"#,
+                json_string(name)
+            ));
+            events.push(format!(
+                r#"{{"ph":"M","pid":1,"tid":{tid},"name":"thread_sort_index","args":{{"sort_index":{tid}}}}}"#
+            ));
+        }
+        for (request, track) in self.requests.iter().zip(&layout.request_tracks) {
+            let (Some(track), Some(submitted), Some((finished, _))) =
+                (track, request.submitted, request.finished)
+            else {
+                continue;
+            };
+            events.push(format!(
+                r#"{{"ph":"X","pid":1,"tid":{track},"ts":{},"dur":{},"name":{},"args":{}}}"#,
+                micros(submitted),
+                micros(finished.saturating_sub(submitted)),
+                json_string(request.info.name.as_deref().unwrap_or("request")),
+                json_args(&self.request_args(request)),
+            ));
+        }
+        for event in &self.events {
+            events.push(format!(
+                r#"{{"ph":"i","s":"t","pid":1,"tid":{},"ts":{},"name":"{}","args":{}}}"#,
+                layout.event_tracks[event.queue.0],
+                micros(event.at),
+                event.kind.name(),
+                json_args(&event.kind.args()),
+            ));
+        }
+        for (queue, at, bytes) in self.byte_counters() {
+            events.push(format!(
+                r#"{{"ph":"C","pid":1,"ts":{},"name":{},"args":{{"bytes":{bytes}}}}}"#,
+                micros(at),
+                json_string(&format!("{} completed bytes", self.queues[queue.0].name)),
+            ));
+        }
+
+        writeln!(writer, r#"{{"displayTimeUnit":"ms","traceEvents":["#)?;
+        for (i, event) in events.iter().enumerate() {
+            let separator = if i + 1 < events.len() { "," } else { "" };
+            writeln!(writer, "{event}{separator}")?;
+        }
+        writeln!(writer, "]}}")
+    }
+
+    /// Write the recording as a Perfetto protobuf trace of track events.
+    pub fn write_perfetto(&self, mut writer: impl Write) -> io::Result<()> {
+        const SEQUENCE_ID: u64 = 1;
+        const TYPE_SLICE_BEGIN: u64 = 1;
+        const TYPE_SLICE_END: u64 = 2;
+        const TYPE_INSTANT: u64 = 3;
+        const TYPE_COUNTER: u64 = 4;
+        // Track uuids start at 1, counter tracks follow the timeline tracks.
+        let layout = self.layout();
+        let track_uuid = |track: usize| track as u64 + 1;
+        let counter_uuid = |queue: QueueId| (layout.tracks.len() + queue.0) as u64 + 1;
+
+        let mut packet = |timestamp: Option<Duration>, field: u32, message: Proto| {
+            let mut p = Proto::default();
+            if let Some(timestamp) = timestamp {
+                p.uint(8, timestamp.as_nanos() as u64);
+            }
+            p.uint(10, SEQUENCE_ID);
+            p.message(field, &message);
+            let mut trace = Proto::default();
+            trace.message(1, &p);
+            writer.write_all(&trace.0)
+        };
+        let descriptor = |uuid: u64, name: &str, counter: bool| {
+            let mut d = Proto::default();
+            d.uint(1, uuid);
+            d.string(2, name);
+            if counter {
+                d.message(8, &Proto::default());
+            }
+            d
+        };
+        let track_event = |kind: u64, uuid: u64, name: Option<&str>, args: &[(&str, Arg)]| {
+            let mut e = Proto::default();
+            e.uint(9, kind);
+            e.uint(11, uuid);
+            if let Some(name) = name {
+                e.string(23, name);
+            }
+            for (name, value) in args {
+                let mut a = Proto::default();
+                a.string(10, name);
+                match value {
+                    Arg::Uint(v) => a.uint(3, *v),
+                    Arg::Hresult(v) => a.uint(4, *v as i64 as u64),
+                    Arg::Str(v) => a.string(6, v),
+                }
+                e.message(4, &a);
+            }
+            e
+        };
+
+        for (track, name) in layout.tracks.iter().enumerate() {
+            packet(None, 60, descriptor(track_uuid(track), name, false))?;
+        }
+        for (q, track) in self.queues.iter().enumerate() {
+            let name = format!("{} completed bytes", track.name);
+            packet(None, 60, descriptor(counter_uuid(QueueId(q)), &name, true))?;
+        }
+
+        // (time, order at equal times, packet): slices end before others begin.
+        let mut events: Vec<(Duration, u8, Proto)> = Vec::new();
+        for (request, track) in self.requests.iter().zip(&layout.request_tracks) {
+            let (Some(track), Some(submitted), Some((finished, _))) =
+                (track, request.submitted, request.finished)
+            else {
+                continue;
+            };
+            let uuid = track_uuid(*track);
+            let name = request.info.name.as_deref().unwrap_or("request");
+            let args = self.request_args(request);
+            events.push((
+                submitted,
+                1,
+                track_event(TYPE_SLICE_BEGIN, uuid, Some(name), &args),
+            ));
+            events.push((finished, 0, track_event(TYPE_SLICE_END, uuid, None, &[])));
+        }
+        for event in &self.events {
+            let uuid = track_uuid(layout.event_tracks[event.queue.0]);
+            let e = track_event(
+                TYPE_INSTANT,
+                uuid,
+                Some(event.kind.name()),
+                &event.kind.args(),
+            );
+            events.push((event.at, 2, e));
+        }
+        for (queue, at, bytes) in self.byte_counters() {
+            let mut e = track_event(TYPE_COUNTER, counter_uuid(queue), None, &[]);
+            e.uint(30, bytes);
+            events.push((at, 2, e));
+        }
+        events.sort_by_key(|(at, order, _)| (*at, *order));
+        for (at, _, event) in events {
+            packet(Some(at), 11, event)?;
+        }
+        Ok(())
+    }
+}
+
+struct Layout {
+    tracks: Vec<String>,
+    event_tracks: Vec<usize>,
+    request_tracks: Vec<Option<usize>>,
+}
+
+enum Arg {
+    Uint(u64),
+    Hresult(i32),
+    Str(String),
+}
+
+fn micros(duration: Duration) -> String {
+    format!("{:.3}", duration.as_nanos() as f64 / 1000.0)
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_args(args: &[(&str, Arg)]) -> String {
+    let args: Vec<String> = args
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Arg::Uint(v) => v.to_string(),
+                Arg::Hresult(v) => json_string(&format!("{:#010x}", *v as u32)),
+                Arg::Str(v) => json_string(v),
+            };
+            format!("{}:{value}", json_string(name))
+        })
+        .collect();
+    format!("{{{}}}", args.join(","))
+}
+
+/// Minimal protobuf message encoder.
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.varint((field as u64) << 3);
+        self.varint(value);
+    }
+
+    fn bytes(&mut self, field: u32, data: &[u8]) {
+        self.varint((field as u64) << 3 | 2);
+        self.varint(data.len() as u64);
+        self.0.extend_from_slice(data);
+    }
+
+    fn string(&mut self, field: u32, value: &str) {
+        self.bytes(field, value.as_bytes());
+    }
+
+    fn message(&mut self, field: u32, message: &Proto) {
+        self.bytes(field, &message.0);
+    }
+}
+
+/// An `IDStorageQueue` that records everything enqueued on it into a shared [`TraceRecorder`].
+#[cfg(windows)]
+pub struct TracedQueue {
+    queue: IDStorageQueue,
+    id: QueueId,
+    recorder: Arc<Mutex<TraceRecorder>>,
+    statuses: Vec<(IDStorageStatusArray, u32)>,
+    signals: Vec<(ID3D12Fence, u64)>,
+    metrics: Option<Arc<Mutex<Metrics>>>,
+    /// Requests that were not recorded in `metrics` yet.
+    unrecorded: Vec<RequestId>,
+    /// Names of files for request spans, keyed by interface pointer.
+    #[cfg(feature = "tracing")]
+    file_names: HashMap<usize, String>,
+    /// Spans of requests that did not finish yet.
+    #[cfg(feature = "tracing")]
+    spans: Vec<(RequestId, tracing::Span)>,
+}
+
+#[cfg(windows)]
+impl TracedQueue {
+    /// Add `queue` to `recorder`, with the priority it was created with.
+    pub fn new(
+        queue: IDStorageQueue,
+        name: &str,
+        priority: Priority,
+        recorder: Arc<Mutex<TraceRecorder>>,
+    ) -> Self {
+        let id = recorder.lock().unwrap().add_queue(name, priority);
+        Self {
+            queue,
+            id,
+            recorder,
+            statuses: Vec::new(),
+            signals: Vec::new(),
+            metrics: None,
+            unrecorded: Vec::new(),
+            #[cfg(feature = "tracing")]
+            file_names: HashMap::new(),
+            #[cfg(feature = "tracing")]
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn queue(&self) -> &IDStorageQueue {
+        &self.queue
+    }
+
+    pub fn id(&self) -> QueueId {
+        self.id
+    }
+
+    /// Record requests enqueued from now on in `metrics` once they finished.
+    pub fn set_metrics(&mut self, metrics: Arc<Mutex<Metrics>>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::EnqueueRequest()` and [`RequestInfo::from_request()`].
+    pub unsafe fn enqueue_request(&mut self, request: &DSTORAGE_REQUEST) -> RequestId {
+        let info = unsafe { RequestInfo::from_request(request) };
+        #[cfg(feature = "tracing")]
+        let span = unsafe { self.request_span(request, &info) };
+        unsafe { self.queue.EnqueueRequest(request) };
+        let id = self.recorder.lock().unwrap().enqueue_request(self.id, info);
+        if self.metrics.is_some() {
+            self.unrecorded.push(id);
+        }
+        #[cfg(feature = "tracing")]
+        self.spans.push((id, span));
+        id
+    }
+
+    /// Name `file` in the spans of requests reading from it.
+    #[cfg(feature = "tracing")]
+    pub fn name_file(&mut self, file: &IDStorageFile, name: &str) {
+        self.file_names
+            .insert(file.as_raw() as usize, name.to_owned());
+    }
+
+    #[cfg(feature = "tracing")]
+    unsafe fn request_span(&self, request: &DSTORAGE_REQUEST, info: &RequestInfo) -> tracing::Span {
+        let (file, offset) = if request.Options.SourceType() == DSTORAGE_REQUEST_SOURCE_FILE {
+            let source = unsafe { &request.Source.File };
+            let file = source
+                .Source
+                .as_ref()
+                .and_then(|file| self.file_names.get(&(file.as_raw() as usize)))
+                .map_or("<unnamed>", String::as_str);
+            (file, source.Offset)
+        } else {
+            ("<memory>", 0)
+        };
+        tracing::info_span!(
+            "dstorage_request",
+            name = info.name.as_deref().unwrap_or(""),
+            queue = self.id.0,
+            file = file,
+            offset = offset,
+            size = info.size,
+            uncompressed_size = info.uncompressed_size,
+            destination = destination_type_name(info.destination_type),
+            compression = compression_format_name(info.compression_format),
+            hresult = tracing::field::Empty,
+            cancelled = tracing::field::Empty,
+        )
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::Submit()`.
+    pub unsafe fn submit(&self) {
+        self.recorder.lock().unwrap().submit(self.id);
+        unsafe { self.queue.Submit() };
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::EnqueueStatus()`.
+    pub unsafe fn enqueue_status(&mut self, status_array: &IDStorageStatusArray, index: u32) {
+        unsafe { self.queue.EnqueueStatus(status_array, index) };
+        self.statuses.push((status_array.clone(), index));
+        self.recorder.lock().unwrap().enqueue_status(self.id, index);
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::EnqueueSignal()`.
+    pub unsafe fn enqueue_signal(&mut self, fence: &ID3D12Fence, value: u64) {
+        unsafe { self.queue.EnqueueSignal(fence, value) };
+        self.signals.push((fence.clone(), value));
+        self.recorder.lock().unwrap().enqueue_signal(self.id, value);
+    }
+
+    /// # Safety
+    /// See `IDStorageQueue::CancelRequestsWithTag()`.
+    pub unsafe fn cancel_requests_with_tag(&mut self, mask: u64, value: u64) {
+        unsafe { self.queue.CancelRequestsWithTag(mask, value) };
+        let mut recorder = self.recorder.lock().unwrap();
+        recorder.cancel(self.id, mask, value);
+        record_metrics(&mut self.unrecorded, self.metrics.as_deref(), &recorder);
+        #[cfg(feature = "tracing")]
+        close_spans(&mut self.spans, &recorder);
+    }
+
+    /// Record the completion of statuses and signals enqueued through this queue.  Completion
+    /// is timestamped when it is observed, so this should be called regularly.  Every failed
+    /// status counts as one error in the metrics.
+    pub fn poll(&mut self) {
+        let mut recorder = self.recorder.lock().unwrap();
+        let mut errors = Vec::new();
+        self.statuses.retain(|(status_array, index)| {
+            if !unsafe { status_array.IsComplete(*index) } {
+                return true;
+            }
+            let hresult = match unsafe { status_array.GetHResult(*index) } {
+                Ok(()) => 0,
+                Err(e) => e.code().0,
+            };
+            recorder.complete_status(self.id, *index, hresult);
+            if hresult < 0 {
+                errors.push(hresult);
+            }
+            false
+        });
+        if let Some(metrics) = &self.metrics {
+            let mut metrics = metrics.lock().unwrap();
+            for hresult in errors {
+                metrics.record_error(hresult);
+            }
+        }
+        self.signals.retain(|(fence, value)| {
+            if unsafe { fence.GetCompletedValue() } < *value {
+                return true;
+            }
+            recorder.complete_signal(self.id, *value);
+            false
+        });
+        record_metrics(&mut self.unrecorded, self.metrics.as_deref(), &recorder);
+        #[cfg(feature = "tracing")]
+        close_spans(&mut self.spans, &recorder);
+    }
+}
+
+/// Record finished requests in `metrics` and forget them.
+#[cfg(windows)]
+fn record_metrics(
+    unrecorded: &mut Vec<RequestId>,
+    metrics: Option<&Mutex<Metrics>>,
+    recorder: &TraceRecorder,
+) {
+    let Some(metrics) = metrics else {
+        return;
+    };
+    let mut metrics = metrics.lock().unwrap();
+    unrecorded.retain(|id| !recorder.record_metrics(*id, &mut metrics));
+}
+
+/// Record the outcome of finished requests in their span and close it.
+#[cfg(all(windows, feature = "tracing"))]
+fn close_spans(spans: &mut Vec<(RequestId, tracing::Span)>, recorder: &TraceRecorder) {
+    spans.retain(|(id, span)| match recorder.requests()[id.0].finished {
+        None => true,
+        Some((_, Outcome::Completed { hresult })) => {
+            span.record(
+                "hresult",
+                tracing::field::display(format_args!("{:#010x}", hresult as u32)),
+            );
+            false
+        }
+        Some((_, Outcome::Cancelled)) => {
+            span.record("cancelled", true);
+            false
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scheduler::SimulatedClock;
+
+    const MS: Duration = Duration::from_millis(1);
+
+    fn info(name: &str, size: u32, tag: u64) -> RequestInfo {
+        RequestInfo {
+            name: Some(name.to_owned()),
+            size,
+            uncompressed_size: size * 2,
+            compression_format: 1,
+            destination_type: 1,
+            cancellation_tag: tag,
+        }
+    }
+
+    fn recording() -> TraceRecorder<SimulatedClock> {
+        let mut recorder = TraceRecorder::new(SimulatedClock::new());
+        let queue = recorder.add_queue("textures", Priority::High);
+        recorder.enqueue_request(queue, info("a", 1000, 1));
+        recorder.enqueue_request(queue, info("b", 3000, 2));
+        recorder.enqueue_status(queue, 0);
+        recorder.clock().advance(MS);
+        recorder.submit(queue);
+        recorder.clock().advance(MS);
+        recorder.complete_status(queue, 0, 0);
+        recorder.enqueue_request(queue, info("c \"quoted\"", 500, 2));
+        recorder.enqueue_signal(queue, 7);
+        recorder.submit(queue);
+        recorder.clock().advance(MS);
+        recorder.cancel(queue, !0, 2);
+        recorder.complete_signal(queue, 7);
+        recorder
+    }
+
+    #[test]
+    fn test_recording() {
+        let recorder = recording();
+        let requests = recorder.requests();
+        assert_eq!(requests[0].submitted, Some(MS));
+        assert_eq!(requests[0].latency(), Some(MS));
+        assert_eq!(
+            requests[1].finished,
+            Some((2 * MS, Outcome::Completed { hresult: 0 }))
+        );
+        assert_eq!(requests[2].finished, Some((3 * MS, Outcome::Cancelled)));
+        assert_eq!(requests[2].latency(), None);
+        assert_eq!(recorder.events().len(), 7);
+
+        let stats = &recorder.stats()[0];
+        assert_eq!((stats.completed, stats.failed, stats.cancelled), (2, 0, 1));
+        assert_eq!(stats.bytes, 4000);
+        assert_eq!(stats.mean_latency, MS);
+        assert_eq!(stats.bandwidth, 4000.0 / 0.001);
+    }
+
+    #[test]
+    fn test_cancel_before_submit() {
+        let mut recorder = TraceRecorder::new(SimulatedClock::new());
+        let queue = recorder.add_queue("meshes", Priority::Normal);
+        recorder.enqueue_request(queue, info("a", 100, 1));
+        recorder.cancel(queue, !0, 1);
+        recorder.clock().advance(MS);
+        recorder.submit(queue);
+
+        let request = &recorder.requests()[0];
+        assert_eq!(request.submitted, None);
+        assert_eq!(request.finished, Some((Duration::ZERO, Outcome::Cancelled)));
+        let mut json = Vec::new();
+        recorder.write_chrome_json(&mut json).unwrap();
+        recorder.write_perfetto(&mut Vec::new()).unwrap();
+    }
+
+    #[test]
+    fn test_record_metrics() {
+        let recorder = recording();
+        let mut metrics = Metrics::with_clock(SimulatedClock::new());
+        for id in 0..3 {
+            assert!(recorder.record_metrics(RequestId(id), &mut metrics));
+        }
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.latency[&Priority::High].count(), 2);
+        assert_eq!(snapshot.latency[&Priority::High].sum(), 2 * MS);
+        assert_eq!(
+            (snapshot.bytes_read, snapshot.bytes_delivered),
+            (4000, 8000)
+        );
+        assert!(snapshot.errors.is_empty());
+    }
+
+    #[test]
+    fn test_lanes() {
+        let recorder = recording();
+        let layout = recorder.layout();
+        // Requests `a` and `b` overlap, `c` starts after `a` finished.
+        assert_eq!(
+            layout.tracks,
+            [
+                "textures (High)",
+                "textures requests 1",
+                "textures requests 2"
+            ]
+        );
+        assert_eq!(layout.request_tracks, [Some(1), Some(2), Some(1)]);
+    }
+
+    #[test]
+    fn test_chrome_json() {
+        let mut json = Vec::new();
+        recording().write_chrome_json(&mut json).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with(r#"{"displayTimeUnit":"ms","traceEvents":["#));
+        assert!(json.contains(
+            r#"{"ph":"X","pid":1,"tid":1,"ts":1000.000,"dur":1000.000,"name":"a","args":{"size":1000,"uncompressed_size":2000,"compression":"gdeflate","destination":"buffer","priority":"High","cancellation_tag":1,"hresult":"0x00000000"}}"#
+        ));
+        assert!(json.contains(r#""name":"c \"quoted\"""#));
+        assert!(json.contains(r#""name":"textures completed bytes","args":{"bytes":4000}"#));
+        assert!(json.trim_end().ends_with("]}"));
+    }
+
+    #[test]
+    fn test_perfetto() {
+        let mut trace = Vec::new();
+        recording().write_perfetto(&mut trace).unwrap();
+
+        // Walk the top-level `Trace.packet` fields.
+        let mut packets = 0;
+        let mut rest = &trace[..];
+        while !rest.is_empty() {
+            assert_eq!(rest[0], 1 << 3 | 2);
+            let mut len = 0;
+            let mut shift = 0;
+            let mut i = 1;
+            loop {
+                len |= ((rest[i] & 0x7F) as usize) << shift;
+                shift += 7;
+                i += 1;
+                if rest[i - 1] < 0x80 {
+                    break;
+                }
+            }
+            rest = &rest[i + len..];
+            packets += 1;
+        }
+        // 3 + 1 track descriptors, 3 slices begin and end, 7 instants, 1 counter sample.
+        assert_eq!(packets, 4 + 6 + 7 + 1);
+    }
+
+    #[cfg(windows)]
+    mod traced_queue {
+        use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+        use windows::Win32::Graphics::Direct3D12::ID3D12Fence;
+        use windows_core::{implement, Ref, HRESULT};
+
+        use super::*;
+        use crate::{
+            IDStorageQueue_Impl, IDStorageStatusArray_Impl, DSTORAGE_ERROR_RECORD,
+            DSTORAGE_QUEUE_INFO, DSTORAGE_REQUEST_SOURCE_MEMORY, DSTORAGE_SOURCE,
+            DSTORAGE_SOURCE_MEMORY, E_DSTORAGE_END_OF_FILE,
+        };
+
+        /// Logs the operations forwarded by a [`TracedQueue`].
+        #[implement(IDStorageQueue)]
+        struct FakeQueue(Rc<RefCell<Vec<String>>>);
+
+        impl IDStorageQueue_Impl for FakeQueue_Impl {
+            fn EnqueueRequest(&self, request: *const DSTORAGE_REQUEST) {
+                let size = unsafe { (*request).Source.Memory.Size };
+                self.0.borrow_mut().push(format!("request {size}"));
+            }
+
+            fn EnqueueStatus(&self, _status_array: Ref<IDStorageStatusArray>, index: u32) {
+                self.0.borrow_mut().push(format!("status {index}"));
+            }
+
+            fn EnqueueSignal(&self, _fence: Ref<ID3D12Fence>, _value: u64) {
+                panic!("not used by the test")
+            }
+
+            fn Submit(&self) {
+                self.0.borrow_mut().push("submit".into());
+            }
+
+            fn CancelRequestsWithTag(&self, mask: u64, value: u64) {
+                self.0.borrow_mut().push(format!("cancel {mask} {value}"));
+            }
+
+            fn Close(&self) {}
+
+            fn GetErrorEvent(&self) -> windows::Win32::Foundation::HANDLE {
+                panic!("not used by TracedQueue")
+            }
+
+            fn RetrieveErrorRecord(&self, _record: *mut DSTORAGE_ERROR_RECORD) {
+                panic!("not used by TracedQueue")
+            }
+
+            fn Query(&self, _info: *mut DSTORAGE_QUEUE_INFO) {
+                panic!("not used by TracedQueue")
+            }
+        }
+
+        /// Result of every status array entry, [`None`] while incomplete.
+        #[implement(IDStorageStatusArray)]
+        struct FakeStatusArray(Rc<RefCell<Vec<Option<HRESULT>>>>);
+
+        impl IDStorageStatusArray_Impl for FakeStatusArray_Impl {
+            fn IsComplete(&self, index: u32) -> bool {
+                self.0.borrow()[index as usize].is_some()
+            }
+
+            fn GetHResult(&self, index: u32) -> windows_core::Result<()> {
+                self.0.borrow()[index as usize].unwrap().ok()
+            }
+        }
+
+        fn request(size: u32, tag: u64) -> DSTORAGE_REQUEST {
+            let mut request = DSTORAGE_REQUEST {
+                Source: DSTORAGE_SOURCE {
+                    Memory: DSTORAGE_SOURCE_MEMORY {
+                        Source: std::ptr::null(),
+                        Size: size,
+                    },
+                },
+                UncompressedSize: size,
+                CancellationTag: tag,
+                ..Default::default()
+            };
+            request
+                .Options
+                .set_SourceType(DSTORAGE_REQUEST_SOURCE_MEMORY);
+            request
+        }
+
+        #[test]
+        fn test_traced_queue() {
+            let log = Rc::<RefCell<Vec<String>>>::default();
+            let statuses = Rc::new(RefCell::new(vec![None; 2]));
+            let status_array: IDStorageStatusArray = FakeStatusArray(statuses.clone()).into();
+            let recorder = Arc::new(Mutex::new(TraceRecorder::default()));
+            let metrics = Arc::new(Mutex::new(Metrics::new()));
+            let mut queue = TracedQueue::new(
+                FakeQueue(log.clone()).into(),
+                "textures",
+                Priority::High,
+                recorder.clone(),
+            );
+            queue.set_metrics(metrics.clone());
+
+            unsafe {
+                queue.enqueue_request(&request(100, 0));
+                queue.enqueue_request(&request(200, 0));
+                queue.enqueue_status(&status_array, 0);
+                queue.enqueue_request(&request(300, 0));
+                queue.enqueue_request(&request(400, 0));
+                queue.enqueue_status(&status_array, 1);
+                queue.enqueue_request(&request(500, 1));
+                queue.submit();
+                queue.cancel_requests_with_tag(!0, 1);
+            }
+            assert_eq!(
+                *log.borrow(),
+                [
+                    "request 100",
+                    "request 200",
+                    "status 0",
+                    "request 300",
+                    "request 400",
+                    "status 1",
+                    "request 500",
+                    "submit",
+                    "cancel 18446744073709551615 1",
+                ]
+            );
+
+            queue.poll();
+            let finished = |recorder: &Mutex<TraceRecorder>| {
+                recorder
+                    .lock()
+                    .unwrap()
+                    .requests()
+                    .iter()
+                    .map(|r| r.finished.map(|(_, outcome)| outcome))
+                    .collect::<Vec<_>>()
+            };
+            let pending = [None, None, None, None, Some(Outcome::Cancelled)];
+            assert_eq!(finished(&recorder), pending);
+            assert_eq!(metrics.lock().unwrap().snapshot().bytes_read, 0);
+
+            statuses.borrow_mut()[0] = Some(HRESULT(0));
+            statuses.borrow_mut()[1] = Some(E_DSTORAGE_END_OF_FILE);
+            queue.poll();
+            let ok = Some(Outcome::Completed { hresult: 0 });
+            let failed = Some(Outcome::Completed {
+                hresult: E_DSTORAGE_END_OF_FILE.0,
+            });
+            assert_eq!(
+                finished(&recorder),
+                [ok, ok, failed, failed, Some(Outcome::Cancelled)]
+            );
+
+            let snapshot = metrics.lock().unwrap().snapshot();
+            assert_eq!(snapshot.latency[&Priority::High].count(), 2);
+            assert_eq!(snapshot.bytes_read, 300);
+            // The failed status counts once, not once per request it covers.
+            assert_eq!(
+                snapshot.errors,
+                BTreeMap::from([(E_DSTORAGE_END_OF_FILE.0, 1)])
+            );
+        }
+    }
+}
@@ -0,0 +1,537 @@
+//! A shared thread waiting on completion events of many queues at once.
+//!
+//! `IDStorageQueue1::EnqueueSetEvent()` signals an event once all requests enqueued before it
+//! completed.  Instead of creating an event and blocking a thread per wait, an [`EventWaiter`]
+//! takes events from a pool, waits on up to 63 of them per thread and either calls a callback or
+//! wakes an [`EventFuture`] when one is signaled.  Another thread is spawned whenever all
+//! existing ones are waiting on as many events as `WaitForMultipleObjects()` accepts.
+//!
+//! [`EventWaiter::watch_errors()`] waits on the error event of a queue as well, so that failures
+//! are pushed to the application instead of having to poll
+//! [`IDStorageQueue::RetrieveErrorRecord()`].
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use windows::Win32::{
+    Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0, WAIT_TIMEOUT},
+    System::Threading::{CreateEventW, SetEvent, WaitForMultipleObjects, INFINITE},
+};
+use windows_core::PCWSTR;
+
+use crate::{IDStorageQueue, IDStorageQueue1, DSTORAGE_ERROR_RECORD};
+
+/// Number of handles a single `WaitForMultipleObjects()` call accepts.
+const MAXIMUM_WAIT_OBJECTS: usize = 64;
+/// How long dropping an [`EventWaiter`] waits for enqueued events to be signaled.
+const DROP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An auto-reset event, closed when dropped.
+pub(crate) struct Event(pub(crate) HANDLE);
+
+// SAFETY: Event handles can be used from any thread.
+unsafe impl Send for Event {}
+
+impl Event {
+    pub(crate) fn new() -> windows_core::Result<Self> {
+        unsafe { CreateEventW(None, false, false, PCWSTR::null()) }.map(Self)
+    }
+}
+
+impl Drop for Event {
+    fn drop(&mut self) {
+        let _ = unsafe { CloseHandle(self.0) };
+    }
+}
+
+#[derive(Default)]
+struct FutureState {
+    done: bool,
+    waker: Option<Waker>,
+}
+
+/// Completes once the event it was created for is signaled, see
+/// [`EventWaiter::enqueue_future()`].
+pub struct EventFuture {
+    state: Arc<Mutex<FutureState>>,
+}
+
+impl Future for EventFuture {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.done {
+            Poll::Ready(())
+        } else {
+            state.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+enum Action {
+    Callback(Box<dyn FnOnce() + Send>),
+    Future(Arc<Mutex<FutureState>>),
+}
+
+impl Action {
+    fn run(self) {
+        match self {
+            Self::Callback(callback) => callback(),
+            Self::Future(state) => {
+                let mut state = state.lock().unwrap();
+                state.done = true;
+                if let Some(waker) = state.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+struct Wait {
+    event: Event,
+    action: Action,
+}
+
+/// A queue whose error event is waited on.
+struct ErrorWatch {
+    queue: IDStorageQueue,
+    callback: Box<dyn FnOnce(DSTORAGE_ERROR_RECORD) + Send>,
+}
+
+// SAFETY: DirectStorage interfaces are free-threaded.
+unsafe impl Send for ErrorWatch {}
+
+#[derive(Default)]
+struct State {
+    /// Set on shutdown, the waits still outstanding at this deadline are abandoned.
+    shutdown: Option<Instant>,
+    waits: Vec<Wait>,
+    errors: Vec<ErrorWatch>,
+    pool: Vec<Event>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    /// Interrupts the waiter thread when waits are added or on shutdown.
+    wake: Event,
+}
+
+// SAFETY: The state is behind a mutex and events can be signaled from any thread.
+unsafe impl Sync for Shared {}
+
+/// A thread waiting on the wake-up event and at most `MAXIMUM_WAIT_OBJECTS - 1` other events.
+struct Worker {
+    shared: Arc<Shared>,
+    thread: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn() -> io::Result<Self> {
+        let shared = Arc::new(Shared {
+            state: Mutex::default(),
+            wake: Event::new()?,
+        });
+        let thread = std::thread::Builder::new()
+            .name("direct-storage-waiter".into())
+            .spawn({
+                let shared = shared.clone();
+                move || run(&shared)
+            })?;
+        Ok(Self { shared, thread })
+    }
+
+    fn is_full(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        1 + state.waits.len() + state.errors.len() >= MAXIMUM_WAIT_OBJECTS
+    }
+
+    fn wake(&self) -> windows_core::Result<()> {
+        unsafe { SetEvent(self.shared.wake.0) }
+    }
+}
+
+/// Waits on completion and error events of queues on dedicated threads.
+///
+/// Dropping the waiter stops watching for errors and blocks until all enqueued events were
+/// signaled, since DirectStorage would otherwise signal closed handles.  Events that are not
+/// signaled within ten seconds, for example because their queue was never submitted, are leaked
+/// instead and their callbacks and futures never complete.
+pub struct EventWaiter {
+    workers: Mutex<Vec<Worker>>,
+    drop_timeout: Duration,
+}
+
+impl EventWaiter {
+    /// Spawn the first waiter thread.
+    pub fn new() -> io::Result<Self> {
+        Ok(Self {
+            workers: Mutex::new(vec![Worker::spawn()?]),
+            drop_timeout: DROP_TIMEOUT,
+        })
+    }
+
+    /// Number of events in the pool that are not currently waited on.
+    pub fn pooled_events(&self) -> usize {
+        self.workers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|w| w.shared.state.lock().unwrap().pool.len())
+            .sum()
+    }
+
+    /// Call `add` with the state of a thread that can wait on one more event, spawning one if
+    /// all are full, then wake that thread.
+    fn add(
+        &self,
+        add: impl FnOnce(&mut State) -> windows_core::Result<()>,
+    ) -> windows_core::Result<()> {
+        // Only the waiter threads remove entries, so a thread can't fill up while the workers
+        // are locked.
+        let mut workers = self.workers.lock().unwrap();
+        let worker = match workers.iter().position(|w| !w.is_full()) {
+            Some(index) => &workers[index],
+            None => {
+                workers.push(Worker::spawn()?);
+                workers.last().unwrap()
+            }
+        };
+        add(&mut worker.shared.state.lock().unwrap())?;
+        worker.wake()
+    }
+
+    fn enqueue(&self, queue: &IDStorageQueue1, action: Action) -> windows_core::Result<()> {
+        self.add(|state| {
+            let event = match state.pool.pop() {
+                Some(event) => event,
+                None => Event::new()?,
+            };
+            unsafe { queue.EnqueueSetEvent(event.0) };
+            state.waits.push(Wait { event, action });
+            Ok(())
+        })
+    }
+
+    /// Enqueue an event on `queue` and call `callback` on a waiter thread once all requests
+    /// enqueued before it completed.  The queue still has to be submitted.
+    pub fn enqueue_callback(
+        &self,
+        queue: &IDStorageQueue1,
+        callback: impl FnOnce() + Send + 'static,
+    ) -> windows_core::Result<()> {
+        self.enqueue(queue, Action::Callback(Box::new(callback)))
+    }
+
+    /// Like [`EventWaiter::enqueue_callback()`], returning a future that completes instead.
+    pub fn enqueue_future(&self, queue: &IDStorageQueue1) -> windows_core::Result<EventFuture> {
+        let state = Arc::<Mutex<FutureState>>::default();
+        self.enqueue(queue, Action::Future(state.clone()))?;
+        Ok(EventFuture { state })
+    }
+
+    /// Call `on_error` on a waiter thread with the error record of `queue` once a request on
+    /// it failed.  The record only holds the first failure, so `on_error` is called at most
+    /// once.
+    pub fn watch_errors(
+        &self,
+        queue: &IDStorageQueue,
+        on_error: impl FnOnce(DSTORAGE_ERROR_RECORD) + Send + 'static,
+    ) -> windows_core::Result<()> {
+        self.add(|state| {
+            state.errors.push(ErrorWatch {
+                queue: queue.clone(),
+                callback: Box::new(on_error),
+            });
+            Ok(())
+        })
+    }
+}
+
+impl Drop for EventWaiter {
+    fn drop(&mut self) {
+        let deadline = Instant::now() + self.drop_timeout;
+        let workers = std::mem::take(self.workers.get_mut().unwrap());
+        for worker in &workers {
+            worker.shared.state.lock().unwrap().shutdown = Some(deadline);
+            let _ = worker.wake();
+        }
+        for worker in workers {
+            let _ = worker.thread.join();
+        }
+    }
+}
+
+fn run(shared: &Shared) {
+    loop {
+        let mut handles = vec![shared.wake.0];
+        let num_errors;
+        let timeout;
+        {
+            let mut state = shared.state.lock().unwrap();
+            timeout = match state.shutdown {
+                None => INFINITE,
+                Some(deadline) => {
+                    state.errors.clear();
+                    if state.waits.is_empty() {
+                        return;
+                    }
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        // DirectStorage may still signal these events, they must stay open.
+                        for wait in state.waits.drain(..) {
+                            std::mem::forget(wait.event);
+                        }
+                        return;
+                    }
+                    remaining.as_millis().clamp(1, INFINITE as u128 - 1) as u32
+                }
+            };
+            num_errors = state.errors.len();
+            handles.extend(
+                state
+                    .errors
+                    .iter()
+                    .map(|e| unsafe { e.queue.GetErrorEvent() }),
+            );
+            handles.extend(state.waits.iter().map(|w| w.event.0));
+        }
+
+        let result = unsafe { WaitForMultipleObjects(&handles, false, timeout) };
+        if result == WAIT_TIMEOUT {
+            continue;
+        }
+        let index = (result.0.wrapping_sub(WAIT_OBJECT_0.0)) as usize;
+        // Abandoned or failed waits can't happen for events; treat them like a wake-up.
+        if index == 0 || index >= handles.len() {
+            continue;
+        }
+
+        // Entries are only removed by this thread, so the indices are still valid.
+        if index <= num_errors {
+            let watch = shared.state.lock().unwrap().errors.swap_remove(index - 1);
+            let record = unsafe { watch.queue.RetrieveErrorRecord() };
+            (watch.callback)(record);
+        } else {
+            let mut state = shared.state.lock().unwrap();
+            let wait = state.waits.swap_remove(index - 1 - num_errors);
+            state.pool.push(wait.event);
+            drop(state);
+            wait.action.run();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        task::Wake,
+        thread::{self, Thread},
+    };
+
+    use windows_core::{implement, Interface, Ref};
+
+    use super::*;
+    use crate::{
+        IDStorageQueue1_Impl, IDStorageQueue_Impl, IDStorageStatusArray, DSTORAGE_QUEUE_INFO,
+        DSTORAGE_REQUEST, E_DSTORAGE_END_OF_FILE,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Signals the events enqueued on it once submitted.  Failures are simulated by signaling
+    /// its error event, after which the error record reports [`E_DSTORAGE_END_OF_FILE`].
+    #[implement(IDStorageQueue, IDStorageQueue1)]
+    struct FakeQueue {
+        enqueued: Mutex<Vec<HANDLE>>,
+        error_event: Event,
+    }
+
+    impl FakeQueue {
+        fn new() -> Self {
+            Self {
+                enqueued: Mutex::default(),
+                error_event: Event::new().unwrap(),
+            }
+        }
+    }
+
+    impl IDStorageQueue_Impl for FakeQueue_Impl {
+        fn EnqueueRequest(&self, _request: *const DSTORAGE_REQUEST) {
+            panic!("not used by EventWaiter")
+        }
+
+        fn EnqueueStatus(&self, _status_array: Ref<IDStorageStatusArray>, _index: u32) {
+            panic!("not used by EventWaiter")
+        }
+
+        fn EnqueueSignal(
+            &self,
+            _fence: Ref<windows::Win32::Graphics::Direct3D12::ID3D12Fence>,
+            _value: u64,
+        ) {
+            panic!("not used by EventWaiter")
+        }
+
+        fn Submit(&self) {
+            for event in self.enqueued.lock().unwrap().drain(..) {
+                unsafe { SetEvent(event) }.unwrap();
+            }
+        }
+
+        fn CancelRequestsWithTag(&self, _mask: u64, _value: u64) {
+            panic!("not used by EventWaiter")
+        }
+
+        fn Close(&self) {}
+
+        fn GetErrorEvent(&self) -> HANDLE {
+            self.error_event.0
+        }
+
+        fn RetrieveErrorRecord(&self, record: *mut DSTORAGE_ERROR_RECORD) {
+            let record = unsafe { &mut *record };
+            record.FailureCount = 1;
+            record.FirstFailure.HResult = E_DSTORAGE_END_OF_FILE;
+        }
+
+        fn Query(&self, _info: *mut DSTORAGE_QUEUE_INFO) {
+            panic!("not used by EventWaiter")
+        }
+    }
+
+    impl IDStorageQueue1_Impl for FakeQueue_Impl {
+        fn EnqueueSetEvent(&self, handle: HANDLE) {
+            self.enqueued.lock().unwrap().push(handle);
+        }
+    }
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Arc::new(ThreadWaker(thread::current())).into();
+        let mut cx = Context::from_waker(&waker);
+        let mut future = std::pin::pin!(future);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+
+    #[test]
+    fn test_callbacks() {
+        let waiter = EventWaiter::new().unwrap();
+        let queue: IDStorageQueue1 = FakeQueue::new().into();
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..3 {
+            let sender = sender.clone();
+            waiter
+                .enqueue_callback(&queue, move || sender.send(i).unwrap())
+                .unwrap();
+        }
+        assert_eq!(waiter.pooled_events(), 0);
+        assert!(receiver.recv_timeout(Duration::from_millis(10)).is_err());
+
+        unsafe { queue.Submit() };
+        let mut completed: Vec<i32> = (0..3)
+            .map(|_| receiver.recv_timeout(TIMEOUT).unwrap())
+            .collect();
+        completed.sort_unstable();
+        assert_eq!(completed, [0, 1, 2]);
+        assert_eq!(waiter.pooled_events(), 3);
+
+        // Pooled events are reused.
+        let future = waiter.enqueue_future(&queue).unwrap();
+        assert_eq!(waiter.pooled_events(), 2);
+        unsafe { queue.Submit() };
+        block_on(future);
+    }
+
+    #[test]
+    fn test_many_waits() {
+        let waiter = EventWaiter::new().unwrap();
+        let queue: IDStorageQueue1 = FakeQueue::new().into();
+        let (sender, receiver) = mpsc::channel();
+        for i in 0..200 {
+            let sender = sender.clone();
+            waiter
+                .enqueue_callback(&queue, move || sender.send(i).unwrap())
+                .unwrap();
+        }
+        // Every thread waits on its wake-up event and 63 others.
+        assert_eq!(waiter.workers.lock().unwrap().len(), 4);
+
+        unsafe { queue.Submit() };
+        let mut completed: Vec<i32> = (0..200)
+            .map(|_| receiver.recv_timeout(TIMEOUT).unwrap())
+            .collect();
+        completed.sort_unstable();
+        assert!(completed.into_iter().eq(0..200));
+    }
+
+    #[test]
+    fn test_watch_errors() {
+        let waiter = EventWaiter::new().unwrap();
+        let queue: IDStorageQueue = FakeQueue::new().into();
+        let (sender, receiver) = mpsc::channel();
+        waiter
+            .watch_errors(&queue, move |record| {
+                sender
+                    .send((record.FailureCount, record.FirstFailure.HResult))
+                    .unwrap()
+            })
+            .unwrap();
+        assert!(receiver.recv_timeout(Duration::from_millis(10)).is_err());
+
+        unsafe { SetEvent(queue.GetErrorEvent()) }.unwrap();
+        assert_eq!(
+            receiver.recv_timeout(TIMEOUT),
+            Ok((1, E_DSTORAGE_END_OF_FILE))
+        );
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let queue: IDStorageQueue1 = FakeQueue::new().into();
+        let (sender, receiver) = mpsc::channel();
+
+        // Completed events don't delay shutdown.
+        let waiter = EventWaiter::new().unwrap();
+        let future = waiter.enqueue_future(&queue).unwrap();
+        unsafe { queue.Submit() };
+        block_on(future);
+        let start = Instant::now();
+        drop(waiter);
+        assert!(start.elapsed() < TIMEOUT);
+
+        // Events that are never signaled are given up on after the timeout.
+        let mut waiter = EventWaiter::new().unwrap();
+        waiter.drop_timeout = Duration::from_millis(10);
+        waiter
+            .enqueue_callback(&queue, move || sender.send(()).unwrap())
+            .unwrap();
+        waiter
+            .watch_errors(&queue.cast().unwrap(), |_| panic!("no error occurred"))
+            .unwrap();
+        drop(waiter);
+        assert_eq!(receiver.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+    }
+}